@@ -0,0 +1,29 @@
+#![no_main]
+use chasm_rs::run::{run_binary, RunOptions};
+use libfuzzer_sys::fuzz_target;
+use wasmi::Module;
+
+/// Enough to run small programs to the end, while still stopping the ones that never end quickly.
+const FUEL: u64 = 100_000;
+
+fuzz_target!(|source: &str| {
+    let binary = match chasm_rs::compile(source) {
+        Ok(binary) => binary,
+        Err(_) => return,
+    };
+    // if the source compiled, then it should be a valid wasm.
+    let _ = Module::from_buffer(&binary).unwrap();
+
+    // running it may trap or run out of fuel, but should never panic
+    let options = RunOptions {
+        fuel: Some(FUEL),
+        ..Default::default()
+    };
+    match run_binary(&binary, &options) {
+        Ok(output) => assert_eq!(output.canvas.len(), 100 * 100),
+        Err(chasm_rs::run::RunError::InvalidModule(err)) => {
+            panic!("the compiler emitted a invalid module: {}", err)
+        }
+        Err(_) => {}
+    }
+});
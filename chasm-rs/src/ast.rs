@@ -0,0 +1,821 @@
+//! The abstract syntax tree of a chasm program.
+//!
+//! The tree is produced by [`parse`](crate::parse), and every node keeps the byte range of the
+//! source code it was parsed from. The [`Display`](core::fmt::Display) implementation of
+//! [`Program`] prints the tree indented, one node per line, with its span.
+use crate::prelude::*;
+use core::fmt::{self, Display, Formatter};
+use logos::Span;
+
+/// A parsed chasm program. Its statements are the body of the procedure `main`, including the
+/// definitions of all other procedures.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program<'s> {
+    /// The top-level statements of the program.
+    pub statements: Vec<Statement<'s>>,
+}
+
+impl Program<'_> {
+    /// If the two programs have the same structure, up to a consistent renaming of the variables
+    /// and procedures, ignoring the spans. So programs that only differ in whitespace and names
+    /// are equal, like `var a = 1 print a` and `var b = 1\nprint b`.
+    ///
+    /// Each procedure, global and table of one program must always correspond to the same name in
+    /// the other, in both directions, and so must each variable in the function that declares it.
+    /// So two procedures can rename their parameters and variables independently. The builtins,
+    /// like `setpixel` and `sign`, and the variables written by `setpixel`, can't be renamed.
+    pub fn alpha_eq(&self, other: &Program) -> bool {
+        let mut renaming = Renaming::default();
+        shared_names(&self.statements, &mut renaming.shared_left);
+        shared_names(&other.statements, &mut renaming.shared_right);
+        renaming.block(&self.statements, &other.statements)
+    }
+}
+
+/// Collect the names that are the same in all functions of a program: the ones of the procedures,
+/// globals and tables.
+fn shared_names<'s>(statements: &[Statement<'s>], names: &mut HashSet<&'s str>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Proc { ident, body, .. } => {
+                names.insert(ident.name);
+                shared_names(body, names);
+            }
+            StatementKind::Global { ident, .. } | StatementKind::Data { ident, .. } => {
+                names.insert(ident.name);
+            }
+            StatementKind::While { body, .. }
+            | StatementKind::Repeat { body, .. }
+            | StatementKind::Loop { body } => shared_names(body, names),
+            StatementKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                shared_names(then_body, names);
+                shared_names(else_body.as_deref().unwrap_or_default(), names);
+            }
+            StatementKind::Switch { cases, default, .. } => {
+                for case in cases {
+                    shared_names(&case.body, names);
+                }
+                shared_names(default.as_deref().unwrap_or_default(), names);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Collect the variables declared in the body of a procedure, not counting the ones of the
+/// procedures it defines. They shadow the globals with the same name.
+fn local_names<'s>(statements: &[Statement<'s>], names: &mut HashSet<&'s str>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Var { ident, .. } => {
+                names.insert(ident.name);
+            }
+            StatementKind::Destructure { targets, .. } => {
+                names.extend(targets.iter().map(|x| x.name));
+            }
+            StatementKind::While { body, .. }
+            | StatementKind::Repeat { body, .. }
+            | StatementKind::Loop { body } => local_names(body, names),
+            StatementKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                local_names(then_body, names);
+                local_names(else_body.as_deref().unwrap_or_default(), names);
+            }
+            StatementKind::Switch { cases, default, .. } => {
+                for case in cases {
+                    local_names(&case.body, names);
+                }
+                local_names(default.as_deref().unwrap_or_default(), names);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A one-to-one correspondence between the names of two programs.
+#[derive(Default)]
+struct Names<'a, 'b> {
+    left: HashMap<&'a str, &'b str>,
+    right: HashMap<&'b str, &'a str>,
+}
+impl<'a, 'b> Names<'a, 'b> {
+    /// Check that `a` corresponds to `b`, recording it if neither was seen before.
+    fn pair(&mut self, a: &'a str, b: &'b str) -> bool {
+        *self.left.entry(a).or_insert(b) == b && *self.right.entry(b).or_insert(a) == a
+    }
+}
+
+/// The correspondence between the names of two programs compared by [`Program::alpha_eq`].
+#[derive(Default)]
+struct Renaming<'a, 'b> {
+    /// The names of the procedures, globals and tables.
+    shared: Names<'a, 'b>,
+    /// The names of the variables of the functions being compared.
+    function: Names<'a, 'b>,
+    shared_left: HashSet<&'a str>,
+    shared_right: HashSet<&'b str>,
+    /// The variables declared by the procedures being compared, empty in `main`.
+    locals_left: HashSet<&'a str>,
+    locals_right: HashSet<&'b str>,
+}
+impl<'a, 'b> Renaming<'a, 'b> {
+    /// Check that `a` corresponds to `b`, recording it if neither was seen before.
+    fn name(&mut self, a: &'a str, b: &'b str) -> bool {
+        let builtin = |x| matches!(x, "setpixel" | "blendpixel" | "setrgb");
+        if builtin(a) || builtin(b) {
+            return a == b;
+        }
+        let shared_a = self.shared_left.contains(a) && !self.locals_left.contains(a);
+        let shared_b = self.shared_right.contains(b) && !self.locals_right.contains(b);
+        match (shared_a, shared_b) {
+            (true, true) => self.shared.pair(a, b),
+            (false, false) => self.function.pair(a, b),
+            _ => false,
+        }
+    }
+
+    /// Compare the parameters and the bodies of two procedures, whose variables are renamed
+    /// independently of the other functions.
+    fn procedure(
+        &mut self,
+        (params, body): (&[Param<'a>], &[Statement<'a>]),
+        (p, b): (&[Param<'b>], &[Statement<'b>]),
+    ) -> bool {
+        let mut locals_left: HashSet<_> = params.iter().map(|x| x.ident.name).collect();
+        local_names(body, &mut locals_left);
+        let mut locals_right: HashSet<_> = p.iter().map(|x| x.ident.name).collect();
+        local_names(b, &mut locals_right);
+
+        let outer = (
+            core::mem::take(&mut self.function),
+            core::mem::replace(&mut self.locals_left, locals_left),
+            core::mem::replace(&mut self.locals_right, locals_right),
+        );
+        let equal = params.len() == p.len()
+            && params.iter().zip(p).all(|(a, b)| {
+                self.name(a.ident.name, b.ident.name)
+                    && match (&a.default, &b.default) {
+                        (Some(a), Some(b)) => self.expression(a, b),
+                        (None, None) => true,
+                        _ => false,
+                    }
+            })
+            && self.block(body, b);
+        (self.function, self.locals_left, self.locals_right) = outer;
+        equal
+    }
+
+    fn block(&mut self, a: &[Statement<'a>], b: &[Statement<'b>]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| self.statement(a, b))
+    }
+
+    fn expressions(&mut self, a: &[Expression<'a>], b: &[Expression<'b>]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| self.expression(a, b))
+    }
+
+    fn optional_block(
+        &mut self,
+        a: &Option<Vec<Statement<'a>>>,
+        b: &Option<Vec<Statement<'b>>>,
+    ) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => self.block(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    fn statement(&mut self, a: &Statement<'a>, b: &Statement<'b>) -> bool {
+        use StatementKind::*;
+        match (&a.kind, &b.kind) {
+            (Print(a), Print(b)) => self.expression(a, b),
+            (PrintString(a), PrintString(b)) => a == b,
+            (Pass, Pass) | (Break, Break) => true,
+            (Var { ident, value }, Var { ident: i, value: v })
+            | (Global { ident, value }, Global { ident: i, value: v })
+            | (Assignment { ident, value }, Assignment { ident: i, value: v }) => {
+                self.name(ident.name, i.name) && self.expression(value, v)
+            }
+            (
+                Data { ident, values },
+                Data {
+                    ident: i,
+                    values: v,
+                },
+            ) => self.name(ident.name, i.name) && self.expressions(values, v),
+            (
+                Destructure {
+                    targets,
+                    ident,
+                    args,
+                },
+                Destructure {
+                    targets: t,
+                    ident: i,
+                    args: r,
+                },
+            ) => {
+                targets.len() == t.len()
+                    && targets
+                        .iter()
+                        .zip(t)
+                        .all(|(a, b)| self.name(a.name, b.name))
+                    && self.name(ident.name, i.name)
+                    && self.expressions(args, r)
+            }
+            (Call { ident, args }, Call { ident: i, args: r }) => {
+                // setpixel also writes to these variables
+                let writes =
+                    ident.name != "setpixel" || ["x", "y", "color"].iter().all(|x| self.name(x, x));
+                writes && self.name(ident.name, i.name) && self.expressions(args, r)
+            }
+            (
+                While { condition, body },
+                While {
+                    condition: c,
+                    body: b,
+                },
+            ) => self.expression(condition, c) && self.block(body, b),
+            (
+                If {
+                    condition,
+                    then_body,
+                    else_body,
+                },
+                If {
+                    condition: c,
+                    then_body: t,
+                    else_body: e,
+                },
+            ) => {
+                self.expression(condition, c)
+                    && self.block(then_body, t)
+                    && self.optional_block(else_body, e)
+            }
+            (Repeat { count, body }, Repeat { count: c, body: b }) => {
+                self.expression(count, c) && self.block(body, b)
+            }
+            (Loop { body }, Loop { body: b }) => self.block(body, b),
+            (Return(a), Return(b)) => self.expressions(a, b),
+            (
+                Switch {
+                    value,
+                    cases,
+                    default,
+                },
+                Switch {
+                    value: v,
+                    cases: c,
+                    default: d,
+                },
+            ) => {
+                self.expression(value, v)
+                    && cases.len() == c.len()
+                    && cases
+                        .iter()
+                        .zip(c)
+                        .all(|(a, b)| a.value == b.value && self.block(&a.body, &b.body))
+                    && self.optional_block(default, d)
+            }
+            (
+                Proc {
+                    ident,
+                    params,
+                    body,
+                },
+                Proc {
+                    ident: i,
+                    params: p,
+                    body: b,
+                },
+            ) => self.name(ident.name, i.name) && self.procedure((params, body), (p, b)),
+            _ => false,
+        }
+    }
+
+    fn expression(&mut self, a: &Expression<'a>, b: &Expression<'b>) -> bool {
+        use ExpressionKind::*;
+        match (&a.kind, &b.kind) {
+            (Number(a), Number(b)) => a == b,
+            (Integer(a), Integer(b)) => a == b,
+            (Variable(a), Variable(b)) | (Reference(a), Reference(b)) => self.name(a, b),
+            (Negate(a), Negate(b)) => self.expression(a, b),
+            (Index { ident, index }, Index { ident: i, index: x }) => {
+                self.name(ident.name, i.name) && self.expression(index, x)
+            }
+            // builtins are never renamed, but procedures are
+            (Call { ident, args }, Call { ident: i, args: r }) => {
+                let builtin = |x| crate::compiler::builtin_num_param(x).is_some();
+                let names = match builtin(ident.name) || builtin(i.name) {
+                    true => ident.name == i.name,
+                    false => self.name(ident.name, i.name),
+                };
+                names && self.expressions(args, r)
+            }
+            (
+                Binary {
+                    op, left, right, ..
+                },
+                Binary {
+                    op: o,
+                    left: l,
+                    right: r,
+                    ..
+                },
+            ) => op == o && self.expression(left, l) && self.expression(right, r),
+            _ => false,
+        }
+    }
+}
+
+/// A name in the source code, of a variable or a procedure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ident<'s> {
+    /// The name itself.
+    pub name: &'s str,
+    /// The byte range of the name.
+    pub span: Span,
+}
+
+/// A statement, with the byte range of the source code it was parsed from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement<'s> {
+    /// The type of statement.
+    pub kind: StatementKind<'s>,
+    /// The byte range of the statement.
+    pub span: Span,
+    /// If the statement continues the chain of the previous one. A chain like "a = b = 1" is
+    /// desugared to "b = 1" and a chained "a = b".
+    pub chained: bool,
+}
+
+/// The type of a statement.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatementKind<'s> {
+    /// `print <expression>`
+    Print(Expression<'s>),
+    /// `prints "<text>"`, holding the text between the quotes.
+    PrintString(&'s str),
+    /// `pass`, a statement that does nothing.
+    Pass,
+    /// `var <ident> = <expression>`
+    Var {
+        /// The declared variable.
+        ident: Ident<'s>,
+        /// The initial value.
+        value: Expression<'s>,
+    },
+    /// `global <ident> = <expression>`, only allowed at the top level.
+    Global {
+        /// The declared variable, shared by all procedures.
+        ident: Ident<'s>,
+        /// The value assigned where it is declared.
+        value: Expression<'s>,
+    },
+    /// `data <ident> = [ <number>,* ]`, a table of constant numbers, only allowed at the top
+    /// level. It is read by a [`ExpressionKind::Index`].
+    Data {
+        /// The name of the table, shared by all procedures.
+        ident: Ident<'s>,
+        /// The numbers of the table, each a [`ExpressionKind::Number`] or
+        /// [`ExpressionKind::Integer`].
+        values: Vec<Expression<'s>>,
+    },
+    /// `var <ident> (, <ident>)+ = <ident> ( <expression>,* )`, declaring a variable for each value
+    /// returned by the called procedure.
+    Destructure {
+        /// The declared variables, in the order of the returned values.
+        targets: Vec<Ident<'s>>,
+        /// The called procedure.
+        ident: Ident<'s>,
+        /// The arguments of the call.
+        args: Vec<Expression<'s>>,
+    },
+    /// `<ident> = <expression>`
+    Assignment {
+        /// The assigned variable.
+        ident: Ident<'s>,
+        /// The new value.
+        value: Expression<'s>,
+    },
+    /// `<ident> ( <expression>,* )`
+    Call {
+        /// The called procedure, or variable holding a procedure reference.
+        ident: Ident<'s>,
+        /// The arguments of the call.
+        args: Vec<Expression<'s>>,
+    },
+    /// `while <expression> <statement>* endwhile`
+    While {
+        /// The loop condition.
+        condition: Expression<'s>,
+        /// The loop body.
+        body: Vec<Statement<'s>>,
+    },
+    /// `if <expression> <statement>* [else <statement>*] endif`
+    If {
+        /// The condition.
+        condition: Expression<'s>,
+        /// The statements executed if the condition is true.
+        then_body: Vec<Statement<'s>>,
+        /// The statements executed if the condition is false, if there is a `else`.
+        else_body: Option<Vec<Statement<'s>>>,
+    },
+    /// `repeat <expression> <statement>* endrepeat`
+    Repeat {
+        /// The number of repetitions.
+        count: Expression<'s>,
+        /// The loop body.
+        body: Vec<Statement<'s>>,
+    },
+    /// `loop <statement>* endloop`, a loop that only ends by a `break`.
+    Loop {
+        /// The loop body.
+        body: Vec<Statement<'s>>,
+    },
+    /// `break`, that exits the innermost `while`, `repeat` or `loop`.
+    Break,
+    /// `return`, `return <expression>` or `return ( <expression>,* )`, that exits the procedure,
+    /// returning the values.
+    Return(Vec<Expression<'s>>),
+    /// `switch <expression> (case <number> <statement>*)* [default <statement>*] endswitch`
+    Switch {
+        /// The value compared against each case.
+        value: Expression<'s>,
+        /// The cases, in order.
+        cases: Vec<Case<'s>>,
+        /// The statements executed if no case matches, if there is a `default`.
+        default: Option<Vec<Statement<'s>>>,
+    },
+    /// `proc <ident> ( <param>,* ) <statement>* endproc`
+    Proc {
+        /// The name of the procedure.
+        ident: Ident<'s>,
+        /// The parameters of the procedure.
+        params: Vec<Param<'s>>,
+        /// The body of the procedure.
+        body: Vec<Statement<'s>>,
+    },
+}
+
+/// A parameter of a procedure: `<ident>` or `<ident> = <expression>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param<'s> {
+    /// The name of the parameter.
+    pub ident: Ident<'s>,
+    /// The value used when the argument is omitted in a call, if any.
+    pub default: Option<Expression<'s>>,
+}
+
+/// A `case` of a `switch` statement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case<'s> {
+    /// The number the value is compared to.
+    pub value: f32,
+    /// The byte range of the `case` and its number.
+    pub span: Span,
+    /// The statements executed if the value matches.
+    pub body: Vec<Statement<'s>>,
+}
+
+/// A expression, with the byte range of the source code it was parsed from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression<'s> {
+    /// The type of expression.
+    pub kind: ExpressionKind<'s>,
+    /// The byte range of the expression.
+    pub span: Span,
+}
+
+/// The type of a expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpressionKind<'s> {
+    /// A number literal, with a decimal point or a exponent.
+    Number(f32),
+    /// A number literal without a decimal point or a exponent, that fits in a `i32`. It is a
+    /// float like any other number, but can be used directly where a integer is expected.
+    Integer(i32),
+    /// The value of a variable.
+    Variable(&'s str),
+    /// `& <ident>`, a reference to a procedure.
+    Reference(&'s str),
+    /// `- <expression>`, the negation of a expression that is not a number literal. A negated
+    /// literal is parsed as a negative literal.
+    Negate(Box<Expression<'s>>),
+    /// `<ident> ( <expression>,* )`, a call to a builtin function, like `sign(x)`, or to a
+    /// procedure that returns a single value.
+    Call {
+        /// The called builtin or procedure.
+        ident: Ident<'s>,
+        /// The arguments of the call.
+        args: Vec<Expression<'s>>,
+    },
+    /// `<ident> [ <expression> ]`, the number at a index of a table declared by a
+    /// [`StatementKind::Data`].
+    Index {
+        /// The name of the table.
+        ident: Ident<'s>,
+        /// The index, starting at 0.
+        index: Box<Expression<'s>>,
+    },
+    /// `( <expression> <op> <expression> )`
+    Binary {
+        /// The operator.
+        op: BinaryOp,
+        /// The byte range of the operator.
+        op_span: Span,
+        /// The left operand.
+        left: Box<Expression<'s>>,
+        /// The right operand.
+        right: Box<Expression<'s>>,
+        /// If this is the `&&` of a chained comparison, like `(a < b < c)`, that is desugared to
+        /// `((a < b) && (b < c))`.
+        chained: bool,
+    },
+}
+
+/// A binary operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    /// `+`
+    Add,
+    /// `-`
+    Sub,
+    /// `*`
+    Mul,
+    /// `/`
+    Div,
+    /// `==`
+    Eq,
+    /// `<`
+    Lt,
+    /// `>`
+    Gt,
+    /// `&&`
+    And,
+}
+impl BinaryOp {
+    /// Get the operator from its source code, if it is one.
+    pub fn from_source(op: &str) -> Option<Self> {
+        Some(match op {
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "==" => BinaryOp::Eq,
+            "<" => BinaryOp::Lt,
+            ">" => BinaryOp::Gt,
+            "&&" => BinaryOp::And,
+            _ => return None,
+        })
+    }
+
+    /// If the operator is a comparison, `==`, `<` or `>`, that can be chained like in
+    /// `(a < b < c)`.
+    pub fn is_comparison(self) -> bool {
+        matches!(self, BinaryOp::Eq | BinaryOp::Lt | BinaryOp::Gt)
+    }
+
+    /// The source code of the operator.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Eq => "==",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::And => "&&",
+        }
+    }
+}
+
+impl Display for Program<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "program")?;
+        for statement in &self.statements {
+            write_statement(f, statement, 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a line of the tree, indented by `depth`.
+fn write_node(
+    f: &mut Formatter<'_>,
+    depth: usize,
+    node: fmt::Arguments,
+    span: &Span,
+) -> fmt::Result {
+    writeln!(
+        f,
+        "{:indent$}{} @{}..{}",
+        "",
+        node,
+        span.start,
+        span.end,
+        indent = depth * 2
+    )
+}
+
+/// Write a labeled block of statements, like the body of a loop.
+fn write_block(
+    f: &mut Formatter<'_>,
+    depth: usize,
+    label: &str,
+    statements: &[Statement],
+) -> fmt::Result {
+    writeln!(f, "{:indent$}{}", "", label, indent = depth * 2)?;
+    for statement in statements {
+        write_statement(f, statement, depth + 1)?;
+    }
+    Ok(())
+}
+
+fn write_statement(f: &mut Formatter<'_>, statement: &Statement, depth: usize) -> fmt::Result {
+    let span = &statement.span;
+    match &statement.kind {
+        StatementKind::Print(value) => {
+            write_node(f, depth, format_args!("print"), span)?;
+            write_expression(f, value, depth + 1)
+        }
+        StatementKind::PrintString(text) => {
+            write_node(f, depth, format_args!("prints {:?}", text), span)
+        }
+        StatementKind::Pass => write_node(f, depth, format_args!("pass"), span),
+        StatementKind::Var { ident, value } => {
+            write_node(f, depth, format_args!("var {}", ident.name), span)?;
+            write_expression(f, value, depth + 1)
+        }
+        StatementKind::Global { ident, value } => {
+            write_node(f, depth, format_args!("global {}", ident.name), span)?;
+            write_expression(f, value, depth + 1)
+        }
+        StatementKind::Data { ident, values } => {
+            write_node(f, depth, format_args!("data {}", ident.name), span)?;
+            for value in values {
+                write_expression(f, value, depth + 1)?;
+            }
+            Ok(())
+        }
+        StatementKind::Destructure {
+            targets,
+            ident,
+            args,
+        } => {
+            let names: Vec<_> = targets.iter().map(|x| x.name).collect();
+            write_node(
+                f,
+                depth,
+                format_args!("var {} = call {}", names.join(", "), ident.name),
+                span,
+            )?;
+            for arg in args {
+                write_expression(f, arg, depth + 1)?;
+            }
+            Ok(())
+        }
+        StatementKind::Assignment { ident, value } => {
+            write_node(f, depth, format_args!("assign {}", ident.name), span)?;
+            write_expression(f, value, depth + 1)
+        }
+        StatementKind::Call { ident, args } => {
+            write_node(f, depth, format_args!("call {}", ident.name), span)?;
+            for arg in args {
+                write_expression(f, arg, depth + 1)?;
+            }
+            Ok(())
+        }
+        StatementKind::While { condition, body } => {
+            write_node(f, depth, format_args!("while"), span)?;
+            write_expression(f, condition, depth + 1)?;
+            write_block(f, depth + 1, "do", body)
+        }
+        StatementKind::If {
+            condition,
+            then_body,
+            else_body,
+        } => {
+            write_node(f, depth, format_args!("if"), span)?;
+            write_expression(f, condition, depth + 1)?;
+            write_block(f, depth + 1, "then", then_body)?;
+            if let Some(else_body) = else_body {
+                write_block(f, depth + 1, "else", else_body)?;
+            }
+            Ok(())
+        }
+        StatementKind::Repeat { count, body } => {
+            write_node(f, depth, format_args!("repeat"), span)?;
+            write_expression(f, count, depth + 1)?;
+            write_block(f, depth + 1, "do", body)
+        }
+        StatementKind::Loop { body } => {
+            write_node(f, depth, format_args!("loop"), span)?;
+            write_block(f, depth + 1, "do", body)
+        }
+        StatementKind::Break => write_node(f, depth, format_args!("break"), span),
+        StatementKind::Return(values) => {
+            write_node(f, depth, format_args!("return"), span)?;
+            for value in values {
+                write_expression(f, value, depth + 1)?;
+            }
+            Ok(())
+        }
+        StatementKind::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            write_node(f, depth, format_args!("switch"), span)?;
+            write_expression(f, value, depth + 1)?;
+            for case in cases {
+                write_node(
+                    f,
+                    depth + 1,
+                    format_args!("case {}", case.value),
+                    &case.span,
+                )?;
+                for statement in &case.body {
+                    write_statement(f, statement, depth + 2)?;
+                }
+            }
+            if let Some(default) = default {
+                write_block(f, depth + 1, "default", default)?;
+            }
+            Ok(())
+        }
+        StatementKind::Proc {
+            ident,
+            params,
+            body,
+        } => {
+            let names: Vec<_> = params.iter().map(|x| x.ident.name).collect();
+            write_node(
+                f,
+                depth,
+                format_args!("proc {}({})", ident.name, names.join(", ")),
+                span,
+            )?;
+            for param in params {
+                if let Some(default) = &param.default {
+                    let name = param.ident.name;
+                    write_node(
+                        f,
+                        depth + 1,
+                        format_args!("default {}", name),
+                        &param.ident.span,
+                    )?;
+                    write_expression(f, default, depth + 2)?;
+                }
+            }
+            for statement in body {
+                write_statement(f, statement, depth + 1)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_expression(f: &mut Formatter<'_>, expression: &Expression, depth: usize) -> fmt::Result {
+    let span = &expression.span;
+    match &expression.kind {
+        ExpressionKind::Number(x) => write_node(f, depth, format_args!("number {}", x), span),
+        ExpressionKind::Integer(x) => write_node(f, depth, format_args!("integer {}", x), span),
+        ExpressionKind::Variable(name) => {
+            write_node(f, depth, format_args!("variable {}", name), span)
+        }
+        ExpressionKind::Reference(name) => {
+            write_node(f, depth, format_args!("reference {}", name), span)
+        }
+        ExpressionKind::Negate(value) => {
+            write_node(f, depth, format_args!("negate"), span)?;
+            write_expression(f, value, depth + 1)
+        }
+        ExpressionKind::Call { ident, args } => {
+            write_node(f, depth, format_args!("call {}", ident.name), span)?;
+            for arg in args {
+                write_expression(f, arg, depth + 1)?;
+            }
+            Ok(())
+        }
+        ExpressionKind::Index { ident, index } => {
+            write_node(f, depth, format_args!("index {}", ident.name), span)?;
+            write_expression(f, index, depth + 1)
+        }
+        ExpressionKind::Binary {
+            op, left, right, ..
+        } => {
+            write_node(f, depth, format_args!("binary {}", op.as_str()), span)?;
+            write_expression(f, left, depth + 1)?;
+            write_expression(f, right, depth + 1)
+        }
+    }
+}
@@ -1,20 +1,34 @@
-use logos::{Logos, Span, SpannedIter};
-use std::collections::HashMap;
-use std::io::Write;
-use std::num::ParseFloatError;
+use crate::leb128::{self, Write};
+use crate::prelude::*;
+use core::num::ParseFloatError;
+use logos::{Logos, Span};
 
 /// Tokens of the chasm language, based completely on the scanner of the original implementation:
 /// https://github.com/ColinEberhardt/chasm/blob/master/src/tokenizer.ts#L41
 /// There are some differences, but I hope that they are equivalent
 #[derive(Logos, PartialEq, Eq, Debug, Clone, Copy)]
+#[allow(missing_docs)]
 pub enum Token {
-    // this regex for number doesn't make a lot of sense, but it is like that in the original
-    #[regex(r"-?[.0-9]+([eE]-?[0-9][0-9])?")]
+    // "12", "1.5", ".5" or "5.", optionally with a exponent. A leading "-" is a operator, so "(5 -3)"
+    // is a subtraction
+    #[regex(r"([0-9]+(\.[0-9]*)?|\.[0-9]+)([eE]-?[0-9][0-9])?")]
     Number,
+    // a double-quoted string, with no escapes, in a single line
+    #[regex(r#""[^"\n]*""#)]
+    String,
+    // a sequence of digits and dots that is not a valid number, like "1.2.3" or ".."
+    #[regex(r"[0-9]*\.[0-9]*\.[.0-9]*|\.")]
+    InvalidNumber,
     #[token("print")]
     Print,
+    #[token("prints")]
+    Prints,
     #[token("var")]
     Var,
+    #[token("global")]
+    Global,
+    #[token("data")]
+    Data,
     #[token("while")]
     While,
     #[token("endwhile")]
@@ -29,11 +43,35 @@ pub enum Token {
     Proc,
     #[token("endproc")]
     EndProc,
+    #[token("switch")]
+    Switch,
+    #[token("case")]
+    Case,
+    #[token("default")]
+    Default,
+    #[token("endswitch")]
+    EndSwitch,
+    #[token("repeat")]
+    Repeat,
+    #[token("endrepeat")]
+    EndRepeat,
+    #[token("loop")]
+    Loop,
+    #[token("endloop")]
+    EndLoop,
+    #[token("break")]
+    Break,
+    #[token("return")]
+    Return,
+    #[token("pass")]
+    Pass,
     #[token(",")]
     Comma,
+    #[token("&")]
+    Ampersand,
     #[regex(r"(\+|-|\*|/|==|<|>|&&)")]
     Operator,
-    #[regex(r"[a-zA-Z]+")]
+    #[regex(r"\p{XID_Start}\p{XID_Continue}*")]
     Identifier,
     #[token("=")]
     Assignment,
@@ -41,6 +79,10 @@ pub enum Token {
     LeftParen,
     #[token(")")]
     RightParen,
+    #[token("[")]
+    LeftBracket,
+    #[token("]")]
+    RightBracket,
     #[error]
     #[regex(r"\s+", logos::skip)]
     Error,
@@ -48,11 +90,16 @@ pub enum Token {
 }
 impl Token {
     /// Return a reference to a static value with the same variant that self
-    fn to_static(self) -> &'static Self {
+    pub(crate) fn to_static(self) -> &'static Self {
         match self {
             Token::Number => &Token::Number,
+            Token::String => &Token::String,
+            Token::InvalidNumber => &Token::InvalidNumber,
             Token::Print => &Token::Print,
+            Token::Prints => &Token::Prints,
             Token::Var => &Token::Var,
+            Token::Global => &Token::Global,
+            Token::Data => &Token::Data,
             Token::While => &Token::While,
             Token::EndWhile => &Token::EndWhile,
             Token::If => &Token::If,
@@ -60,23 +107,42 @@ impl Token {
             Token::Else => &Token::Else,
             Token::Proc => &Token::Proc,
             Token::EndProc => &Token::EndProc,
+            Token::Switch => &Token::Switch,
+            Token::Case => &Token::Case,
+            Token::Default => &Token::Default,
+            Token::EndSwitch => &Token::EndSwitch,
+            Token::Repeat => &Token::Repeat,
+            Token::EndRepeat => &Token::EndRepeat,
+            Token::Loop => &Token::Loop,
+            Token::EndLoop => &Token::EndLoop,
+            Token::Break => &Token::Break,
+            Token::Return => &Token::Return,
+            Token::Pass => &Token::Pass,
             Token::Comma => &Token::Comma,
+            Token::Ampersand => &Token::Ampersand,
             Token::Operator => &Token::Operator,
             Token::Identifier => &Token::Identifier,
             Token::Assignment => &Token::Assignment,
             Token::LeftParen => &Token::LeftParen,
             Token::RightParen => &Token::RightParen,
+            Token::LeftBracket => &Token::LeftBracket,
+            Token::RightBracket => &Token::RightBracket,
             Token::Error => &Token::Error,
             Token::Eof => &Token::Eof,
         }
     }
 }
-impl std::fmt::Display for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Token {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let s = match self {
             Token::Number => "<number>",
+            Token::String => "<string>",
+            Token::InvalidNumber => "<invalid number>",
             Token::Print => "\"print\"",
+            Token::Prints => "\"prints\"",
             Token::Var => "\"var\"",
+            Token::Global => "\"global\"",
+            Token::Data => "\"data\"",
             Token::While => "\"while\"",
             Token::EndWhile => "\"endwhile\"",
             Token::If => "\"if\"",
@@ -84,12 +150,26 @@ impl std::fmt::Display for Token {
             Token::Else => "\"else\"",
             Token::Proc => "\"proc\"",
             Token::EndProc => "\"endproc\"",
+            Token::Switch => "\"switch\"",
+            Token::Case => "\"case\"",
+            Token::Default => "\"default\"",
+            Token::EndSwitch => "\"endswitch\"",
+            Token::Repeat => "\"repeat\"",
+            Token::EndRepeat => "\"endrepeat\"",
+            Token::Loop => "\"loop\"",
+            Token::EndLoop => "\"endloop\"",
+            Token::Break => "\"break\"",
+            Token::Return => "\"return\"",
+            Token::Pass => "\"pass\"",
             Token::Comma => "\",\"",
+            Token::Ampersand => "\"&\"",
             Token::Operator => "<operator>",
             Token::Identifier => "<identifier>",
             Token::Assignment => "\"=\"",
             Token::LeftParen => "\"(\"",
             Token::RightParen => "\")\"",
+            Token::LeftBracket => "\"[\"",
+            Token::RightBracket => "\"]\"",
             Token::Error => "<error>",
             Token::Eof => "<eof>",
         };
@@ -97,12 +177,16 @@ impl std::fmt::Display for Token {
     }
 }
 
+use crate::ast::{
+    self, BinaryOp, Case, Expression, ExpressionKind, Ident, Param, Statement, StatementKind,
+};
 use crate::wasm_macro::wasm;
+use crate::CompileOptions;
 
 /// Print a slice in the format "0, 1, 2 or 3"
 struct OrList<'a>(&'a [Token]);
-impl std::fmt::Display for OrList<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for OrList<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let len = self.0.len();
         if len == 0 {
             return write!(f, "nothing");
@@ -118,6 +202,83 @@ impl std::fmt::Display for OrList<'_> {
     }
 }
 
+/// Write a list of types, like "number and number".
+struct TypeList<'a>(&'a [Type]);
+impl core::fmt::Display for TypeList<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, ty) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " and ")?;
+            }
+            write!(f, "{}", ty)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a string as a quoted JSON string, escaping it as needed.
+///
+/// # Example
+/// ```
+/// let json = chasm_rs::JsonString("say \"hi\"\n").to_string();
+///
+/// assert_eq!(json, r#""say \"hi\"\n""#);
+/// ```
+pub struct JsonString<'a>(pub &'a str);
+impl core::fmt::Display for JsonString<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// The line and column of the byte `offset` of `source`. The fist line and column are 1, and the
+/// column is counted in characters. The end of the source, where a unexpected eof is reported, is
+/// right after the content of the last line. Any other offset out of every line is `(0, 0)`.
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    if offset == source.len() {
+        let last = source.lines().last().unwrap_or_default();
+        let line = source.lines().count().max(1);
+        return (line, last.chars().count() + 1);
+    }
+    source
+        .lines()
+        .enumerate()
+        .find_map(|(line, x)| {
+            let start = x.as_ptr() as usize - source.as_ptr() as usize;
+            (start..start + x.len()).contains(&offset).then(|| {
+                let column = x[..offset - start].chars().count();
+                (line + 1, column + 1)
+            })
+        })
+        .unwrap_or((0, 0))
+}
+
+/// The fields of the JSON object of a error or warning, without the surrounding braces.
+fn json_fields(source: &str, message: &str, span: &Span, code: &str) -> String {
+    let (line, column) = line_column(source, span.start);
+    format!(
+        r#""message":{},"line":{},"column":{},"span_start":{},"span_end":{},"code":"{}""#,
+        JsonString(message),
+        line,
+        column,
+        span.start,
+        span.end,
+        code
+    )
+}
+
 /// A compilation error.
 ///
 /// Contains a span and a reference to the source code to allow better error formatting.
@@ -132,26 +293,88 @@ pub struct Error<'source> {
 }
 impl Error<'_> {
     /// Get the line and column of the start of the Error's span. The fist line and column are 1.
+    ///
+    /// The column is counted in characters, not bytes.
     pub fn get_line_column(&self) -> (usize, usize) {
-        self.source
-            .lines()
-            .enumerate()
-            .find_map(|(line, x)| {
-                let start = x.as_ptr() as usize - self.source.as_ptr() as usize;
-                let column = self.span.start - start;
-                (start..start + x.len())
-                    .contains(&self.span.start)
-                    .then(|| (line + 1, column + 1))
-            })
-            .unwrap_or((0, 0))
+        line_column(self.source, self.span.start)
+    }
+
+    /// The message of the error. It is the [`Display`](core::fmt::Display) of its kind, except for
+    /// the errors of invalid numbers, which quote the number, like ``failed to parse `1.2.3` as a
+    /// number``, and the mismatched block ends, which give the position of the opened block.
+    pub fn message(&self) -> String {
+        let text = self.source.get(self.span.clone()).unwrap_or_default();
+        match &self.kind {
+            ErrorKind::InvalidNumber => format!("failed to parse `{}` as a number", text),
+            // a string is only unrecognized if it has no closing quote
+            ErrorKind::UnexpectedCharacter if text.starts_with('"') => {
+                "unterminated string literal".to_string()
+            }
+            ErrorKind::UnexpectedCharacter => format!("unexpected character `{}`", text),
+            ErrorKind::ParseFloatError(x) => {
+                format!("failed to parse `{}` as a number ({})", text, x)
+            }
+            ErrorKind::MismatchedBlockEnd { opened_at, .. } => {
+                let (line, column) = line_column(self.source, opened_at.start);
+                format!("{}, for the block opened at {}:{}", self.kind, line, column)
+            }
+            kind => kind.to_string(),
+        }
+    }
+
+    /// Serialize the error as a JSON object, with the fields `message`, `line`, `column`,
+    /// `span_start`, `span_end` and `code`. The line and column are the ones of
+    /// [`Error::get_line_column`].
+    ///
+    /// # Example
+    /// ```
+    /// let error = chasm_rs::compile("print ?").unwrap_err();
+    ///
+    /// assert_eq!(
+    ///     error.to_json(),
+    ///     r#"{"message":"unexpected character `?`","line":1,"column":7,"span_start":6,"span_end":7,"code":"E0011"}"#
+    /// );
+    /// ```
+    pub fn to_json(&self) -> String {
+        format!("{{{}}}", self.json_fields())
+    }
+
+    /// The fields of the JSON object of [`Error::to_json`], without the surrounding braces, so a
+    /// host can extend the object with fields of its own.
+    pub fn json_fields(&self) -> String {
+        json_fields(self.source, &self.message(), &self.span, self.kind.code())
+    }
+
+    /// Get the column of the start of the Error's span, like [`Error::get_line_column`], but
+    /// expanding each tab to the next multiple of `tab_width`. The first column is 1. A `tab_width`
+    /// of 0 is treated as 1, so each tab is a single column.
+    pub fn column_with_tabs(&self, tab_width: usize) -> usize {
+        let tab_width = tab_width.max(1);
+        let line_start = self.source[..self.span.start]
+            .rfind('\n')
+            .map_or(0, |x| x + 1);
+        let column =
+            self.source[line_start..self.span.start]
+                .chars()
+                .fold(0, |column, c| match c {
+                    '\t' => (column / tab_width + 1) * tab_width,
+                    _ => column + 1,
+                });
+        column + 1
     }
 }
-impl std::fmt::Display for Error<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Error<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let (line, column) = self.get_line_column();
-        write!(f, "error at {}:{}: ", line, column)?;
+        write!(f, "error at {}:{}: {}", line, column, self.message())
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for Error<'_> {}
 
-        match &self.kind {
+impl core::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
             ErrorKind::UnexpectedToken { expected, received } => {
                 write!(
                     f,
@@ -160,6 +383,9 @@ impl std::fmt::Display for Error<'_> {
                     received
                 )
             }
+            ErrorKind::InvalidNumber => {
+                write!(f, "invalid number")
+            }
             ErrorKind::ParseFloatError(x) => {
                 write!(f, "failed to parse float number ({})", x)
             }
@@ -173,17 +399,110 @@ impl std::fmt::Display for Error<'_> {
             ErrorKind::UnexpectedType { expected, received } => {
                 write!(
                     f,
-                    "unexpected number type, expected {:?}, received {:?}",
-                    expected, received
+                    "unexpected type, expected {}, received {}",
+                    TypeList(expected),
+                    TypeList(received)
                 )
             }
             ErrorKind::UndeclaredProc { name } => {
                 write!(f, "Undeclared procedural {:?}", name)
             }
+            ErrorKind::EmptyProgram => {
+                write!(f, "the program has no statements")
+            }
+            ErrorKind::UnknownBuiltin { name } => {
+                write!(f, "unknown builtin function {:?}", name)
+            }
+            ErrorKind::BreakOutsideLoop => {
+                write!(f, "`break` outside of a loop")
+            }
+            ErrorKind::NotConstant => {
+                write!(f, "the expression is not a constant")
+            }
+            ErrorKind::UnexpectedCharacter => {
+                write!(f, "unexpected character")
+            }
+            ErrorKind::ModuleTooLarge {
+                section,
+                size,
+                limit,
+            } => {
+                write!(
+                    f,
+                    "the {} section has {} bytes, more than the limit of {}",
+                    section, size, limit
+                )
+            }
+            ErrorKind::ResultNumberMismatch { expected, received } => {
+                write!(
+                    f,
+                    "number of returned values mismatch, expected {}, received {}",
+                    expected, received
+                )
+            }
+            ErrorKind::MultivalueDisabled => {
+                write!(
+                    f,
+                    "returning more than one value requires the multivalue option"
+                )
+            }
+            ErrorKind::AssignmentInExpression => {
+                write!(
+                    f,
+                    "unexpected assignment in a expression, use `==` to compare values"
+                )
+            }
+            ErrorKind::TooManyLocals { limit } => {
+                write!(f, "the function has more than {} locals", limit)
+            }
+            ErrorKind::TooManyFunctions { limit } => {
+                write!(f, "the module has more than {} functions", limit)
+            }
+            ErrorKind::UndeclaredData { name } => {
+                write!(f, "undeclared data table {:?}", name)
+            }
+            ErrorKind::NonBooleanCondition => {
+                write!(f, "conditions must be a comparison like `(x > 0)`")
+            }
+            ErrorKind::MismatchedBlockEnd {
+                expected, found, ..
+            } => {
+                write!(
+                    f,
+                    "mismatched block end, expected {}, found {}",
+                    expected, found
+                )
+            }
+            ErrorKind::NameClash { name } => {
+                write!(f, "{:?} is both a variable and a procedure", name)
+            }
+            ErrorKind::MissingReturn { name } => {
+                write!(
+                    f,
+                    "the procedure {:?} returns values, but can reach its end without a return",
+                    name
+                )
+            }
+            ErrorKind::DuplicateData { name } => {
+                write!(f, "the data table {:?} is declared more than once", name)
+            }
+            ErrorKind::DataOutOfBounds { name, len } => {
+                write!(
+                    f,
+                    "index out of the data table {:?}, that has {} values",
+                    name, len
+                )
+            }
+            ErrorKind::InvalidMemoryLimits { min, max } => {
+                write!(
+                    f,
+                    "the memory has a maximum of {} pages, less than its minimum of {}",
+                    max, min
+                )
+            }
         }
     }
 }
-impl std::error::Error for Error<'_> {}
 
 /// The type of compilation error.
 #[derive(Debug, PartialEq, Eq)]
@@ -195,6 +514,8 @@ pub enum ErrorKind {
         /// Received token
         received: Token,
     },
+    /// A sequence of digits and dots that is not a valid number, like `1.2.3`.
+    InvalidNumber,
     /// The parsing of a number in string format to float has failed.
     ParseFloatError(ParseFloatError),
     /// There is a mismatch in the number of arguments in a procedure call and a procedure
@@ -217,545 +538,3083 @@ pub enum ErrorKind {
         /// The name of the undefined procedure
         name: String,
     },
+    /// The program has no statements, and [`CompileOptions::allow_empty`] is disabled.
+    ///
+    /// [`CompileOptions::allow_empty`]: crate::CompileOptions::allow_empty
+    EmptyProgram,
+    /// A function is called in a expression, but it is not a builtin function. Procedures can
+    /// only be called as a statement.
+    UnknownBuiltin {
+        /// The name of the called function
+        name: String,
+    },
+    /// A `break` that is not inside a `while`, `repeat` or `loop`.
+    BreakOutsideLoop,
+    /// The expression given to [`compile_expr`](crate::compile_expr), or the default value of a
+    /// parameter, is not a constant, because it uses variables or calls functions.
+    NotConstant,
+    /// A character that is not part of any token, like `@`, or a string literal without a
+    /// closing quote.
+    UnexpectedCharacter,
+    /// A section of the module is larger than the size limit of WebAssembly, whose sizes are
+    /// encoded as `u32`, or the body of a function is larger than
+    /// [`CompileOptions::max_function_size`]. The data of the string literals and of the tables
+    /// is also limited, so it ends before [`RGB_CANVAS_OFFSET`].
+    ///
+    /// [`CompileOptions::max_function_size`]: crate::CompileOptions::max_function_size
+    ModuleTooLarge {
+        /// The name of the section, like `"code"`.
+        section: &'static str,
+        /// The size of the section, or of the function body, in bytes.
+        size: u64,
+        /// The maximum size, in bytes
+        limit: u64,
+    },
+    /// The number of values returned by a procedure doesn't match: a `return` with a different
+    /// number of values than the first one of its procedure, a `var a, b = f()` with a different
+    /// number of variables, or a reference to a procedure that returns values.
+    ResultNumberMismatch {
+        /// Expected number of values
+        expected: u32,
+        /// Received number of values
+        received: u32,
+    },
+    /// A `return` has more than one value, but [`CompileOptions::multivalue`] is disabled.
+    ///
+    /// [`CompileOptions::multivalue`]: crate::CompileOptions::multivalue
+    MultivalueDisabled,
+    /// A `=` where a operator was expected, in a expression like `(x = 1)`, that was likely meant
+    /// to be the comparison `==`.
+    AssignmentInExpression,
+    /// A function has more locals, counting its parameters, variables and the hidden locals
+    /// created by the compiler, than [`CompileOptions::max_locals`].
+    ///
+    /// [`CompileOptions::max_locals`]: crate::CompileOptions::max_locals
+    TooManyLocals {
+        /// The maximum number of locals
+        limit: u32,
+    },
+    /// The module has more functions, counting the imported ones, than
+    /// [`CompileOptions::max_functions`].
+    ///
+    /// [`CompileOptions::max_functions`]: crate::CompileOptions::max_functions
+    TooManyFunctions {
+        /// The maximum number of functions
+        limit: u32,
+    },
+    /// A table is indexed, like `palette[i]`, but it is not declared with `data` anywhere in the
+    /// program.
+    UndeclaredData {
+        /// The name of the undeclared table
+        name: String,
+    },
+    /// The condition of a `while` or a `if` is a number, like `while (x)`, instead of a
+    /// comparison.
+    NonBooleanCondition,
+    /// A block is closed by the end of another kind of block, like a `while` closed by a `endif`.
+    MismatchedBlockEnd {
+        /// The end of the open block, like [`Token::EndWhile`]
+        expected: Token,
+        /// The received end, like [`Token::EndIf`]
+        found: Token,
+        /// The span of the keyword that opened the block, like the `while`
+        opened_at: Span,
+    },
+    /// A variable, or a parameter, has the name of a procedure, so calling that name would be
+    /// ambiguous.
+    NameClash {
+        /// The name of the variable and of the procedure
+        name: String,
+    },
+    /// A procedure has a `return` with values, but there is a path that reaches its end without
+    /// returning.
+    MissingReturn {
+        /// The name of the procedure
+        name: String,
+    },
+    /// A table is declared with `data` again, with a name already used by another table.
+    DuplicateData {
+        /// The name of the table
+        name: String,
+    },
+    /// A table is indexed with a constant, like `t[5]`, that is not a index of one of its values.
+    DataOutOfBounds {
+        /// The name of the table
+        name: String,
+        /// The number of values of the table
+        len: u32,
+    },
+    /// [`CompileOptions::memory_max_pages`] is less than the minimum size of the memory:
+    /// [`CompileOptions::memory_min_pages`], or 2 pages if the program calls `setrgb`, whose
+    /// canvas is in the second page.
+    ///
+    /// [`CompileOptions::memory_max_pages`]: crate::CompileOptions::memory_max_pages
+    /// [`CompileOptions::memory_min_pages`]: crate::CompileOptions::memory_min_pages
+    InvalidMemoryLimits {
+        /// The minimum size of the memory, in pages
+        min: u32,
+        /// The maximum size of the memory, in pages
+        max: u32,
+    },
+}
+impl ErrorKind {
+    /// A stable code identifying the type of error, like `"E0001"`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::UnexpectedToken { .. } => "E0001",
+            ErrorKind::ParseFloatError(_) => "E0002",
+            ErrorKind::ArgumentNumberMismatch { .. } => "E0003",
+            ErrorKind::UnexpectedType { .. } => "E0004",
+            ErrorKind::UndeclaredProc { .. } => "E0005",
+            ErrorKind::InvalidNumber => "E0006",
+            ErrorKind::EmptyProgram => "E0007",
+            ErrorKind::UnknownBuiltin { .. } => "E0008",
+            ErrorKind::BreakOutsideLoop => "E0009",
+            ErrorKind::NotConstant => "E0010",
+            ErrorKind::UnexpectedCharacter => "E0011",
+            ErrorKind::ModuleTooLarge { .. } => "E0012",
+            ErrorKind::ResultNumberMismatch { .. } => "E0013",
+            ErrorKind::MultivalueDisabled => "E0014",
+            ErrorKind::AssignmentInExpression => "E0015",
+            ErrorKind::TooManyLocals { .. } => "E0016",
+            ErrorKind::TooManyFunctions { .. } => "E0017",
+            ErrorKind::UndeclaredData { .. } => "E0018",
+            ErrorKind::NonBooleanCondition => "E0019",
+            ErrorKind::MismatchedBlockEnd { .. } => "E0020",
+            ErrorKind::NameClash { .. } => "E0021",
+            ErrorKind::MissingReturn { .. } => "E0022",
+            ErrorKind::DuplicateData { .. } => "E0023",
+            ErrorKind::DataOutOfBounds { .. } => "E0024",
+            ErrorKind::InvalidMemoryLimits { .. } => "E0025",
+        }
+    }
+}
+
+/// A compilation warning. It doesn't prevent the compilation, but may indicate a mistake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A variable declaration shadows a variable of a outer block.
+    Shadowed {
+        /// The name of the variable
+        name: String,
+        /// The byte range of the declaration
+        span: Span,
+        /// The byte range of the shadowed declaration
+        outer_span: Span,
+    },
+    /// A `loop` has no `break` that exits it, so it never ends.
+    PossibleInfiniteLoop {
+        /// The byte range of the loop
+        span: Span,
+    },
+    /// A variable is declared, but its value is never read in the procedure.
+    UnusedVariable {
+        /// The name of the variable
+        name: String,
+        /// The byte range of the declaration
+        span: Span,
+    },
+    /// The condition of a `while` or a `if` only compares constants, like `(1 == 2)`, so it is
+    /// always true or always false.
+    ConstantCondition {
+        /// The value of the condition
+        value: bool,
+        /// The byte range of the condition
+        span: Span,
+    },
+}
+impl Warning {
+    /// The byte range of the source code this warning is referencing.
+    pub fn span(&self) -> Span {
+        match self {
+            Warning::Shadowed { span, .. }
+            | Warning::PossibleInfiniteLoop { span }
+            | Warning::UnusedVariable { span, .. }
+            | Warning::ConstantCondition { span, .. } => span.clone(),
+        }
+    }
+
+    /// A stable code identifying the type of warning, like `"W0001"`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::Shadowed { .. } => "W0001",
+            Warning::PossibleInfiniteLoop { .. } => "W0002",
+            Warning::UnusedVariable { .. } => "W0003",
+            Warning::ConstantCondition { .. } => "W0004",
+        }
+    }
+
+    /// Serialize the warning as a JSON object, with the same fields as [`Error::to_json`]. The
+    /// warning doesn't keep the `source` it was emitted for, which is needed for its line and
+    /// column.
+    ///
+    /// # Example
+    /// ```
+    /// let source = "var x = 1";
+    /// let options = chasm_rs::CompileOptions::default();
+    /// let (_, warnings) = chasm_rs::compile_with_warnings(source, &options).unwrap();
+    ///
+    /// assert_eq!(
+    ///     warnings[0].to_json(source),
+    ///     r#"{"message":"the variable \"x\" is never read","line":1,"column":5,"span_start":4,"span_end":5,"code":"W0003"}"#
+    /// );
+    /// ```
+    pub fn to_json(&self, source: &str) -> String {
+        format!(
+            "{{{}}}",
+            json_fields(source, &self.to_string(), &self.span(), self.code())
+        )
+    }
+}
+impl core::fmt::Display for Warning {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Warning::Shadowed { name, .. } => {
+                write!(
+                    f,
+                    "declaration of {:?} shadows a variable of a outer block",
+                    name
+                )
+            }
+            Warning::PossibleInfiniteLoop { .. } => {
+                write!(f, "this loop has no `break`, so it never ends")
+            }
+            Warning::UnusedVariable { name, .. } => {
+                write!(f, "the variable {:?} is never read", name)
+            }
+            Warning::ConstantCondition { value, .. } => {
+                write!(f, "condition always {}", value)
+            }
+        }
+    }
 }
 
 type Res<'s, T = ()> = Result<T, Error<'s>>;
 
 type LocalIdx = u32;
 type FuncIdx = u32;
+type TypeIdx = u32;
+type GlobalIdx = u32;
+
+/// The generated body of a function: the declaration of its locals, its code, and the number of
+/// locals, not counting the parameters.
+type FunctionCode = (Vec<u8>, Vec<u8>, u32);
+
+/// The memory offset where the data of the string literals starts, right after the canvas.
+pub const STRING_DATA_OFFSET: u32 = 100 * 100;
+
+/// The memory offset of the RGB canvas written by `setrgb`, with 3 bytes per pixel, at the start
+/// of the second page of the memory.
+pub const RGB_CANVAS_OFFSET: u32 = 64 * 1024;
 
+/// The maximum size of a section, or of a function body, that is encoded as a `u32`.
+const MAX_SIZE: u64 = u32::MAX as u64;
+
+/// A WebAssembly value type.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Type {
+    /// A 32-bit integer, used for booleans.
     I32,
+    /// A 32-bit float, the type of all chasm numbers.
     F32,
 }
-
-pub struct Procedure {
-    pub idx: FuncIdx,
-    pub num_param: u32,
-    pub code: Vec<u8>,
+impl Type {
+    /// The name of the type in WebAssembly, like `"f32"`.
+    pub fn wasm_name(&self) -> &'static str {
+        match self {
+            Type::I32 => "i32",
+            Type::F32 => "f32",
+        }
+    }
+}
+impl core::fmt::Display for Type {
+    /// Write the name of the type in chasm, "number" or "condition", for the booleans.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Type::I32 => write!(f, "condition"),
+            Type::F32 => write!(f, "number"),
+        }
+    }
 }
 
-struct Context {
-    code: Vec<u8>,
-    symbols: HashMap<String, LocalIdx>,
+/// The signature of a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncType {
+    /// The types of the parameters.
+    pub params: Vec<Type>,
+    /// The types of the results.
+    pub results: Vec<Type>,
 }
-impl Context {
-    fn local_index_for_symbol(&mut self, symbol: &str) -> LocalIdx {
-        if let Some(idx) = self.symbols.get(symbol) {
-            *idx
-        } else {
-            let len = self.symbols.len() as u32;
-            self.symbols.insert(symbol.to_string(), len);
-            len
+impl FuncType {
+    /// The type of a procedure with `num_param` parameters.
+    fn procedure(num_param: u32) -> Self {
+        Self::returning(num_param, 0)
+    }
+
+    /// The type of a procedure with `num_param` parameters, that returns `num_results` values.
+    fn returning(num_param: u32, num_results: u32) -> Self {
+        Self {
+            params: vec![Type::F32; num_param as usize],
+            results: vec![Type::F32; num_results as usize],
         }
     }
 }
 
-/// Compile the source code to webassembly code.
-pub struct Parser<'source> {
-    source: &'source str,
-    lexer: SpannedIter<'source, Token>,
-    last: (Token, Span),
-    current: (Token, Span),
-    next: (Token, Span),
-    procedures: HashMap<String, Procedure>,
+/// A compiled procedure, including the implicit `main` procedure of the top-level statements.
+#[derive(Clone, Debug)]
+pub struct Procedure {
+    pub(crate) name: String,
+    pub(crate) idx: FuncIdx,
+    pub(crate) ty: TypeIdx,
+    pub(crate) num_param: u32,
+    pub(crate) num_results: u32,
+    pub(crate) num_locals: u32,
+    /// The declaration of the locals, that comes before the code in the function body.
+    pub(crate) locals: Vec<u8>,
+    pub(crate) code: Vec<u8>,
 }
-impl<'s> Parser<'s> {
-    pub fn parse(source: &'s str) -> Result<Vec<Procedure>, Error<'s>> {
-        let lexer = Token::lexer(source).spanned();
-        let mut parser = Self {
-            source,
-            last: (Token::Error, 0..0),
-            current: (Token::Error, 0..0),
-            next: (Token::Error, 0..0),
-            lexer,
-            procedures: HashMap::new(),
-        };
-        parser.eat_token();
-        parser.eat_token();
+impl Procedure {
+    /// The name of the procedure, `"main"` for the top-level statements.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        let main_proc = Procedure {
-            idx: 1,
-            num_param: 0,
-            code: Vec::new(),
-        };
-        parser.procedures.insert("main".to_string(), main_proc);
+    /// The function index of the procedure in the module.
+    pub fn idx(&self) -> u32 {
+        self.idx
+    }
 
-        let mut ctx = Context {
-            code: Vec::new(),
-            symbols: HashMap::new(),
-        };
+    /// The number of parameters of the procedure.
+    pub fn param_count(&self) -> u32 {
+        self.num_param
+    }
 
-        // compile statements
-        while parser.current.0 != Token::Eof {
-            parser.statement(&mut ctx)?;
-        }
-        parser.match_token(Token::Eof)?;
-        wasm!(&mut ctx.code, end);
+    /// The number of values returned by the procedure, the ones of its `return` statements.
+    pub fn result_count(&self) -> u32 {
+        self.num_results
+    }
 
-        let locals_index = ctx.code.len();
+    /// The number of locals declared in the function, not counting the parameters. This includes
+    /// the hidden locals created by the compiler, like the ones of `setpixel` and `repeat`.
+    pub fn local_count(&self) -> u32 {
+        self.num_locals
+    }
 
-        // write the vector of locals of the function
-        leb128::write::unsigned(&mut ctx.code, 1).unwrap();
-        leb128::write::unsigned(&mut ctx.code, ctx.symbols.len() as u64).unwrap();
-        wasm!(&mut ctx.code, f32);
+    /// The declaration of the locals of the function, as encoded at the start of its body in the
+    /// code section.
+    pub fn locals(&self) -> &[u8] {
+        &self.locals
+    }
 
-        // move locals to the start
-        let len = ctx.code.len();
-        ctx.code.rotate_right(len - locals_index);
+    /// The instructions of the function, as encoded in the code section after the declaration of
+    /// its [`locals`](Procedure::locals).
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
 
-        parser.procedures.get_mut("main").unwrap().code = ctx.code;
+    /// The size of the body of the function in the code section, without the size prefix.
+    pub(crate) fn size(&self) -> u64 {
+        (self.locals.len() + self.code.len()) as u64
+    }
+}
 
-        let mut procedures: Vec<_> = Vec::with_capacity(parser.procedures.len());
-        for (name, p) in parser.procedures.into_iter() {
-            if p.code.is_empty() {
-                return Err(Error {
-                    source: parser.source,
-                    span: parser.current.1,
-                    kind: ErrorKind::UndeclaredProc { name },
-                });
+/// A compiled chasm program, ready to be written as a WebAssembly module with
+/// [`Module::serialize`]. It can be modified before that, like renaming its exports.
+#[derive(Clone, Debug)]
+pub struct Module {
+    /// The function types used by the module. The type 0 is the type of the imported "print", and
+    /// it is followed by the types of "prints" and "printb", the ones that are imported.
+    pub types: Vec<FuncType>,
+    /// If the program prints a string, and the module needs to import "prints", as the function
+    /// 1.
+    pub imports_prints: bool,
+    /// If the program prints a condition, and the module needs to import "printb", as the last
+    /// imported function.
+    pub imports_printb: bool,
+    /// The procedures of the program, sorted by function index.
+    pub procedures: Vec<Procedure>,
+    /// If a procedure is referenced, and the module needs a table with all procedures.
+    pub uses_table: bool,
+    /// If the program calls `setrgb`, so its output is the RGB canvas, at [`RGB_CANVAS_OFFSET`].
+    pub uses_rgb: bool,
+    /// The name and index of the exported functions.
+    pub exports: Vec<(String, FuncIdx)>,
+    /// The warnings emitted while compiling.
+    pub warnings: Vec<Warning>,
+    /// The bytes of the tables and the string literals, to be placed in the memory at the offset
+    /// 10000, right after the canvas.
+    pub data: Vec<u8>,
+    /// The number of global variables.
+    pub globals: u32,
+    /// The minimum size, in pages, of the imported memory.
+    pub memory_min_pages: u32,
+    /// The maximum size, in pages, of the imported memory, if any.
+    pub memory_max_pages: Option<u32>,
+    /// If a `producers` custom section is written.
+    pub producers_section: bool,
+}
+
+/// A variable visible in the current scope.
+#[derive(Clone)]
+struct Binding {
+    idx: LocalIdx,
+    /// Where the variable was declared.
+    span: Span,
+    /// The number of blocks enclosing the declaration.
+    depth: usize,
+}
+
+/// Where the value of a variable is stored.
+#[derive(Clone, Copy)]
+enum Variable {
+    Local(LocalIdx),
+    Global(GlobalIdx),
+}
+impl Variable {
+    fn get(self, w: &mut Vec<u8>) {
+        match self {
+            Variable::Local(idx) => {
+                wasm!(w, local.get idx);
+            }
+            Variable::Global(idx) => {
+                wasm!(w, global.get idx);
             }
-            procedures.push(p);
         }
-        procedures.sort_by_key(|x| x.idx);
-        Ok(procedures)
     }
 
-    fn eat_token(&mut self) {
-        self.last = self.current.clone();
-        self.current = self.next.clone();
-        self.next = self.lexer.next().unwrap_or_else(|| {
-            let end = self.source.len();
-            (Token::Eof, end..end)
-        });
+    fn set(self, w: &mut Vec<u8>) {
+        match self {
+            Variable::Local(idx) => {
+                wasm!(w, local.set idx);
+            }
+            Variable::Global(idx) => {
+                wasm!(w, global.set idx);
+            }
+        }
     }
+}
 
-    fn match_token(&mut self, token: Token) -> Res<'s> {
-        if self.current.0 != token {
-            Err(Error {
-                source: self.source,
-                span: self.current.1.clone(),
-                kind: ErrorKind::UnexpectedToken {
-                    expected: std::slice::from_ref(token.to_static()),
-                    received: self.current.clone().0,
-                },
-            })
-        } else {
-            self.eat_token();
-            Ok(())
+struct Context {
+    code: Vec<u8>,
+    symbols: HashMap<String, Binding>,
+    /// For each open block, the bindings that were replaced or added by declarations inside it, to
+    /// be restored at its end.
+    scopes: Vec<Vec<(String, Option<Binding>)>>,
+    /// The type of each local, including the function parameters.
+    locals: Vec<Type>,
+    /// The variables that are only stepped by one, and are stored in `i32` locals. See
+    /// [`int_counters`].
+    int_counters: HashSet<String>,
+    /// The number of labels of the open `block`, `loop` and `if` instructions.
+    labels: u32,
+    /// For each enclosing loop, the value of `labels` right after opening the block that a
+    /// `break` exits.
+    loop_exits: Vec<u32>,
+    /// The number of values returned by the function.
+    results: u32,
+    /// The hidden locals that hold the arguments of `setpixel`, created at its first call, and
+    /// reused by the other calls of the function.
+    setpixel_locals: Option<[LocalIdx; 3]>,
+    /// The operands shared by the two comparisons of a chain being compiled, like the `f()` of
+    /// `(0 < f() < 10)`, by their span. Each is kept in a hidden local, and only evaluated by the
+    /// first comparison, which sets the flag.
+    chain_operands: Vec<(Span, LocalIdx, bool)>,
+    /// The hidden local that holds the index of a table while it is checked, with `bounds_checks`.
+    table_index: Option<LocalIdx>,
+}
+impl Context {
+    fn new(params: Vec<(String, Span)>) -> Self {
+        Self {
+            code: Vec::new(),
+            locals: vec![Type::F32; params.len()],
+            // function arguments are the starting locals index
+            symbols: params
+                .into_iter()
+                .zip(0..)
+                .map(|((name, span), idx)| {
+                    (
+                        name,
+                        Binding {
+                            idx,
+                            span,
+                            depth: 0,
+                        },
+                    )
+                })
+                .collect(),
+            scopes: Vec::new(),
+            int_counters: HashSet::new(),
+            labels: 0,
+            loop_exits: Vec::new(),
+            results: 0,
+            setpixel_locals: None,
+            chain_operands: Vec::new(),
+            table_index: None,
         }
     }
 
-    fn expect_type(&mut self, rec: Type, expec: Type, start: usize) -> Res<'s, Type> {
-        if rec != expec {
-            Err(Error {
-                source: self.source,
-                span: start..self.last.1.end,
-                kind: ErrorKind::UnexpectedType {
-                    expected: std::slice::from_ref(match expec {
-                        Type::I32 => &Type::I32,
-                        Type::F32 => &Type::F32,
-                    }),
-                    received: vec![rec],
-                },
-            })
-        } else {
-            Ok(rec)
+    /// Find a declared variable. The locals shadow the `globals`.
+    fn lookup(&self, symbol: &str, globals: &HashMap<&str, GlobalIdx>) -> Option<Variable> {
+        match self.symbols.get(symbol) {
+            Some(binding) => Some(Variable::Local(binding.idx)),
+            None => globals.get(symbol).map(|&idx| Variable::Global(idx)),
         }
     }
 
-    fn procedure_from_symbol<'a>(
-        &'a mut self,
+    /// Get a variable being used or assigned. If it was never declared, and is not one of the
+    /// `globals`, it is declared as a local in the scope of the function.
+    fn variable_for_symbol(
+        &mut self,
         symbol: &str,
-        num_param: u32,
-    ) -> Res<'s, &'a mut Procedure> {
-        if self.procedures.get_mut(symbol).is_some() {
-            // need to get twice, because of the borrow checker
-            let proc = self.procedures.get_mut(symbol).unwrap();
-
-            // Err($a) ==>> Err(Error { source: self.source, span: self.current.1.clone(), kind: $a })
-            if proc.num_param != num_param {
-                return Err(Error {
-                    source: self.source,
-                    span: self.current.1.clone(),
-                    kind: ErrorKind::ArgumentNumberMismatch {
-                        expected: proc.num_param,
-                        received: num_param,
-                    },
-                });
-            }
-
-            Ok(proc)
+        span: Span,
+        globals: &HashMap<&str, GlobalIdx>,
+    ) -> Variable {
+        if let Some(variable) = self.lookup(symbol, globals) {
+            variable
         } else {
-            let idx = (self.procedures.len() + 1) as FuncIdx;
-            let proc = Procedure {
+            let idx = self.new_hidden_local(Type::F32);
+            let binding = Binding {
                 idx,
-                num_param,
-                code: Vec::new(),
+                span,
+                depth: 0,
             };
+            self.symbols.insert(symbol.to_string(), binding);
+            Variable::Local(idx)
+        }
+    }
 
-            self.procedures.insert(symbol.to_string(), proc);
-            let proc = self.procedures.get_mut(symbol).unwrap();
+    /// Declare a variable in the current scope, and get its local. If it shadows a variable of a
+    /// outer scope, the span of that declaration is also returned.
+    fn declare_symbol(&mut self, symbol: &str, span: Span) -> (LocalIdx, Option<Span>) {
+        let depth = self.scopes.len();
+        let outer = match self.symbols.get(symbol) {
+            // redeclaring in the same scope, the variable is reused
+            Some(binding) if binding.depth == depth => return (binding.idx, None),
+            x => x.cloned(),
+        };
 
-            Ok(proc)
+        let ty = if self.int_counters.contains(symbol) {
+            Type::I32
+        } else {
+            Type::F32
+        };
+        let idx = self.new_hidden_local(ty);
+        let outer_span = outer.as_ref().map(|x| x.span.clone());
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((symbol.to_string(), outer));
         }
+        self.symbols
+            .insert(symbol.to_string(), Binding { idx, span, depth });
+        (idx, outer_span)
     }
 
-    // parse "<statement>*"
-    fn statement(&mut self, ctx: &mut Context) -> Res<'s> {
-        match self.current.0 {
-            Token::Print => self.print_statement(ctx)?,
-            Token::Var => self.variable_declaration(ctx)?,
-            Token::Identifier => match self.next.0 {
-                Token::Assignment => self.variable_assignment(ctx)?,
-                Token::LeftParen => self.proc_call(ctx)?,
-                _ => {
-                    return Err(Error {
-                        source: self.source,
-                        span: self.current.1.clone(),
-                        kind: ErrorKind::UnexpectedToken {
-                            expected: &[Token::Assignment, Token::LeftParen],
-                            received: self.next.clone().0,
-                        },
-                    })
-                }
-            },
-            Token::While => self.while_statement(ctx)?,
-            Token::If => self.if_statement(ctx)?,
-            Token::Proc => self.proc_statement()?,
-            _ => {
-                return Err(Error {
-                    source: self.source,
-                    span: self.current.1.clone(),
-                    kind: ErrorKind::UnexpectedToken {
-                        expected: &[Token::Print, Token::Var, Token::Identifier, Token::While],
-                        received: self.current.clone().0,
-                    },
-                })
+    fn enter_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Remove the variables declared in the current scope, restoring the ones they shadowed.
+    fn exit_scope(&mut self) {
+        let scope = self.scopes.pop().expect("there is no scope to exit");
+        for (symbol, outer) in scope.into_iter().rev() {
+            match outer {
+                Some(binding) => self.symbols.insert(symbol, binding),
+                None => self.symbols.remove(&symbol),
+            };
+        }
+    }
+
+    /// If the variable is a integer counter, stored in a `i32` local.
+    fn is_int(&self, variable: Variable) -> bool {
+        match variable {
+            Variable::Local(idx) => self.locals[idx as usize] == Type::I32,
+            Variable::Global(_) => false,
+        }
+    }
+
+    /// Create a new local that can't be referenced by the source code.
+    fn new_hidden_local(&mut self, ty: Type) -> LocalIdx {
+        self.locals.push(ty);
+        (self.locals.len() - 1) as LocalIdx
+    }
+
+    /// The hidden locals of the arguments of `setpixel`, shared by all its calls in the function.
+    fn setpixel_locals(&mut self) -> [LocalIdx; 3] {
+        if let Some(locals) = self.setpixel_locals {
+            return locals;
+        }
+        let locals = [(); 3].map(|_| self.new_hidden_local(Type::F32));
+        self.setpixel_locals = Some(locals);
+        locals
+    }
+
+    /// The hidden local of the index of a table, shared by all the reads of the function.
+    fn table_index_local(&mut self) -> LocalIdx {
+        match self.table_index {
+            Some(local) => local,
+            None => {
+                let local = self.new_hidden_local(Type::F32);
+                self.table_index = Some(local);
+                local
+            }
+        }
+    }
+
+    /// Write the declaration of the locals, after the whole function was generated, so it
+    /// includes every variable and hidden local that was created, with its type. The first
+    /// `num_param` locals are the parameters, that are not declared. The declaration is kept apart
+    /// from the code, and only written before it when the module is serialized, so the code is
+    /// never copied.
+    fn finalize_locals(self, num_param: usize) -> FunctionCode {
+        let mut locals = Vec::new();
+        write_locals(&mut locals, &self.locals[num_param..]);
+        let num_locals = (self.locals.len() - num_param) as u32;
+        (locals, self.code, num_locals)
+    }
+}
+
+/// The number of bytes of `x` encoded as a unsigned LEB128.
+fn leb128_len(x: u64) -> u64 {
+    let bits = 64 - x.leading_zeros().min(63) as u64;
+    bits.div_ceil(7)
+}
+
+/// Check that a section, or function body, of `size` bytes fits in the size `limit`.
+pub(crate) fn check_size(section: &'static str, size: u64, limit: u64) -> Result<(), ErrorKind> {
+    if size > limit {
+        return Err(ErrorKind::ModuleTooLarge {
+            section,
+            size,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Check that each function body fits in `max_function_size`, and that the sections that grow
+/// with the program, the code and the data sections, fit in the size limit of WebAssembly, so the
+/// module is never serialized with a truncated size. On error, also return the name of the
+/// procedure that is too large, or the largest one if it is the code section.
+fn check_sizes<'a>(
+    procedures: &'a [Procedure],
+    data: &[u8],
+    max_function_size: u32,
+) -> Result<(), (ErrorKind, Option<&'a str>)> {
+    let mut code_size = leb128_len(procedures.len() as u64);
+    for p in procedures {
+        let size = p.size();
+        check_size("code", size, max_function_size as u64).map_err(|x| (x, Some(p.name())))?;
+        code_size += leb128_len(size) + size;
+    }
+    check_size("code", code_size, MAX_SIZE).map_err(|x| {
+        let largest = procedures.iter().max_by_key(|p| p.size());
+        (x, largest.map(|p| p.name()))
+    })?;
+
+    // the memory index, the offset expression, and the size of the data
+    let offset_size = 2 + leb128_len(STRING_DATA_OFFSET as u64) + 1;
+    let data_size = 1 + offset_size + leb128_len(data.len() as u64) + data.len() as u64;
+    check_size("data", data_size, MAX_SIZE).map_err(|x| (x, None))
+}
+
+/// Write the vector of locals of a function, grouping consecutive locals of the same type.
+fn write_locals(w: &mut Vec<u8>, locals: &[Type]) {
+    let mut groups: Vec<(u32, Type)> = Vec::new();
+    for &ty in locals {
+        match groups.last_mut() {
+            Some((n, last)) if *last == ty => *n += 1,
+            _ => groups.push((1, ty)),
+        }
+    }
+
+    leb128::write_unsigned(w, groups.len() as u64);
+    for (n, ty) in groups {
+        leb128::write_unsigned(w, n as u64);
+        match ty {
+            Type::I32 => {
+                wasm!(w, i32);
+            }
+            Type::F32 => {
+                wasm!(w, f32);
+            }
+        }
+    }
+}
+
+/// The body of a function, `main` or a procedure, whose code can be generated independently of
+/// the other functions.
+struct FunctionBody<'a, 's> {
+    name: &'s str,
+    /// The name of the procedure, or all statements of `main`.
+    span: Span,
+    params: &'a [Param<'s>],
+    body: &'a [Statement<'s>],
+}
+
+/// Compile a abstract syntax tree to webassembly code.
+///
+/// The compilation has two passes. First, the whole program is resolved in order, assigning the
+/// indices of the procedures, the offsets of the string literals and the types of indirect calls.
+/// Then, the code of each function is generated independently, in parallel if the feature
+/// `parallel` is enabled.
+pub struct Compiler<'source> {
+    source: &'source str,
+    options: CompileOptions,
+    procedures: HashMap<String, Procedure>,
+    /// The span and number of arguments of each call to a procedure that was not defined yet. A
+    /// reference to the procedure is recorded with `None` arguments.
+    unresolved_calls: HashMap<String, Vec<(Span, Option<u32>)>>,
+    /// The default value of each parameter of each procedure defined in the program, collected
+    /// before compiling, so calls can omit arguments even before the definition.
+    defaults: HashMap<&'source str, Vec<Option<Expression<'source>>>>,
+    types: Vec<FuncType>,
+    uses_table: bool,
+    warnings: Vec<Warning>,
+    /// The number of imported functions, which come before the procedures in the function index
+    /// space.
+    imported: FuncIdx,
+    data: Vec<u8>,
+    /// The offset of each string literal in the memory. Equal strings share the same bytes.
+    strings: HashMap<&'source str, u32>,
+    /// The start of the span of each call to a procedure reference held by a variable.
+    indirect_calls: HashSet<usize>,
+    /// The global variables, declared at the top level, and visible in all procedures.
+    globals: HashMap<&'source str, GlobalIdx>,
+    /// The memory offset and the number of values of each constant table, declared at the top
+    /// level. The tables are placed at the start of the data, before the string literals.
+    tables: HashMap<&'source str, (u32, u32)>,
+    /// If the program is the one written in the source, and not the optimized one, where a
+    /// constant index may have been propagated from a variable, and only be read at runtime.
+    as_written: bool,
+}
+impl<'s> Compiler<'s> {
+    pub fn compile(
+        source: &'s str,
+        program: &ast::Program<'s>,
+        options: &CompileOptions,
+    ) -> Result<Module, Error<'s>> {
+        Self::compile_all_errors(source, program, options).map_err(|mut errors| errors.remove(0))
+    }
+
+    /// Compile the program, like [`Compiler::compile`], but on failure return all the errors
+    /// found, instead of only the first one. A error when resolving the procedures stops the
+    /// compilation, but each procedure can report one error when generating its code.
+    pub fn compile_all_errors(
+        source: &'s str,
+        program: &ast::Program<'s>,
+        options: &CompileOptions,
+    ) -> Result<Module, Vec<Error<'s>>> {
+        if program.statements.is_empty() && !options.allow_empty {
+            return Err(vec![Error {
+                source,
+                span: 0..0,
+                kind: ErrorKind::EmptyProgram,
+            }]);
+        }
+
+        let imports_prints = prints_string(&program.statements);
+        let uses_rgb = any_statement(
+            &program.statements,
+            &|kind| matches!(kind, StatementKind::Call { ident, .. } if ident.name == "setrgb"),
+        );
+        let imports_printb = prints_condition(&program.statements);
+        let mut compiler = Self::new(source, options, imports_prints, imports_printb);
+        let functions = compiler.resolve(program)?;
+
+        let mut errors = Vec::new();
+        for (function, result) in functions.iter().zip(compiler.generate(&functions)) {
+            match result {
+                Ok((locals, code, num_locals)) => {
+                    let proc = compiler.procedures.get_mut(function.name).unwrap();
+                    proc.locals = locals;
+                    proc.code = code;
+                    proc.num_locals = num_locals;
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        // the error that comes first in the source is reported first, like if the functions were
+        // compiled in the order they are defined
+        errors.sort_by_key(|err| err.span.start);
+
+        // sorted by index, so types are assigned in a deterministic order
+        let mut procedures: Vec<_> = core::mem::take(&mut compiler.procedures)
+            .into_iter()
+            .collect();
+        procedures.sort_by_key(|(_, p)| p.idx);
+
+        let defined: HashSet<&str> = functions.iter().map(|f| f.name).collect();
+        let mut exports = vec![("main".to_string(), compiler.imported)];
+        let procedures: Vec<_> = procedures
+            .into_iter()
+            .filter_map(|(name, mut p)| {
+                if !defined.contains(name.as_str()) {
+                    let span = match compiler.unresolved_calls.get(&name) {
+                        Some(calls) => calls[0].0.clone(),
+                        None => source.len()..source.len(),
+                    };
+                    errors.push(Error {
+                        source: compiler.source,
+                        span,
+                        kind: ErrorKind::UndeclaredProc { name },
+                    });
+                    return None;
+                }
+                p.ty = compiler.type_index(FuncType::returning(p.num_param, p.num_results));
+                // "frame(t)" is exported, to allow hosts to render animations
+                if name == "frame" && p.num_param == 1 {
+                    exports.push((name, p.idx));
+                }
+                Some(p)
+            })
+            .collect();
+        // the procedures exported by the options, that are not already exported
+        for name in &options.exports {
+            if exports.iter().any(|(export, _)| export == name) {
+                continue;
+            }
+            match procedures.iter().find(|p| &p.name == name) {
+                Some(p) => exports.push((name.clone(), p.idx)),
+                None => errors.push(Error {
+                    source: compiler.source,
+                    span: source.len()..source.len(),
+                    kind: ErrorKind::UndeclaredProc { name: name.clone() },
+                }),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        check_sizes(&procedures, &compiler.data, options.max_function_size).map_err(
+            |(kind, name)| {
+                // the error points to the procedure that is too large, if any
+                let span = functions
+                    .iter()
+                    .find(|f| Some(f.name) == name)
+                    .map_or(0..0, |f| f.span.clone());
+                vec![Error {
+                    source: compiler.source,
+                    span,
+                    kind,
+                }]
+            },
+        )?;
+        // the RGB canvas is in the second page
+        let memory_min_pages = match uses_rgb {
+            true => options.memory_min_pages.max(2),
+            false => options.memory_min_pages,
+        };
+        if let Some(max) = options
+            .memory_max_pages
+            .filter(|&max| max < memory_min_pages)
+        {
+            return Err(vec![Error {
+                source: compiler.source,
+                span: 0..0,
+                kind: ErrorKind::InvalidMemoryLimits {
+                    min: memory_min_pages,
+                    max,
+                },
+            }]);
+        }
+        Ok(Module {
+            types: compiler.types,
+            imports_prints,
+            imports_printb,
+            procedures,
+            uses_table: compiler.uses_table,
+            uses_rgb,
+            exports,
+            warnings: compiler.warnings,
+            data: compiler.data,
+            globals: compiler.globals.len() as u32,
+            memory_min_pages,
+            memory_max_pages: options.memory_max_pages,
+            producers_section: options.producers_section,
+        })
+    }
+
+    /// A compiler for the program, with the types of its imports, and the `main` procedure.
+    fn new(
+        source: &'s str,
+        options: &CompileOptions,
+        imports_prints: bool,
+        imports_printb: bool,
+    ) -> Self {
+        let mut compiler = Self {
+            source,
+            options: options.clone(),
+            procedures: HashMap::new(),
+            unresolved_calls: HashMap::new(),
+            defaults: HashMap::new(),
+            // the type of the "print" function
+            types: vec![FuncType::procedure(1)],
+            uses_table: false,
+            warnings: Vec::new(),
+            imported: 1,
+            data: Vec::new(),
+            strings: HashMap::new(),
+            indirect_calls: HashSet::new(),
+            globals: HashMap::new(),
+            tables: HashMap::new(),
+            as_written: !options.optimize,
+        };
+        if imports_prints {
+            // the type of the "prints" function, receiving a pointer and a length
+            compiler.types.push(FuncType {
+                params: vec![Type::I32, Type::I32],
+                results: Vec::new(),
+            });
+            compiler.imported += 1;
+        }
+        if imports_printb {
+            // the type of the "printb" function, receiving a condition
+            compiler.types.push(FuncType {
+                params: vec![Type::I32],
+                results: Vec::new(),
+            });
+            compiler.imported += 1;
+        }
+
+        let main_proc = Procedure {
+            name: "main".to_string(),
+            idx: compiler.imported,
+            ty: 0,
+            num_param: 0,
+            num_results: 0,
+            num_locals: 0,
+            locals: Vec::new(),
+            code: Vec::new(),
+        };
+        compiler.procedures.insert("main".to_string(), main_proc);
+
+        compiler
+    }
+
+    /// The first pass of the compilation: resolve the statements of the program in order,
+    /// collecting the warnings, and return the bodies of all functions.
+    fn resolve<'a>(
+        &mut self,
+        program: &'a ast::Program<'s>,
+    ) -> Result<Vec<FunctionBody<'a, 's>>, Vec<Error<'s>>> {
+        self.collect_defaults(&program.statements)
+            .map_err(|err| vec![err])?;
+        // the globals are known before their declaration, so procedures defined earlier can use
+        // them
+        for statement in &program.statements {
+            if let StatementKind::Global { ident, .. } = &statement.kind {
+                let idx = self.globals.len() as GlobalIdx;
+                self.globals.entry(ident.name).or_insert(idx);
+            }
+        }
+        // the tables too, that can only be declared once
+        for statement in &program.statements {
+            if let StatementKind::Data { ident, values } = &statement.kind {
+                if self.tables.contains_key(ident.name) {
+                    return Err(vec![Error {
+                        source: self.source,
+                        span: ident.span.clone(),
+                        kind: ErrorKind::DuplicateData {
+                            name: ident.name.to_string(),
+                        },
+                    }]);
+                }
+                let mut bytes = Vec::with_capacity(values.len() * 4);
+                for value in values {
+                    let value = match value.kind {
+                        ExpressionKind::Number(x) => x,
+                        ExpressionKind::Integer(x) => x as f32,
+                        _ => unreachable!("the parser only accepts number literals in a table"),
+                    };
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                let base = self
+                    .push_data(&bytes, &statement.span)
+                    .map_err(|err| vec![err])?;
+                self.tables.insert(ident.name, (base, values.len() as u32));
+            }
+        }
+
+        // resolve the statements, collecting the bodies of all functions
+        let main_span = match (program.statements.first(), program.statements.last()) {
+            (Some(first), Some(last)) => first.span.start..last.span.end,
+            _ => 0..0,
+        };
+        let mut functions = vec![FunctionBody {
+            name: "main",
+            span: main_span,
+            params: &[],
+            body: &program.statements,
+        }];
+        let mut ctx = Context::new(Vec::new());
+        for statement in &program.statements {
+            self.resolve_statement(&mut ctx, statement, &mut functions)
+                .map_err(|err| vec![err])?;
+        }
+
+        for function in &functions {
+            unused_variables(function.body, &mut self.warnings);
+        }
+        // in the order of the source, like the errors
+        self.warnings.sort_by_key(|warning| warning.span().start);
+        Ok(functions)
+    }
+
+    /// The warnings of the program, found by resolving it, without generating its code. Warnings
+    /// are about the program as written, so [`compile_to_module`](crate::compile_to_module) takes
+    /// them from the program before optimizing it, together with the errors that only it has, like
+    /// a constant index out of a table.
+    pub fn warnings(
+        source: &'s str,
+        program: &ast::Program<'s>,
+        options: &CompileOptions,
+    ) -> Result<Vec<Warning>, Error<'s>> {
+        let imports_prints = prints_string(&program.statements);
+        let imports_printb = prints_condition(&program.statements);
+        let mut compiler = Self::new(source, options, imports_prints, imports_printb);
+        compiler.as_written = true;
+        compiler
+            .resolve(program)
+            .map_err(|mut errors| errors.remove(0))?;
+        Ok(compiler.warnings)
+    }
+
+    /// Record the default values of the parameters of all procedures defined in `statements`,
+    /// including the ones nested in blocks.
+    ///
+    /// The default values are compiled at each call that omits them, so they must be constants,
+    /// or procedure references, that have the same value in the scope of any caller. They are
+    /// stored folded.
+    fn collect_defaults(&mut self, statements: &[Statement<'s>]) -> Res<'s> {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Proc {
+                    ident,
+                    params,
+                    body,
+                } => {
+                    let mut defaults = Vec::with_capacity(params.len());
+                    for param in params {
+                        defaults.push(match &param.default {
+                            Some(default) => Some(self.constant_default(default)?),
+                            None => None,
+                        });
+                    }
+                    self.defaults.entry(ident.name).or_insert(defaults);
+                    self.collect_defaults(body)?;
+                }
+                StatementKind::While { body, .. }
+                | StatementKind::Repeat { body, .. }
+                | StatementKind::Loop { body } => self.collect_defaults(body)?,
+                StatementKind::If {
+                    then_body,
+                    else_body,
+                    ..
+                } => {
+                    self.collect_defaults(then_body)?;
+                    self.collect_defaults(else_body.as_deref().unwrap_or_default())?;
+                }
+                StatementKind::Switch { cases, default, .. } => {
+                    for case in cases {
+                        self.collect_defaults(&case.body)?;
+                    }
+                    self.collect_defaults(default.as_deref().unwrap_or_default())?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Fold the default value of a parameter, failing if it is not a constant or a procedure
+    /// reference, like when it uses a variable.
+    fn constant_default(&self, default: &Expression<'s>) -> Res<'s, Expression<'s>> {
+        if let ExpressionKind::Reference(_) = default.kind {
+            return Ok(default.clone());
+        }
+        let mut value = default.clone();
+        match crate::optimize::fold_constant(&mut value) {
+            Some(_) => Ok(value),
+            None => Err(Error {
+                source: self.source,
+                span: default.span.clone(),
+                kind: ErrorKind::NotConstant,
+            }),
+        }
+    }
+
+    /// Get the index of the given function type, adding it to the type section if necessary.
+    fn type_index(&mut self, ty: FuncType) -> TypeIdx {
+        match self.types.iter().position(|x| *x == ty) {
+            Some(idx) => idx as TypeIdx,
+            None => {
+                self.types.push(ty);
+                (self.types.len() - 1) as TypeIdx
             }
         }
+    }
+
+    /// Get the index of a function type that was already added to the type section.
+    fn existing_type_index(&self, ty: &FuncType) -> TypeIdx {
+        self.types
+            .iter()
+            .position(|x| x == ty)
+            .expect("the type was not added when resolving") as TypeIdx
+    }
+
+    fn expect_type(&self, rec: Type, expec: Type, span: &Span) -> Res<'s, Type> {
+        if rec != expec {
+            Err(Error {
+                source: self.source,
+                span: span.clone(),
+                kind: ErrorKind::UnexpectedType {
+                    expected: core::slice::from_ref(match expec {
+                        Type::I32 => &Type::I32,
+                        Type::F32 => &Type::F32,
+                    }),
+                    received: vec![rec],
+                },
+            })
+        } else {
+            Ok(rec)
+        }
+    }
+
+    /// Get the procedure being called, with `num_param` arguments, at the given `span`.
+    ///
+    /// If the procedure was not defined yet, the call is recorded, to be checked against its
+    /// definition later.
+    fn procedure_from_call<'a>(
+        &'a mut self,
+        symbol: &str,
+        num_param: u32,
+        span: Span,
+    ) -> Res<'s, &'a mut Procedure> {
+        if let Some(calls) = self.unresolved_calls.get_mut(symbol) {
+            calls.push((span, Some(num_param)));
+            return Ok(self.procedures.get_mut(symbol).unwrap());
+        }
+
+        if self.procedures.get_mut(symbol).is_some() {
+            // need to get twice, because of the borrow checker
+            let proc = self.procedures.get_mut(symbol).unwrap();
+
+            if proc.num_param != num_param {
+                return Err(Error {
+                    source: self.source,
+                    span,
+                    kind: ErrorKind::ArgumentNumberMismatch {
+                        expected: proc.num_param,
+                        received: num_param,
+                    },
+                });
+            }
+
+            Ok(proc)
+        } else {
+            self.unresolved_calls
+                .insert(symbol.to_string(), vec![(span.clone(), Some(num_param))]);
+            self.new_procedure(symbol, num_param, span)
+        }
+    }
+
+    /// Get the procedure being referenced at the given `span`.
+    fn procedure_from_reference(&mut self, symbol: &str, span: Span) -> Res<'s, &mut Procedure> {
+        if let Some(calls) = self.unresolved_calls.get_mut(symbol) {
+            calls.push((span, None));
+            Ok(self.procedures.get_mut(symbol).unwrap())
+        } else if self.procedures.contains_key(symbol) {
+            Ok(self.procedures.get_mut(symbol).unwrap())
+        } else {
+            // the number of parameters will only be known in the definition
+            self.unresolved_calls
+                .insert(symbol.to_string(), vec![(span.clone(), None)]);
+            self.new_procedure(symbol, 0, span)
+        }
+    }
+
+    /// Get the procedure being defined, with `num_param` parameters, at the given `span`.
+    ///
+    /// Any previous call to the procedure is checked against the number of parameters, and the
+    /// first one that mismatch is reported.
+    fn procedure_from_definition<'a>(
+        &'a mut self,
+        symbol: &str,
+        num_param: u32,
+        span: Span,
+    ) -> Res<'s, &'a mut Procedure> {
+        if let Some(calls) = self.unresolved_calls.remove(symbol) {
+            let mismatch = calls
+                .into_iter()
+                .find_map(|(span, n)| n.filter(|&n| n != num_param).map(|n| (span, n)));
+            if let Some((span, received)) = mismatch {
+                return Err(Error {
+                    source: self.source,
+                    span,
+                    kind: ErrorKind::ArgumentNumberMismatch {
+                        expected: num_param,
+                        received,
+                    },
+                });
+            }
+            let proc = self.procedures.get_mut(symbol).unwrap();
+            proc.num_param = num_param;
+            return Ok(proc);
+        }
+
+        if self.procedures.get_mut(symbol).is_some() {
+            // need to get twice, because of the borrow checker
+            let proc = self.procedures.get_mut(symbol).unwrap();
+
+            if proc.num_param != num_param {
+                return Err(Error {
+                    source: self.source,
+                    span,
+                    kind: ErrorKind::ArgumentNumberMismatch {
+                        expected: proc.num_param,
+                        received: num_param,
+                    },
+                });
+            }
+
+            Ok(proc)
+        } else {
+            self.new_procedure(symbol, num_param, span)
+        }
+    }
+
+    /// Create a new procedure, first used at the given `span`, failing if the module would have
+    /// more functions than [`CompileOptions::max_functions`].
+    fn new_procedure(
+        &mut self,
+        symbol: &str,
+        num_param: u32,
+        span: Span,
+    ) -> Res<'s, &mut Procedure> {
+        let idx = self.procedures.len() as FuncIdx + self.imported;
+        if idx >= self.options.max_functions {
+            return Err(Error {
+                source: self.source,
+                span,
+                kind: ErrorKind::TooManyFunctions {
+                    limit: self.options.max_functions,
+                },
+            });
+        }
+        let proc = Procedure {
+            name: symbol.to_string(),
+            idx,
+            ty: 0,
+            num_param,
+            num_results: 0,
+            num_locals: 0,
+            locals: Vec::new(),
+            code: Vec::new(),
+        };
+
+        self.procedures.insert(symbol.to_string(), proc);
+        Ok(self.procedures.get_mut(symbol).unwrap())
+    }
+
+    /// Append `bytes` to the data placed after the canvas, returning their address, failing if
+    /// the data would reach the RGB canvas, at [`RGB_CANVAS_OFFSET`]. The error points to `span`,
+    /// the statement that has the data.
+    fn push_data(&mut self, bytes: &[u8], span: &Span) -> Res<'s, u32> {
+        let address = STRING_DATA_OFFSET + self.data.len() as u32;
+        let limit = (RGB_CANVAS_OFFSET - STRING_DATA_OFFSET) as u64;
+        let size = (self.data.len() + bytes.len()) as u64;
+        if size > limit {
+            return Err(Error {
+                source: self.source,
+                span: span.clone(),
+                kind: ErrorKind::ModuleTooLarge {
+                    section: "data",
+                    size,
+                    limit,
+                },
+            });
+        }
+        self.data.extend_from_slice(bytes);
+        Ok(address)
+    }
+
+    /// Resolve a statement of a function, in the order the code is generated: declare its
+    /// variables, get the procedures it calls or references, and place its string literals. The
+    /// bodies of the procedures it defines are pushed to `functions`, and resolved too.
+    fn resolve_statement<'a>(
+        &mut self,
+        ctx: &mut Context,
+        statement: &'a Statement<'s>,
+        functions: &mut Vec<FunctionBody<'a, 's>>,
+    ) -> Res<'s> {
+        match &statement.kind {
+            StatementKind::Print(value) => self.resolve_expression(ctx, value)?,
+            StatementKind::Global { ident, value } => {
+                self.check_variable_name(ident)?;
+                self.resolve_expression(ctx, value)?;
+            }
+            StatementKind::PrintString(text) => {
+                if !self.strings.contains_key(text) {
+                    let ptr = self.push_data(text.as_bytes(), &statement.span)?;
+                    self.strings.insert(text, ptr);
+                }
+            }
+            StatementKind::Pass | StatementKind::Data { .. } => {}
+            StatementKind::Var { ident, value } => {
+                self.resolve_expression(ctx, value)?;
+                self.check_variable_name(ident)?;
+                self.declare_variable(ctx, ident);
+            }
+            StatementKind::Assignment { ident, value } => {
+                self.check_variable_name(ident)?;
+                ctx.variable_for_symbol(ident.name, ident.span.clone(), &self.globals);
+                self.resolve_expression(ctx, value)?;
+            }
+            StatementKind::Call { ident, args } if ident.name == "setpixel" => {
+                for (arg, name) in args.iter().zip(["x", "y", "color"]) {
+                    self.resolve_expression(ctx, arg)?;
+                    if self.options.legacy_setpixel_sideeffects {
+                        ctx.variable_for_symbol(name, ident.span.clone(), &self.globals);
+                    }
+                }
+            }
+            StatementKind::Call { ident, args }
+                if matches!(ident.name, "blendpixel" | "setrgb") =>
+            {
+                for arg in args {
+                    self.resolve_expression(ctx, arg)?;
+                }
+                let expected = if ident.name == "setrgb" { 5 } else { 3 };
+                if args.len() != expected {
+                    return Err(Error {
+                        source: self.source,
+                        span: statement.span.clone(),
+                        kind: ErrorKind::ArgumentNumberMismatch {
+                            expected: expected as u32,
+                            received: args.len() as u32,
+                        },
+                    });
+                }
+            }
+            StatementKind::Call { ident, args } => {
+                // a variable holding a procedure reference, that can't have the name of a
+                // procedure
+                let is_reference = ctx.lookup(ident.name, &self.globals).is_some();
+                self.resolve_call(ctx, &statement.span, ident, args, is_reference)?;
+            }
+            StatementKind::Destructure {
+                targets,
+                ident,
+                args,
+            } => {
+                self.resolve_call(ctx, &statement.span, ident, args, false)?;
+                for target in targets {
+                    self.check_variable_name(target)?;
+                    self.declare_variable(ctx, target);
+                }
+            }
+            StatementKind::Return(values) => {
+                for value in values {
+                    self.resolve_expression(ctx, value)?;
+                }
+            }
+            StatementKind::While { condition, body } => {
+                self.resolve_expression(ctx, condition)?;
+                let value = self.warn_constant_condition(condition);
+                if value == Some(true) && !breaks_loop(body) {
+                    self.warnings.push(Warning::PossibleInfiniteLoop {
+                        span: statement.span.clone(),
+                    });
+                }
+                self.resolve_block(ctx, body, functions)?;
+            }
+            StatementKind::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                self.resolve_expression(ctx, condition)?;
+                self.warn_constant_condition(condition);
+                self.resolve_block(ctx, then_body, functions)?;
+                if let Some(else_body) = else_body {
+                    self.resolve_block(ctx, else_body, functions)?;
+                }
+            }
+            StatementKind::Repeat { count, body } => {
+                self.resolve_expression(ctx, count)?;
+                self.resolve_block(ctx, body, functions)?;
+            }
+            StatementKind::Loop { body } => {
+                if !breaks_loop(body) {
+                    self.warnings.push(Warning::PossibleInfiniteLoop {
+                        span: statement.span.clone(),
+                    });
+                }
+                self.resolve_block(ctx, body, functions)?;
+            }
+            StatementKind::Break => {}
+            StatementKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                self.resolve_expression(ctx, value)?;
+                for case in cases {
+                    self.resolve_block(ctx, &case.body, functions)?;
+                }
+                if let Some(default) = default {
+                    self.resolve_block(ctx, default, functions)?;
+                }
+            }
+            StatementKind::Proc {
+                ident,
+                params,
+                body,
+            } => {
+                for param in params {
+                    self.check_variable_name(&param.ident)?;
+                }
+                let num_param = params.len() as u32;
+                let proc =
+                    self.procedure_from_definition(ident.name, num_param, ident.span.clone())?;
+                // the other returns are checked against the first one when generating the code
+                proc.num_results = first_return(body).map_or(0, |values| values.len() as u32);
+                if proc.num_results != 0 && !always_returns(body) {
+                    return Err(Error {
+                        source: self.source,
+                        span: ident.span.clone(),
+                        kind: ErrorKind::MissingReturn {
+                            name: ident.name.to_string(),
+                        },
+                    });
+                }
+                functions.push(FunctionBody {
+                    name: ident.name,
+                    span: ident.span.clone(),
+                    params,
+                    body,
+                });
+
+                let mut ctx = Context::new(param_symbols(params));
+                for statement in body {
+                    self.resolve_statement(&mut ctx, statement, functions)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn if the condition of a `while` or a `if` is always true or always false, returning its
+    /// value.
+    fn warn_constant_condition(&mut self, condition: &Expression<'s>) -> Option<bool> {
+        let value = crate::optimize::constant_condition(condition)?;
+        self.warnings.push(Warning::ConstantCondition {
+            value,
+            span: condition.span.clone(),
+        });
+        Some(value)
+    }
+
+    /// Fail if a variable has the name of a procedure defined anywhere in the program, so a call
+    /// of that name would call the variable or the procedure depending on the order of the
+    /// source.
+    fn check_variable_name(&self, ident: &Ident<'s>) -> Res<'s> {
+        // `defaults` has a entry for every procedure
+        if self.defaults.contains_key(ident.name) {
+            return Err(Error {
+                source: self.source,
+                span: ident.span.clone(),
+                kind: ErrorKind::NameClash {
+                    name: ident.name.to_string(),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Declare a variable, warning if it shadows a variable of a outer block.
+    fn declare_variable(&mut self, ctx: &mut Context, ident: &Ident<'s>) {
+        let (_, outer_span) = ctx.declare_symbol(ident.name, ident.span.clone());
+        if let Some(outer_span) = outer_span {
+            self.warnings.push(Warning::Shadowed {
+                name: ident.name.to_string(),
+                span: ident.span.clone(),
+                outer_span,
+            });
+        }
+    }
+
+    /// Resolve the call at `span`, to a procedure, or to a variable holding a procedure reference
+    /// if `is_reference` is set, including the default values of the omitted arguments.
+    fn resolve_call(
+        &mut self,
+        ctx: &mut Context,
+        span: &Span,
+        ident: &Ident<'s>,
+        args: &[Expression<'s>],
+        is_reference: bool,
+    ) -> Res<'s> {
+        for arg in args {
+            self.resolve_expression(ctx, arg)?;
+        }
+        let mut n = args.len() as u32;
+
+        let defaults: Option<Vec<_>> = match self.defaults.get(ident.name) {
+            Some(defaults) if !is_reference && args.len() < defaults.len() => {
+                defaults[args.len()..].iter().cloned().collect()
+            }
+            _ => None,
+        };
+        if let Some(defaults) = defaults {
+            for default in &defaults {
+                self.resolve_expression(ctx, default)?;
+            }
+            n += defaults.len() as u32;
+        }
+
+        if is_reference {
+            self.indirect_calls.insert(span.start);
+            self.type_index(FuncType::procedure(n));
+            self.uses_table = true;
+        } else {
+            self.procedure_from_call(ident.name, n, span.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Resolve a block of statements, in a new scope.
+    fn resolve_block<'a>(
+        &mut self,
+        ctx: &mut Context,
+        statements: &'a [Statement<'s>],
+        functions: &mut Vec<FunctionBody<'a, 's>>,
+    ) -> Res<'s> {
+        ctx.enter_scope();
+        for statement in statements {
+            self.resolve_statement(ctx, statement, functions)?;
+        }
+        ctx.exit_scope();
+        Ok(())
+    }
+
+    /// Resolve the variables and procedure references of a expression.
+    fn resolve_expression(&mut self, ctx: &mut Context, expression: &Expression<'s>) -> Res<'s> {
+        match &expression.kind {
+            ExpressionKind::Number(_) | ExpressionKind::Integer(_) => {}
+            ExpressionKind::Variable(symbol) => {
+                self.check_variable_name(&Ident {
+                    name: symbol,
+                    span: expression.span.clone(),
+                })?;
+                ctx.variable_for_symbol(symbol, expression.span.clone(), &self.globals);
+            }
+            ExpressionKind::Reference(symbol) => {
+                self.procedure_from_reference(symbol, expression.span.clone())?;
+                self.uses_table = true;
+            }
+            ExpressionKind::Negate(value) => self.resolve_expression(ctx, value)?,
+            ExpressionKind::Index { ident, index } => {
+                let len = match self.tables.get(ident.name) {
+                    Some(&(_, len)) => len,
+                    None => {
+                        return Err(Error {
+                            source: self.source,
+                            span: ident.span.clone(),
+                            kind: ErrorKind::UndeclaredData {
+                                name: ident.name.to_string(),
+                            },
+                        })
+                    }
+                };
+                // a constant index must be in the table, which a NaN is not. This is checked on
+                // the program as written, by `warnings`, when optimizing.
+                let constant = crate::optimize::fold_constant(&mut index.as_ref().clone());
+                let out = matches!(constant, Some(x) if !(0.0..len as f32).contains(&x));
+                if out && self.as_written {
+                    return Err(Error {
+                        source: self.source,
+                        span: index.span.clone(),
+                        kind: ErrorKind::DataOutOfBounds {
+                            name: ident.name.to_string(),
+                            len,
+                        },
+                    });
+                }
+                self.resolve_expression(ctx, index)?;
+            }
+            // a procedure that returns a single value, if there is no builtin with its name
+            ExpressionKind::Call { ident, args }
+                if builtin_num_param(ident.name).is_none()
+                    && self.defaults.contains_key(ident.name) =>
+            {
+                self.resolve_call(ctx, &expression.span, ident, args, false)?;
+            }
+            ExpressionKind::Call { args, .. } => {
+                for arg in args {
+                    self.resolve_expression(ctx, arg)?;
+                }
+            }
+            ExpressionKind::Binary { left, right, .. } => {
+                self.resolve_expression(ctx, left)?;
+                self.resolve_expression(ctx, right)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate the code of each function.
+    fn generate(&self, functions: &[FunctionBody<'_, 's>]) -> Vec<Res<'s, FunctionCode>> {
+        #[cfg(feature = "parallel")]
+        if self.options.parallel {
+            use rayon::prelude::*;
+            return functions.par_iter().map(|x| self.function(x)).collect();
+        }
+        functions.iter().map(|x| self.function(x)).collect()
+    }
+
+    /// Generate the code of a function, and its vector of locals.
+    fn function(&self, function: &FunctionBody<'_, 's>) -> Res<'s, FunctionCode> {
+        let num_param = function.params.len();
+        if let Some(param) = function.params.get(self.options.max_locals as usize) {
+            return Err(self.too_many_locals(param.ident.span.clone()));
+        }
+        let mut ctx = Context::new(param_symbols(function.params));
+        if self.options.optimize {
+            ctx.int_counters = int_counters(
+                function.body,
+                function.params,
+                &self.globals,
+                self.options.legacy_setpixel_sideeffects,
+            );
+        }
+
+        ctx.results = self.procedures[function.name].num_results;
+
+        for statement in function.body {
+            self.statement(&mut ctx, statement)?;
+        }
+        // the end of a procedure that returns values is never reached, but its code must still
+        // be valid
+        if ctx.results != 0 {
+            wasm!(&mut ctx.code, unreachable);
+        }
+        wasm!(&mut ctx.code, end);
+        remove_double_eqz(&mut ctx.code);
+
+        Ok(ctx.finalize_locals(num_param))
+    }
+
+    /// Generate the code of a statement, failing if the function has more locals than
+    /// [`CompileOptions::max_locals`] after it.
+    fn statement(&self, ctx: &mut Context, statement: &Statement<'s>) -> Res<'s> {
+        self.statement_kind(ctx, statement)?;
+        if ctx.locals.len() > self.options.max_locals as usize {
+            return Err(self.too_many_locals(statement.span.clone()));
+        }
+        Ok(())
+    }
+
+    fn too_many_locals(&self, span: Span) -> Error<'s> {
+        Error {
+            source: self.source,
+            span,
+            kind: ErrorKind::TooManyLocals {
+                limit: self.options.max_locals,
+            },
+        }
+    }
+
+    fn statement_kind(&self, ctx: &mut Context, statement: &Statement<'s>) -> Res<'s> {
+        match &statement.kind {
+            StatementKind::Print(value) => self.print_statement(ctx, value),
+            StatementKind::PrintString(text) => {
+                self.print_string_statement(ctx, text);
+                Ok(())
+            }
+            StatementKind::Pass => {
+                wasm!(&mut ctx.code, nop);
+                Ok(())
+            }
+            // the table is in the data section, so it has no code
+            StatementKind::Data { .. } => Ok(()),
+            StatementKind::Var { ident, value } => self.variable_declaration(ctx, ident, value),
+            StatementKind::Global { ident, value } => {
+                self.value(ctx, value)?;
+                let idx = self.globals[ident.name];
+                wasm!(&mut ctx.code, global.set idx);
+                Ok(())
+            }
+            StatementKind::Assignment { ident, value } => {
+                self.variable_assignment(ctx, ident, value)
+            }
+            StatementKind::Call { ident, args } => self.proc_call(ctx, statement, ident, args),
+            StatementKind::Destructure {
+                targets,
+                ident,
+                args,
+            } => self.destructure(ctx, statement, targets, ident, args),
+            StatementKind::Return(values) => self.return_statement(ctx, statement, values),
+            StatementKind::While { condition, body } => self.while_statement(ctx, condition, body),
+            StatementKind::If {
+                condition,
+                then_body,
+                else_body,
+            } => self.if_statement(ctx, condition, then_body, else_body.as_deref()),
+            StatementKind::Repeat { count, body } => self.repeat_statement(ctx, count, body),
+            StatementKind::Loop { body } => self.loop_statement(ctx, body),
+            StatementKind::Break => {
+                let exit = *ctx
+                    .loop_exits
+                    .last()
+                    .expect("the parser only accepts break in loops");
+                let depth = ctx.labels - exit;
+                wasm!(&mut ctx.code, br depth);
+                Ok(())
+            }
+            StatementKind::Switch {
+                value,
+                cases,
+                default,
+            } => self.switch_statement(ctx, value, cases, default.as_deref()),
+            // the procedures are compiled separately, see `Compiler::function`
+            StatementKind::Proc { .. } => Ok(()),
+        }
+    }
+
+    /// Compile a block of statements, in a new scope.
+    fn block(&self, ctx: &mut Context, statements: &[Statement<'s>]) -> Res<'s> {
+        ctx.enter_scope();
+        for statement in statements {
+            self.statement(ctx, statement)?;
+        }
+        ctx.exit_scope();
+        Ok(())
+    }
+
+    /// Compile a expression that must be of type `ty`.
+    fn typed_expression(
+        &self,
+        ctx: &mut Context,
+        expression: &Expression<'s>,
+        ty: Type,
+    ) -> Res<'s> {
+        let rec = self.expression(ctx, expression)?;
+        self.expect_type(rec, ty, &expression.span)?;
+        Ok(())
+    }
+
+    /// Compile the condition of a `while` or a `if`, which must be a comparison, not a number.
+    fn condition(&self, ctx: &mut Context, condition: &Expression<'s>) -> Res<'s> {
+        if self.expression(ctx, condition)? == Type::F32 {
+            return Err(Error {
+                source: self.source,
+                span: condition.span.clone(),
+                kind: ErrorKind::NonBooleanCondition,
+            });
+        }
         Ok(())
     }
 
-    /// Parse "print <expression>"
-    fn print_statement(&mut self, ctx: &mut Context) -> Res<'s> {
-        self.match_token(Token::Print)?;
-        let start = self.current.1.start;
-        let expr = self.expression(ctx)?;
-        self.expect_type(expr, Type::F32, start)?;
-        wasm!(&mut ctx.code, (call 0x0));
+    /// Compile a value that is stored in a variable, printed, or passed to or returned from a
+    /// procedure. Values are numbers, so a procedure reference, that is a `i32` index in the table,
+    /// is converted to a float.
+    fn value(&self, ctx: &mut Context, value: &Expression<'s>) -> Res<'s> {
+        if let ExpressionKind::Reference(_) = value.kind {
+            self.typed_expression(ctx, value, Type::I32)?;
+            wasm!(&mut ctx.code, f32.convert_i32_s);
+            return Ok(());
+        }
+        self.typed_expression(ctx, value, Type::F32)
+    }
+
+    /// Compile a float expression, truncated to a integer. A integer literal is compiled directly
+    /// to a integer constant.
+    fn integer_expression(&self, ctx: &mut Context, expression: &Expression<'s>) -> Res<'s> {
+        if let ExpressionKind::Integer(number) = expression.kind {
+            wasm!(&mut ctx.code, i32.const number);
+        } else {
+            self.typed_expression(ctx, expression, Type::F32)?;
+            wasm!(&mut ctx.code, i32.trunc_f32_s);
+        }
         Ok(())
     }
 
-    /// Parse "var <ident> = <expression>"
-    fn variable_declaration(&mut self, ctx: &mut Context) -> Res<'s> {
-        // the "var" keyword is purely aesthetic
-        self.match_token(Token::Var)?;
+    /// Compile "print <expression>"
+    fn print_statement(&self, ctx: &mut Context, value: &Expression<'s>) -> Res<'s> {
+        if is_condition(value) {
+            self.typed_expression(ctx, value, Type::I32)?;
+            // "printb" is the last imported function
+            let idx = self.imported - 1;
+            wasm!(&mut ctx.code, (call idx));
+        } else {
+            self.value(ctx, value)?;
+            wasm!(&mut ctx.code, (call 0x0));
+        }
+        Ok(())
+    }
 
-        self.variable_assignment(ctx)
+    /// Compile "prints <string>"
+    fn print_string_statement(&self, ctx: &mut Context, text: &str) {
+        let ptr = self.strings[text];
+        let len = text.len() as u32;
+        wasm!(&mut ctx.code, (i32.const ptr) (i32.const len) (call 0x1));
     }
 
-    /// Parse "<ident> = <expression>"
-    fn variable_assignment(&mut self, ctx: &mut Context) -> Res<'s> {
-        let ident = self.current.clone();
-        self.match_token(Token::Identifier)?;
-        let idx = ctx.local_index_for_symbol(&self.source[ident.1]);
+    /// Compile "var <ident> = <expression>"
+    fn variable_declaration(
+        &self,
+        ctx: &mut Context,
+        ident: &Ident<'s>,
+        value: &Expression<'s>,
+    ) -> Res<'s> {
+        if let (true, &ExpressionKind::Integer(n)) =
+            (ctx.int_counters.contains(ident.name), &value.kind)
+        {
+            // a redeclaration may reuse a float local, of a use before the first declaration
+            let (idx, _) = ctx.declare_symbol(ident.name, ident.span.clone());
+            if ctx.is_int(Variable::Local(idx)) {
+                wasm!(&mut ctx.code, (i32.const n) (local.set idx));
+                return Ok(());
+            }
+            let number = n as f32;
+            wasm!(&mut ctx.code, (f32.const number) (local.set idx));
+            return Ok(());
+        }
 
-        self.match_token(Token::Assignment)?;
+        // the expression is compiled before the declaration, so it can reference a variable that
+        // will be shadowed
+        self.value(ctx, value)?;
 
-        let start = self.current.1.start;
-        let expr = self.expression(ctx)?;
-        self.expect_type(expr, Type::F32, start)?;
+        // the shadowing was already warned about when resolving
+        let (idx, _) = ctx.declare_symbol(ident.name, ident.span.clone());
         wasm!(&mut ctx.code, local.set idx);
         Ok(())
     }
 
-    /// Parse "<ident> ( <args>,* )"
-    fn proc_call(&mut self, ctx: &mut Context) -> Res<'s> {
-        let symbol = self.current.clone();
-        self.match_token(Token::Identifier)?;
-        let ident = &self.source[symbol.1];
-
-        self.match_token(Token::LeftParen)?;
+    /// Compile "<ident> = <expression>"
+    fn variable_assignment(
+        &self,
+        ctx: &mut Context,
+        ident: &Ident<'s>,
+        value: &Expression<'s>,
+    ) -> Res<'s> {
+        let variable = ctx.variable_for_symbol(ident.name, ident.span.clone(), &self.globals);
+        if ctx.is_int(variable) {
+            // a integer counter is only assigned a integer, or stepped by one
+            match &value.kind {
+                &ExpressionKind::Integer(n) => {
+                    wasm!(&mut ctx.code, i32.const n);
+                }
+                ExpressionKind::Binary {
+                    op, left, right, ..
+                } => {
+                    let n = match right.kind {
+                        ExpressionKind::Integer(n) => n,
+                        _ => unreachable!("not a integer counter"),
+                    };
+                    variable.get(&mut ctx.code);
+                    wasm!(&mut ctx.code, i32.const n);
+                    match op {
+                        BinaryOp::Add => wasm!(&mut ctx.code, i32.add),
+                        BinaryOp::Sub => wasm!(&mut ctx.code, i32.sub),
+                        _ => unreachable!("not a integer counter"),
+                    }
+                    debug_assert!(
+                        matches!(left.kind, ExpressionKind::Variable(x) if x == ident.name)
+                    );
+                }
+                _ => unreachable!("not a integer counter"),
+            }
+            variable.set(&mut ctx.code);
+            return Ok(());
+        }
+        self.value(ctx, value)?;
+        variable.set(&mut ctx.code);
+        Ok(())
+    }
 
+    /// Compile "<ident> ( <args>,* )"
+    fn proc_call(
+        &self,
+        ctx: &mut Context,
+        statement: &Statement<'s>,
+        ident: &Ident<'s>,
+        args: &[Expression<'s>],
+    ) -> Res<'s> {
         // setpixel calls are hardcoded in the compiler
-        if ident == "setpixel" {
-            // yes, setpixel calls cause side effects in variables
-            let start = self.current.1.start;
-            let expr = self.expression(ctx)?;
-            self.expect_type(expr, Type::F32, start)?;
-            let x_idx = ctx.local_index_for_symbol("x");
-            wasm!(&mut ctx.code, local.set x_idx);
-
-            self.match_token(Token::Comma)?;
-
-            let start = self.current.1.start;
-            let expr = self.expression(ctx)?;
-            self.expect_type(expr, Type::F32, start)?;
-            let y_idx = ctx.local_index_for_symbol("y");
-            wasm!(&mut ctx.code, local.set y_idx);
-
-            self.match_token(Token::Comma)?;
-
-            let start = self.current.1.start;
-            let expr = self.expression(ctx)?;
-            self.expect_type(expr, Type::F32, start)?;
-            let color_idx = ctx.local_index_for_symbol("color");
-            wasm!(&mut ctx.code, local.set color_idx);
-
+        if ident.name == "setpixel" {
+            // the arguments are stored in hidden locals, or in the variables "x", "y" and
+            // "color", with the legacy side effects
+            let mut variables = [Variable::Local(0); 3];
+            if !self.options.legacy_setpixel_sideeffects {
+                variables = ctx.setpixel_locals().map(Variable::Local);
+            }
+            for ((arg, name), variable) in args.iter().zip(["x", "y", "color"]).zip(&mut variables)
+            {
+                // each argument must be a float, the error points to the offending argument
+                self.typed_expression(ctx, arg, Type::F32)?;
+                if self.options.legacy_setpixel_sideeffects {
+                    *variable = ctx.variable_for_symbol(name, ident.span.clone(), &self.globals);
+                }
+                variable.set(&mut ctx.code);
+            }
+            let [x, y, color] = variables;
+
+            self.pixel_address(ctx, x, y);
+            // fetch color
+            color.get(&mut ctx.code);
+            self.color_to_int(ctx);
+            // write to memory
+            wasm!(&mut ctx.code, i32.store8 0 0);
+            if self.options.skip_oob_setpixel {
+                wasm!(&mut ctx.code, end);
+            }
+        } else if ident.name == "blendpixel" {
+            // the coordinates are stored in hidden locals, unlike setpixel
+            let x = Variable::Local(ctx.new_hidden_local(Type::F32));
+            self.typed_expression(ctx, &args[0], Type::F32)?;
+            x.set(&mut ctx.code);
+            let y = Variable::Local(ctx.new_hidden_local(Type::F32));
+            self.typed_expression(ctx, &args[1], Type::F32)?;
+            y.set(&mut ctx.code);
+
+            self.pixel_address(ctx, x, y);
+            let address = ctx.new_hidden_local(Type::I32);
+            wasm!(&mut ctx.code,
+                (local.set address)
+                (local.get address)
+                // the current color, plus the given one
+                (local.get address)
+                (i32.load8_u 0 0)
+                (f32.convert_i32_s)
+            );
+            self.typed_expression(ctx, &args[2], Type::F32)?;
             wasm!(&mut ctx.code,
-                // compute ((y*100) + x)
-                (local.get y_idx)
-                (f32.const 100.0)
-                (f32.mul)
-                (local.get x_idx)
                 (f32.add)
-                // convert to integer
-                (i32.trunc_f32_s)
-                // fetch color
-                (local.get color_idx)
-                (i32.trunc_f32_s)
-                // write to memory
-                (i32.store8 0 0)
+                (f32.const 0.0)
+                (f32.max)
+                (f32.const 255.0)
+                (f32.min)
             );
-
-            self.match_token(Token::RightParen)?;
+            self.color_to_int(ctx);
+            wasm!(&mut ctx.code, i32.store8 0 0);
+            if self.options.skip_oob_setpixel {
+                wasm!(&mut ctx.code, end);
+            }
+        } else if ident.name == "setrgb" {
+            // the coordinates are stored in hidden locals, like blendpixel
+            let x = Variable::Local(ctx.new_hidden_local(Type::F32));
+            self.typed_expression(ctx, &args[0], Type::F32)?;
+            x.set(&mut ctx.code);
+            let y = Variable::Local(ctx.new_hidden_local(Type::F32));
+            self.typed_expression(ctx, &args[1], Type::F32)?;
+            y.set(&mut ctx.code);
+
+            // compute ((y*100 + x) * 3 + RGB_CANVAS_OFFSET)
+            self.pixel_address(ctx, x, y);
+            let address = ctx.new_hidden_local(Type::I32);
+            wasm!(&mut ctx.code,
+                (i32.const 3)
+                (i32.mul)
+                (i32.const RGB_CANVAS_OFFSET)
+                (i32.add)
+                (local.set address)
+            );
+            // write the red, green and blue channels, in order
+            for (channel, arg) in (0..).zip(&args[2..]) {
+                wasm!(&mut ctx.code, local.get address);
+                self.typed_expression(ctx, arg, Type::F32)?;
+                self.color_to_int(ctx);
+                wasm!(&mut ctx.code, instr I32Store8 { align: 0, offset: channel });
+            }
+            if self.options.skip_oob_setpixel {
+                wasm!(&mut ctx.code, end);
+            }
         } else {
-            let mut n = 0;
-            while self.current.0 != Token::RightParen {
-                let start = self.current.1.start;
-                let expr = self.expression(ctx)?;
-                self.expect_type(expr, Type::F32, start)?;
-                n += 1;
-                if self.current.0 != Token::RightParen {
-                    self.match_token(Token::Comma)?;
-                } else {
-                    break;
-                }
+            // the values returned by a procedure called as a statement are discarded
+            for _ in 0..self.call(ctx, &statement.span, ident, args)? {
+                wasm!(&mut ctx.code, drop);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile the call at `span` to a procedure, or to a variable holding a procedure reference,
+    /// returning the number of values it leaves on the stack.
+    fn call(
+        &self,
+        ctx: &mut Context,
+        span: &Span,
+        ident: &Ident<'s>,
+        args: &[Expression<'s>],
+    ) -> Res<'s, u32> {
+        for arg in args {
+            self.value(ctx, arg)?;
+        }
+        let mut n = args.len() as u32;
+
+        // a variable holding a procedure reference, that is not shadowed by a procedure
+        let reference = ctx
+            .lookup(ident.name, &self.globals)
+            .filter(|_| self.indirect_calls.contains(&span.start));
+        let is_reference = reference.is_some();
+
+        // the omitted trailing arguments are replaced by their default values, if all have one
+        let defaults: Option<Vec<_>> = match self.defaults.get(ident.name) {
+            Some(defaults) if !is_reference && args.len() < defaults.len() => {
+                defaults[args.len()..].iter().cloned().collect()
             }
-            self.match_token(Token::RightParen)?;
+            _ => None,
+        };
+        if let Some(defaults) = defaults {
+            for default in &defaults {
+                self.value(ctx, default)?;
+            }
+            n += defaults.len() as u32;
+        }
+
+        match reference {
+            Some(variable) => {
+                let ty = self.existing_type_index(&FuncType::procedure(n));
+                variable.get(&mut ctx.code);
+                wasm!(&mut ctx.code,
+                    (i32.trunc_f32_s)
+                    (call_indirect ty)
+                );
+                // a procedure that returns values can't be referenced
+                Ok(0)
+            }
+            None => {
+                let proc = &self.procedures[ident.name];
+                let idx = proc.idx;
+                wasm!(&mut ctx.code, call idx);
+                Ok(proc.num_results)
+            }
+        }
+    }
 
-            let idx = self.procedure_from_symbol(ident, n)?.idx;
+    /// Compile "var <ident> (, <ident>)+ = <ident> ( <expression>,* )"
+    fn destructure(
+        &self,
+        ctx: &mut Context,
+        statement: &Statement<'s>,
+        targets: &[Ident<'s>],
+        ident: &Ident<'s>,
+        args: &[Expression<'s>],
+    ) -> Res<'s> {
+        let num_results = self.call(ctx, &statement.span, ident, args)?;
+        if num_results != targets.len() as u32 {
+            return Err(Error {
+                source: self.source,
+                span: ident.span.clone(),
+                kind: ErrorKind::ResultNumberMismatch {
+                    expected: targets.len() as u32,
+                    received: num_results,
+                },
+            });
+        }
+
+        // the shadowing was already warned about when resolving
+        let locals: Vec<_> = targets
+            .iter()
+            .map(|target| ctx.declare_symbol(target.name, target.span.clone()).0)
+            .collect();
+        // the last value is on the top of the stack
+        for idx in locals.into_iter().rev() {
+            wasm!(&mut ctx.code, local.set idx);
+        }
+        Ok(())
+    }
 
-            wasm!(&mut ctx.code, call idx);
+    /// Compile "return <expression>" or "return ( <expression>,* )"
+    fn return_statement(
+        &self,
+        ctx: &mut Context,
+        statement: &Statement<'s>,
+        values: &[Expression<'s>],
+    ) -> Res<'s> {
+        let received = values.len() as u32;
+        if received > 1 && !self.options.multivalue {
+            return Err(Error {
+                source: self.source,
+                span: statement.span.clone(),
+                kind: ErrorKind::MultivalueDisabled,
+            });
         }
+        if received != ctx.results {
+            return Err(Error {
+                source: self.source,
+                span: statement.span.clone(),
+                kind: ErrorKind::ResultNumberMismatch {
+                    expected: ctx.results,
+                    received,
+                },
+            });
+        }
+        for value in values {
+            self.value(ctx, value)?;
+        }
+        wasm!(&mut ctx.code, return);
         Ok(())
     }
 
-    /// Parse "while <expression> <statements>* endwhile"
-    fn while_statement(&mut self, ctx: &mut Context) -> Res<'s> {
-        self.match_token(Token::While)?;
+    /// Push the address of the pixel at `x` and `y`, `((y*100) + x)`, clamping the coordinates if
+    /// `clamp_setpixel` is enabled. If `skip_oob_setpixel` is enabled, this first opens a `if`
+    /// that skips the pixels out of the canvas, which must be closed by the caller. If
+    /// `bounds_checks` is enabled, a address out of the canvas traps.
+    fn pixel_address(&self, ctx: &mut Context, x: Variable, y: Variable) {
+        // only write if (0 <= x < 100) && (0 <= y < 100)
+        if self.options.skip_oob_setpixel {
+            x.get(&mut ctx.code);
+            wasm!(&mut ctx.code, (f32.const 0.0) (f32.ge));
+            x.get(&mut ctx.code);
+            wasm!(&mut ctx.code, (f32.const 100.0) (f32.lt) (i32.and));
+            y.get(&mut ctx.code);
+            wasm!(&mut ctx.code, (f32.const 0.0) (f32.ge) (i32.and));
+            y.get(&mut ctx.code);
+            wasm!(&mut ctx.code, (f32.const 100.0) (f32.lt) (i32.and) (if));
+        }
 
-        // start a block, and a loop block
-        wasm!(&mut ctx.code, (block) (loop));
+        // compute ((y*100) + x)
+        y.get(&mut ctx.code);
+        if self.options.clamp_setpixel {
+            wasm!(&mut ctx.code, (f32.const 0.0) (f32.max) (f32.const 99.0) (f32.min));
+        }
+        wasm!(&mut ctx.code, (f32.const 100.0) (f32.mul));
+        x.get(&mut ctx.code);
+        if self.options.clamp_setpixel {
+            wasm!(&mut ctx.code, (f32.const 0.0) (f32.max) (f32.const 99.0) (f32.min));
+        }
+        // add and convert to integer
+        wasm!(&mut ctx.code, (f32.add)(i32.trunc_f32_s));
 
-        // if the expression is false, jump to the end of the block
-        let start = self.current.1.start;
-        let expr = self.expression(ctx)?;
-        self.expect_type(expr, Type::I32, start)?;
-        wasm!(&mut ctx.code, (i32.eqz) (br_if 1));
+        // trap if the address is out of the canvas, comparing it as unsigned so negative
+        // addresses are out too
+        if self.options.bounds_checks {
+            let address = ctx.new_hidden_local(Type::I32);
+            wasm!(&mut ctx.code,
+                (local.set address)
+                (local.get address) (i32.const 10000) (i32.ge_u)
+                (if) (unreachable) (end)
+                (local.get address)
+            );
+        }
+    }
 
-        while self.current.0 != Token::EndWhile {
-            self.statement(ctx)?;
+    /// Convert the color on the top of the stack to a integer, to be written to the canvas.
+    fn color_to_int(&self, ctx: &mut Context) {
+        if self.options.saturating_color {
+            wasm!(&mut ctx.code, i32.trunc_sat_f32_s);
+        } else {
+            wasm!(&mut ctx.code, i32.trunc_f32_s);
         }
+    }
+
+    /// Compile "while <expression> <statements>* endwhile"
+    fn while_statement(
+        &self,
+        ctx: &mut Context,
+        condition: &Expression<'s>,
+        body: &[Statement<'s>],
+    ) -> Res<'s> {
+        // instead of negating the condition to branch out of the loop, the body is in a if block
+        // that runs on the true condition, and branches back to the start of the loop. A false
+        // condition falls through the if block, and the end of the loop.
+        wasm!(&mut ctx.code, loop);
+        ctx.labels += 1;
+        self.condition(ctx, condition)?;
+        wasm!(&mut ctx.code, if);
 
-        self.match_token(Token::EndWhile)?;
+        // a break exits the if block
+        ctx.labels += 1;
+        ctx.loop_exits.push(ctx.labels);
+        self.block(ctx, body)?;
+        ctx.labels -= 2;
+        ctx.loop_exits.pop();
 
-        // jump to the start of the loop block
-        wasm!(&mut ctx.code, (br 0) (end) (end));
+        wasm!(&mut ctx.code, (br 1) (end) (end));
 
         Ok(())
     }
 
-    /// Parse "if <expresion> <expression>* endif" or "if <expression> <expression>* else
+    /// Compile "if <expresion> <expression>* endif" or "if <expression> <expression>* else
     /// <expression>* endif"
-    fn if_statement(&mut self, ctx: &mut Context) -> Res<'s> {
-        self.match_token(Token::If)?;
-
-        // condition
-        let start = self.current.1.start;
-        let expr = self.expression(ctx)?;
-        self.expect_type(expr, Type::I32, start)?;
+    fn if_statement(
+        &self,
+        ctx: &mut Context,
+        condition: &Expression<'s>,
+        then_body: &[Statement<'s>],
+        else_body: Option<&[Statement<'s>]>,
+    ) -> Res<'s> {
+        self.condition(ctx, condition)?;
 
         wasm!(&mut ctx.code, if);
+        ctx.labels += 1;
 
-        while !(self.current.0 == Token::EndIf || self.current.0 == Token::Else) {
-            self.statement(ctx)?;
-        }
-        if self.current.0 == Token::Else {
-            self.match_token(Token::Else)?;
+        self.block(ctx, then_body)?;
+        if let Some(else_body) = else_body {
             wasm!(&mut ctx.code, else);
-            while self.current.0 != Token::EndIf {
-                self.statement(ctx)?;
-            }
+            self.block(ctx, else_body)?;
         }
 
-        self.match_token(Token::EndIf)?;
         wasm!(&mut ctx.code, end);
+        ctx.labels -= 1;
 
         Ok(())
     }
 
-    /// Parse "proc <ident> ( <args>,* ) <statement>* endproc"
-    fn proc_statement(&mut self) -> Res<'s> {
-        self.match_token(Token::Proc)?;
-
-        let name = self.current.clone();
-        self.match_token(Token::Identifier)?;
-        let name = &self.source[name.1];
+    /// Compile "repeat <expression> <statement>* endrepeat"
+    fn repeat_statement(
+        &self,
+        ctx: &mut Context,
+        count: &Expression<'s>,
+        body: &[Statement<'s>],
+    ) -> Res<'s> {
+        // the count is truncated to a integer, and stored in a hidden counter
+        self.integer_expression(ctx, count)?;
+        let count_idx = ctx.new_hidden_local(Type::I32);
+        wasm!(&mut ctx.code, local.set count_idx);
 
-        let mut args = Vec::new();
-
-        self.match_token(Token::LeftParen)?;
-        while self.current.0 != Token::RightParen {
-            let arg = self.current.clone();
-            self.match_token(Token::Identifier)?;
+        // start a block, and a loop block
+        wasm!(&mut ctx.code, (block) (loop));
 
-            let arg = &self.source[arg.1];
-            args.push(arg.to_string());
+        // if the counter reached zero, jump to the end of the block
+        wasm!(&mut ctx.code,
+            (local.get count_idx)
+            (i32.const 0)
+            (i32.le_s)
+            (br_if 1)
+        );
+
+        self.loop_body(ctx, body)?;
+
+        // decrement the counter, and jump to the start of the loop block
+        wasm!(&mut ctx.code,
+            (local.get count_idx)
+            (i32.const 1)
+            (i32.sub)
+            (local.set count_idx)
+            (br 0)
+            (end)
+            (end)
+        );
 
-            if self.current.0 != Token::RightParen {
-                self.match_token(Token::Comma)?;
-            } else {
-                break;
-            }
-        }
-        self.match_token(Token::RightParen)?;
+        Ok(())
+    }
 
-        let num_param = args.len() as u32;
-        self.procedure_from_symbol(name, num_param)?;
+    /// Compile "loop <statement>* endloop"
+    fn loop_statement(&self, ctx: &mut Context, body: &[Statement<'s>]) -> Res<'s> {
+        // a block to be exited by a break, and a loop block that always jumps back to its start
+        wasm!(&mut ctx.code, (block) (loop));
+        self.loop_body(ctx, body)?;
+        wasm!(&mut ctx.code, (br 0) (end) (end));
+        Ok(())
+    }
 
-        let mut ctx = Context {
-            code: Vec::new(),
-            // function arguments are the starting locals index
-            symbols: args.into_iter().zip(0..).collect(),
-        };
+    /// Compile the body of a loop, inside a block that a `break` exits, and a `loop` block.
+    fn loop_body(&self, ctx: &mut Context, body: &[Statement<'s>]) -> Res<'s> {
+        ctx.labels += 1;
+        ctx.loop_exits.push(ctx.labels);
+        ctx.labels += 1;
+        self.block(ctx, body)?;
+        ctx.labels -= 2;
+        ctx.loop_exits.pop();
+        Ok(())
+    }
 
-        while self.current.0 != Token::EndProc {
-            self.statement(&mut ctx)?;
+    /// Compile "switch <expression> (case <number> <statement>*)* [default <statement>*]
+    /// endswitch"
+    fn switch_statement(
+        &self,
+        ctx: &mut Context,
+        value: &Expression<'s>,
+        cases: &[Case<'s>],
+        default: Option<&[Statement<'s>]>,
+    ) -> Res<'s> {
+        // store the value in a local, to compare it against each case
+        self.typed_expression(ctx, value, Type::F32)?;
+        let value_idx = ctx.new_hidden_local(Type::F32);
+        wasm!(&mut ctx.code, local.set value_idx);
+
+        // each case is a if block, nested in the else block of the previous case
+        for case in cases {
+            let number = case.value;
+            wasm!(&mut ctx.code,
+                (local.get value_idx)
+                (f32.const number)
+                (f32.eq)
+                (if)
+            );
+            ctx.labels += 1;
+            self.block(ctx, &case.body)?;
+            wasm!(&mut ctx.code, else);
         }
-        self.match_token(Token::EndProc)?;
-        wasm!(&mut ctx.code, end);
 
-        let locals_index = ctx.code.len();
+        if let Some(default) = default {
+            self.block(ctx, default)?;
+        }
 
-        // write the vector of locals of the function
-        leb128::write::unsigned(&mut ctx.code, 1).unwrap();
-        leb128::write::unsigned(
-            &mut ctx.code,
-            // don't need to add locals for the argumentes
-            (ctx.symbols.len() - num_param as usize) as u64,
-        )
-        .unwrap();
-        wasm!(&mut ctx.code, f32);
+        for _ in cases {
+            wasm!(&mut ctx.code, end);
+        }
+        ctx.labels -= cases.len() as u32;
 
-        // move locals to the start
-        let len = ctx.code.len();
-        ctx.code.rotate_right(len - locals_index);
+        Ok(())
+    }
 
-        self.procedure_from_symbol(name, num_param).unwrap().code = ctx.code;
+    /// Compile "<ident> ( <expression>,* )", a call to a builtin function, or to a procedure that
+    /// returns a single value.
+    fn builtin_call(
+        &self,
+        ctx: &mut Context,
+        expression: &Expression<'s>,
+        ident: &Ident<'s>,
+        args: &[Expression<'s>],
+    ) -> Res<'s, Type> {
+        let num_param = match builtin_num_param(ident.name) {
+            Some(num_param) => num_param,
+            None if self.defaults.contains_key(ident.name) => {
+                let received = self.call(ctx, &expression.span, ident, args)?;
+                if received != 1 {
+                    return Err(Error {
+                        source: self.source,
+                        span: ident.span.clone(),
+                        kind: ErrorKind::ResultNumberMismatch {
+                            expected: 1,
+                            received,
+                        },
+                    });
+                }
+                return Ok(Type::F32);
+            }
+            None => {
+                return Err(Error {
+                    source: self.source,
+                    span: ident.span.clone(),
+                    kind: ErrorKind::UnknownBuiltin {
+                        name: ident.name.to_string(),
+                    },
+                })
+            }
+        };
+        if args.len() != num_param {
+            return Err(Error {
+                source: self.source,
+                span: expression.span.clone(),
+                kind: ErrorKind::ArgumentNumberMismatch {
+                    expected: num_param as u32,
+                    received: args.len() as u32,
+                },
+            });
+        }
+        for arg in args {
+            self.typed_expression(ctx, arg, Type::F32)?;
+        }
 
-        Ok(())
+        match ident.name {
+            "sign" => {
+                // (x > 0) - (x < 0), which is 0 for zero and NaN
+                let x = ctx.new_hidden_local(Type::F32);
+                wasm!(&mut ctx.code,
+                    (local.set x)
+                    (local.get x) (f32.const 0.0) (f32.gt)
+                    (local.get x) (f32.const 0.0) (f32.lt)
+                    (i32.sub)
+                    (f32.convert_i32_s)
+                );
+            }
+            "fract" => {
+                // x - floor(x)
+                let x = ctx.new_hidden_local(Type::F32);
+                wasm!(&mut ctx.code,
+                    (local.set x)
+                    (local.get x)
+                    (local.get x) (f32.floor)
+                    (f32.sub)
+                );
+            }
+            // rounds toward zero, like the conversion to a integer of `setpixel`
+            "trunc" => wasm!(&mut ctx.code, f32.trunc),
+            // rounds half to even, so `round(2.5)` is 2, and `round(3.5)` is 4
+            "round" => wasm!(&mut ctx.code, f32.nearest),
+            // the magnitude of the first argument, with the sign of the second
+            "copysign" => wasm!(&mut ctx.code, f32.copysign),
+            "maxpixel" => {
+                // the maximum of the bytes of the canvas, that are all non-negative
+                let i = ctx.new_hidden_local(Type::I32);
+                let max = ctx.new_hidden_local(Type::F32);
+                wasm!(&mut ctx.code,
+                    (f32.const 0.0) (local.set max)
+                    (i32.const 0) (local.set i)
+                    (block) (loop)
+                        // exit when (i < 10000) is false
+                        (local.get i) (i32.const 10000) (i32.lt_s) (i32.eqz) (br_if 1)
+                        (local.get max)
+                        (local.get i) (i32.load8_u 0 0) (f32.convert_i32_s)
+                        (f32.max)
+                        (local.set max)
+                        (local.get i) (i32.const 1) (i32.add) (local.set i)
+                        (br 0)
+                    (end) (end)
+                    (local.get max)
+                );
+            }
+            _ => unreachable!(),
+        }
+        Ok(Type::F32)
     }
 
-    /// Parse "<number>" or "<ident>" or "( <expression> <op> <expression> )"
-    fn expression(&mut self, ctx: &mut Context) -> Res<'s, Type> {
-        match self.current.0 {
-            Token::Number => {
-                let number = match self.source[self.current.1.clone()].parse::<f32>() {
-                    Ok(x) => x,
-                    Err(err) => {
-                        return Err(Error {
-                            source: self.source,
-                            span: self.current.1.clone(),
-                            kind: ErrorKind::ParseFloatError(err),
-                        })
-                    }
-                };
-                self.match_token(Token::Number)?;
+    /// Compile "<number>" or "<ident>" or "& <ident>" or "( <expression> <op> <expression> )"
+    fn expression(&self, ctx: &mut Context, expression: &Expression<'s>) -> Res<'s, Type> {
+        let shared = ctx
+            .chain_operands
+            .iter()
+            .position(|(span, ..)| *span == expression.span);
+        if let Some(i) = shared {
+            let (span, local, evaluated) = ctx.chain_operands.remove(i);
+            if evaluated {
+                wasm!(&mut ctx.code, local.get local);
+                ctx.chain_operands.push((span, local, evaluated));
+                return Ok(Type::F32);
+            }
+            let ty = self.expression(ctx, expression)?;
+            // a operand that is not a number fails the comparison
+            if ty == Type::F32 {
+                wasm!(&mut ctx.code, (local.set local) (local.get local));
+                ctx.chain_operands.push((span, local, true));
+            }
+            return Ok(ty);
+        }
+        match &expression.kind {
+            &ExpressionKind::Number(number) => {
+                wasm!(&mut ctx.code, (f32.const number));
+                Ok(Type::F32)
+            }
+            &ExpressionKind::Integer(number) => {
+                let number = number as f32;
                 wasm!(&mut ctx.code, (f32.const number));
                 Ok(Type::F32)
             }
-            Token::Identifier => {
-                let ident = self.current.clone();
-                self.match_token(Token::Identifier)?;
+            ExpressionKind::Variable(symbol) => {
+                let variable =
+                    ctx.variable_for_symbol(symbol, expression.span.clone(), &self.globals);
 
-                let symbol = &self.source[ident.1];
-                let idx = ctx.local_index_for_symbol(symbol);
+                variable.get(&mut ctx.code);
+                if ctx.is_int(variable) {
+                    wasm!(&mut ctx.code, f32.convert_i32_s);
+                }
+                Ok(Type::F32)
+            }
+            ExpressionKind::Reference(symbol) => {
+                // the indirect calls have no results, so their type wouldn't match
+                let proc = &self.procedures[*symbol];
+                if proc.num_results != 0 {
+                    return Err(Error {
+                        source: self.source,
+                        span: expression.span.clone(),
+                        kind: ErrorKind::ResultNumberMismatch {
+                            expected: 0,
+                            received: proc.num_results,
+                        },
+                    });
+                }
+                // a procedure reference is its index in the table, which is its function index
+                // minus the imported functions
+                let idx = proc.idx;
 
-                wasm!(&mut ctx.code, local.get idx);
+                wasm!(&mut ctx.code, i32.const (idx - self.imported));
+                Ok(Type::I32)
+            }
+            ExpressionKind::Negate(value) => {
+                self.typed_expression(ctx, value, Type::F32)?;
+                wasm!(&mut ctx.code, f32.neg);
+                Ok(Type::F32)
+            }
+            ExpressionKind::Call { ident, args } => self.builtin_call(ctx, expression, ident, args),
+            ExpressionKind::Index { ident, index } => {
+                self.typed_expression(ctx, index, Type::F32)?;
+                let (base, len) = self.tables[ident.name];
+                if self.options.bounds_checks {
+                    // trap if the index is out of `0..len`, or is NaN
+                    let index = ctx.table_index_local();
+                    let len = len as f32;
+                    wasm!(&mut ctx.code,
+                        (local.set index)
+                        (local.get index) (f32.const 0.0) (f32.ge)
+                        (local.get index) (f32.const len) (f32.lt)
+                        (i32.and) (i32.eqz)
+                        (if) (unreachable) (end)
+                        (local.get index)
+                    );
+                } else if len == 0 {
+                    // there is no value to read
+                    wasm!(&mut ctx.code, (drop) (unreachable) (f32.const 0.0));
+                    return Ok(Type::F32);
+                } else {
+                    // a index out of the table reads the value at its closest end
+                    let last = (len - 1) as f32;
+                    wasm!(&mut ctx.code,
+                        (f32.const 0.0) (f32.max) (f32.const last) (f32.min)
+                    );
+                }
+                wasm!(&mut ctx.code,
+                    (i32.trunc_f32_s)
+                    (i32.const 4) (i32.mul)
+                    (f32.load 2 base)
+                );
                 Ok(Type::F32)
             }
-            Token::LeftParen => {
-                self.match_token(Token::LeftParen)?;
+            ExpressionKind::Binary {
+                op,
+                op_span,
+                left,
+                right,
+                chained,
+            } => {
+                let operands = (int_operand(ctx, left), int_operand(ctx, right));
+                if let (true, Some(a), Some(b)) = (op.is_comparison(), operands.0, operands.1) {
+                    if matches!(a, IntOperand::Counter(_)) || matches!(b, IntOperand::Counter(_)) {
+                        // compare integer counters without converting them to floats
+                        for operand in [a, b] {
+                            match operand {
+                                IntOperand::Counter(variable) => variable.get(&mut ctx.code),
+                                IntOperand::Literal(n) => {
+                                    wasm!(&mut ctx.code, i32.const n);
+                                }
+                            }
+                        }
+                        match op {
+                            BinaryOp::Eq => wasm!(&mut ctx.code, i32.eq),
+                            BinaryOp::Lt => wasm!(&mut ctx.code, i32.lt_s),
+                            _ => wasm!(&mut ctx.code, i32.gt_s),
+                        }
+                        return Ok(Type::I32);
+                    }
+                }
 
-                // left
-                let type_a = self.expression(ctx)?;
+                if *op == BinaryOp::And {
+                    // the operand shared by the comparisons of a chain, like the `b` of `(a < b <
+                    // c)`, may call a procedure, so it is evaluated only once
+                    let shared = match &right.kind {
+                        ExpressionKind::Binary { left: middle, .. } if *chained => {
+                            match middle.kind {
+                                ExpressionKind::Number(_)
+                                | ExpressionKind::Integer(_)
+                                | ExpressionKind::Variable(_)
+                                | ExpressionKind::Reference(_) => None,
+                                _ => Some(middle.span.clone()),
+                            }
+                        }
+                        _ => None,
+                    };
+                    let span = match shared {
+                        Some(span) => span,
+                        None => return self.and_expression(ctx, op_span, left, right),
+                    };
+                    let local = ctx.new_hidden_local(Type::F32);
+                    ctx.chain_operands.push((span.clone(), local, false));
+                    let result = self.and_expression(ctx, op_span, left, right);
+                    ctx.chain_operands.retain(|(x, ..)| *x != span);
+                    return result;
+                }
 
-                let op_token = self.current.clone();
-                self.match_token(Token::Operator)?;
-                let op = &self.source[op_token.1.clone()];
+                let type_a = self.expression(ctx, left)?;
+                let type_b = self.expression(ctx, right)?;
+                if type_a != Type::F32 || type_b != Type::F32 {
+                    return Err(self.operand_error(op_span, Type::F32, type_a, type_b));
+                }
 
-                // right
-                let type_b = self.expression(ctx)?;
+                match op {
+                    BinaryOp::Add => wasm!(&mut ctx.code, f32.add),
+                    BinaryOp::Sub => wasm!(&mut ctx.code, f32.sub),
+                    BinaryOp::Mul => wasm!(&mut ctx.code, f32.mul),
+                    BinaryOp::Div => wasm!(&mut ctx.code, f32.div),
+                    BinaryOp::Eq => wasm!(&mut ctx.code, f32.eq),
+                    BinaryOp::Lt => wasm!(&mut ctx.code, f32.lt),
+                    BinaryOp::Gt => wasm!(&mut ctx.code, f32.gt),
+                    BinaryOp::And => unreachable!("compiled by and_expression"),
+                }
 
-                // op
                 match op {
-                    "+" | "-" | "*" | "/" | "<" | ">" | "==" => {
-                        if type_a != Type::F32 || type_b != Type::F32 {
-                            return Err(Error {
-                                source: self.source,
-                                span: op_token.1,
-                                kind: ErrorKind::UnexpectedType {
-                                    expected: &[Type::F32, Type::F32],
-                                    received: vec![type_a, type_b],
-                                },
-                            });
-                        }
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => Ok(Type::F32),
+                    BinaryOp::Eq | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::And => Ok(Type::I32),
+                }
+            }
+        }
+    }
+
+    /// Compile "( <condition> && <condition> )", only evaluating the right condition if the left
+    /// one is true, like in `(x > 0) && (maxpixel() > 0)`. The result is kept in a hidden local,
+    /// that is false unless the right condition is evaluated.
+    fn and_expression(
+        &self,
+        ctx: &mut Context,
+        op_span: &Span,
+        left: &Expression<'s>,
+        right: &Expression<'s>,
+    ) -> Res<'s, Type> {
+        let result = ctx.new_hidden_local(Type::I32);
+        // reset it, because the expression can be evaluated again in a loop
+        wasm!(&mut ctx.code, (i32.const 0) (local.set result));
+
+        let type_a = self.expression(ctx, left)?;
+        wasm!(&mut ctx.code, if);
+        ctx.labels += 1;
+        let type_b = self.expression(ctx, right)?;
+        wasm!(&mut ctx.code, (local.set result) (end));
+        ctx.labels -= 1;
+
+        if type_a != Type::I32 || type_b != Type::I32 {
+            return Err(self.operand_error(op_span, Type::I32, type_a, type_b));
+        }
+        wasm!(&mut ctx.code, local.get result);
+        Ok(Type::I32)
+    }
+
+    /// The error of a binary operator whose operands are not both of the type `operand`.
+    fn operand_error(&self, op_span: &Span, operand: Type, a: Type, b: Type) -> Error<'s> {
+        Error {
+            source: self.source,
+            span: op_span.clone(),
+            kind: ErrorKind::UnexpectedType {
+                expected: match operand {
+                    Type::I32 => &[Type::I32, Type::I32],
+                    Type::F32 => &[Type::F32, Type::F32],
+                },
+                received: vec![a, b],
+            },
+        }
+    }
+}
+
+/// Remove each pair of consecutive `i32.eqz` instructions from `code`, because negating a condition
+/// twice does nothing.
+pub(crate) fn remove_double_eqz(code: &mut Vec<u8>) {
+    // most functions have no `i32.eqz` at all
+    if !code.contains(&0x45) {
+        return;
+    }
+    let mut out = Vec::with_capacity(code.len());
+    let mut rest = &code[..];
+    let mut pending_eqz = false;
+    while !rest.is_empty() {
+        let start = rest;
+        let instr = match crate::instr::Instr::decode(&mut rest) {
+            Ok(instr) => instr,
+            // leave code that can't be decoded unchanged
+            Err(_) => return,
+        };
+        if instr == crate::instr::Instr::I32Eqz {
+            pending_eqz = !pending_eqz;
+            continue;
+        }
+        if pending_eqz {
+            wasm!(&mut out, i32.eqz);
+            pending_eqz = false;
+        }
+        out.extend_from_slice(&start[..start.len() - rest.len()]);
+    }
+    if pending_eqz {
+        wasm!(&mut out, i32.eqz);
+    }
+    *code = out;
+}
+
+/// The variables of a function body that can be stored in a `i32` local: the ones that are
+/// always declared with a integer, like `var i = 0`, and are only assigned a integer or stepped
+/// by one, like `i = (i + 1)`. This makes a counter exact past 2^24, where a `f32` can no longer
+/// be incremented.
+///
+/// The integers must be at most 2^24 in magnitude, where a `f32` still represents them exactly,
+/// so the counter starts with the same value as a `f32` variable, and only overflows after 2^31
+/// steps.
+///
+/// With `legacy_setpixel`, `setpixel` assigns its arguments to `x`, `y` and `color`, so they are
+/// not counters.
+fn int_counters(
+    body: &[Statement],
+    params: &[Param],
+    globals: &HashMap<&str, GlobalIdx>,
+    legacy_setpixel: bool,
+) -> HashSet<String> {
+    fn collect<'a>(
+        statements: &'a [Statement],
+        legacy_setpixel: bool,
+        candidates: &mut HashSet<&'a str>,
+        excluded: &mut HashSet<&'a str>,
+    ) {
+        let exact = |n: i32| n.unsigned_abs() <= 1 << 24;
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Var { ident, value } => match value.kind {
+                    ExpressionKind::Integer(n) if exact(n) => {
+                        candidates.insert(ident.name);
                     }
-                    "&&" => {
-                        if type_a != Type::I32 || type_b != Type::I32 {
-                            return Err(Error {
-                                source: self.source,
-                                span: op_token.1,
-                                kind: ErrorKind::UnexpectedType {
-                                    expected: &[Type::I32, Type::I32],
-                                    received: vec![type_a, type_b],
-                                },
-                            });
+                    _ => {
+                        excluded.insert(ident.name);
+                    }
+                },
+                StatementKind::Assignment { ident, value } => {
+                    let stepped = match &value.kind {
+                        &ExpressionKind::Integer(n) => exact(n),
+                        ExpressionKind::Binary {
+                            op: BinaryOp::Add | BinaryOp::Sub,
+                            left,
+                            right,
+                            ..
+                        } => {
+                            matches!(left.kind, ExpressionKind::Variable(x) if x == ident.name)
+                                && matches!(right.kind, ExpressionKind::Integer(1))
                         }
+                        _ => false,
+                    };
+                    if !stepped {
+                        excluded.insert(ident.name);
                     }
-                    _ => unreachable!("I already match the token operator"),
                 }
-                match op {
-                    "+" => wasm!(&mut ctx.code, f32.add),
-                    "-" => wasm!(&mut ctx.code, f32.sub),
-                    "*" => wasm!(&mut ctx.code, f32.mul),
-                    "/" => wasm!(&mut ctx.code, f32.div),
-                    "==" => wasm!(&mut ctx.code, f32.eq),
-                    "<" => wasm!(&mut ctx.code, f32.lt),
-                    ">" => wasm!(&mut ctx.code, f32.gt),
-                    "&&" => wasm!(&mut ctx.code, i32.and),
-                    _ => unreachable!("I already match the token operator"),
+                StatementKind::Global { ident, .. } => {
+                    excluded.insert(ident.name);
+                }
+                // the legacy setpixel assigns its arguments to "x", "y" and "color"
+                StatementKind::Call { ident, .. } if ident.name == "setpixel" => {
+                    if legacy_setpixel {
+                        excluded.extend(["x", "y", "color"]);
+                    }
+                }
+                // the called name may be a variable holding a procedure reference
+                StatementKind::Call { ident, .. } => {
+                    excluded.insert(ident.name);
+                }
+                StatementKind::Destructure { targets, .. } => {
+                    excluded.extend(targets.iter().map(|x| x.name));
+                }
+                StatementKind::While { body, .. }
+                | StatementKind::Repeat { body, .. }
+                | StatementKind::Loop { body } => {
+                    collect(body, legacy_setpixel, candidates, excluded);
+                }
+                StatementKind::If {
+                    then_body,
+                    else_body,
+                    ..
+                } => {
+                    collect(then_body, legacy_setpixel, candidates, excluded);
+                    collect(
+                        else_body.as_deref().unwrap_or_default(),
+                        legacy_setpixel,
+                        candidates,
+                        excluded,
+                    );
+                }
+                StatementKind::Switch { cases, default, .. } => {
+                    for case in cases {
+                        collect(&case.body, legacy_setpixel, candidates, excluded);
+                    }
+                    collect(
+                        default.as_deref().unwrap_or_default(),
+                        legacy_setpixel,
+                        candidates,
+                        excluded,
+                    );
                 }
+                // the body of a procedure is a different function
+                StatementKind::Print(_)
+                | StatementKind::PrintString(_)
+                | StatementKind::Pass
+                | StatementKind::Break
+                | StatementKind::Return(_)
+                | StatementKind::Data { .. }
+                | StatementKind::Proc { .. } => {}
+            }
+        }
+    }
 
-                self.match_token(Token::RightParen)?;
+    let mut candidates = HashSet::new();
+    let mut excluded: HashSet<&str> = params.iter().map(|x| x.ident.name).collect();
+    excluded.extend(globals.keys());
+    collect(body, legacy_setpixel, &mut candidates, &mut excluded);
+    candidates
+        .difference(&excluded)
+        .map(|x| x.to_string())
+        .collect()
+}
 
-                match op {
-                    "+" | "-" | "*" | "/" => Ok(Type::F32),
-                    "==" | "<" | ">" | "&&" => Ok(Type::I32),
-                    _ => unreachable!("I already match the token operator"),
+/// A operand of a comparison that can be compared as a `i32`.
+#[derive(Clone, Copy)]
+enum IntOperand {
+    /// A variable that is a integer counter.
+    Counter(Variable),
+    /// A integer literal.
+    Literal(i32),
+}
+
+/// The operand as a `i32`, if it is a integer counter or a integer literal.
+fn int_operand(ctx: &Context, expression: &Expression) -> Option<IntOperand> {
+    match expression.kind {
+        ExpressionKind::Integer(n) => Some(IntOperand::Literal(n)),
+        ExpressionKind::Variable(symbol) => match ctx.symbols.get(symbol) {
+            Some(binding) if ctx.locals[binding.idx as usize] == Type::I32 => {
+                Some(IntOperand::Counter(Variable::Local(binding.idx)))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The symbols of the parameters of a procedure, to create its [`Context`].
+fn param_symbols(params: &[Param]) -> Vec<(String, Span)> {
+    params
+        .iter()
+        .map(|x| (x.ident.name.to_string(), x.ident.span.clone()))
+        .collect()
+}
+
+/// Check if any of the statements has a `break` that exits the loop they are in, not counting the
+/// ones of nested loops, or a `return`.
+fn breaks_loop(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Break | StatementKind::Return(_) => true,
+        StatementKind::If {
+            then_body,
+            else_body,
+            ..
+        } => breaks_loop(then_body) || breaks_loop(else_body.as_deref().unwrap_or_default()),
+        StatementKind::Switch { cases, default, .. } => {
+            cases.iter().any(|case| breaks_loop(&case.body))
+                || breaks_loop(default.as_deref().unwrap_or_default())
+        }
+        _ => false,
+    })
+}
+
+/// The number of parameters of the builtin function `name`, if there is one.
+pub(crate) fn builtin_num_param(name: &str) -> Option<usize> {
+    match name {
+        "maxpixel" => Some(0),
+        "sign" | "fract" | "trunc" | "round" => Some(1),
+        "copysign" => Some(2),
+        _ => None,
+    }
+}
+
+/// Check if the statements always end in a `return`, so the code after them is never reached.
+fn always_returns(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::If {
+            then_body,
+            else_body: Some(else_body),
+            ..
+        } => always_returns(then_body) && always_returns(else_body),
+        StatementKind::Switch {
+            cases,
+            default: Some(default),
+            ..
+        } => cases.iter().all(|case| always_returns(&case.body)) && always_returns(default),
+        // a loop without a `break` only ends by returning
+        StatementKind::Loop { body } => !has_break(body),
+        _ => false,
+    })
+}
+
+/// Check if any of the statements has a `break` that exits the loop they are in, not counting the
+/// ones of nested loops.
+fn has_break(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Break => true,
+        StatementKind::If {
+            then_body,
+            else_body,
+            ..
+        } => has_break(then_body) || has_break(else_body.as_deref().unwrap_or_default()),
+        StatementKind::Switch { cases, default, .. } => {
+            cases.iter().any(|case| has_break(&case.body))
+                || has_break(default.as_deref().unwrap_or_default())
+        }
+        _ => false,
+    })
+}
+
+/// Check if any of the statements, including the nested ones, prints a string.
+fn prints_string(statements: &[Statement]) -> bool {
+    any_statement(statements, &|kind| {
+        matches!(kind, StatementKind::PrintString(_))
+    })
+}
+
+/// Check if any of the statements, including the nested ones, prints a condition.
+fn prints_condition(statements: &[Statement]) -> bool {
+    any_statement(
+        statements,
+        &|kind| matches!(kind, StatementKind::Print(value) if is_condition(value)),
+    )
+}
+
+/// Check if any of the statements, including the nested ones and the ones of nested procedures,
+/// matches `predicate`.
+pub(crate) fn any_statement(
+    statements: &[Statement],
+    predicate: &dyn Fn(&StatementKind) -> bool,
+) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        kind if predicate(kind) => true,
+        StatementKind::Proc { body, .. }
+        | StatementKind::While { body, .. }
+        | StatementKind::Repeat { body, .. }
+        | StatementKind::Loop { body } => any_statement(body, predicate),
+        StatementKind::If {
+            then_body,
+            else_body,
+            ..
+        } => {
+            any_statement(then_body, predicate)
+                || any_statement(else_body.as_deref().unwrap_or_default(), predicate)
+        }
+        StatementKind::Switch { cases, default, .. } => {
+            cases
+                .iter()
+                .any(|case| any_statement(&case.body, predicate))
+                || any_statement(default.as_deref().unwrap_or_default(), predicate)
+        }
+        _ => false,
+    })
+}
+
+/// Warn about each variable declared in the body of a function whose value is never read in it,
+/// not counting the procedures it defines, that can't read it. Variables with the same name, in
+/// different blocks, are only warned if none is read.
+fn unused_variables(body: &[Statement], warnings: &mut Vec<Warning>) {
+    fn collect<'a, 's>(
+        statements: &'a [Statement<'s>],
+        declared: &mut Vec<&'a Ident<'s>>,
+        read: &mut HashSet<&'s str>,
+    ) {
+        fn expression<'s>(value: &Expression<'s>, read: &mut HashSet<&'s str>) {
+            match &value.kind {
+                ExpressionKind::Number(_)
+                | ExpressionKind::Integer(_)
+                | ExpressionKind::Reference(_) => {}
+                ExpressionKind::Variable(name) => {
+                    read.insert(name);
+                }
+                ExpressionKind::Negate(value) | ExpressionKind::Index { index: value, .. } => {
+                    expression(value, read)
+                }
+                ExpressionKind::Call { args, .. } => {
+                    args.iter().for_each(|arg| expression(arg, read));
+                }
+                ExpressionKind::Binary { left, right, .. } => {
+                    expression(left, read);
+                    expression(right, read);
                 }
             }
-            _ => Err(Error {
-                source: self.source,
-                span: self.current.1.clone(),
-                kind: ErrorKind::UnexpectedToken {
-                    expected: &[Token::Number, Token::LeftParen],
-                    received: self.current.clone().0,
-                },
-            }),
+        }
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Var { ident, value } => {
+                    declared.push(ident);
+                    expression(value, read);
+                }
+                StatementKind::Destructure {
+                    targets,
+                    ident,
+                    args,
+                } => {
+                    declared.extend(targets);
+                    // a variable holding a procedure reference is read by calling it
+                    read.insert(ident.name);
+                    args.iter().for_each(|arg| expression(arg, read));
+                }
+                StatementKind::Call { ident, args } => {
+                    read.insert(ident.name);
+                    args.iter().for_each(|arg| expression(arg, read));
+                }
+                StatementKind::Print(value)
+                | StatementKind::Global { value, .. }
+                | StatementKind::Assignment { value, .. } => expression(value, read),
+                StatementKind::Return(values) => {
+                    values.iter().for_each(|value| expression(value, read));
+                }
+                StatementKind::While { condition, body }
+                | StatementKind::Repeat {
+                    count: condition,
+                    body,
+                } => {
+                    expression(condition, read);
+                    collect(body, declared, read);
+                }
+                StatementKind::If {
+                    condition,
+                    then_body,
+                    else_body,
+                } => {
+                    expression(condition, read);
+                    collect(then_body, declared, read);
+                    collect(else_body.as_deref().unwrap_or_default(), declared, read);
+                }
+                StatementKind::Loop { body } => collect(body, declared, read),
+                StatementKind::Switch {
+                    value,
+                    cases,
+                    default,
+                } => {
+                    expression(value, read);
+                    for case in cases {
+                        collect(&case.body, declared, read);
+                    }
+                    collect(default.as_deref().unwrap_or_default(), declared, read);
+                }
+                StatementKind::PrintString(_)
+                | StatementKind::Pass
+                | StatementKind::Break
+                | StatementKind::Data { .. }
+                | StatementKind::Proc { .. } => {}
+            }
+        }
+    }
+
+    let mut declared = Vec::new();
+    let mut read = HashSet::new();
+    collect(body, &mut declared, &mut read);
+    let mut warned = HashSet::new();
+    for ident in declared {
+        if !read.contains(ident.name) && warned.insert(ident.name) {
+            warnings.push(Warning::UnusedVariable {
+                name: ident.name.to_string(),
+                span: ident.span.clone(),
+            });
         }
     }
 }
+
+/// If the expression is a condition, a comparison or a `&&`, whose type is a `i32`.
+fn is_condition(expression: &Expression) -> bool {
+    matches!(
+        &expression.kind,
+        ExpressionKind::Binary { op, .. } if op.is_comparison() || *op == BinaryOp::And
+    )
+}
+
+/// The values of the first `return` of the statements, including the nested ones, but not the
+/// ones of nested procedures.
+fn first_return<'a, 's>(statements: &'a [Statement<'s>]) -> Option<&'a [Expression<'s>]> {
+    statements
+        .iter()
+        .find_map(|statement| match &statement.kind {
+            StatementKind::Return(values) => Some(values.as_slice()),
+            StatementKind::While { body, .. }
+            | StatementKind::Repeat { body, .. }
+            | StatementKind::Loop { body } => first_return(body),
+            StatementKind::If {
+                then_body,
+                else_body,
+                ..
+            } => first_return(then_body)
+                .or_else(|| first_return(else_body.as_deref().unwrap_or_default())),
+            StatementKind::Switch { cases, default, .. } => cases
+                .iter()
+                .find_map(|case| first_return(&case.body))
+                .or_else(|| first_return(default.as_deref().unwrap_or_default())),
+            _ => None,
+        })
+}
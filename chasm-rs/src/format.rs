@@ -0,0 +1,253 @@
+//! Print a program back to source code, in a canonical style.
+use crate::ast::{Expression, ExpressionKind, Program, Statement, StatementKind};
+use crate::prelude::*;
+
+/// The indentation of each nested block.
+const INDENT: &str = "    ";
+
+/// Write the program in the canonical style: one statement per line, blocks indented by four
+/// spaces, single spaces around operators and after commas, and at most one blank line between
+/// statements, where the source had any. The number literals are written as in the source, so
+/// they keep their exact value.
+pub(crate) fn format_program(source: &str, program: &Program) -> String {
+    let mut f = Formatter {
+        source,
+        out: String::new(),
+    };
+    f.block(&program.statements, 0);
+    f.out
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    out: String,
+}
+impl Formatter<'_> {
+    /// Write each statement of a block in its own line, keeping a blank line between two
+    /// statements that were separated by one. A chain, like `a = b = 1`, is written back in a
+    /// single line.
+    fn block(&mut self, statements: &[Statement], depth: usize) {
+        let mut i = 0;
+        while i < statements.len() {
+            let statement = &statements[i];
+            if i > 0 {
+                let gap = self
+                    .source
+                    .get(statements[i - 1].span.end..statement.span.start)
+                    .unwrap_or_default();
+                if gap.matches('\n').count() > 1 {
+                    self.out.push('\n');
+                }
+            }
+            let len = statements[i + 1..].iter().take_while(|x| x.chained).count();
+            if len == 0 {
+                self.statement(statement, depth);
+            } else {
+                self.chain(&statements[i..=i + len], depth);
+            }
+            i += len + 1;
+        }
+    }
+
+    /// Write a desugared chain, like "b = 1" and "a = b", back as "a = b = 1".
+    fn chain(&mut self, chain: &[Statement], depth: usize) {
+        let mut line = String::new();
+        if let StatementKind::Var { .. } = chain[0].kind {
+            line.push_str("var ");
+        }
+        let mut value = None;
+        for statement in chain.iter().rev() {
+            match &statement.kind {
+                StatementKind::Var { ident, value: x }
+                | StatementKind::Assignment { ident, value: x } => {
+                    line.push_str(ident.name);
+                    line.push_str(" = ");
+                    value = Some(x);
+                }
+                _ => unreachable!("only assignments are chained"),
+            }
+        }
+        line.push_str(&self.expression(value.expect("a chain is not empty")));
+        self.line(depth, &line);
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        for _ in 0..depth {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn statement(&mut self, statement: &Statement, depth: usize) {
+        let line = match &statement.kind {
+            StatementKind::Print(value) => format!("print {}", self.expression(value)),
+            StatementKind::PrintString(text) => format!("prints \"{}\"", text),
+            StatementKind::Pass => "pass".to_string(),
+            StatementKind::Var { ident, value } => {
+                format!("var {} = {}", ident.name, self.expression(value))
+            }
+            StatementKind::Global { ident, value } => {
+                format!("global {} = {}", ident.name, self.expression(value))
+            }
+            StatementKind::Data { ident, values } => {
+                format!("data {} = [{}]", ident.name, self.list(values))
+            }
+            StatementKind::Destructure {
+                targets,
+                ident,
+                args,
+            } => {
+                let names: Vec<_> = targets.iter().map(|x| x.name).collect();
+                format!(
+                    "var {} = {}({})",
+                    names.join(", "),
+                    ident.name,
+                    self.list(args)
+                )
+            }
+            StatementKind::Assignment { ident, value } => {
+                format!("{} = {}", ident.name, self.expression(value))
+            }
+            StatementKind::Call { ident, args } => format!("{}({})", ident.name, self.list(args)),
+            StatementKind::While { condition, body } => {
+                let line = format!("while {}", self.expression(condition));
+                return self.body(depth, &line, body, "endwhile");
+            }
+            StatementKind::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let line = format!("if {}", self.expression(condition));
+                self.line(depth, &line);
+                self.block(then_body, depth + 1);
+                if let Some(else_body) = else_body {
+                    self.line(depth, "else");
+                    self.block(else_body, depth + 1);
+                }
+                return self.line(depth, "endif");
+            }
+            StatementKind::Repeat { count, body } => {
+                let line = format!("repeat {}", self.expression(count));
+                return self.body(depth, &line, body, "endrepeat");
+            }
+            StatementKind::Loop { body } => return self.body(depth, "loop", body, "endloop"),
+            StatementKind::Break => "break".to_string(),
+            StatementKind::Return(values) => match values.as_slice() {
+                [] => "return".to_string(),
+                [value] => format!("return {}", self.expression(value)),
+                values => format!("return ({})", self.list(values)),
+            },
+            StatementKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let line = format!("switch {}", self.expression(value));
+                self.line(depth, &line);
+                for case in cases {
+                    // the number as written in the source, after the `case`
+                    let number = self.literal(case.span.start + "case".len()..case.span.end);
+                    self.line(depth + 1, &format!("case {}", number));
+                    self.block(&case.body, depth + 2);
+                }
+                if let Some(default) = default {
+                    self.line(depth + 1, "default");
+                    self.block(default, depth + 2);
+                }
+                return self.line(depth, "endswitch");
+            }
+            StatementKind::Proc {
+                ident,
+                params,
+                body,
+            } => {
+                let params: Vec<_> = params
+                    .iter()
+                    .map(|param| match &param.default {
+                        Some(default) => {
+                            format!("{} = {}", param.ident.name, self.expression(default))
+                        }
+                        None => param.ident.name.to_string(),
+                    })
+                    .collect();
+                let line = format!("proc {}({})", ident.name, params.join(", "));
+                return self.body(depth, &line, body, "endproc");
+            }
+        };
+        self.line(depth, &line);
+    }
+
+    /// Write a statement with a body, like a loop, between its first and its last line.
+    fn body(&mut self, depth: usize, first: &str, body: &[Statement], last: &str) {
+        self.line(depth, first);
+        self.block(body, depth + 1);
+        self.line(depth, last);
+    }
+
+    /// The expressions separated by commas, like the arguments of a call.
+    fn list(&self, expressions: &[Expression]) -> String {
+        let list: Vec<_> = expressions.iter().map(|x| self.expression(x)).collect();
+        list.join(", ")
+    }
+
+    /// The source code of a number literal, without whitespace, like between a `-` and the digits.
+    fn literal(&self, span: core::ops::Range<usize>) -> String {
+        self.source[span].split_whitespace().collect()
+    }
+
+    fn expression(&self, expression: &Expression) -> String {
+        match &expression.kind {
+            ExpressionKind::Number(_) | ExpressionKind::Integer(_) => {
+                self.literal(expression.span.clone())
+            }
+            ExpressionKind::Variable(name) => name.to_string(),
+            ExpressionKind::Reference(name) => format!("&{}", name),
+            ExpressionKind::Negate(value) => format!("-{}", self.expression(value)),
+            ExpressionKind::Call { ident, args } => format!("{}({})", ident.name, self.list(args)),
+            ExpressionKind::Index { ident, index } => {
+                format!("{}[{}]", ident.name, self.expression(index))
+            }
+            ExpressionKind::Binary { chained: true, .. } => {
+                format!("({})", self.comparisons(expression))
+            }
+            ExpressionKind::Binary {
+                op, left, right, ..
+            } => format!(
+                "({} {} {})",
+                self.expression(left),
+                op.as_str(),
+                self.expression(right)
+            ),
+        }
+    }
+    /// The comparisons of a chain, like `a < b < c`, without the parentheses.
+    fn comparisons(&self, expression: &Expression) -> String {
+        match &expression.kind {
+            ExpressionKind::Binary {
+                left,
+                right,
+                chained: true,
+                ..
+            } => match &right.kind {
+                ExpressionKind::Binary { op, right, .. } => format!(
+                    "{} {} {}",
+                    self.comparisons(left),
+                    op.as_str(),
+                    self.expression(right)
+                ),
+                _ => unreachable!("a chain ends in a comparison"),
+            },
+            ExpressionKind::Binary {
+                op, left, right, ..
+            } => format!(
+                "{} {} {}",
+                self.expression(left),
+                op.as_str(),
+                self.expression(right)
+            ),
+            _ => unreachable!("a chain starts with a comparison"),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! The WebAssembly instructions emitted by the compiler, with their binary encoding, and a
+//! disassembler for the modules created by it.
+use crate::leb128;
+use crate::prelude::*;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+
+/// A WebAssembly instruction, of the subset that the compiler emits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(missing_docs)]
+pub enum Instr {
+    Unreachable,
+    Nop,
+    /// A block with a empty block type.
+    Block,
+    /// A loop with a empty block type.
+    Loop,
+    /// A if with a empty block type.
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(u32),
+    /// A indirect call, with the given type index, on the table 0.
+    CallIndirect(u32),
+    Drop,
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Load8U {
+        align: u32,
+        offset: u32,
+    },
+    F32Load {
+        align: u32,
+        offset: u32,
+    },
+    I32Store8 {
+        align: u32,
+        offset: u32,
+    },
+    I32Const(i32),
+    F32Const(f32),
+    I32Eqz,
+    I32Eq,
+    I32LtS,
+    I32GtS,
+    I32LeS,
+    I32GeU,
+    F32Eq,
+    F32Lt,
+    F32Gt,
+    F32Ge,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32And,
+    F32Add,
+    F32Sub,
+    F32Mul,
+    F32Div,
+    F32Neg,
+    F32Floor,
+    F32Trunc,
+    F32Nearest,
+    F32Min,
+    F32Max,
+    F32Copysign,
+    I32TruncF32S,
+    F32ConvertI32S,
+    I32TruncSatF32S,
+}
+
+/// A error when decoding a instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The code ended in the middle of a instruction.
+    UnexpectedEnd,
+    /// The opcode is not one emitted by the compiler.
+    UnknownOpcode(u8),
+    /// A immediate is not valid, like a unexpected block type.
+    InvalidImmediate,
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of code"),
+            DecodeError::UnknownOpcode(x) => write!(f, "unknown opcode 0x{:02x}", x),
+            DecodeError::InvalidImmediate => write!(f, "invalid immediate"),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+pub(crate) fn read_u8(code: &mut &[u8]) -> Result<u8, DecodeError> {
+    let (&byte, rest) = code.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+    *code = rest;
+    Ok(byte)
+}
+
+pub(crate) fn read_u32(code: &mut &[u8]) -> Result<u32, DecodeError> {
+    let x = leb128::read_unsigned(code).ok_or(DecodeError::UnexpectedEnd)?;
+    u32::try_from(x).map_err(|_| DecodeError::InvalidImmediate)
+}
+
+fn read_i32(code: &mut &[u8]) -> Result<i32, DecodeError> {
+    let x = leb128::read_signed(code).ok_or(DecodeError::UnexpectedEnd)?;
+    i32::try_from(x).map_err(|_| DecodeError::InvalidImmediate)
+}
+
+fn read_empty_block_type(code: &mut &[u8]) -> Result<(), DecodeError> {
+    match read_u8(code)? {
+        0x40 => Ok(()),
+        _ => Err(DecodeError::InvalidImmediate),
+    }
+}
+
+impl Instr {
+    /// Decode the instruction at the start of `code`, advancing it past the instruction.
+    pub fn decode(code: &mut &[u8]) -> Result<Self, DecodeError> {
+        use Instr::*;
+        let instr = match read_u8(code)? {
+            0x00 => Unreachable,
+            0x01 => Nop,
+            0x02 => {
+                read_empty_block_type(code)?;
+                Block
+            }
+            0x03 => {
+                read_empty_block_type(code)?;
+                Loop
+            }
+            0x04 => {
+                read_empty_block_type(code)?;
+                If
+            }
+            0x05 => Else,
+            0x0b => End,
+            0x0c => Br(read_u32(code)?),
+            0x0d => BrIf(read_u32(code)?),
+            0x0f => Return,
+            0x10 => Call(read_u32(code)?),
+            0x11 => {
+                let ty = read_u32(code)?;
+                if read_u8(code)? != 0x00 {
+                    return Err(DecodeError::InvalidImmediate);
+                }
+                CallIndirect(ty)
+            }
+            0x1a => Drop,
+            0x20 => LocalGet(read_u32(code)?),
+            0x21 => LocalSet(read_u32(code)?),
+            0x23 => GlobalGet(read_u32(code)?),
+            0x24 => GlobalSet(read_u32(code)?),
+            0x2a => F32Load {
+                align: read_u32(code)?,
+                offset: read_u32(code)?,
+            },
+            0x2d => I32Load8U {
+                align: read_u32(code)?,
+                offset: read_u32(code)?,
+            },
+            0x3a => I32Store8 {
+                align: read_u32(code)?,
+                offset: read_u32(code)?,
+            },
+            0x41 => I32Const(read_i32(code)?),
+            0x43 => {
+                if code.len() < 4 {
+                    return Err(DecodeError::UnexpectedEnd);
+                }
+                let (bytes, rest) = code.split_at(4);
+                *code = rest;
+                F32Const(f32::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            0x45 => I32Eqz,
+            0x46 => I32Eq,
+            0x48 => I32LtS,
+            0x4a => I32GtS,
+            0x4c => I32LeS,
+            0x4f => I32GeU,
+            0x5b => F32Eq,
+            0x5d => F32Lt,
+            0x5e => F32Gt,
+            0x60 => F32Ge,
+            0x6a => I32Add,
+            0x6b => I32Sub,
+            0x6c => I32Mul,
+            0x71 => I32And,
+            0x8c => F32Neg,
+            0x8e => F32Floor,
+            0x8f => F32Trunc,
+            0x90 => F32Nearest,
+            0x92 => F32Add,
+            0x93 => F32Sub,
+            0x94 => F32Mul,
+            0x95 => F32Div,
+            0x96 => F32Min,
+            0x97 => F32Max,
+            0x98 => F32Copysign,
+            0xa8 => I32TruncF32S,
+            0xb2 => F32ConvertI32S,
+            0xfc => match read_u32(code)? {
+                0x00 => I32TruncSatF32S,
+                _ => return Err(DecodeError::InvalidImmediate),
+            },
+            x => return Err(DecodeError::UnknownOpcode(x)),
+        };
+        Ok(instr)
+    }
+}
+
+impl Instr {
+    /// Encode the instruction in the WebAssembly binary format, appending it to `out`. This is
+    /// the inverse of [`Instr::decode`].
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        use Instr::*;
+        match *self {
+            Unreachable => out.push(0x00),
+            Nop => out.push(0x01),
+            Block => out.extend_from_slice(&[0x02, 0x40]),
+            Loop => out.extend_from_slice(&[0x03, 0x40]),
+            If => out.extend_from_slice(&[0x04, 0x40]),
+            Else => out.push(0x05),
+            End => out.push(0x0b),
+            Br(x) => write_with_u32(out, 0x0c, x),
+            BrIf(x) => write_with_u32(out, 0x0d, x),
+            Return => out.push(0x0f),
+            Call(x) => write_with_u32(out, 0x10, x),
+            CallIndirect(x) => {
+                write_with_u32(out, 0x11, x);
+                // the table 0
+                out.push(0x00);
+            }
+            Drop => out.push(0x1a),
+            LocalGet(x) => write_with_u32(out, 0x20, x),
+            LocalSet(x) => write_with_u32(out, 0x21, x),
+            GlobalGet(x) => write_with_u32(out, 0x23, x),
+            GlobalSet(x) => write_with_u32(out, 0x24, x),
+            F32Load { align, offset } => {
+                write_with_u32(out, 0x2a, align);
+                leb128::write_unsigned(out, offset as u64);
+            }
+            I32Load8U { align, offset } => {
+                write_with_u32(out, 0x2d, align);
+                leb128::write_unsigned(out, offset as u64);
+            }
+            I32Store8 { align, offset } => {
+                write_with_u32(out, 0x3a, align);
+                leb128::write_unsigned(out, offset as u64);
+            }
+            I32Const(x) => {
+                out.push(0x41);
+                leb128::write_signed(out, x as i64);
+            }
+            F32Const(x) => {
+                out.push(0x43);
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            I32Eqz => out.push(0x45),
+            I32Eq => out.push(0x46),
+            I32LtS => out.push(0x48),
+            I32GtS => out.push(0x4a),
+            I32LeS => out.push(0x4c),
+            I32GeU => out.push(0x4f),
+            F32Eq => out.push(0x5b),
+            F32Lt => out.push(0x5d),
+            F32Gt => out.push(0x5e),
+            F32Ge => out.push(0x60),
+            I32Add => out.push(0x6a),
+            I32Sub => out.push(0x6b),
+            I32Mul => out.push(0x6c),
+            I32And => out.push(0x71),
+            F32Neg => out.push(0x8c),
+            F32Floor => out.push(0x8e),
+            F32Trunc => out.push(0x8f),
+            F32Nearest => out.push(0x90),
+            F32Add => out.push(0x92),
+            F32Sub => out.push(0x93),
+            F32Mul => out.push(0x94),
+            F32Div => out.push(0x95),
+            F32Min => out.push(0x96),
+            F32Max => out.push(0x97),
+            F32Copysign => out.push(0x98),
+            I32TruncF32S => out.push(0xa8),
+            F32ConvertI32S => out.push(0xb2),
+            // a instruction with the prefix 0xFC, followed by its index
+            I32TruncSatF32S => write_with_u32(out, 0xfc, 0x00),
+        }
+    }
+}
+
+/// Write a opcode followed by a `u32` immediate.
+fn write_with_u32(out: &mut Vec<u8>, opcode: u8, x: u32) {
+    out.push(opcode);
+    leb128::write_unsigned(out, x as u64);
+}
+
+/// Write the instruction in the WebAssembly text format, like `local.get 0` or `f32.const 100`.
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instr::*;
+        let mnemonic = match self {
+            Unreachable => "unreachable",
+            Nop => "nop",
+            Block => "block",
+            Loop => "loop",
+            If => "if",
+            Else => "else",
+            End => "end",
+            Br(x) => return write!(f, "br {}", x),
+            BrIf(x) => return write!(f, "br_if {}", x),
+            Return => "return",
+            Call(x) => return write!(f, "call {}", x),
+            CallIndirect(x) => return write!(f, "call_indirect (type {})", x),
+            Drop => "drop",
+            LocalGet(x) => return write!(f, "local.get {}", x),
+            LocalSet(x) => return write!(f, "local.set {}", x),
+            GlobalGet(x) => return write!(f, "global.get {}", x),
+            GlobalSet(x) => return write!(f, "global.set {}", x),
+            F32Load { align, offset }
+            | I32Load8U { align, offset }
+            | I32Store8 { align, offset } => {
+                match self {
+                    F32Load { .. } => write!(f, "f32.load")?,
+                    I32Load8U { .. } => write!(f, "i32.load8_u")?,
+                    _ => write!(f, "i32.store8")?,
+                }
+                if *offset != 0 {
+                    write!(f, " offset={}", offset)?;
+                }
+                if *align != 0 {
+                    write!(f, " align={}", 1u64 << align)?;
+                }
+                return Ok(());
+            }
+            I32Const(x) => return write!(f, "i32.const {}", x),
+            F32Const(x) => return write!(f, "f32.const {}", x),
+            I32Eqz => "i32.eqz",
+            I32Eq => "i32.eq",
+            I32LtS => "i32.lt_s",
+            I32GtS => "i32.gt_s",
+            I32LeS => "i32.le_s",
+            I32GeU => "i32.ge_u",
+            F32Eq => "f32.eq",
+            F32Lt => "f32.lt",
+            F32Gt => "f32.gt",
+            F32Ge => "f32.ge",
+            I32Add => "i32.add",
+            I32Sub => "i32.sub",
+            I32Mul => "i32.mul",
+            I32And => "i32.and",
+            F32Add => "f32.add",
+            F32Sub => "f32.sub",
+            F32Mul => "f32.mul",
+            F32Div => "f32.div",
+            F32Neg => "f32.neg",
+            F32Floor => "f32.floor",
+            F32Trunc => "f32.trunc",
+            F32Nearest => "f32.nearest",
+            F32Min => "f32.min",
+            F32Max => "f32.max",
+            F32Copysign => "f32.copysign",
+            I32TruncF32S => "i32.trunc_f32_s",
+            F32ConvertI32S => "f32.convert_i32_s",
+            I32TruncSatF32S => "i32.trunc_sat_f32_s",
+        };
+        write!(f, "{}", mnemonic)
+    }
+}
+
+/// Disassemble the code section of a module created by the compiler, returning the instructions
+/// of each function, in the WebAssembly text format, like `local.get 0` or `f32.const 100`.
+///
+/// # Example
+/// ```
+/// let binary = chasm_rs::compile("print 2").unwrap();
+/// let functions = chasm_rs::instr::disassemble(&binary).unwrap();
+///
+/// assert_eq!(functions, [["f32.const 2", "call 0", "end"]]);
+/// ```
+pub fn disassemble(binary: &[u8]) -> Result<Vec<Vec<String>>, DecodeError> {
+    if binary.len() < 8 {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let mut functions = Vec::new();
+    let mut rest = &binary[8..];
+    while !rest.is_empty() {
+        let id = read_u8(&mut rest)?;
+        let len = read_u32(&mut rest)? as usize;
+        if rest.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (mut section, next) = rest.split_at(len);
+        rest = next;
+        // only the code section is disassembled
+        if id != 10 {
+            continue;
+        }
+        for _ in 0..read_u32(&mut section)? {
+            let len = read_u32(&mut section)? as usize;
+            if section.len() < len {
+                return Err(DecodeError::UnexpectedEnd);
+            }
+            let (mut body, next) = section.split_at(len);
+            section = next;
+            // skip the declaration of locals
+            for _ in 0..read_u32(&mut body)? {
+                read_u32(&mut body)?;
+                read_u8(&mut body)?;
+            }
+            let mut instrs = Vec::new();
+            while !body.is_empty() {
+                instrs.push(Instr::decode(&mut body)?.to_string());
+            }
+            functions.push(instrs);
+        }
+    }
+    Ok(functions)
+}
@@ -0,0 +1,94 @@
+//! The LEB128 encoding of the integers of a WebAssembly module, and the [`Write`] trait of the
+//! buffers the module is written to, used in place of `std::io::Write`, so the crate doesn't
+//! need `std`.
+use crate::prelude::*;
+
+/// A buffer of bytes that can't fail to be written.
+pub trait Write {
+    /// Write all bytes of `buf` at the end of the buffer.
+    fn write_all(&mut self, buf: &[u8]);
+}
+impl Write for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) {
+        self.extend_from_slice(buf);
+    }
+}
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write_all(&mut self, buf: &[u8]) {
+        (**self).write_all(buf);
+    }
+}
+
+/// Write `val` as a unsigned LEB128, returning the number of bytes written.
+pub fn write_unsigned<W: Write + ?Sized>(w: &mut W, mut val: u64) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        len += 1;
+        if val == 0 {
+            w.write_all(&[byte]);
+            return len;
+        }
+        w.write_all(&[byte | 0x80]);
+    }
+}
+
+/// Write `val` as a signed LEB128, returning the number of bytes written.
+pub fn write_signed<W: Write + ?Sized>(w: &mut W, mut val: i64) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7f) as u8;
+        // arithmetic shift, so a negative number stays negative
+        val >>= 7;
+        len += 1;
+        let sign_bit = byte & 0x40 != 0;
+        if (val == 0 && !sign_bit) || (val == -1 && sign_bit) {
+            w.write_all(&[byte]);
+            return len;
+        }
+        w.write_all(&[byte | 0x80]);
+    }
+}
+
+/// Read a unsigned LEB128 from the start of `bytes`, advancing it. Return `None` if `bytes` ends
+/// before the number, or if the number doesn't fit in a `u64`.
+pub fn read_unsigned(bytes: &mut &[u8]) -> Option<u64> {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        if shift == 63 && byte > 1 {
+            return None;
+        }
+        result |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+        shift += 7;
+    }
+}
+
+/// Read a signed LEB128 from the start of `bytes`, advancing it. Return `None` if `bytes` ends
+/// before the number, or if the number doesn't fit in a `i64`.
+pub fn read_signed(bytes: &mut &[u8]) -> Option<i64> {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        if shift == 63 && byte != 0 && byte != 0x7f {
+            return None;
+        }
+        result |= i64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                // sign extend
+                result |= !0 << shift;
+            }
+            return Some(result);
+        }
+    }
+}
@@ -1,25 +1,58 @@
-//! This crate is a single-pass compiler to WebAssembly for the language chasm.
+//! This crate is a compiler to WebAssembly for the language chasm.
 //!
 //! chasm is a very simple language created by Colin Eberhardt, to introduce the basic building
 //! blocks of compilers, and reveal some of the inner workings of WebAssembly. This is a
 //! implementation of the compiler in Rust.
+//!
+//! Without the default feature `std`, the crate is `no_std`, and only needs `alloc`. It can still
+//! compile programs, but the interpreter of [`run`] is not available.
 #![deny(missing_docs)]
-use std::io::Write;
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
 
 mod wasm_macro;
 use wasm_macro::wasm;
 
+pub mod ast;
 pub(crate) mod compiler;
-pub use compiler::{Error, ErrorKind};
+mod format;
+pub mod instr;
+mod leb128;
+mod optimize;
+mod parser;
+#[cfg(feature = "std")]
+pub mod run;
+use leb128::Write;
+use prelude::*;
+
+/// The items of the standard prelude that come from `alloc`, and the maps of the compiler, so
+/// each module is the same with or without `std`.
+mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+    pub use alloc::{format, vec};
 
-#[cfg(test)]
+    #[cfg(feature = "std")]
+    pub use std::collections::{HashMap, HashSet};
+    // without `std` there is no source of randomness for the keys of a hash map
+    #[cfg(not(feature = "std"))]
+    pub use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+}
+pub use compiler::{
+    Error, ErrorKind, FuncType, JsonString, Module, Procedure, Token, Type, Warning,
+    RGB_CANVAS_OFFSET,
+};
+
+// the tests run the compiled programs, which needs `std`
+#[cfg(all(test, feature = "std"))]
 mod run_wasm;
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod test;
 
 fn write_section(w: &mut Vec<u8>, section_type: u8, f: impl Fn(&mut Vec<u8>)) {
     // section type
-    w.write_all(&[section_type]).unwrap();
+    w.write_all(&[section_type]);
     let section_start = w.len();
 
     f(w);
@@ -28,19 +61,424 @@ fn write_section(w: &mut Vec<u8>, section_type: u8, f: impl Fn(&mut Vec<u8>)) {
 
     // write the length
     let section_len = w.len() - section_start;
-    let len = leb128::write::unsigned(w, section_len as u64).unwrap();
+    let len = leb128::write_unsigned(w, section_len as u64);
     // move it to the start
     w[section_start..].rotate_right(len);
 }
 
+/// Write a vector of value types.
+fn write_valtypes(w: &mut Vec<u8>, types: &[Type]) {
+    leb128::write_unsigned(w, types.len() as u64);
+    for ty in types {
+        match ty {
+            Type::I32 => {
+                wasm!(w, i32);
+            }
+            Type::F32 => {
+                wasm!(w, f32);
+            }
+        }
+    }
+}
+
+/// Options to change how a chasm program is compiled.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// Clamp the coordinates of `setpixel` to the canvas, in the range `0..=99`, instead of
+    /// trapping or writing out of the canvas when they are out of range. A NaN coordinate still
+    /// traps.
+    pub clamp_setpixel: bool,
+    /// Skip the calls to `setpixel` with coordinates out of the canvas, or NaN, instead of
+    /// trapping or writing out of the canvas.
+    pub skip_oob_setpixel: bool,
+    /// Trap with a `unreachable` instruction when `setpixel` or `blendpixel` would access a
+    /// address out of the canvas, in the range `0..10000`, instead of writing to the memory after
+    /// it, where the string literals are. A coordinate out of `0..100` still wraps to another
+    /// pixel if the address is in the canvas, like `(100, 0)`, that is `(0, 1)`. Also trap when a
+    /// `data` table is read with a index out of it, or NaN, instead of reading its closest value.
+    pub bounds_checks: bool,
+    /// Convert the color of `setpixel` to a integer with the saturating `i32.trunc_sat_f32_s`, so
+    /// a NaN color writes 0, instead of trapping. This instruction is part of the non-trapping
+    /// float-to-int conversions extension, which some engines may not support.
+    pub saturating_color: bool,
+    /// Allow a procedure to return more than one value, like `return (x, y)`, using the
+    /// multi-value extension, which some engines may not support. The values are received with
+    /// `var a, b = f()`. If disabled, returning more than one value is a
+    /// [`ErrorKind::MultivalueDisabled`] error. Disabled by default.
+    pub multivalue: bool,
+    /// Emit a `producers` custom section, recording that the module was processed by chasm-rs,
+    /// and its version. Enabled by default.
+    pub producers_section: bool,
+    /// The minimum size, in pages of 64KiB, of the memory imported by the module. The canvas
+    /// occupies the first 10000 bytes of the first page. The default is 1.
+    pub memory_min_pages: u32,
+    /// The maximum size, in pages of 64KiB, of the memory imported by the module, if any. It
+    /// can't be smaller than `memory_min_pages`, or than 2 if the program calls `setrgb`, which is
+    /// a [`ErrorKind::InvalidMemoryLimits`] error.
+    pub memory_max_pages: Option<u32>,
+    /// Compile a program without statements to a module whose `main` does nothing. If disabled,
+    /// it is a [`ErrorKind::EmptyProgram`] error instead. Enabled by default.
+    pub allow_empty: bool,
+    /// Optimize the program before compiling it, propagating the variables that are declared with
+    /// a constant and never reassigned, and folding the arithmetic on constants. The variables
+    /// that are declared with a integer and only stepped by one, like loop counters, are stored as
+    /// `i32`, so they stay exact past 2^24. The arithmetic in a `while` condition that
+    /// doesn't change during the loop is computed once, before it, and the arithmetic that the
+    /// condition shares with the start of the loop body is computed once per iteration. Disabled
+    /// by default, so the generated code follows the source closely.
+    pub optimize: bool,
+    /// Generate the code of the procedures in parallel, if the crate feature `parallel` is
+    /// enabled. The output is the same either way. Enabled by default, and ignored without the
+    /// feature.
+    pub parallel: bool,
+    /// The names of the procedures to export, besides `"main"`, so a host can call them. Each one
+    /// is exported with its own name, and a name that is not a procedure of the program is a
+    /// [`ErrorKind::UndeclaredProc`] error. Empty by default.
+    pub exports: Vec<String>,
+    /// The maximum number of locals of a function, counting its parameters, variables and the
+    /// hidden locals created by the compiler. A function with more is a
+    /// [`ErrorKind::TooManyLocals`] error. The default is 50000, the limit of the WebAssembly
+    /// JavaScript API.
+    pub max_locals: u32,
+    /// The maximum number of functions of the module, counting the imported ones. A program with
+    /// more procedures is a [`ErrorKind::TooManyFunctions`] error. The default is 1000000, the
+    /// limit of the WebAssembly JavaScript API.
+    pub max_functions: u32,
+    /// The maximum size, in bytes, of the body of a function, including the declaration of its
+    /// locals. A larger function is a [`ErrorKind::ModuleTooLarge`] error. The default is
+    /// 7654321, the limit of the WebAssembly JavaScript API.
+    pub max_function_size: u32,
+    /// Assign the arguments of `setpixel` to the variables `x`, `y` and `color`, declaring them
+    /// if needed, like the original chasm compiler did. Disabled by default, so `setpixel` doesn't
+    /// change any variable.
+    ///
+    /// This is deprecated, and only kept for the programs that rely on the old behavior.
+    pub legacy_setpixel_sideeffects: bool,
+}
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            clamp_setpixel: false,
+            skip_oob_setpixel: false,
+            bounds_checks: false,
+            saturating_color: false,
+            multivalue: false,
+            producers_section: true,
+            memory_min_pages: 1,
+            memory_max_pages: None,
+            allow_empty: true,
+            optimize: false,
+            parallel: true,
+            exports: Vec::new(),
+            max_locals: 50_000,
+            max_functions: 1_000_000,
+            max_function_size: 7_654_321,
+            legacy_setpixel_sideeffects: false,
+        }
+    }
+}
+
+/// Split the given chasm source code in tokens, returning each one with its byte range. The
+/// whitespace is skipped, and a unrecognized character is a [`Token::Error`].
+///
+/// # Example
+/// ```
+/// use chasm_rs::Token;
+///
+/// let tokens = chasm_rs::tokenize("var x = 1");
+///
+/// assert_eq!(
+///     tokens,
+///     [
+///         (Token::Var, 0..3),
+///         (Token::Identifier, 4..5),
+///         (Token::Assignment, 6..7),
+///         (Token::Number, 8..9)
+///     ]
+/// );
+/// ```
+pub fn tokenize(source: &str) -> Vec<(Token, core::ops::Range<usize>)> {
+    use logos::Logos;
+    Token::lexer(source).spanned().collect()
+}
+
+/// Parse the given chasm source code, returning its abstract syntax tree.
+///
+/// # Example
+/// ```
+/// let program = chasm_rs::parse("print (1 + 2)").unwrap();
+///
+/// assert_eq!(program.statements.len(), 1);
+/// ```
+pub fn parse<'s>(source: &'s str) -> Result<ast::Program<'s>, Error<'s>> {
+    parser::Parser::parse(source)
+}
+
+/// Format the given chasm source code in a canonical style: one statement per line, blocks
+/// indented by four spaces, single spaces around operators and after commas, and at most one blank
+/// line between statements. The result always ends with a line break, unless the program is empty.
+///
+/// The source is parsed, so a program with a syntax error is not formatted. Formatting a formatted
+/// program returns it unchanged.
+///
+/// # Example
+/// ```
+/// let formatted = chasm_rs::format("var x=(1+2) if (x>2) print x endif").unwrap();
+///
+/// assert_eq!(formatted, "var x = (1 + 2)\nif (x > 2)\n    print x\nendif\n");
+/// ```
+pub fn format(source: &str) -> Result<String, Error<'_>> {
+    let program = parse(source)?;
+    Ok(format::format_program(source, &program))
+}
+
+/// Evaluate the given source code, that must be a single constant expression, like
+/// `((3 * 2) - 1)`. The expression is folded in the same way as when
+/// [`CompileOptions::optimize`] is enabled, so the result is the same as computing it at runtime.
+///
+/// Return a [`ErrorKind::NotConstant`] error if the expression uses variables or calls
+/// functions, or if it is a comparison, that has no float value.
+///
+/// # Example
+/// ```
+/// assert_eq!(chasm_rs::compile_expr("((3 * 2) - (21 / 7))").unwrap(), 3.0);
+/// assert!(chasm_rs::compile_expr("(x + 1)").is_err());
+/// ```
+pub fn compile_expr(source: &str) -> Result<f32, Error<'_>> {
+    let mut expression = parser::Parser::parse_expression(source)?;
+    optimize::fold_constant(&mut expression).ok_or_else(|| Error {
+        source,
+        span: expression.span.clone(),
+        kind: ErrorKind::NotConstant,
+    })
+}
+
+/// Evaluate the given program without running it, returning the values that it prints, in order.
+/// This only works for a program whose statements are `print`, `var`, `global`, assignments and
+/// `pass`, with values that fold to constants in the same way as [`compile_expr`], so it has no
+/// loops, branches, calls or drawing.
+///
+/// Return `None` if the program does anything else, or if it doesn't compile.
+///
+/// # Example
+/// ```
+/// assert_eq!(chasm_rs::try_const_eval("var x = 2 print x print (x * 3)"), Some(vec![2.0, 6.0]));
+/// assert_eq!(chasm_rs::try_const_eval("repeat 2 print 1 endrepeat"), None);
+/// ```
+pub fn try_const_eval(source: &str) -> Option<Vec<f32>> {
+    let program = parse(source).ok()?;
+    // the evaluation doesn't check the program, so it is compiled to find its errors
+    compiler::Compiler::compile(source, &program, &CompileOptions::default()).ok()?;
+    optimize::eval_prints(&program)
+}
+
+/// Check if the given chasm source code compiles, without creating the WebAssembly module, and
+/// return all the errors found if it doesn't. Each unrecognized character is reported and skipped,
+/// and parsing stops at the first syntax error, but after that each procedure, and the `main`
+/// procedure, can report its own error. The first error is the one returned by [`compile`].
+///
+/// # Example
+/// ```
+/// let errors = chasm_rs::validate_source("proc f() var a = (1 < 2) endproc var b = (1 < 2) f()");
+///
+/// assert_eq!(errors.unwrap_err().len(), 2);
+/// assert!(chasm_rs::validate_source("print 1").is_ok());
+/// ```
+pub fn validate_source(source: &str) -> Result<(), Vec<Error<'_>>> {
+    let (program, mut errors) = parser::Parser::parse_recovering(source);
+    match program {
+        Ok(program) => {
+            let options = CompileOptions::default();
+            if let Err(compile_errors) =
+                compiler::Compiler::compile_all_errors(source, &program, &options)
+            {
+                errors.extend(compile_errors);
+            }
+        }
+        Err(err) => {
+            let i = errors.partition_point(|x| x.span.start <= err.span.start);
+            errors.insert(i, err);
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Compile the given chasm source code, returning its procedures sorted by function index,
+/// starting with the `main` procedure.
+///
+/// # Example
+/// ```
+/// let procedures = chasm_rs::procedures("proc f(a, b) print (a + b) endproc f(1, 2)").unwrap();
+///
+/// assert_eq!(procedures[0].name(), "main");
+/// assert_eq!(procedures[1].name(), "f");
+/// assert_eq!(procedures[1].param_count(), 2);
+/// ```
+pub fn procedures<'s>(source: &'s str) -> Result<Vec<Procedure>, Error<'s>> {
+    let program = parse(source)?;
+    let module = compiler::Compiler::compile(source, &program, &CompileOptions::default())?;
+    Ok(module.procedures)
+}
+
+/// A import of a compiled module, that a host must provide to instantiate it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportReq {
+    /// The module of the import, which is always `"env"`.
+    pub module: &'static str,
+    /// The name of the import, like `"print"`.
+    pub name: &'static str,
+    /// The type of the import, in the WebAssembly text format, like `(func (param f32))` or
+    /// `(memory 1)`.
+    pub signature: String,
+}
+impl core::fmt::Display for ImportReq {
+    /// Write the import as `module name signature`, like `env print (func (param f32))`.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} {} {}", self.module, self.name, self.signature)
+    }
+}
+
+/// A short and stable hash of a canvas, or of any memory, for tests that check the rendered output
+/// of a program without storing it, like `"28ad088dd153090f"`. It is the first 16 hexadecimal
+/// digits of the BLAKE3 hash of the bytes. Requires the crate feature `blake3`.
+///
+/// The hash only depends on the bytes, but the code generated by the compiler may change between
+/// versions, like using more precise instructions, which can change the rendered pixels, and so
+/// the hash of a program.
+///
+/// # Example
+/// ```
+/// let canvas = [0u8; 100 * 100];
+///
+/// assert_eq!(chasm_rs::canvas_hash(&canvas).len(), 16);
+/// assert_eq!(chasm_rs::canvas_hash(&canvas), chasm_rs::canvas_hash(&[0; 10000]));
+/// ```
+#[cfg(feature = "blake3")]
+pub fn canvas_hash(canvas: &[u8]) -> String {
+    blake3::hash(canvas).to_hex()[..16].to_string()
+}
+
+/// The maximum number of instructions executed by [`render_to_rgba`]. The examples in the
+/// repository execute less than 100 million.
+#[cfg(feature = "std")]
+pub const RENDER_FUEL: u64 = 1_000_000_000;
+
+/// A error of [`render_to_rgba`], when compiling or when running the program.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum ChasmError<'s> {
+    /// The program failed to compile.
+    Compile(Error<'s>),
+    /// The program trapped, or executed more than [`RENDER_FUEL`] instructions.
+    Run(run::RunError),
+}
+#[cfg(feature = "std")]
+impl core::fmt::Display for ChasmError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ChasmError::Compile(x) => write!(f, "{}", x),
+            ChasmError::Run(x) => write!(f, "{}", x),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for ChasmError<'_> {}
+#[cfg(feature = "std")]
+impl<'s> From<Error<'s>> for ChasmError<'s> {
+    fn from(err: Error<'s>) -> Self {
+        ChasmError::Compile(err)
+    }
+}
+#[cfg(feature = "std")]
+impl From<run::RunError> for ChasmError<'_> {
+    fn from(err: run::RunError) -> Self {
+        ChasmError::Run(err)
+    }
+}
+
+/// Compile and run the given chasm source code with the bundled interpreter, and return its
+/// canvas as RGBA pixels, 4 bytes per pixel, row by row. The 100x100 canvas is scaled to `width`
+/// by `height` pixels, repeating the nearest pixel. A program that calls `setrgb` renders its RGB
+/// canvas, and any other renders the grayscale one, with each byte as the red, green and blue.
+///
+/// The program is stopped after [`RENDER_FUEL`] instructions, so a program that never ends is a
+/// error, like a trap. Requires the crate feature `std`.
+///
+/// # Example
+/// ```
+/// let rgba = chasm_rs::render_to_rgba("setpixel(0, 0, 200)", 200, 200).unwrap();
+///
+/// assert_eq!(rgba.len(), 200 * 200 * 4);
+/// assert_eq!(rgba[..12], [200, 200, 200, 255, 200, 200, 200, 255, 0, 0, 0, 255]);
+/// ```
+#[cfg(feature = "std")]
+pub fn render_to_rgba(source: &str, width: u32, height: u32) -> Result<Vec<u8>, ChasmError<'_>> {
+    let module = compile_to_module(source, &CompileOptions::default())?;
+    let options = run::RunOptions {
+        fuel: Some(RENDER_FUEL),
+        rgb: module.uses_rgb,
+        ..Default::default()
+    };
+    let output = run::run_binary(&module.serialize(), &options)?;
+
+    let (width, height) = (width as usize, height as usize);
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = (y * 100 / height) * 100 + x * 100 / width;
+            match module.uses_rgb {
+                true => rgba.extend_from_slice(&output.canvas[pixel * 3..pixel * 3 + 3]),
+                false => rgba.extend_from_slice(&[output.canvas[pixel]; 3]),
+            }
+            rgba.push(255);
+        }
+    }
+    Ok(rgba)
+}
+
+/// Compile the given chasm source code, with the default options, and return the imports that a
+/// host must provide to instantiate the module, in the order they are declared in it. See
+/// [`Module::imports`].
+///
+/// # Example
+/// ```
+/// let imports = chasm_rs::imports_used("prints \"hi\"").unwrap();
+/// let names: Vec<_> = imports.iter().map(|x| x.name).collect();
+///
+/// assert_eq!(names, ["print", "prints", "memory"]);
+/// assert_eq!(imports[1].signature, "(func (param i32 i32))");
+/// ```
+pub fn imports_used(source: &str) -> Result<Vec<ImportReq>, Error<'_>> {
+    let module = compile_to_module(source, &CompileOptions::default())?;
+    Ok(module.imports())
+}
+
 /// Compile the given chasm source code in a WebAssembly module.
 ///
 /// The created module imports the function `"env" "print"` that received a f32 and return nothing,
-/// and a memory `"env" "memory"` with a minimal size of 1, and exports the function `"main"`, that
-/// has no argument or return, which is the code entry point.
+/// and a memory `"env" "memory"` with a minimal size of 1 page (see
+/// [`CompileOptions::memory_min_pages`]), and exports the function `"main"`, that has no argument
+/// or return, which is the code entry point.
+///
+/// If the program prints a string, the module also imports the function `"env" "prints"`, that
+/// receives the pointer and length of a UTF-8 string in the memory and return nothing. If it
+/// prints a condition, like `print (a < b)`, it imports the function `"env" "printb"`, that
+/// receives a i32, 1 for true or 0 for false, and return nothing.
 ///
 /// At the end of the execution of the created module, the rendered 100x100 output will be in the
-/// linear memory, in the range 0..10000.
+/// linear memory, in the range 0..10000. The tables declared with `data` are placed right after
+/// it, as `f32`, followed by the string literals.
+///
+/// Unless disabled in [`CompileOptions`], the module also contains a `producers` custom section,
+/// identifying chasm-rs and its version.
+///
+/// If the program defines a procedure `frame` with one parameter, it is also exported as
+/// `"frame"`, so a host can render a animation by calling it repeatedly after `"main"`. Other
+/// procedures can be exported with [`CompileOptions::exports`].
 ///
 /// # Example
 /// ```
@@ -61,56 +499,217 @@ fn write_section(w: &mut Vec<u8>, section_type: u8, f: impl Fn(&mut Vec<u8>)) {
 /// assert!(wasm.is_ok());
 /// ```
 pub fn compile<'s>(source: &'s str) -> Result<Vec<u8>, Error<'s>> {
-    let functions = compiler::Parser::parse(source)?;
+    compile_with_options(source, &CompileOptions::default())
+}
 
-    let mut binary = wasm!( new
-        (magic version)
-    );
+/// Compile the given chasm source code in a WebAssembly module, like [`compile`], but with the
+/// given options.
+pub fn compile_with_options<'s>(
+    source: &'s str,
+    options: &CompileOptions,
+) -> Result<Vec<u8>, Error<'s>> {
+    compile_with_warnings(source, options).map(|(binary, _)| binary)
+}
 
-    // section type
-    write_section(&mut binary, wasm!(section_type type), |mut w| {
-        // number of types
-        leb128::write::unsigned(&mut w, 1 + functions.len() as u64).unwrap();
-        // print function type
-        wasm!(&mut w, (functype (vec f32) (vec)));
-        for f in &functions {
-            wasm!(&mut w, functype);
-            leb128::write::unsigned(&mut w, f.num_param as u64).unwrap();
-            for _ in 0..f.num_param {
-                wasm!(&mut w, f32);
+/// Compile the given chasm source code in a WebAssembly module, like [`compile_with_options`],
+/// but also return the warnings emitted during compilation.
+pub fn compile_with_warnings<'s>(
+    source: &'s str,
+    options: &CompileOptions,
+) -> Result<(Vec<u8>, Vec<Warning>), Error<'s>> {
+    let mut module = compile_to_module(source, options)?;
+    let warnings = core::mem::take(&mut module.warnings);
+    Ok((module.serialize(), warnings))
+}
+
+/// Compile the given chasm source code, returning the [`Module`] before it is serialized, so it
+/// can be modified, like renaming its exports. [`Module::serialize`] then creates the same binary
+/// as [`compile_with_options`].
+///
+/// # Example
+/// ```
+/// let options = chasm_rs::CompileOptions::default();
+/// let mut module = chasm_rs::compile_to_module("print 1", &options).unwrap();
+/// module.exports[0].0 = "start".to_string();
+///
+/// let binary = module.serialize();
+/// assert!(binary.windows(5).any(|x| x == b"start"));
+/// ```
+pub fn compile_to_module<'s>(
+    source: &'s str,
+    options: &CompileOptions,
+) -> Result<Module, Error<'s>> {
+    let mut program = parse(source)?;
+    if !options.optimize {
+        return compiler::Compiler::compile(source, &program, options);
+    }
+    // the warnings are about the program as written, not the optimized one
+    let warnings = compiler::Compiler::warnings(source, &program, options)?;
+    optimize::optimize(&mut program);
+    let mut module = compiler::Compiler::compile(source, &program, options)?;
+    module.warnings = warnings;
+    Ok(module)
+}
+
+impl Module {
+    /// The imports of the module, in the order they are declared in it: `"print"`, `"prints"` and
+    /// `"printb"`, if the program prints strings or conditions, and the memory.
+    pub fn imports(&self) -> Vec<ImportReq> {
+        let func = |name, ty: &FuncType| {
+            let params: Vec<_> = ty.params.iter().map(|x| x.wasm_name()).collect();
+            ImportReq {
+                module: "env",
+                name,
+                signature: format!("(func (param {}))", params.join(" ")),
             }
-            wasm!(&mut w, (vec));
+        };
+        let mut imports = vec![func("print", &self.types[0])];
+        let mut ty = 1;
+        if self.imports_prints {
+            imports.push(func("prints", &self.types[ty]));
+            ty += 1;
         }
-    });
-
-    wasm!(&mut binary,
-        (section import (vec
-            (import "env" "print" (function 0x0))
-            (import "env" "memory" (memory 1))))
-    );
-
-    // (section function (vec 1))
-    write_section(&mut binary, wasm!(section_type function), |mut w| {
-        // number of functions
-        leb128::write::unsigned(&mut w, functions.len() as u64).unwrap();
-        // print function type
-        for f in &functions {
-            leb128::write::unsigned(&mut w, f.idx as u64).unwrap();
+        if self.imports_printb {
+            imports.push(func("printb", &self.types[ty]));
         }
-    });
-
-    wasm!(&mut binary, (section export (vec (export "main" function 0x1))));
-
-    // section code
-    write_section(&mut binary, wasm!(section_type code), |mut w| {
-        // number of functions
-        leb128::write::unsigned(&mut w, functions.len() as u64).unwrap();
-        // print function type
-        for f in &functions {
-            leb128::write::unsigned(&mut w, f.code.len() as u64).unwrap();
-            w.write_all(&f.code).unwrap();
+        let signature = match self.memory_max_pages {
+            Some(max) => format!("(memory {} {})", self.memory_min_pages, max),
+            None => format!("(memory {})", self.memory_min_pages),
+        };
+        imports.push(ImportReq {
+            module: "env",
+            name: "memory",
+            signature,
+        });
+        imports
+    }
+
+    /// Write the module in the WebAssembly binary format.
+    pub fn serialize(&self) -> Vec<u8> {
+        let functions = &self.procedures;
+
+        let mut binary = wasm!( new
+            (magic version)
+        );
+
+        // section type
+        write_section(&mut binary, wasm!(section_type type), |mut w| {
+            // number of types
+            leb128::write_unsigned(&mut w, self.types.len() as u64);
+            for ty in &self.types {
+                wasm!(&mut w, functype);
+                write_valtypes(w, &ty.params);
+                write_valtypes(w, &ty.results);
+            }
+        });
+
+        write_section(&mut binary, wasm!(section_type import), |mut w| {
+            let min = self.memory_min_pages;
+            // "print", the memory, and the optional functions
+            let count = 2 + self.imports_prints as u64 + self.imports_printb as u64;
+            leb128::write_unsigned(&mut w, count);
+            wasm!(&mut w, import "env" "print" (function 0x0));
+            let mut ty = 1;
+            if self.imports_prints {
+                wasm!(&mut w, import "env" "prints" (function ty));
+                ty += 1;
+            }
+            if self.imports_printb {
+                wasm!(&mut w, import "env" "printb" (function ty));
+            }
+            match self.memory_max_pages {
+                Some(max) => {
+                    wasm!(&mut w, import "env" "memory" (memory min max));
+                }
+                None => {
+                    wasm!(&mut w, import "env" "memory" (memory min));
+                }
+            }
+        });
+
+        // (section function (vec 1))
+        write_section(&mut binary, wasm!(section_type function), |mut w| {
+            // number of functions
+            leb128::write_unsigned(&mut w, functions.len() as u64);
+            // print function type
+            for f in functions {
+                leb128::write_unsigned(&mut w, f.ty as u64);
+            }
+        });
+
+        if self.uses_table {
+            // a table with all procedures, so they can be called by reference
+            write_section(&mut binary, wasm!(section_type table), |mut w| {
+                // one table of funcref, with limits with only a minimum
+                wasm!(&mut w, (1)(funcref)(0x00));
+                leb128::write_unsigned(&mut w, functions.len() as u64);
+            });
+        }
+
+        if self.globals > 0 {
+            // the global variables, all mutable f32 initialized to 0
+            write_section(&mut binary, wasm!(section_type global), |mut w| {
+                leb128::write_unsigned(&mut w, self.globals as u64);
+                for _ in 0..self.globals {
+                    wasm!(&mut w, (f32) (0x01) (f32.const 0) (end));
+                }
+            });
+        }
+
+        write_section(&mut binary, wasm!(section_type export), |mut w| {
+            leb128::write_unsigned(&mut w, self.exports.len() as u64);
+            for (name, idx) in &self.exports {
+                wasm!(&mut w, (data name.as_bytes()) (exporttypefunc));
+                leb128::write_unsigned(&mut w, *idx as u64);
+            }
+        });
+
+        if self.uses_table {
+            // initialize the table, starting at offset 0
+            write_section(&mut binary, wasm!(section_type element), |mut w| {
+                wasm!(&mut w, (1) (0x00) (i32.const 0) (end));
+                leb128::write_unsigned(&mut w, functions.len() as u64);
+                for f in functions {
+                    leb128::write_unsigned(&mut w, f.idx as u64);
+                }
+            });
+        }
+
+        // section code
+        write_section(&mut binary, wasm!(section_type code), |mut w| {
+            // number of functions
+            leb128::write_unsigned(&mut w, functions.len() as u64);
+            // print function type
+            for f in functions {
+                leb128::write_unsigned(&mut w, f.size());
+                w.write_all(&f.locals);
+                w.write_all(&f.code);
+            }
+        });
+
+        if !self.data.is_empty() {
+            // the string literals, placed right after the canvas
+            write_section(&mut binary, wasm!(section_type data), |mut w| {
+                let offset = compiler::STRING_DATA_OFFSET;
+                wasm!(&mut w, (1) (0x00) (i32.const offset) (end) (data &self.data));
+            });
+        }
+
+        if self.producers_section {
+            // https://github.com/WebAssembly/tool-conventions/blob/main/ProducersSection.md
+            write_section(&mut binary, wasm!(section_type custom), |mut w| {
+                wasm!(&mut w,
+                    (str "producers")
+                    // one field, with one value
+                    (1)
+                    (str "processed-by")
+                    (1)
+                    (str "chasm-rs")
+                    (data env!("CARGO_PKG_VERSION").as_bytes())
+                );
+            });
         }
-    });
 
-    Ok(binary)
+        binary
+    }
 }
@@ -0,0 +1,708 @@
+//! Optimization passes on the syntax tree, run before compiling when
+//! [`CompileOptions::optimize`](crate::CompileOptions::optimize) is enabled.
+
+use crate::ast::{BinaryOp, Expression, ExpressionKind, Ident, Program, Statement, StatementKind};
+use crate::prelude::*;
+
+/// Run all optimization passes on the program.
+pub fn optimize(program: &mut Program) {
+    propagate_constants(&mut program.statements, &[]);
+    hoist_invariants(&mut program.statements, &mut 0);
+}
+
+/// Replace each use of a variable that is declared with a constant, and never reassigned, by the
+/// constant, folding the expressions that become constant.
+///
+/// `body` is the body of a function with the parameters `params`. Nested procedures are optimized
+/// as functions of their own.
+fn propagate_constants(body: &mut [Statement], params: &[&str]) {
+    let mut declarations = HashMap::new();
+    let mut assigned = HashSet::new();
+    count_writes(body, &mut declarations, &mut assigned);
+
+    // a variable declared more than once may be shadowed, so it is left alone
+    let candidates = declarations
+        .into_iter()
+        .filter(|&(name, n)| n == 1 && !assigned.contains(name) && !params.contains(&name))
+        .map(|(name, _)| name)
+        .collect();
+
+    propagate_block(body, &candidates, &mut HashMap::new());
+}
+
+/// Count the declarations of each variable, and collect the assigned ones, including the ones
+/// written by the legacy `setpixel`, not looking inside nested procedures.
+fn count_writes<'s>(
+    statements: &[Statement<'s>],
+    declarations: &mut HashMap<&'s str, u32>,
+    assigned: &mut HashSet<&'s str>,
+) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Var { ident, .. } => *declarations.entry(ident.name).or_default() += 1,
+            StatementKind::Destructure { targets, .. } => {
+                for target in targets {
+                    *declarations.entry(target.name).or_default() += 1;
+                }
+            }
+            StatementKind::Assignment { ident, .. } => {
+                assigned.insert(ident.name);
+            }
+            StatementKind::Call { ident, .. } if ident.name == "setpixel" => {
+                assigned.extend(["x", "y", "color"]);
+            }
+            StatementKind::While { body, .. }
+            | StatementKind::Repeat { body, .. }
+            | StatementKind::Loop { body } => count_writes(body, declarations, assigned),
+            StatementKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                count_writes(then_body, declarations, assigned);
+                count_writes(
+                    else_body.as_deref().unwrap_or_default(),
+                    declarations,
+                    assigned,
+                );
+            }
+            StatementKind::Switch { cases, default, .. } => {
+                for case in cases {
+                    count_writes(&case.body, declarations, assigned);
+                }
+                count_writes(
+                    default.as_deref().unwrap_or_default(),
+                    declarations,
+                    assigned,
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Propagate the constants through a block. A constant is only known after its declaration, and
+/// until the end of the block that declares it.
+fn propagate_block<'s>(
+    statements: &mut [Statement<'s>],
+    candidates: &HashSet<&'s str>,
+    constants: &mut HashMap<&'s str, ExpressionKind<'s>>,
+) {
+    let outer = constants.clone();
+    for statement in statements {
+        match &mut statement.kind {
+            StatementKind::Print(value) => fold(value, constants),
+            StatementKind::PrintString(_)
+            | StatementKind::Pass
+            | StatementKind::Break
+            | StatementKind::Data { .. } => {}
+            StatementKind::Var { ident, value } => {
+                fold(value, constants);
+                if candidates.contains(ident.name) && is_literal(value) {
+                    constants.insert(ident.name, value.kind.clone());
+                }
+            }
+            StatementKind::Global { value, .. } | StatementKind::Assignment { value, .. } => {
+                fold(value, constants)
+            }
+            StatementKind::Call { args, .. }
+            | StatementKind::Destructure { args, .. }
+            | StatementKind::Return(args) => {
+                for arg in args {
+                    fold(arg, constants);
+                }
+            }
+            StatementKind::While { condition, body } => {
+                fold(condition, constants);
+                propagate_block(body, candidates, constants);
+            }
+            StatementKind::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                fold(condition, constants);
+                propagate_block(then_body, candidates, constants);
+                if let Some(else_body) = else_body {
+                    propagate_block(else_body, candidates, constants);
+                }
+            }
+            StatementKind::Repeat { count, body } => {
+                fold(count, constants);
+                propagate_block(body, candidates, constants);
+            }
+            StatementKind::Loop { body } => propagate_block(body, candidates, constants),
+            StatementKind::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                fold(value, constants);
+                for case in cases {
+                    propagate_block(&mut case.body, candidates, constants);
+                }
+                if let Some(default) = default {
+                    propagate_block(default, candidates, constants);
+                }
+            }
+            StatementKind::Proc { params, body, .. } => {
+                // the default values are compiled at the call site, so they are left alone
+                let params: Vec<_> = params.iter().map(|x| x.ident.name).collect();
+                propagate_constants(body, &params);
+            }
+        }
+    }
+    *constants = outer;
+}
+
+/// Fold the expression, returning its value if it is a constant, that has no variables or calls.
+pub fn fold_constant(expression: &mut Expression) -> Option<f32> {
+    fold(expression, &HashMap::new());
+    literal_value(expression)
+}
+
+/// The value of a condition that is the same each time it is evaluated, because after folding it
+/// only compares constants, like `(1 == 2)`, or is a `&&` with a condition that is always false.
+pub fn constant_condition(condition: &Expression) -> Option<bool> {
+    let mut condition = condition.clone();
+    fold(&mut condition, &HashMap::new());
+    condition_value(&condition)
+}
+
+fn condition_value(condition: &Expression) -> Option<bool> {
+    let (op, left, right) = match &condition.kind {
+        ExpressionKind::Binary {
+            op, left, right, ..
+        } => (op, left, right),
+        _ => return None,
+    };
+    if let BinaryOp::And = op {
+        return match (condition_value(left), condition_value(right)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        };
+    }
+    let (a, b) = (literal_value(left)?, literal_value(right)?);
+    match op {
+        BinaryOp::Eq => Some(a == b),
+        BinaryOp::Lt => Some(a < b),
+        BinaryOp::Gt => Some(a > b),
+        _ => None,
+    }
+}
+
+/// The values printed by a program that only declares and assigns variables and prints numbers, in
+/// order, folding each expression with the current values of the variables. Return `None` if the
+/// program has any other statement, or if a value is not a constant.
+pub fn eval_prints(program: &Program) -> Option<Vec<f32>> {
+    let mut values = HashMap::new();
+    let mut printed = Vec::new();
+    for statement in &program.statements {
+        match &statement.kind {
+            StatementKind::Print(value) => printed.push(eval(value, &values)?),
+            StatementKind::Var { ident, value }
+            | StatementKind::Global { ident, value }
+            | StatementKind::Assignment { ident, value } => {
+                let x = eval(value, &values)?;
+                values.insert(ident.name, literal(x));
+            }
+            StatementKind::Pass => {}
+            _ => return None,
+        }
+    }
+    Some(printed)
+}
+
+fn eval<'s>(value: &Expression<'s>, values: &HashMap<&'s str, ExpressionKind<'s>>) -> Option<f32> {
+    let mut value = value.clone();
+    fold(&mut value, values);
+    literal_value(&value)
+}
+
+/// The names of the variables that hold the hoisted loop invariants, in the order they are used in
+/// a function. They are not valid identifiers, so they never clash with a variable of the program,
+/// and each one is declared only once per function, so it never shadows another. When they run
+/// out, the remaining invariants of the function are left in place.
+const TEMPS: [&str; 16] = [
+    "$0", "$1", "$2", "$3", "$4", "$5", "$6", "$7", "$8", "$9", "$10", "$11", "$12", "$13", "$14",
+    "$15",
+];
+
+/// Move the subexpressions of each `while` condition that don't change during the loop to
+/// variables declared right before it, so they are computed only once, and share the ones that the
+/// start of the body computes again, like `(i * i)`. `temps` is the number of [`TEMPS`] already
+/// used in the current function.
+fn hoist_invariants<'s>(statements: &mut Vec<Statement<'s>>, temps: &mut usize) {
+    for mut statement in core::mem::take(statements) {
+        match &mut statement.kind {
+            StatementKind::While { condition, body } => {
+                hoist_invariants(body, temps);
+                // a procedure called in a expression may write to any global, at any point
+                if calls_procedure(condition) || any_calls_procedure(body) {
+                    statements.push(statement);
+                    continue;
+                }
+                let mut assigned = HashSet::new();
+                if loop_writes(body, &mut assigned) {
+                    hoist(condition, &assigned, temps, statements);
+                }
+                share_subexpressions(condition, body, temps, statements);
+            }
+            StatementKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                hoist_invariants(then_body, temps);
+                if let Some(else_body) = else_body {
+                    hoist_invariants(else_body, temps);
+                }
+            }
+            StatementKind::Repeat { body, .. } | StatementKind::Loop { body } => {
+                hoist_invariants(body, temps)
+            }
+            StatementKind::Switch { cases, default, .. } => {
+                for case in cases {
+                    hoist_invariants(&mut case.body, temps);
+                }
+                if let Some(default) = default {
+                    hoist_invariants(default, temps);
+                }
+            }
+            StatementKind::Proc { body, .. } => hoist_invariants(body, &mut 0),
+            _ => {}
+        }
+        statements.push(statement);
+    }
+}
+
+/// If any expression of the statements, including the nested ones, calls a procedure.
+fn any_calls_procedure(statements: &[Statement]) -> bool {
+    crate::compiler::any_statement(statements, &|kind| match kind {
+        StatementKind::Print(x)
+        | StatementKind::Var { value: x, .. }
+        | StatementKind::Global { value: x, .. }
+        | StatementKind::Assignment { value: x, .. }
+        | StatementKind::While { condition: x, .. }
+        | StatementKind::If { condition: x, .. }
+        | StatementKind::Repeat { count: x, .. }
+        | StatementKind::Switch { value: x, .. } => calls_procedure(x),
+        StatementKind::Call { args, .. }
+        | StatementKind::Destructure { args, .. }
+        | StatementKind::Return(args) => args.iter().any(calls_procedure),
+        _ => false,
+    })
+}
+
+/// If the expression calls a procedure, instead of only builtins.
+fn calls_procedure(expression: &Expression) -> bool {
+    match &expression.kind {
+        ExpressionKind::Call { ident, args } => {
+            crate::compiler::builtin_num_param(ident.name).is_none()
+                || args.iter().any(calls_procedure)
+        }
+        ExpressionKind::Negate(value) | ExpressionKind::Index { index: value, .. } => {
+            calls_procedure(value)
+        }
+        ExpressionKind::Binary { left, right, .. } => {
+            calls_procedure(left) || calls_procedure(right)
+        }
+        _ => false,
+    }
+}
+
+/// Collect the variables that may be written by the body of a loop, including the ones written by
+/// `setpixel`. Return false if the body calls a procedure, that may write to any global, so nothing
+/// can be hoisted out of the loop.
+fn loop_writes<'s>(statements: &[Statement<'s>], assigned: &mut HashSet<&'s str>) -> bool {
+    statements.iter().all(|statement| match &statement.kind {
+        StatementKind::Var { ident, .. } | StatementKind::Assignment { ident, .. } => {
+            assigned.insert(ident.name);
+            true
+        }
+        StatementKind::Call { ident, .. } => match ident.name {
+            "setpixel" => {
+                assigned.extend(["x", "y", "color"]);
+                true
+            }
+            "blendpixel" | "setrgb" => true,
+            _ => false,
+        },
+        StatementKind::Destructure { .. } => false,
+        StatementKind::While { body, .. }
+        | StatementKind::Repeat { body, .. }
+        | StatementKind::Loop { body } => loop_writes(body, assigned),
+        StatementKind::If {
+            then_body,
+            else_body,
+            ..
+        } => {
+            loop_writes(then_body, assigned)
+                && loop_writes(else_body.as_deref().unwrap_or_default(), assigned)
+        }
+        StatementKind::Switch { cases, default, .. } => {
+            cases.iter().all(|case| loop_writes(&case.body, assigned))
+                && loop_writes(default.as_deref().unwrap_or_default(), assigned)
+        }
+        _ => true,
+    })
+}
+
+/// Replace each arithmetic operation in the expression that is invariant, by a variable declared in
+/// a statement pushed to `statements`. The right side of a `&&` is only evaluated when the left
+/// one is true, so it is left in place.
+fn hoist<'s>(
+    expression: &mut Expression<'s>,
+    assigned: &HashSet<&'s str>,
+    temps: &mut usize,
+    statements: &mut Vec<Statement<'s>>,
+) {
+    if *temps == TEMPS.len() {
+        return;
+    }
+    if matches!(expression.kind, ExpressionKind::Binary { .. })
+        && is_invariant(expression, assigned)
+    {
+        let name = TEMPS[*temps];
+        *temps += 1;
+        let span = expression.span.clone();
+        let variable = Expression {
+            kind: ExpressionKind::Variable(name),
+            span: span.clone(),
+        };
+        let value = core::mem::replace(expression, variable);
+        statements.push(Statement {
+            kind: StatementKind::Var {
+                ident: Ident {
+                    name,
+                    span: span.clone(),
+                },
+                value,
+            },
+            span,
+            chained: false,
+        });
+        return;
+    }
+    match &mut expression.kind {
+        ExpressionKind::Negate(value) | ExpressionKind::Index { index: value, .. } => {
+            hoist(value, assigned, temps, statements)
+        }
+        ExpressionKind::Binary {
+            op, left, right, ..
+        } => {
+            hoist(left, assigned, temps, statements);
+            if *op != BinaryOp::And {
+                hoist(right, assigned, temps, statements);
+            }
+        }
+        ExpressionKind::Call { args, .. } => {
+            for arg in args {
+                hoist(arg, assigned, temps, statements);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Store each arithmetic operation of a `while` condition that the body computes again, before
+/// assigning any of its variables, in a variable declared in a statement pushed to `statements`.
+/// The body reads the variable instead, and recomputes it at its end, for the next check of the
+/// condition. So a condition like `(((i * i) + (j * j)) < 4)` followed by `var n = ((i * i) - (j *
+/// j))` computes each square once per iteration.
+fn share_subexpressions<'s>(
+    condition: &mut Expression<'s>,
+    body: &mut Vec<Statement<'s>>,
+    temps: &mut usize,
+    statements: &mut Vec<Statement<'s>>,
+) {
+    let mut shared = Vec::new();
+    shareable(condition, body, &mut shared);
+    for value in shared {
+        if *temps == TEMPS.len() {
+            return;
+        }
+        let name = TEMPS[*temps];
+        *temps += 1;
+        substitute(condition, &value, name);
+        let mut variables = HashSet::new();
+        collect_variables(&value, &mut variables);
+        for statement in body.iter_mut() {
+            match &mut statement.kind {
+                StatementKind::Print(x) => substitute(x, &value, name),
+                StatementKind::Var { ident, value: x }
+                | StatementKind::Assignment { ident, value: x } => {
+                    substitute(x, &value, name);
+                    if variables.contains(ident.name) {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let span = value.span.clone();
+        let ident = Ident {
+            name,
+            span: span.clone(),
+        };
+        body.push(Statement {
+            kind: StatementKind::Assignment {
+                ident: ident.clone(),
+                value: value.clone(),
+            },
+            span: span.clone(),
+            chained: false,
+        });
+        statements.push(Statement {
+            kind: StatementKind::Var { ident, value },
+            span,
+            chained: false,
+        });
+    }
+}
+
+/// Collect the largest arithmetic operations of the condition that are also computed by the start
+/// of the body. They must be pure, so computing them once more at the end of the body changes
+/// nothing, and their variables must not be declared again in the body, which would shadow them.
+fn shareable<'s>(
+    condition: &Expression<'s>,
+    body: &[Statement<'s>],
+    shared: &mut Vec<Expression<'s>>,
+) {
+    let is_arithmetic = matches!(
+        &condition.kind,
+        ExpressionKind::Binary { op, .. } if !op.is_comparison() && *op != BinaryOp::And
+    );
+    if is_arithmetic && is_pure(condition) && !shared.iter().any(|x| same_value(x, condition)) {
+        let mut variables = HashSet::new();
+        collect_variables(condition, &mut variables);
+        let shadowed = body.iter().any(|statement| match &statement.kind {
+            StatementKind::Var { ident, .. } => variables.contains(ident.name),
+            StatementKind::Destructure { targets, .. } => {
+                targets.iter().any(|x| variables.contains(x.name))
+            }
+            _ => false,
+        });
+        if !shadowed && computed_in_prefix(body, condition, &variables) {
+            shared.push(condition.clone());
+            return;
+        }
+    }
+    match &condition.kind {
+        ExpressionKind::Negate(value) => shareable(value, body, shared),
+        ExpressionKind::Binary { left, right, .. } => {
+            shareable(left, body, shared);
+            shareable(right, body, shared);
+        }
+        _ => {}
+    }
+}
+
+/// If the statements at the start of the body that are only prints, declarations and assignments
+/// compute `value`, before assigning any of its `variables`.
+fn computed_in_prefix(body: &[Statement], value: &Expression, variables: &HashSet<&str>) -> bool {
+    for statement in body {
+        match &statement.kind {
+            StatementKind::Print(x) if contains(x, value) => return true,
+            StatementKind::Print(_) => {}
+            StatementKind::Var { ident, value: x }
+            | StatementKind::Assignment { ident, value: x } => {
+                if contains(x, value) {
+                    return true;
+                }
+                if variables.contains(ident.name) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// If the expression is only arithmetic on literals and variables. Calls may read the canvas, and
+/// indexing a table may trap, so they are never moved.
+fn is_pure(expression: &Expression) -> bool {
+    match &expression.kind {
+        ExpressionKind::Number(_) | ExpressionKind::Integer(_) | ExpressionKind::Variable(_) => {
+            true
+        }
+        ExpressionKind::Negate(value) => is_pure(value),
+        ExpressionKind::Binary {
+            op, left, right, ..
+        } => !op.is_comparison() && *op != BinaryOp::And && is_pure(left) && is_pure(right),
+        _ => false,
+    }
+}
+
+fn collect_variables<'s>(expression: &Expression<'s>, variables: &mut HashSet<&'s str>) {
+    match &expression.kind {
+        ExpressionKind::Variable(name) => {
+            variables.insert(name);
+        }
+        ExpressionKind::Negate(value) => collect_variables(value, variables),
+        ExpressionKind::Binary { left, right, .. } => {
+            collect_variables(left, variables);
+            collect_variables(right, variables);
+        }
+        _ => {}
+    }
+}
+
+/// If the two pure expressions compute the same value, ignoring their spans.
+fn same_value(a: &Expression, b: &Expression) -> bool {
+    match (&a.kind, &b.kind) {
+        (ExpressionKind::Number(x), ExpressionKind::Number(y)) => x.to_bits() == y.to_bits(),
+        (ExpressionKind::Integer(x), ExpressionKind::Integer(y)) => x == y,
+        (ExpressionKind::Variable(x), ExpressionKind::Variable(y)) => x == y,
+        (ExpressionKind::Negate(x), ExpressionKind::Negate(y)) => same_value(x, y),
+        (
+            ExpressionKind::Binary {
+                op: op_a,
+                left: left_a,
+                right: right_a,
+                ..
+            },
+            ExpressionKind::Binary {
+                op: op_b,
+                left: left_b,
+                right: right_b,
+                ..
+            },
+        ) => op_a == op_b && same_value(left_a, left_b) && same_value(right_a, right_b),
+        _ => false,
+    }
+}
+
+/// If `value` is a subexpression of the expression.
+fn contains(expression: &Expression, value: &Expression) -> bool {
+    if same_value(expression, value) {
+        return true;
+    }
+    match &expression.kind {
+        ExpressionKind::Negate(x) | ExpressionKind::Index { index: x, .. } => contains(x, value),
+        ExpressionKind::Binary { left, right, .. } => {
+            contains(left, value) || contains(right, value)
+        }
+        ExpressionKind::Call { args, .. } => args.iter().any(|x| contains(x, value)),
+        _ => false,
+    }
+}
+
+/// Replace each occurrence of `value` in the expression by the variable `name`.
+fn substitute<'s>(expression: &mut Expression<'s>, value: &Expression<'s>, name: &'s str) {
+    if same_value(expression, value) {
+        expression.kind = ExpressionKind::Variable(name);
+        return;
+    }
+    match &mut expression.kind {
+        ExpressionKind::Negate(x) | ExpressionKind::Index { index: x, .. } => {
+            substitute(x, value, name)
+        }
+        ExpressionKind::Binary { left, right, .. } => {
+            substitute(left, value, name);
+            substitute(right, value, name);
+        }
+        ExpressionKind::Call { args, .. } => {
+            for arg in args {
+                substitute(arg, value, name);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// If the expression is only arithmetic on literals and on variables that are not assigned. Calls
+/// are never invariant, because `maxpixel` reads the canvas, and neither are the indices of
+/// tables, that trap when out of bounds, and so must only be computed where the source does.
+fn is_invariant(expression: &Expression, assigned: &HashSet<&str>) -> bool {
+    match &expression.kind {
+        ExpressionKind::Number(_) | ExpressionKind::Integer(_) => true,
+        ExpressionKind::Variable(name) => !assigned.contains(name),
+        ExpressionKind::Negate(value) => is_invariant(value, assigned),
+        ExpressionKind::Binary {
+            op, left, right, ..
+        } => {
+            !op.is_comparison()
+                && *op != BinaryOp::And
+                && is_invariant(left, assigned)
+                && is_invariant(right, assigned)
+        }
+        ExpressionKind::Reference(_)
+        | ExpressionKind::Call { .. }
+        | ExpressionKind::Index { .. } => false,
+    }
+}
+
+fn is_literal(expression: &Expression) -> bool {
+    literal_value(expression).is_some()
+}
+
+fn literal_value(expression: &Expression) -> Option<f32> {
+    match expression.kind {
+        ExpressionKind::Number(x) => Some(x),
+        ExpressionKind::Integer(x) => Some(x as f32),
+        _ => None,
+    }
+}
+
+/// The literal of a folded value. It is a integer if the value is exactly a integer, so it can
+/// still be used where a integer is expected, like in the count of a `repeat`.
+fn literal(x: f32) -> ExpressionKind<'static> {
+    let is_integer = (-2147483648.0..2147483648.0).contains(&x)
+        && x as i32 as f32 == x
+        && !(x == 0.0 && x.is_sign_negative());
+    if is_integer {
+        ExpressionKind::Integer(x as i32)
+    } else {
+        ExpressionKind::Number(x)
+    }
+}
+
+/// Replace the known variables in the expression by their constants, and fold the arithmetic
+/// operations whose operands are all constants. The floats operations are the same as the ones of
+/// WebAssembly, so the result is the same as computing it at runtime.
+fn fold<'s>(expression: &mut Expression<'s>, constants: &HashMap<&'s str, ExpressionKind<'s>>) {
+    let folded = match &mut expression.kind {
+        ExpressionKind::Variable(name) => constants.get(name).cloned(),
+        ExpressionKind::Negate(value) => {
+            fold(value, constants);
+            literal_value(value).map(|x| literal(-x))
+        }
+        ExpressionKind::Binary {
+            op, left, right, ..
+        } => {
+            fold(left, constants);
+            fold(right, constants);
+            match (literal_value(left), literal_value(right)) {
+                (Some(a), Some(b)) => match op {
+                    BinaryOp::Add => Some(literal(a + b)),
+                    BinaryOp::Sub => Some(literal(a - b)),
+                    BinaryOp::Mul => Some(literal(a * b)),
+                    BinaryOp::Div => Some(literal(a / b)),
+                    // comparisons are integers, that have no literal
+                    BinaryOp::Eq | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::And => None,
+                },
+                _ => None,
+            }
+        }
+        ExpressionKind::Call { args, .. } => {
+            for arg in args {
+                fold(arg, constants);
+            }
+            None
+        }
+        ExpressionKind::Index { index, .. } => {
+            fold(index, constants);
+            None
+        }
+        ExpressionKind::Number(_) | ExpressionKind::Integer(_) | ExpressionKind::Reference(_) => {
+            None
+        }
+    };
+    if let Some(kind) = folded {
+        expression.kind = kind;
+    }
+}
@@ -0,0 +1,919 @@
+use core::num::ParseFloatError;
+
+use logos::{Logos, Span, SpannedIter};
+
+use crate::ast::{
+    BinaryOp, Case, Expression, ExpressionKind, Ident, Param, Program, Statement, StatementKind,
+};
+use crate::compiler::{Error, ErrorKind, Token};
+use crate::prelude::*;
+
+type Res<'s, T = ()> = Result<T, Error<'s>>;
+
+/// Parse the source code to a abstract syntax tree.
+pub struct Parser<'source> {
+    source: &'source str,
+    lexer: SpannedIter<'source, Token>,
+    last: (Token, Span),
+    current: (Token, Span),
+    next: (Token, Span),
+    /// The number of loops enclosing the current statement, in the current procedure.
+    loops: u32,
+    /// A error for each unrecognized character, that the parser skips.
+    character_errors: Vec<Error<'source>>,
+    /// The value of each number token lexed and not parsed yet, by the start of its span. Each
+    /// number is parsed once, when it is lexed.
+    numbers: HashMap<usize, Result<f32, ParseFloatError>>,
+}
+impl<'s> Parser<'s> {
+    fn new(source: &'s str) -> Self {
+        let lexer = Token::lexer(source).spanned();
+        let mut parser = Self {
+            source,
+            last: (Token::Error, 0..0),
+            current: (Token::Error, 0..0),
+            next: (Token::Error, 0..0),
+            lexer,
+            loops: 0,
+            character_errors: Vec::new(),
+            numbers: HashMap::new(),
+        };
+        parser.eat_token();
+        parser.eat_token();
+        parser
+    }
+
+    pub fn parse(source: &'s str) -> Result<Program<'s>, Error<'s>> {
+        let (program, character_errors) = Self::parse_recovering(source);
+        first_error(program, character_errors)
+    }
+
+    /// Parse the source code like [`Parser::parse`], but skipping the unrecognized characters,
+    /// so the rest of the program is still parsed. Return the program, or the first syntax error,
+    /// and a [`ErrorKind::UnexpectedCharacter`] error for each skipped character.
+    pub fn parse_recovering(source: &'s str) -> (Result<Program<'s>, Error<'s>>, Vec<Error<'s>>) {
+        let mut parser = Self::new(source);
+        let program = parser.program();
+        (program, parser.character_errors)
+    }
+
+    fn program(&mut self) -> Res<'s, Program<'s>> {
+        let mut statements = Vec::new();
+        while self.current.0 != Token::Eof {
+            self.top_level_statement(&mut statements)?;
+        }
+        self.match_token(Token::Eof)?;
+
+        Ok(Program { statements })
+    }
+
+    /// Parse a source code that is a single expression, without any statement.
+    pub fn parse_expression(source: &'s str) -> Result<Expression<'s>, Error<'s>> {
+        let mut parser = Self::new(source);
+        let expression = parser.expression().and_then(|expression| {
+            parser.match_token(Token::Eof)?;
+            Ok(expression)
+        });
+        first_error(expression, parser.character_errors)
+    }
+
+    fn eat_token(&mut self) {
+        self.last = self.current.clone();
+        self.current = self.next.clone();
+        self.next = loop {
+            match self.lexer.next() {
+                Some((Token::Error, span)) => self.character_errors.push(Error {
+                    source: self.source,
+                    span,
+                    kind: ErrorKind::UnexpectedCharacter,
+                }),
+                Some((Token::Number, span)) => {
+                    let number = self.source[span.clone()].parse::<f32>();
+                    self.numbers.insert(span.start, number);
+                    break (Token::Number, span);
+                }
+                Some(token) => break token,
+                None => {
+                    let end = self.source.len();
+                    break (Token::Eof, end..end);
+                }
+            }
+        };
+    }
+
+    fn match_token(&mut self, token: Token) -> Res<'s> {
+        if self.current.0 != token {
+            Err(Error {
+                source: self.source,
+                span: self.current.1.clone(),
+                kind: ErrorKind::UnexpectedToken {
+                    expected: core::slice::from_ref(token.to_static()),
+                    received: self.current.clone().0,
+                },
+            })
+        } else {
+            self.eat_token();
+            Ok(())
+        }
+    }
+
+    /// The span from `start` to the end of the last matched token.
+    fn span_from(&self, start: usize) -> Span {
+        start..self.last.1.end
+    }
+
+    /// Parse "<ident>"
+    fn ident(&mut self) -> Res<'s, Ident<'s>> {
+        let span = self.current.1.clone();
+        self.match_token(Token::Identifier)?;
+        Ok(Ident {
+            name: &self.source[span.clone()],
+            span,
+        })
+    }
+
+    /// Parse "<statement>", or "global <ident> = <expression>" or
+    /// "data <ident> = [ <number>,* ]", which are only allowed at the top level, pushing it to
+    /// `statements`.
+    fn top_level_statement(&mut self, statements: &mut Vec<Statement<'s>>) -> Res<'s> {
+        if self.current.0 == Token::Data {
+            return self.data_statement(statements);
+        }
+        if self.current.0 != Token::Global {
+            return self.statement(statements);
+        }
+        let start = self.current.1.start;
+        self.match_token(Token::Global)?;
+        let ident = self.ident()?;
+        self.match_token(Token::Assignment)?;
+        let value = self.expression()?;
+        statements.push(Statement {
+            kind: StatementKind::Global { ident, value },
+            span: self.span_from(start),
+            chained: false,
+        });
+        Ok(())
+    }
+
+    /// Parse "data <ident> = [ <number>,* ]", pushing it to `statements`.
+    fn data_statement(&mut self, statements: &mut Vec<Statement<'s>>) -> Res<'s> {
+        let start = self.current.1.start;
+        self.match_token(Token::Data)?;
+        let ident = self.ident()?;
+        self.match_token(Token::Assignment)?;
+        self.match_token(Token::LeftBracket)?;
+        let mut values = Vec::new();
+        while self.current.0 != Token::RightBracket {
+            values.push(self.literal()?);
+            if self.current.0 != Token::RightBracket {
+                self.match_token(Token::Comma)?;
+            }
+        }
+        self.match_token(Token::RightBracket)?;
+        statements.push(Statement {
+            kind: StatementKind::Data { ident, values },
+            span: self.span_from(start),
+            chained: false,
+        });
+        Ok(())
+    }
+
+    /// Parse "<number>" or "- <number>", as a number literal expression.
+    fn literal(&mut self) -> Res<'s, Expression<'s>> {
+        let negative =
+            self.current.0 == Token::Operator && &self.source[self.current.1.clone()] == "-";
+        let (token, span) = if negative { &self.next } else { &self.current };
+        if !matches!(token, Token::Number | Token::InvalidNumber) {
+            return Err(Error {
+                source: self.source,
+                span: span.clone(),
+                kind: ErrorKind::UnexpectedToken {
+                    expected: &[Token::Number],
+                    received: *token,
+                },
+            });
+        }
+        self.expression()
+    }
+
+    /// Parse "<statement>", pushing it to `statements`. A chained assignment, like
+    /// "var a = b = <expression>", pushes a statement for each variable.
+    fn statement(&mut self, statements: &mut Vec<Statement<'s>>) -> Res<'s> {
+        let start = self.current.1.start;
+        let len = statements.len();
+        let kind = match self.current.0 {
+            Token::Print => self.print_statement()?,
+            Token::Prints => self.print_string_statement()?,
+            Token::Pass => {
+                self.match_token(Token::Pass)?;
+                StatementKind::Pass
+            }
+            Token::Var => self.variable_declaration(statements)?,
+            Token::Identifier => match self.next.0 {
+                Token::Assignment => self.variable_assignment(statements)?,
+                Token::LeftParen => self.proc_call()?,
+                _ => {
+                    return Err(Error {
+                        source: self.source,
+                        span: self.current.1.clone(),
+                        kind: ErrorKind::UnexpectedToken {
+                            expected: &[Token::Assignment, Token::LeftParen],
+                            received: self.next.clone().0,
+                        },
+                    })
+                }
+            },
+            Token::While => self.while_statement()?,
+            Token::If => self.if_statement()?,
+            Token::Switch => self.switch_statement()?,
+            Token::Repeat => self.repeat_statement()?,
+            Token::Loop => self.loop_statement()?,
+            Token::Break => self.break_statement()?,
+            Token::Return => self.return_statement()?,
+            Token::Proc => self.proc_statement()?,
+            _ => {
+                return Err(Error {
+                    source: self.source,
+                    span: self.current.1.clone(),
+                    kind: ErrorKind::UnexpectedToken {
+                        expected: &[Token::Print, Token::Var, Token::Identifier, Token::While],
+                        received: self.current.clone().0,
+                    },
+                })
+            }
+        };
+        statements.push(Statement {
+            kind,
+            span: self.span_from(start),
+            // the last statement of a chain, after the ones pushed while parsing it
+            chained: statements.len() > len,
+        });
+        Ok(())
+    }
+
+    /// Parse "<statement>*" until one of the `end` tokens, of the block opened by the keyword at
+    /// `opened_at`. The end of another kind of block, like a "endif" in a "while", is a
+    /// [`ErrorKind::MismatchedBlockEnd`].
+    fn block(&mut self, end: &[Token], opened_at: &Span) -> Res<'s, Vec<Statement<'s>>> {
+        let mut statements = Vec::new();
+        while !end.contains(&self.current.0) {
+            if is_block_end(self.current.0) {
+                let expected = end.iter().find(|&&x| is_block_end(x));
+                return Err(Error {
+                    source: self.source,
+                    span: self.current.1.clone(),
+                    kind: ErrorKind::MismatchedBlockEnd {
+                        expected: *expected.expect("every block has a end"),
+                        found: self.current.0,
+                        opened_at: opened_at.clone(),
+                    },
+                });
+            }
+            self.statement(&mut statements)?;
+        }
+        Ok(statements)
+    }
+
+    /// Parse "print <expression>"
+    fn print_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        self.match_token(Token::Print)?;
+        Ok(StatementKind::Print(self.expression()?))
+    }
+
+    /// Parse "prints <string>"
+    fn print_string_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        self.match_token(Token::Prints)?;
+        let span = self.current.1.clone();
+        self.match_token(Token::String)?;
+        // strip the quotes
+        let text = &self.source[span.start + 1..span.end - 1];
+        Ok(StatementKind::PrintString(text))
+    }
+
+    /// Parse "var <ident> = (<ident> =)* <expression>" or "var <ident> (, <ident>)+ = <ident> (
+    /// <args>,* )"
+    fn variable_declaration(
+        &mut self,
+        statements: &mut Vec<Statement<'s>>,
+    ) -> Res<'s, StatementKind<'s>> {
+        self.match_token(Token::Var)?;
+        if self.next.0 == Token::Comma {
+            return self.destructure();
+        }
+        self.assignment_chain(true, statements)
+    }
+
+    /// Parse "<ident> (, <ident>)+ = <ident> ( <args>,* )", after the "var"
+    fn destructure(&mut self) -> Res<'s, StatementKind<'s>> {
+        let mut targets = vec![self.ident()?];
+        while self.current.0 == Token::Comma {
+            self.match_token(Token::Comma)?;
+            targets.push(self.ident()?);
+        }
+        self.match_token(Token::Assignment)?;
+        let ident = self.ident()?;
+        self.match_token(Token::LeftParen)?;
+        let args = self.arguments()?;
+        self.match_token(Token::RightParen)?;
+        Ok(StatementKind::Destructure {
+            targets,
+            ident,
+            args,
+        })
+    }
+
+    /// Parse "<ident> = (<ident> =)* <expression>"
+    fn variable_assignment(
+        &mut self,
+        statements: &mut Vec<Statement<'s>>,
+    ) -> Res<'s, StatementKind<'s>> {
+        self.assignment_chain(false, statements)
+    }
+
+    /// Parse "<ident> = (<ident> =)* <expression>", declaring each variable if `declare` is set.
+    ///
+    /// A chain like "a = b = c = <expression>" is desugared to "c = <expression>", "b = c" and
+    /// "a = b", so the expression is evaluated once. All but the first one are pushed to
+    /// `statements`, and the first is returned. The statements after "c = <expression>" are
+    /// [`Statement::chained`], so the chain can be formatted back.
+    fn assignment_chain(
+        &mut self,
+        declare: bool,
+        statements: &mut Vec<Statement<'s>>,
+    ) -> Res<'s, StatementKind<'s>> {
+        let kind = |ident, value| {
+            if declare {
+                StatementKind::Var { ident, value }
+            } else {
+                StatementKind::Assignment { ident, value }
+            }
+        };
+
+        let mut targets = vec![self.ident()?];
+        self.match_token(Token::Assignment)?;
+        while self.current.0 == Token::Identifier && self.next.0 == Token::Assignment {
+            targets.push(self.ident()?);
+            self.match_token(Token::Assignment)?;
+        }
+        let mut value = self.expression()?;
+
+        let end = value.span.end;
+        let mut chained = false;
+        while targets.len() > 1 {
+            let ident = targets.pop().unwrap();
+            let next_value = Expression {
+                kind: ExpressionKind::Variable(ident.name),
+                span: ident.span.clone(),
+            };
+            statements.push(Statement {
+                span: ident.span.start..end,
+                kind: kind(ident, value),
+                chained,
+            });
+            chained = true;
+            value = next_value;
+        }
+        Ok(kind(targets.pop().unwrap(), value))
+    }
+
+    /// Parse "<ident> ( <args>,* )"
+    fn proc_call(&mut self) -> Res<'s, StatementKind<'s>> {
+        let ident = self.ident()?;
+        self.match_token(Token::LeftParen)?;
+
+        let mut args = Vec::new();
+        // setpixel calls are hardcoded to receive exactly 3 arguments
+        if ident.name == "setpixel" {
+            args.push(self.expression()?);
+            self.match_token(Token::Comma)?;
+            args.push(self.expression()?);
+            self.match_token(Token::Comma)?;
+            args.push(self.expression()?);
+        } else {
+            args = self.arguments()?;
+        }
+        self.match_token(Token::RightParen)?;
+
+        Ok(StatementKind::Call { ident, args })
+    }
+
+    /// Parse "<expression>,*", until a ")"
+    fn arguments(&mut self) -> Res<'s, Vec<Expression<'s>>> {
+        let mut args = Vec::new();
+        while self.current.0 != Token::RightParen {
+            args.push(self.expression()?);
+            if self.current.0 != Token::RightParen {
+                self.match_token(Token::Comma)?;
+            } else {
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    /// Parse "while <expression> <statements>* endwhile"
+    fn while_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        let opened_at = self.current.1.clone();
+        self.match_token(Token::While)?;
+        let condition = self.condition()?;
+        let body = self.loop_body(Token::EndWhile, &opened_at)?;
+        self.match_token(Token::EndWhile)?;
+        Ok(StatementKind::While { condition, body })
+    }
+
+    /// Parse "if <expresion> <expression>* endif" or "if <expression> <expression>* else
+    /// <expression>* endif"
+    fn if_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        let opened_at = self.current.1.clone();
+        self.match_token(Token::If)?;
+        let condition = self.condition()?;
+
+        let then_body = self.block(&[Token::EndIf, Token::Else], &opened_at)?;
+        let mut else_body = None;
+        if self.current.0 == Token::Else {
+            self.match_token(Token::Else)?;
+            else_body = Some(self.block(&[Token::EndIf], &opened_at)?);
+        }
+        self.match_token(Token::EndIf)?;
+
+        Ok(StatementKind::If {
+            condition,
+            then_body,
+            else_body,
+        })
+    }
+
+    /// Parse "repeat <expression> <statement>* endrepeat"
+    fn repeat_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        let opened_at = self.current.1.clone();
+        self.match_token(Token::Repeat)?;
+        let count = self.expression()?;
+        let body = self.loop_body(Token::EndRepeat, &opened_at)?;
+        self.match_token(Token::EndRepeat)?;
+        Ok(StatementKind::Repeat { count, body })
+    }
+
+    /// Parse "loop <statement>* endloop"
+    fn loop_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        let opened_at = self.current.1.clone();
+        self.match_token(Token::Loop)?;
+        let body = self.loop_body(Token::EndLoop, &opened_at)?;
+        self.match_token(Token::EndLoop)?;
+        Ok(StatementKind::Loop { body })
+    }
+
+    /// Parse the body of a loop, where a "break" is allowed, until `end`.
+    fn loop_body(&mut self, end: Token, opened_at: &Span) -> Res<'s, Vec<Statement<'s>>> {
+        self.loops += 1;
+        let body = self.block(&[end], opened_at);
+        self.loops -= 1;
+        body
+    }
+
+    /// Parse "break", that must be inside a loop.
+    fn break_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        if self.loops == 0 {
+            return Err(Error {
+                source: self.source,
+                span: self.current.1.clone(),
+                kind: ErrorKind::BreakOutsideLoop,
+            });
+        }
+        self.match_token(Token::Break)?;
+        Ok(StatementKind::Break)
+    }
+
+    /// Parse "return", "return <expression>" or "return ( <expression> (, <expression>)+ )". A
+    /// single value can also be in parentheses, like "return (x)".
+    fn return_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        self.match_token(Token::Return)?;
+        let has_value = match self.current.0 {
+            Token::Number | Token::InvalidNumber | Token::Ampersand => true,
+            // a assignment is the statement after a bare "return"
+            Token::Identifier => self.next.0 != Token::Assignment,
+            Token::Operator => &self.source[self.current.1.clone()] == "-",
+            Token::LeftParen => return self.return_values(),
+            _ => false,
+        };
+        if !has_value {
+            return Ok(StatementKind::Return(Vec::new()));
+        }
+        Ok(StatementKind::Return(vec![self.expression()?]))
+    }
+
+    /// Parse the values of "return ( <expression>,* )", or of "return ( <expression> <op>
+    /// <expression> )", that returns a single binary expression.
+    fn return_values(&mut self) -> Res<'s, StatementKind<'s>> {
+        let start = self.current.1.start;
+        self.match_token(Token::LeftParen)?;
+        if self.current.0 == Token::RightParen {
+            self.match_token(Token::RightParen)?;
+            return Ok(StatementKind::Return(Vec::new()));
+        }
+        let first = self.expression()?;
+        match self.current.0 {
+            Token::RightParen => {
+                self.match_token(Token::RightParen)?;
+                Ok(StatementKind::Return(vec![first]))
+            }
+            Token::Comma => {
+                self.match_token(Token::Comma)?;
+                let mut values = vec![first];
+                values.extend(self.arguments()?);
+                self.match_token(Token::RightParen)?;
+                Ok(StatementKind::Return(values))
+            }
+            _ => {
+                let kind = self.binary_rest(first)?;
+                Ok(StatementKind::Return(vec![Expression {
+                    kind,
+                    span: self.span_from(start),
+                }]))
+            }
+        }
+    }
+
+    /// Parse "switch <expression> (case <number> <statement>*)* [default <statement>*] endswitch"
+    fn switch_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        let opened_at = self.current.1.clone();
+        self.match_token(Token::Switch)?;
+        let value = self.expression()?;
+
+        let mut cases = Vec::new();
+        while self.current.0 == Token::Case {
+            let start = self.current.1.start;
+            self.match_token(Token::Case)?;
+            let number = self.number()?;
+            let span = self.span_from(start);
+            let body = self.block(&[Token::Case, Token::Default, Token::EndSwitch], &opened_at)?;
+            cases.push(Case {
+                value: number,
+                span,
+                body,
+            });
+        }
+
+        let mut default = None;
+        if self.current.0 == Token::Default {
+            self.match_token(Token::Default)?;
+            default = Some(self.block(&[Token::EndSwitch], &opened_at)?);
+        }
+
+        self.match_token(Token::EndSwitch)?;
+
+        Ok(StatementKind::Switch {
+            value,
+            cases,
+            default,
+        })
+    }
+
+    /// Parse "proc <ident> ( <param>,* ) <statement>* endproc", where "<param>" is "<ident>" or
+    /// "<ident> = <expression>"
+    fn proc_statement(&mut self) -> Res<'s, StatementKind<'s>> {
+        let opened_at = self.current.1.clone();
+        self.match_token(Token::Proc)?;
+        let ident = self.ident()?;
+
+        let mut params: Vec<Param> = Vec::new();
+        self.match_token(Token::LeftParen)?;
+        while self.current.0 != Token::RightParen {
+            let ident = self.ident()?;
+            // after a parameter with a default value, all parameters must have one
+            let default = if self.current.0 == Token::Assignment
+                || params.last().is_some_and(|x| x.default.is_some())
+            {
+                self.match_token(Token::Assignment)?;
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            params.push(Param { ident, default });
+            if self.current.0 != Token::RightParen {
+                self.match_token(Token::Comma)?;
+            } else {
+                break;
+            }
+        }
+        self.match_token(Token::RightParen)?;
+
+        // a procedure can't break out of the loops around its declaration
+        let loops = core::mem::replace(&mut self.loops, 0);
+        let body = self.block(&[Token::EndProc], &opened_at);
+        self.loops = loops;
+        let body = body?;
+        self.match_token(Token::EndProc)?;
+
+        Ok(StatementKind::Proc {
+            ident,
+            params,
+            body,
+        })
+    }
+
+    /// If the current token is the operator "-", match it and return true.
+    fn minus(&mut self) -> Res<'s, bool> {
+        let is_minus =
+            self.current.0 == Token::Operator && &self.source[self.current.1.clone()] == "-";
+        if is_minus {
+            self.match_token(Token::Operator)?;
+        }
+        Ok(is_minus)
+    }
+
+    /// Parse "<number>" or "- <number>"
+    fn number(&mut self) -> Res<'s, f32> {
+        if self.minus()? {
+            return Ok(-self.number()?);
+        }
+        if self.current.0 == Token::InvalidNumber {
+            return Err(Error {
+                source: self.source,
+                span: self.current.1.clone(),
+                kind: ErrorKind::InvalidNumber,
+            });
+        }
+        if self.current.0 != Token::Number {
+            // like the `x` of `case x`
+            return Err(Error {
+                source: self.source,
+                span: self.current.1.clone(),
+                kind: ErrorKind::UnexpectedToken {
+                    expected: &[Token::Number],
+                    received: self.current.0,
+                },
+            });
+        }
+        let number = self
+            .numbers
+            .remove(&self.current.1.start)
+            .expect("each number token is parsed when it is lexed");
+        let number = match number {
+            Ok(x) => x,
+            Err(err) => {
+                return Err(Error {
+                    source: self.source,
+                    span: self.current.1.clone(),
+                    kind: ErrorKind::ParseFloatError(err),
+                })
+            }
+        };
+        self.match_token(Token::Number)?;
+        Ok(number)
+    }
+
+    /// Parse the condition of a `if` or a `while`, that is a expression, or a chain of binary
+    /// operations without the outer parentheses, like "x > 0" or "(i * i) < n && i > 0".
+    fn condition(&mut self) -> Res<'s, Expression<'s>> {
+        let left = self.expression()?;
+        self.bare_binary(left, 0)
+    }
+
+    /// Parse the binary operations after `left` that have a precedence of at least `min`, like in
+    /// "<expression> (<op> <expression>)*". `*` and `/` bind tighter than `+` and `-`, that bind
+    /// tighter than the comparisons, and `&&` binds the loosest. Chained comparisons, like `a < b <
+    /// c`, are desugared like in parentheses.
+    fn bare_binary(&mut self, mut left: Expression<'s>, min: u8) -> Res<'s, Expression<'s>> {
+        // the right operand of the last comparison, if `left` is one
+        let mut middle: Option<Expression<'s>> = None;
+        loop {
+            let op_span = self.current.1.clone();
+            if self.current.0 == Token::Assignment {
+                return Err(Error {
+                    source: self.source,
+                    span: op_span,
+                    kind: ErrorKind::AssignmentInExpression,
+                });
+            }
+            if self.current.0 != Token::Operator {
+                return Ok(left);
+            }
+            let op = BinaryOp::from_source(&self.source[op_span.clone()])
+                .expect("I already check the token operator");
+            if precedence(op) < min {
+                return Ok(left);
+            }
+            self.match_token(Token::Operator)?;
+            let right = self.expression()?;
+            let right = self.bare_binary(right, precedence(op) + 1)?;
+
+            let span = left.span.start..right.span.end;
+            left = match middle.take().filter(|_| op.is_comparison()) {
+                Some(middle) => {
+                    let comparison = Expression {
+                        span: middle.span.start..right.span.end,
+                        kind: ExpressionKind::Binary {
+                            op,
+                            op_span: op_span.clone(),
+                            left: Box::new(middle),
+                            right: Box::new(right.clone()),
+                            chained: false,
+                        },
+                    };
+                    Expression {
+                        span,
+                        kind: ExpressionKind::Binary {
+                            op: BinaryOp::And,
+                            op_span,
+                            left: Box::new(left),
+                            right: Box::new(comparison),
+                            chained: true,
+                        },
+                    }
+                }
+                None => Expression {
+                    span,
+                    kind: ExpressionKind::Binary {
+                        op,
+                        op_span,
+                        left: Box::new(left),
+                        right: Box::new(right.clone()),
+                        chained: false,
+                    },
+                },
+            };
+            if op.is_comparison() {
+                middle = Some(right);
+            }
+        }
+    }
+
+    /// Parse the rest of "( <expression> <op> <expression> )", after the left operand.
+    fn binary_rest(&mut self, left: Expression<'s>) -> Res<'s, ExpressionKind<'s>> {
+        let op_span = self.current.1.clone();
+        // likely a comparison, like `(x = 1)`, mistyped
+        if self.current.0 == Token::Assignment {
+            return Err(Error {
+                source: self.source,
+                span: op_span,
+                kind: ErrorKind::AssignmentInExpression,
+            });
+        }
+        self.match_token(Token::Operator)?;
+        let op = BinaryOp::from_source(&self.source[op_span.clone()])
+            .expect("I already match the token operator");
+
+        let right = self.expression()?;
+
+        let chain_start = left.span.start;
+        let mut kind = ExpressionKind::Binary {
+            op,
+            op_span,
+            left: Box::new(left),
+            right: Box::new(right.clone()),
+            chained: false,
+        };
+
+        // a chained comparison, like `(a < b < c)`, is desugared to `((a < b) && (b < c))`. `b`
+        // may call a procedure, so the compiler evaluates it only once.
+        let mut middle = right;
+        while op.is_comparison() && self.current.0 == Token::Operator {
+            let next_span = self.current.1.clone();
+            let next_op = BinaryOp::from_source(&self.source[next_span.clone()])
+                .expect("I already check the token operator");
+            if !next_op.is_comparison() {
+                break;
+            }
+            self.match_token(Token::Operator)?;
+            let right = self.expression()?;
+            let chain = Expression {
+                span: chain_start..middle.span.end,
+                kind,
+            };
+            let comparison = Expression {
+                span: middle.span.start..right.span.end,
+                kind: ExpressionKind::Binary {
+                    op: next_op,
+                    op_span: next_span.clone(),
+                    left: Box::new(middle),
+                    right: Box::new(right.clone()),
+                    chained: false,
+                },
+            };
+            kind = ExpressionKind::Binary {
+                op: BinaryOp::And,
+                op_span: next_span,
+                left: Box::new(chain),
+                right: Box::new(comparison),
+                chained: true,
+            };
+            middle = right;
+        }
+
+        self.match_token(Token::RightParen)?;
+
+        Ok(kind)
+    }
+
+    /// Parse "<number>" or "<ident>" or "& <ident>" or "( <expression> <op> <expression> )" or
+    /// "- <expression>" or "<ident> ( <expression>,* )"
+    fn expression(&mut self) -> Res<'s, Expression<'s>> {
+        let start = self.current.1.start;
+        if self.minus()? {
+            let value = self.expression()?;
+            let kind = match value.kind {
+                ExpressionKind::Number(x) => ExpressionKind::Number(-x),
+                ExpressionKind::Integer(x) => ExpressionKind::Integer(-x),
+                _ => ExpressionKind::Negate(Box::new(value)),
+            };
+            return Ok(Expression {
+                kind,
+                span: self.span_from(start),
+            });
+        }
+        let kind = match self.current.0 {
+            Token::Number => {
+                let text = &self.source[self.current.1.clone()];
+                let integer = !text.contains(['.', 'e', 'E']);
+                match text.parse::<i32>() {
+                    Ok(x) if integer => {
+                        self.numbers.remove(&self.current.1.start);
+                        self.match_token(Token::Number)?;
+                        ExpressionKind::Integer(x)
+                    }
+                    _ => ExpressionKind::Number(self.number()?),
+                }
+            }
+            Token::InvalidNumber => ExpressionKind::Number(self.number()?),
+            Token::Identifier if self.next.0 == Token::LeftParen => {
+                let ident = self.ident()?;
+                self.match_token(Token::LeftParen)?;
+                let args = self.arguments()?;
+                self.match_token(Token::RightParen)?;
+                ExpressionKind::Call { ident, args }
+            }
+            Token::Identifier if self.next.0 == Token::LeftBracket => {
+                let ident = self.ident()?;
+                self.match_token(Token::LeftBracket)?;
+                let index = self.expression()?;
+                self.match_token(Token::RightBracket)?;
+                ExpressionKind::Index {
+                    ident,
+                    index: Box::new(index),
+                }
+            }
+            Token::Identifier => ExpressionKind::Variable(self.ident()?.name),
+            Token::Ampersand => {
+                self.match_token(Token::Ampersand)?;
+                ExpressionKind::Reference(self.ident()?.name)
+            }
+            Token::LeftParen => {
+                self.match_token(Token::LeftParen)?;
+                let left = self.expression()?;
+                self.binary_rest(left)?
+            }
+            _ => {
+                return Err(Error {
+                    source: self.source,
+                    span: self.current.1.clone(),
+                    kind: ErrorKind::UnexpectedToken {
+                        expected: &[Token::Number, Token::LeftParen],
+                        received: self.current.clone().0,
+                    },
+                })
+            }
+        };
+        Ok(Expression {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+}
+
+/// The precedence of a operator in a condition without parentheses. Operators with a higher
+/// precedence bind tighter.
+fn precedence(op: BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Mul | BinaryOp::Div => 3,
+        BinaryOp::Add | BinaryOp::Sub => 2,
+        BinaryOp::Eq | BinaryOp::Lt | BinaryOp::Gt => 1,
+        BinaryOp::And => 0,
+    }
+}
+
+/// If the token ends a block, like "endwhile". The "else", "case" and "default" also end the
+/// blocks before them, but they are not the end of the statement.
+fn is_block_end(token: Token) -> bool {
+    matches!(
+        token,
+        Token::EndWhile
+            | Token::EndIf
+            | Token::EndProc
+            | Token::EndSwitch
+            | Token::EndRepeat
+            | Token::EndLoop
+    )
+}
+
+/// The error that comes first in the source code, between the result of the parser and the errors
+/// of the unrecognized characters, that are in order.
+fn first_error<'s, T>(result: Res<'s, T>, character_errors: Vec<Error<'s>>) -> Res<'s, T> {
+    let character_error = character_errors.into_iter().next();
+    match (result, character_error) {
+        (result, None) => result,
+        (Err(err), Some(character_error)) if err.span.start < character_error.span.start => {
+            Err(err)
+        }
+        (_, Some(character_error)) => Err(character_error),
+    }
+}
@@ -0,0 +1,875 @@
+//! A interpreter for the modules created by the compiler.
+//!
+//! It only supports the subset of WebAssembly that the compiler emits, but it allows inspecting
+//! the execution in ways that a external engine doesn't, like tracing each executed instruction.
+//!
+//! # Example
+//! ```
+//! use chasm_rs::run::{run_binary, RunOptions};
+//!
+//! let binary = chasm_rs::compile("print (1 + 1) setpixel(0, 0, 255)").unwrap();
+//! let output = run_binary(&binary, &RunOptions::default()).unwrap();
+//!
+//! assert_eq!(output.printed, "2\n");
+//! assert_eq!(output.canvas[0], 255);
+//! ```
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::instr::{self, DecodeError, Instr};
+
+const PAGE_SIZE: usize = 64 * 1024;
+
+/// The default of [`RunOptions::max_call_depth`]. Each call is a recursion of the interpreter, so
+/// this is low enough to fit in the 2 MiB stack of a spawned thread, even in a debug build.
+pub const DEFAULT_MAX_CALL_DEPTH: u32 = 500;
+
+/// Options to change how a module is run.
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Record each executed instruction in [`Output::trace`]. Off by default, because it slows
+    /// down the execution, and the trace of a real program is huge.
+    pub trace: bool,
+    /// The number of decimal places of the numbers printed by `print`. If `None`, the default, a
+    /// number is printed with the shortest representation that identifies the `f32`.
+    pub print_precision: Option<usize>,
+    /// A flag that another thread can set to stop the execution, like when it takes too long. It
+    /// is checked before each instruction, and the execution stops with [`Output::interrupted`]
+    /// set, keeping the canvas and the text printed until then.
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// Count the calls to each function defined by the module in [`Output::calls`]. Off by
+    /// default.
+    pub profile: bool,
+    /// The maximum number of nested calls of functions defined by the module, including the call
+    /// to `"main"`. A call deeper than that stops the execution with
+    /// [`RunError::StackOverflow`], instead of overflowing the native stack of the interpreter.
+    /// If `None`, the default, [`DEFAULT_MAX_CALL_DEPTH`] is used.
+    pub max_call_depth: Option<u32>,
+    /// Return the whole linear memory in [`Output::memory`], so the bytes written out of the
+    /// canvas can be inspected. Off by default.
+    pub full_memory: bool,
+    /// Print the conditions printed by `print`, like `print (a < b)`, as `true` or `false`,
+    /// instead of `1` or `0`. Off by default.
+    pub bool_print: bool,
+    /// Return the RGB canvas written by `setrgb` in [`Output::canvas`], instead of the grayscale
+    /// one. Off by default.
+    pub rgb: bool,
+    /// The maximum number of instructions to execute. Executing more stops with
+    /// [`RunError::OutOfFuel`], so even a program that never ends can be run safely, like when
+    /// fuzzing. If `None`, the default, there is no limit.
+    pub fuel: Option<u64>,
+    /// The maximum number of calls to the imported print functions. One more stops with
+    /// [`RunError::OutputLimitExceeded`], so a program that prints in a endless loop doesn't
+    /// produce a endless output. If `None`, the default, there is no limit.
+    pub max_prints: Option<u64>,
+}
+
+/// A executed instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// The index of the function that executed the instruction.
+    pub function: u32,
+    /// The executed instruction.
+    pub instr: Instr,
+}
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "func {}: {}", self.function, self.instr)
+    }
+}
+
+/// The result of running a module.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Output {
+    /// The 100x100 canvas, the first 10000 bytes of the memory, at the end of the execution. If
+    /// [`RunOptions::rgb`] is enabled, it is the RGB canvas instead, the 30000 bytes at
+    /// [`RGB_CANVAS_OFFSET`](crate::RGB_CANVAS_OFFSET), with the red, green and blue of each pixel.
+    pub canvas: Vec<u8>,
+    /// The text printed by the program. Each printed number is followed by a line break, but
+    /// printed strings are not.
+    pub printed: String,
+    /// The executed instructions, if [`RunOptions::trace`] is enabled.
+    pub trace: Vec<TraceEntry>,
+    /// If the execution was stopped by [`RunOptions::interrupt`], before `"main"` returned.
+    pub interrupted: bool,
+    /// The number of times each function defined by the module was called, by function index,
+    /// including the call to `"main"`, if [`RunOptions::profile`] is enabled. The name of each
+    /// function can be found in [`crate::Module::procedures`].
+    pub calls: HashMap<u32, u64>,
+    /// The whole linear memory at the end of the execution, including the canvas, if
+    /// [`RunOptions::full_memory`] is enabled.
+    pub memory: Vec<u8>,
+}
+
+/// A error when running a module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunError {
+    /// The module is malformed, or uses a feature not supported by the interpreter.
+    InvalidModule(String),
+    /// The execution trapped.
+    Trap(Trap),
+    /// The calls were nested deeper than [`RunOptions::max_call_depth`], like in a unbounded
+    /// recursion.
+    StackOverflow,
+    /// More instructions were executed than the [`RunOptions::fuel`].
+    OutOfFuel,
+    /// The program printed more times than [`RunOptions::max_prints`].
+    OutputLimitExceeded {
+        /// The text printed before the execution stopped.
+        printed: String,
+    },
+}
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::InvalidModule(x) => write!(f, "invalid module: {}", x),
+            RunError::Trap(x) => write!(f, "trap: {}", x),
+            RunError::StackOverflow => write!(f, "call stack exhausted"),
+            RunError::OutOfFuel => write!(f, "all fuel consumed"),
+            RunError::OutputLimitExceeded { .. } => write!(f, "the output limit was exceeded"),
+        }
+    }
+}
+impl std::error::Error for RunError {}
+impl From<DecodeError> for RunError {
+    fn from(err: DecodeError) -> Self {
+        RunError::InvalidModule(err.to_string())
+    }
+}
+
+/// Why the execution stopped before returning.
+enum Stop {
+    Error(RunError),
+    /// The [`RunOptions::interrupt`] flag was set.
+    Interrupted,
+}
+impl From<RunError> for Stop {
+    fn from(err: RunError) -> Self {
+        Stop::Error(err)
+    }
+}
+
+/// The reason of a trap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// A memory access out of the bounds of the memory.
+    OutOfBoundsMemoryAccess,
+    /// A conversion of a NaN, or of a float out of the range of the integer type.
+    InvalidConversionToInteger,
+    /// A indirect call to a index out of the table.
+    UndefinedElement,
+    /// A indirect call to a function of a unexpected type.
+    IndirectCallTypeMismatch,
+    /// A `unreachable` instruction was executed, like by a failed bounds check.
+    Unreachable,
+}
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Trap::OutOfBoundsMemoryAccess => "out of bounds memory access",
+            Trap::InvalidConversionToInteger => "invalid conversion to integer",
+            Trap::UndefinedElement => "undefined element",
+            Trap::IndirectCallTypeMismatch => "indirect call type mismatch",
+            Trap::Unreachable => "unreachable",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Run the `"main"` function of a module created by the compiler, returning the canvas and the
+/// printed numbers.
+pub fn run_binary(binary: &[u8], options: &RunOptions) -> Result<Output, RunError> {
+    let module = Module::decode(binary)?;
+    let main = module
+        .main
+        .ok_or_else(|| RunError::InvalidModule("there is no \"main\" export".to_string()))?;
+
+    let mut memory = vec![0; module.memory_pages * PAGE_SIZE];
+    for (offset, bytes) in &module.data {
+        memory
+            .get_mut(*offset..*offset + bytes.len())
+            .ok_or(RunError::Trap(Trap::OutOfBoundsMemoryAccess))?
+            .copy_from_slice(bytes);
+    }
+
+    let mut machine = Machine {
+        module: &module,
+        options,
+        memory,
+        globals: module.globals.clone(),
+        stack: Vec::new(),
+        depth: 0,
+        fuel: options.fuel,
+        prints: options.max_prints,
+        output: Output::default(),
+    };
+    match machine.call(main) {
+        Ok(()) => {}
+        Err(Stop::Interrupted) => machine.output.interrupted = true,
+        Err(Stop::Error(err)) => return Err(err),
+    }
+
+    let mut output = machine.output;
+    if options.full_memory {
+        output.memory = machine.memory.clone();
+    }
+    if options.rgb {
+        let start = crate::RGB_CANVAS_OFFSET as usize;
+        output.canvas = machine.memory.get(start..).unwrap_or_default().to_vec();
+        output.canvas.resize(100 * 100 * 3, 0);
+    } else {
+        output.canvas = machine.memory;
+        output.canvas.resize(100 * 100, 0);
+    }
+    Ok(output)
+}
+
+/// A function provided by the host.
+enum HostFunction {
+    Print,
+    PrintString,
+    PrintBool,
+}
+
+#[derive(PartialEq, Eq)]
+struct FuncType {
+    params: Vec<u8>,
+    results: Vec<u8>,
+}
+
+struct Function {
+    ty: u32,
+    /// The number of locals, not including the parameters.
+    num_locals: usize,
+    code: Vec<Instr>,
+    /// For each "block", "loop", "if" or "else", the index of its "end".
+    ends: Vec<usize>,
+    /// For each "if", the index of its "else", if any.
+    elses: Vec<Option<usize>>,
+}
+impl Function {
+    fn new(ty: u32, num_locals: usize, code: Vec<Instr>) -> Result<Self, RunError> {
+        let mut ends = vec![0; code.len()];
+        let mut elses = vec![None; code.len()];
+        let mut open = Vec::new();
+        for (i, instr) in code.iter().enumerate() {
+            match instr {
+                Instr::Block | Instr::Loop | Instr::If => open.push(i),
+                Instr::Else => {
+                    let start = *open.last().ok_or(DecodeError::InvalidImmediate)?;
+                    elses[start] = Some(i);
+                    // a branch out of the "then" jumps over the "else"
+                    open.push(i);
+                }
+                Instr::End => {
+                    // the last "end" closes the function body
+                    if let Some(start) = open.pop() {
+                        ends[start] = i;
+                        if code[start] == Instr::Else {
+                            let start = open.pop().ok_or(DecodeError::InvalidImmediate)?;
+                            ends[start] = i;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            ty,
+            num_locals,
+            code,
+            ends,
+            elses,
+        })
+    }
+}
+
+/// The parts of a decoded module that are needed to run it.
+struct Module {
+    types: Vec<FuncType>,
+    /// The type of each imported function.
+    imports: Vec<(u32, HostFunction)>,
+    memory_pages: usize,
+    functions: Vec<Function>,
+    table: Vec<Option<u32>>,
+    /// The initial value of each global.
+    globals: Vec<u32>,
+    /// The offset and bytes of each data segment.
+    data: Vec<(usize, Vec<u8>)>,
+    main: Option<u32>,
+}
+impl Module {
+    fn decode(binary: &[u8]) -> Result<Self, RunError> {
+        let invalid = |x: &str| RunError::InvalidModule(x.to_string());
+
+        if binary.len() < 8 || &binary[..8] != b"\0asm\x01\0\0\0" {
+            return Err(invalid("missing magic number and version"));
+        }
+
+        let mut module = Module {
+            types: Vec::new(),
+            imports: Vec::new(),
+            memory_pages: 0,
+            functions: Vec::new(),
+            table: Vec::new(),
+            globals: Vec::new(),
+            data: Vec::new(),
+            main: None,
+        };
+        let mut function_types = Vec::new();
+
+        let mut rest = &binary[8..];
+        while !rest.is_empty() {
+            let id = instr::read_u8(&mut rest)?;
+            let len = instr::read_u32(&mut rest)? as usize;
+            if rest.len() < len {
+                return Err(DecodeError::UnexpectedEnd.into());
+            }
+            let (mut s, next) = rest.split_at(len);
+            rest = next;
+            let s = &mut s;
+
+            match id {
+                // type
+                1 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        if instr::read_u8(s)? != 0x60 {
+                            return Err(invalid("expected a function type"));
+                        }
+                        let params = read_bytes(s)?.to_vec();
+                        let results = read_bytes(s)?.to_vec();
+                        module.types.push(FuncType { params, results });
+                    }
+                }
+                // import
+                2 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        let import_module = read_bytes(s)?;
+                        let name = read_bytes(s)?;
+                        match instr::read_u8(s)? {
+                            0x00 => {
+                                let ty = instr::read_u32(s)?;
+                                let function = match (import_module, name) {
+                                    (b"env", b"print") => HostFunction::Print,
+                                    (b"env", b"prints") => HostFunction::PrintString,
+                                    (b"env", b"printb") => HostFunction::PrintBool,
+                                    _ => {
+                                        return Err(RunError::InvalidModule(format!(
+                                            "unknown import {:?} {:?}",
+                                            String::from_utf8_lossy(import_module),
+                                            String::from_utf8_lossy(name)
+                                        )))
+                                    }
+                                };
+                                module.imports.push((ty, function));
+                            }
+                            0x02 => {
+                                let (min, _) = read_limits(s)?;
+                                module.memory_pages = min as usize;
+                            }
+                            _ => return Err(invalid("unsupported import")),
+                        }
+                    }
+                }
+                // function
+                3 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        function_types.push(instr::read_u32(s)?);
+                    }
+                }
+                // table
+                4 => {
+                    if instr::read_u32(s)? != 1 || instr::read_u8(s)? != 0x70 {
+                        return Err(invalid("expected a single funcref table"));
+                    }
+                    let (min, _) = read_limits(s)?;
+                    module.table = vec![None; min as usize];
+                }
+                // global
+                6 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        // the value type and mutability are not checked
+                        instr::read_u8(s)?;
+                        instr::read_u8(s)?;
+                        let value = match (Instr::decode(s)?, Instr::decode(s)?) {
+                            (Instr::I32Const(x), Instr::End) => x as u32,
+                            (Instr::F32Const(x), Instr::End) => x.to_bits(),
+                            _ => return Err(invalid("unsupported global initializer")),
+                        };
+                        module.globals.push(value);
+                    }
+                }
+                // export
+                7 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        let name = read_bytes(s)?;
+                        let kind = instr::read_u8(s)?;
+                        let idx = instr::read_u32(s)?;
+                        if name == b"main" && kind == 0x00 {
+                            module.main = Some(idx);
+                        }
+                    }
+                }
+                // element
+                9 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        let flags = instr::read_u32(s)?;
+                        let offset = match (flags, Instr::decode(s)?, Instr::decode(s)?) {
+                            (0, Instr::I32Const(x), Instr::End) => x as usize,
+                            _ => return Err(invalid("unsupported element segment")),
+                        };
+                        for i in 0..instr::read_u32(s)? as usize {
+                            let idx = instr::read_u32(s)?;
+                            *module
+                                .table
+                                .get_mut(offset + i)
+                                .ok_or_else(|| invalid("element segment out of the table"))? =
+                                Some(idx);
+                        }
+                    }
+                }
+                // code
+                10 => {
+                    if instr::read_u32(s)? as usize != function_types.len() {
+                        return Err(invalid("the function and code sections don't match"));
+                    }
+                    for &ty in &function_types {
+                        let body = read_bytes(s)?;
+                        module.functions.push(decode_function(ty, body)?);
+                    }
+                }
+                // data
+                11 => {
+                    for _ in 0..instr::read_u32(s)? {
+                        let flags = instr::read_u32(s)?;
+                        let offset = match (flags, Instr::decode(s)?, Instr::decode(s)?) {
+                            (0, Instr::I32Const(x), Instr::End) => x as u32 as usize,
+                            _ => return Err(invalid("unsupported data segment")),
+                        };
+                        module.data.push((offset, read_bytes(s)?.to_vec()));
+                    }
+                }
+                // custom sections, and the ones that the compiler doesn't emit, are ignored
+                _ => {}
+            }
+        }
+
+        if module.functions.len() != function_types.len() {
+            return Err(invalid("the function and code sections don't match"));
+        }
+
+        Ok(module)
+    }
+
+    fn function_type(&self, idx: u32) -> &FuncType {
+        let idx = idx as usize;
+        let ty = match self.imports.get(idx) {
+            Some((ty, _)) => *ty,
+            None => self.functions[idx - self.imports.len()].ty,
+        };
+        &self.types[ty as usize]
+    }
+}
+
+/// Read a vector of bytes, prefixed by its length.
+fn read_bytes<'a>(s: &mut &'a [u8]) -> Result<&'a [u8], DecodeError> {
+    let len = instr::read_u32(s)? as usize;
+    if s.len() < len {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = s.split_at(len);
+    *s = rest;
+    Ok(bytes)
+}
+
+fn read_limits(s: &mut &[u8]) -> Result<(u32, Option<u32>), DecodeError> {
+    match instr::read_u8(s)? {
+        0x00 => Ok((instr::read_u32(s)?, None)),
+        0x01 => Ok((instr::read_u32(s)?, Some(instr::read_u32(s)?))),
+        _ => Err(DecodeError::InvalidImmediate),
+    }
+}
+
+fn decode_function(ty: u32, mut body: &[u8]) -> Result<Function, RunError> {
+    let body = &mut body;
+    let mut num_locals = 0;
+    for _ in 0..instr::read_u32(body)? {
+        num_locals += instr::read_u32(body)? as usize;
+        instr::read_u8(body)?;
+    }
+    let mut code = Vec::new();
+    while !body.is_empty() {
+        code.push(Instr::decode(body)?);
+    }
+    Function::new(ty, num_locals, code)
+}
+
+fn f32_min(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        // min(0, -0) is -0
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+fn f32_max(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        // max(0, -0) is 0
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+/// A open block, loop or if.
+struct Label {
+    /// The instruction where the execution continues when branching to this label.
+    target: usize,
+    is_loop: bool,
+    /// The height of the value stack when entering the block.
+    height: usize,
+}
+
+struct Machine<'a> {
+    module: &'a Module,
+    options: &'a RunOptions,
+    memory: Vec<u8>,
+    globals: Vec<u32>,
+    /// The value stack. Both i32 and f32 are stored as their bits.
+    stack: Vec<u32>,
+    /// The number of functions being executed.
+    depth: u32,
+    /// The number of instructions that can still be executed, if limited by [`RunOptions::fuel`].
+    fuel: Option<u64>,
+    /// The number of prints that can still be done, if limited by [`RunOptions::max_prints`].
+    prints: Option<u64>,
+    output: Output,
+}
+impl Machine<'_> {
+    fn pop(&mut self) -> u32 {
+        self.stack.pop().expect("the value stack is empty")
+    }
+
+    fn pop_f32(&mut self) -> f32 {
+        f32::from_bits(self.pop())
+    }
+
+    fn push_f32(&mut self, x: f32) {
+        self.stack.push(x.to_bits());
+    }
+
+    fn binary_f32(&mut self, op: impl Fn(f32, f32) -> f32) {
+        let b = self.pop_f32();
+        let a = self.pop_f32();
+        self.push_f32(op(a, b));
+    }
+
+    fn compare_f32(&mut self, op: impl Fn(f32, f32) -> bool) {
+        let b = self.pop_f32();
+        let a = self.pop_f32();
+        self.stack.push(op(a, b) as u32);
+    }
+
+    fn compare_i32(&mut self, op: impl Fn(i32, i32) -> bool) {
+        let b = self.pop() as i32;
+        let a = self.pop() as i32;
+        self.stack.push(op(a, b) as u32);
+    }
+
+    fn binary_i32(&mut self, op: impl Fn(i32, i32) -> i32) {
+        let b = self.pop() as i32;
+        let a = self.pop() as i32;
+        self.stack.push(op(a, b) as u32);
+    }
+
+    /// Call the function `idx`, with its arguments on the top of the stack.
+    fn call(&mut self, idx: u32) -> Result<(), Stop> {
+        let module = self.module;
+        let imported = module.imports.len();
+        if let Some((_, function)) = module.imports.get(idx as usize) {
+            if let Some(prints) = &mut self.prints {
+                if *prints == 0 {
+                    let printed = std::mem::take(&mut self.output.printed);
+                    return Err(RunError::OutputLimitExceeded { printed }.into());
+                }
+                *prints -= 1;
+            }
+            match function {
+                HostFunction::Print => {
+                    let x = self.pop_f32();
+                    match self.options.print_precision {
+                        Some(precision) => writeln!(self.output.printed, "{:.*}", precision, x),
+                        None => writeln!(self.output.printed, "{}", x),
+                    }
+                    .unwrap();
+                }
+                HostFunction::PrintString => {
+                    let len = self.pop() as usize;
+                    let ptr = self.pop() as usize;
+                    let bytes = self
+                        .memory
+                        .get(ptr..ptr + len)
+                        .ok_or(RunError::Trap(Trap::OutOfBoundsMemoryAccess))?;
+                    self.output.printed += &String::from_utf8_lossy(bytes);
+                }
+                HostFunction::PrintBool => {
+                    let x = self.pop() as i32;
+                    match (self.options.bool_print, x) {
+                        (true, 0) => writeln!(self.output.printed, "false"),
+                        (true, _) => writeln!(self.output.printed, "true"),
+                        (false, _) => writeln!(self.output.printed, "{}", x),
+                    }
+                    .unwrap();
+                }
+            }
+            return Ok(());
+        }
+
+        let function = module
+            .functions
+            .get(idx as usize - imported)
+            .ok_or_else(|| RunError::InvalidModule(format!("unknown function {}", idx)))?;
+        if self.options.profile {
+            *self.output.calls.entry(idx).or_insert(0) += 1;
+        }
+        let max_depth = self
+            .options
+            .max_call_depth
+            .unwrap_or(DEFAULT_MAX_CALL_DEPTH);
+        if self.depth >= max_depth {
+            return Err(RunError::StackOverflow.into());
+        }
+        let ty = module.function_type(idx);
+        let num_params = ty.params.len();
+        let mut locals = self.stack.split_off(self.stack.len() - num_params);
+        locals.resize(num_params + function.num_locals, 0);
+        let height = self.stack.len();
+        self.depth += 1;
+        let result = self.execute(idx, function, &mut locals);
+        self.depth -= 1;
+        result?;
+        // a `return` may leave other values below the results
+        let results = self.stack.split_off(self.stack.len() - ty.results.len());
+        self.stack.truncate(height);
+        self.stack.extend(results);
+        Ok(())
+    }
+
+    fn execute(&mut self, idx: u32, function: &Function, locals: &mut [u32]) -> Result<(), Stop> {
+        let mut labels: Vec<Label> = Vec::new();
+        let mut pc = 0;
+        while let Some(&instr) = function.code.get(pc) {
+            if let Some(interrupt) = &self.options.interrupt {
+                if interrupt.load(Ordering::Relaxed) {
+                    return Err(Stop::Interrupted);
+                }
+            }
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(RunError::OutOfFuel.into());
+                }
+                *fuel -= 1;
+            }
+            if self.options.trace {
+                self.output.trace.push(TraceEntry {
+                    function: idx,
+                    instr,
+                });
+            }
+            let start = pc;
+            pc += 1;
+
+            let mut branch = None;
+            match instr {
+                Instr::Unreachable => return Err(RunError::Trap(Trap::Unreachable).into()),
+                Instr::Nop => {}
+                Instr::Block => labels.push(Label {
+                    target: function.ends[start] + 1,
+                    is_loop: false,
+                    height: self.stack.len(),
+                }),
+                Instr::Loop => labels.push(Label {
+                    target: start + 1,
+                    is_loop: true,
+                    height: self.stack.len(),
+                }),
+                Instr::If => {
+                    let condition = self.pop();
+                    let end = function.ends[start];
+                    if condition != 0 {
+                        labels.push(Label {
+                            target: end + 1,
+                            is_loop: false,
+                            height: self.stack.len(),
+                        });
+                    } else if let Some(else_) = function.elses[start] {
+                        labels.push(Label {
+                            target: end + 1,
+                            is_loop: false,
+                            height: self.stack.len(),
+                        });
+                        pc = else_ + 1;
+                    } else {
+                        pc = end + 1;
+                    }
+                }
+                // the end of the "then" block, jump to the "end" of the if
+                Instr::Else => pc = function.ends[start],
+                Instr::End => {
+                    if labels.pop().is_none() {
+                        return Ok(());
+                    }
+                }
+                Instr::Br(depth) => branch = Some(depth),
+                Instr::BrIf(depth) => {
+                    if self.pop() != 0 {
+                        branch = Some(depth);
+                    }
+                }
+                Instr::Return => return Ok(()),
+                Instr::Call(callee) => self.call(callee)?,
+                Instr::CallIndirect(ty) => {
+                    let element = self.pop() as usize;
+                    let callee = self
+                        .module
+                        .table
+                        .get(element)
+                        .copied()
+                        .flatten()
+                        .ok_or(RunError::Trap(Trap::UndefinedElement))?;
+                    if *self.module.function_type(callee) != self.module.types[ty as usize] {
+                        return Err(RunError::Trap(Trap::IndirectCallTypeMismatch).into());
+                    }
+                    self.call(callee)?;
+                }
+                Instr::Drop => {
+                    self.pop();
+                }
+                Instr::LocalGet(x) => self.stack.push(locals[x as usize]),
+                Instr::LocalSet(x) => locals[x as usize] = self.pop(),
+                Instr::GlobalGet(x) => self.stack.push(self.globals[x as usize]),
+                Instr::GlobalSet(x) => self.globals[x as usize] = self.pop(),
+                Instr::I32Load8U { offset, .. } => {
+                    let address = self.pop() as u64 + offset as u64;
+                    let byte = self
+                        .memory
+                        .get(address as usize)
+                        .ok_or(RunError::Trap(Trap::OutOfBoundsMemoryAccess))?;
+                    self.stack.push(*byte as u32);
+                }
+                Instr::F32Load { offset, .. } => {
+                    let address = (self.pop() as u64 + offset as u64) as usize;
+                    let bytes = self
+                        .memory
+                        .get(address..address + 4)
+                        .ok_or(RunError::Trap(Trap::OutOfBoundsMemoryAccess))?;
+                    self.stack
+                        .push(u32::from_le_bytes(bytes.try_into().unwrap()));
+                }
+                Instr::I32Store8 { offset, .. } => {
+                    let value = self.pop();
+                    let address = self.pop() as u64 + offset as u64;
+                    let byte = self
+                        .memory
+                        .get_mut(address as usize)
+                        .ok_or(RunError::Trap(Trap::OutOfBoundsMemoryAccess))?;
+                    *byte = value as u8;
+                }
+                Instr::I32Const(x) => self.stack.push(x as u32),
+                Instr::F32Const(x) => self.push_f32(x),
+                Instr::I32Eqz => {
+                    let x = self.pop();
+                    self.stack.push((x == 0) as u32);
+                }
+                Instr::I32Eq => self.compare_i32(|a, b| a == b),
+                Instr::I32LtS => self.compare_i32(|a, b| a < b),
+                Instr::I32GtS => self.compare_i32(|a, b| a > b),
+                Instr::I32LeS => self.compare_i32(|a, b| a <= b),
+                Instr::I32GeU => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.stack.push((a >= b) as u32);
+                }
+                Instr::F32Eq => self.compare_f32(|a, b| a == b),
+                Instr::F32Lt => self.compare_f32(|a, b| a < b),
+                Instr::F32Gt => self.compare_f32(|a, b| a > b),
+                Instr::F32Ge => self.compare_f32(|a, b| a >= b),
+                Instr::I32Add => self.binary_i32(i32::wrapping_add),
+                Instr::I32Sub => self.binary_i32(i32::wrapping_sub),
+                Instr::I32Mul => self.binary_i32(i32::wrapping_mul),
+                Instr::I32And => self.binary_i32(|a, b| a & b),
+                Instr::F32Add => self.binary_f32(|a, b| a + b),
+                Instr::F32Sub => self.binary_f32(|a, b| a - b),
+                Instr::F32Mul => self.binary_f32(|a, b| a * b),
+                Instr::F32Div => self.binary_f32(|a, b| a / b),
+                Instr::F32Neg => {
+                    let x = self.pop_f32();
+                    self.push_f32(-x);
+                }
+                Instr::F32Floor => {
+                    let x = self.pop_f32();
+                    self.push_f32(x.floor());
+                }
+                Instr::F32Trunc => {
+                    let x = self.pop_f32();
+                    self.push_f32(x.trunc());
+                }
+                Instr::F32Nearest => {
+                    let x = self.pop_f32();
+                    self.push_f32(x.round_ties_even());
+                }
+                Instr::F32Min => self.binary_f32(f32_min),
+                Instr::F32Max => self.binary_f32(f32_max),
+                Instr::F32Copysign => self.binary_f32(f32::copysign),
+                Instr::I32TruncF32S => {
+                    let x = self.pop_f32();
+                    if x.is_nan() || x <= -2147483904.0 || x >= 2147483648.0 {
+                        return Err(RunError::Trap(Trap::InvalidConversionToInteger).into());
+                    }
+                    self.stack.push(x as i32 as u32);
+                }
+                Instr::I32TruncSatF32S => {
+                    // a Rust cast saturates, and converts NaN to 0
+                    let x = self.pop_f32();
+                    self.stack.push(x as i32 as u32);
+                }
+                Instr::F32ConvertI32S => {
+                    let x = self.pop() as i32;
+                    self.push_f32(x as f32);
+                }
+            }
+
+            if let Some(depth) = branch {
+                let depth = depth as usize;
+                // a branch out of all blocks returns from the function
+                if depth >= labels.len() {
+                    return Ok(());
+                }
+                let i = labels.len() - 1 - depth;
+                let label = &labels[i];
+                // all blocks have a empty type, so no value is carried by the branch
+                self.stack.truncate(label.height);
+                pc = label.target;
+                // a branch to a loop stays inside it
+                labels.truncate(if label.is_loop { i + 1 } else { i });
+            }
+        }
+        Ok(())
+    }
+}
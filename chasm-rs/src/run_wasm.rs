@@ -9,41 +9,8 @@ use wasmi::{
     ModuleImportResolver, ModuleInstance, RuntimeValue, Signature, ValueType,
 };
 
-pub fn dump_hex(data: &[u8]) {
-    let mut bytes = data;
-    let mut offset = 0;
-    loop {
-        const D: usize = 16;
-        print!("{:04x}: ", offset);
-        for i in 0..D {
-            if i < bytes.len() {
-                print!("{:02x} ", bytes[i]);
-            } else {
-                print!("   ");
-            }
-        }
-        print!(": ");
-        for &b in bytes.iter().take(D) {
-            let c = b as char;
-            print!(
-                "{}",
-                if c.is_ascii_graphic() || c == ' ' {
-                    c
-                } else {
-                    '.'
-                }
-            );
-        }
-        println!();
-        if bytes.len() < D {
-            return;
-        }
-        bytes = &bytes[D..];
-        offset += D;
-    }
-}
-
-struct EnvModuleResolver(wasmi::MemoryRef);
+/// Resolve the imports of the module, allocating the memory with the size it requires.
+struct EnvModuleResolver(std::cell::RefCell<Option<wasmi::MemoryRef>>);
 impl ModuleImportResolver for EnvModuleResolver {
     fn resolve_func(
         &self,
@@ -52,6 +19,11 @@ impl ModuleImportResolver for EnvModuleResolver {
     ) -> Result<wasmi::FuncRef, wasmi::Error> {
         let func = match field_name {
             "print" => FuncInstance::alloc_host(Signature::new(&[ValueType::F32][..], None), 0),
+            "prints" => FuncInstance::alloc_host(
+                Signature::new(&[ValueType::I32, ValueType::I32][..], None),
+                1,
+            ),
+            "printb" => FuncInstance::alloc_host(Signature::new(&[ValueType::I32][..], None), 2),
             _ => {
                 return Err(InterpreterError::Function(format!(
                     "host module doesn't export function with name {}",
@@ -65,17 +37,24 @@ impl ModuleImportResolver for EnvModuleResolver {
     fn resolve_memory(
         &self,
         field_name: &str,
-        _memory_type: &wasmi::MemoryDescriptor,
+        memory_type: &wasmi::MemoryDescriptor,
     ) -> Result<wasmi::MemoryRef, InterpreterError> {
         let mem = match field_name {
-            "memory" => self.0.clone(),
+            "memory" => MemoryInstance::alloc(
+                Pages(memory_type.initial() as usize),
+                memory_type.maximum().map(|x| Pages(x as usize)),
+            )?,
             _ => panic!("HAHAHAH!!"),
         };
+        *self.0.borrow_mut() = Some(mem.clone());
         Ok(mem)
     }
 }
 
-struct Runtime<W: Write>(Arc<Mutex<W>>);
+struct Runtime<W: Write> {
+    out: Arc<Mutex<W>>,
+    memory: wasmi::MemoryRef,
+}
 impl<W: Write> Externals for Runtime<W> {
     fn invoke_index(
         &mut self,
@@ -85,7 +64,25 @@ impl<W: Write> Externals for Runtime<W> {
         match index {
             0 => {
                 let n: f32 = args.nth::<F32>(0).into();
-                writeln!(self.0.lock().unwrap(), "{}", n).unwrap();
+                writeln!(self.out.lock().unwrap(), "{}", n).unwrap();
+            }
+            1 => {
+                let ptr: u32 = args.nth(0);
+                let len: u32 = args.nth(1);
+                let bytes = self
+                    .memory
+                    .get(ptr, len as usize)
+                    .map_err(|_| wasmi::Trap::new(wasmi::TrapKind::MemoryAccessOutOfBounds))?;
+                write!(
+                    self.out.lock().unwrap(),
+                    "{}",
+                    String::from_utf8_lossy(&bytes)
+                )
+                .unwrap();
+            }
+            2 => {
+                let x: i32 = args.nth(0);
+                writeln!(self.out.lock().unwrap(), "{}", x).unwrap();
             }
             _ => panic!("HAHAHAH!!!"),
         };
@@ -97,11 +94,17 @@ pub fn run_binary<W: Write + Send + 'static>(
     binary: &[u8],
     out: Arc<Mutex<W>>,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    dump_hex(&binary);
-
-    let mut runtime = Runtime(out);
+    run_export(binary, "main", &[], out)
+}
 
-    let module = Module::from_buffer(&binary)?;
+/// Call the exported function `name` with the given arguments, returning the canvas.
+pub fn run_export<W: Write + Send + 'static>(
+    binary: &[u8],
+    name: &str,
+    args: &[f32],
+    out: Arc<Mutex<W>>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let module = Module::from_buffer(binary)?;
     // let memory = Memory::new(&store, MemoryType::new(1, None, false)).unwrap();
     // let import_object = imports! {
     //     "env" => {
@@ -109,11 +112,20 @@ pub fn run_binary<W: Write + Send + 'static>(
     //         "memory" => memory.clone(),
     //     }
     // };
-    let memory = MemoryInstance::alloc(Pages(1), None).unwrap();
-    let resolver = &EnvModuleResolver(memory.clone());
+    let resolver = &EnvModuleResolver(Default::default());
     let import_object = ImportsBuilder::default().with_resolver("env", resolver);
     let instance = ModuleInstance::new(&module, &import_object)?.assert_no_start();
-    instance.invoke_export("main", &[], &mut runtime)?;
+    let memory = resolver
+        .0
+        .borrow()
+        .clone()
+        .expect("the module imports a memory");
+    let mut runtime = Runtime {
+        out,
+        memory: memory.clone(),
+    };
+    let args: Vec<_> = args.iter().map(|&x| RuntimeValue::F32(x.into())).collect();
+    instance.invoke_export(name, &args, &mut runtime)?;
     let mut data = memory.direct_access().as_ref().to_owned();
     data.resize(100 * 100, 0);
     Ok(data)
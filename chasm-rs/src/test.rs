@@ -24,6 +24,9 @@ fn check_output(source: &str, expected: Result<&str, ErrorKind>) {
         (expected, Ok(binary)) => {
             let out = Arc::new(Mutex::new(String::new()));
             run_wasm::run_binary(&binary, out.clone()).unwrap();
+            // the bundled interpreter must agree with wasmi
+            let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+            assert_eq!(*out.lock().unwrap(), output.printed);
             match expected {
                 Err(expected) => panic!("expected {:?}, received {:?}", expected, binary),
                 Ok(expected) => assert_eq!(*out.lock().unwrap(), expected),
@@ -71,7 +74,69 @@ test_output!(
      proc C (x, y, z) print ((x+y)+z) endproc
      A(1)",
      Ok("7\n"))
-    (setpixel_side_effect, "print 0 setpixel(0, 1, 2) print x print y print color", Ok("0\n0\n1\n2\n"))
+    (switch_case, "
+     var a = 2
+     switch a
+     case 1 print 10
+     case 2 print 20 print 21
+     default print 30
+     endswitch",
+     Ok("20\n21\n"))
+    (switch_default, "
+     switch (1 + 2)
+     case 1 print 10
+     case 2 print 20
+     default print 30
+     endswitch
+     switch 5 case 4 print 40 endswitch",
+     Ok("30\n"))
+    (unicode_ident, "var ação = 3 var 变量 = (ação * 2) print 变量", Ok("6\n"))
+    (repeat, "repeat 3 print 1 endrepeat", Ok("1\n1\n1\n"))
+    (repeat_nested, "
+     var n = 0
+     repeat 2.9
+        repeat (n + 1) print n endrepeat
+        n = (n + 1)
+     endrepeat
+     repeat -1 print 5 endrepeat",
+     Ok("0\n1\n1\n"))
+    (proc_reference, "
+     proc double(x) print (x * 2) endproc
+     proc apply(f, v) f(v) endproc
+     apply(&double, 21)
+     var g = &triple
+     g(2)
+     proc triple(x) print (x * 3) endproc",
+     Ok("42\n6\n"))
+    (default_args, "proc f(a, b = 10) print (a + b) endproc f(5) f(5, 1)", Ok("15\n6\n"))
+    (default_args_forward, "f(1) proc f(a, b = 2, c = 3) print ((a + b) + c) endproc",
+     Ok("6\n"))
+    (default_args_missing, "proc f(a, b = 10) print (a + b) endproc f()",
+        Err(ErrorKind::ArgumentNumberMismatch { expected: 2, received: 0 }))
+    (default_args_constant, "proc f(a, b = (2 * 5)) print (a + b) endproc f(5)", Ok("15\n"))
+    (default_args_not_constant, "proc f(a, b = a) print b endproc var a = 7 f(1)",
+        Err(ErrorKind::NotConstant))
+    (default_args_order, "proc f(a = 1, b) print (a + b) endproc",
+        Err(ErrorKind::UnexpectedToken { expected: &[Token::Assignment], received: Token::RightParen }))
+    (scope_shadow, "
+     var x = 1
+     var z = 1
+     if (1 == 1)
+        var x = (x + 1)
+        z = (z + 1)
+        print x
+        repeat 1 var x = 10 x = (x + 1) print x endrepeat
+        x = 4
+     endif
+     print x
+     print z",
+     Ok("2\n11\n1\n2\n"))
+    (pass, "if (1 == 1) pass else print 2 endif", Ok(""))
+    (pass_only_bodies, "while (0 == 1) pass endwhile proc f() pass endproc f() if (1==1) pass else pass endif print 3", Ok("3\n"))
+    (leading_dot, "print .5", Ok("0.5\n"))
+    (trailing_dot, "print 5.", Ok("5\n"))
+    (dot_number_sum, "print (.25 + 2.)", Ok("2.25\n"))
+    (setpixel_side_effect, "var x = 5 print 0 setpixel(0, 1, 2) print x print y print color", Ok("0\n5\n0\n0\n"))
     (print_print, 
         "print print",
         Err(ErrorKind::UnexpectedToken {
@@ -79,10 +144,16 @@ test_output!(
             expected: &[Token::Number, Token::LeftParen]
         })
     )
+    (if_bare_condition, "if 1==1 print 1 endif if 1==2 print 2 endif", Ok("1\n"))
+    (while_bare_condition, "var i = 0 while i < 3 print i i = (i + 1) endwhile", Ok("0\n1\n2\n"))
+    (bare_condition_precedence, "if 1 + 2 * 3 == 7 && 2 - 1 > 0 print 1 endif", Ok("1\n"))
+    (bare_condition_chained, "var i = 5 if 0 < i < 3 print 1 else print 2 endif", Ok("2\n"))
+    (bare_condition_assignment, "var x = 1 if x = 1 print x endif",
+        Err(ErrorKind::AssignmentInExpression))
     (while_float, "while 0.0 print 1 endwhile",
-        Err(ErrorKind::UnexpectedType { expected: &[Type::I32], received: vec![Type::F32] }))
+        Err(ErrorKind::NonBooleanCondition))
     (if_float, "if 0.0 print 1 endif",
-        Err(ErrorKind::UnexpectedType { expected: &[Type::I32], received: vec![Type::F32] }))
+        Err(ErrorKind::NonBooleanCondition))
     (equal_bool, "print ((0.0 == 0.0) == (1.0 == 1.0))",
         Err(ErrorKind::UnexpectedType { expected: &[Type::F32, Type::F32], received: vec![Type::I32, Type::I32] }))
     (divide_bool, "print ((0.0 == 0.0) / (1.0 == 1.0))",
@@ -92,6 +163,164 @@ test_output!(
 
     (unknown_proc, "\n\n\nvM(8)",
         Err(ErrorKind::UndeclaredProc { name: "vM".to_string() }))
+    (lone_dot, "print .", Err(ErrorKind::InvalidNumber))
+    (sub_spaced, "print (5 - 3)", Ok("2\n"))
+    (sub_unspaced, "print (5 -3)", Ok("2\n"))
+    (sub_variable_unspaced, "var a = 5 print (a -3)", Ok("2\n"))
+    (negate, "var a = 5 print -a print -(a * 2) print (2 - -3) print - -1.5", Ok("-5\n-10\n5\n1.5\n"))
+    (negative_case, "switch -1 case -1 print 1 default print 0 endswitch", Ok("1\n"))
+    (sign, "print sign(-3) print sign(0) print sign(2.5) print sign((1 - 1))", Ok("-1\n0\n1\n0\n"))
+    (fract, "print fract(2.25) print fract(-0.25) print fract(3)", Ok("0.25\n0.75\n0\n"))
+    (builtin_nested, "var x = 1.5 print sign(fract(x))", Ok("1\n"))
+    (chained_var, "var a = b = 5 print (a+b)", Ok("10\n"))
+    (chained_assignment, "var a = 1 var b = 2 var c = 3 a = b = c = (a + 10) print a print b print c",
+        Ok("11\n11\n11\n"))
+    (return_single_value, "proc f(x) if (x > 1) return ((x * 2)) endif print x return (x) endproc f(1) f(2)",
+        Ok("1\n"))
+    (return_missing, "proc f(x) if (x > 1) return ((x * 2)) endif print x endproc f(1)",
+        Err(ErrorKind::MissingReturn { name: "f".to_string() }))
+    (return_in_all_branches, "
+     proc f(x)
+       if (x > 1) return (1) else return (2) endif
+     endproc
+     proc g(x)
+       loop if (x > 3) return (x) endif x = (x + 1) endloop
+     endproc
+     proc h(x)
+       switch x case 1 return (1) default return (2) endswitch
+     endproc
+     f(1) g(1) h(1) print 1",
+        Ok("1\n"))
+    (return_missing_after_break, "proc g(x) loop if (x > 3) break endif return (x) endloop endproc g(1)",
+        Err(ErrorKind::MissingReturn { name: "g".to_string() }))
+    (return_early, "proc f(x) loop if (x > 3) return endif x = (x + 1) endloop endproc f(0) print 1",
+        Ok("1\n"))
+    (return_count_mismatch, "proc f(x) return (x) return () endproc f(1)",
+        Err(ErrorKind::ResultNumberMismatch { expected: 1, received: 0 }))
+    (return_multivalue_disabled, "proc f(x) return (x, x) endproc var a, b = f(1)",
+        Err(ErrorKind::MultivalueDisabled))
+    (return_expression, "
+     proc square(a) return (a*a) endproc
+     proc neg(a) return -a endproc
+     proc id(a) return a endproc
+     proc twice(a) return square(a) endproc
+     print square(3) print neg(2) print id(4) print twice(2)",
+        Ok("9\n-2\n4\n4\n"))
+    (chained_comparison_call, "
+     proc f() print 7 return 5 endproc
+     proc g() print 8 return 6 endproc
+     if (0 < f() < 10) print 1 endif
+     if 0 < f() < 10 print 2 endif
+     if (0 < f() < g() < 10) print 3 endif
+     if (9 < f() < 10) print 4 endif
+     var i = 0
+     while (i < (i + f()) < 7) i = (i + 1) endwhile",
+        Ok("7\n1\n7\n2\n7\n8\n3\n7\n7\n7\n7\n"))
+    (return_before_assignment, "proc f(x) if (x > 1) return endif x = 2 print x endproc f(5) f(0)",
+        Ok("2\n"))
+    (trunc, "print trunc(2.9) print trunc(-2.9) print trunc(3)", Ok("2\n-2\n3\n"))
+    (round_half_even, "print round(2.5) print round(0.5) print round(3.5) print round(-2.5) print round(2.6)",
+        Ok("2\n0\n4\n-2\n3\n"))
+    (copysign, "print copysign(3, -1) print copysign(-3, 1) print copysign(-2, -0.0)",
+        Ok("-3\n3\n-2\n"))
+    (builtin_arguments, "print sign(1, 2)",
+        Err(ErrorKind::ArgumentNumberMismatch { expected: 1, received: 2 }))
+    (builtin_unknown, "print f(1)",
+        Err(ErrorKind::UnknownBuiltin { name: "f".to_string() }))
+    (call_in_expression, "
+     var r = double(2)
+     print (double(r) + 1)
+     proc double(x) return ((x * 2)) endproc
+     print double(double(1))",
+        Ok("9\n4\n"))
+    (call_in_expression_no_result, "proc f(x) pass endproc print f(1)",
+        Err(ErrorKind::ResultNumberMismatch { expected: 1, received: 0 }))
+    (call_in_expression_arguments, "proc f(x) return (x) endproc print f(1, 2)",
+        Err(ErrorKind::ArgumentNumberMismatch { expected: 1, received: 2 }))
+    (prints, "prints \"hi\"", Ok("hi"))
+    (prints_label, "prints \"x = \" print 2 prints \"\"", Ok("x = 2\n"))
+    (prints_proc_reference, "
+     proc f(a)
+       prints \"a: \"
+       print a
+     endproc
+     var g = &f
+     g(1)
+     f(2)", Ok("a: 1\na: 2\n"))
+    (reference_name_clash, "
+     proc f(a) print a endproc
+     var g = &f
+     g(1)
+     proc g(a) print (a + 10) endproc
+     g(2)", Err(ErrorKind::NameClash { name: "g".to_string() }))
+    (parameter_name_clash, "proc f(a) print a endproc proc apply(f) f(1) endproc apply(&f)",
+        Err(ErrorKind::NameClash { name: "f".to_string() }))
+    (reference_is_integer, "proc f() pass endproc print (&f + 1)",
+        Err(ErrorKind::UnexpectedType { expected: &[Type::F32, Type::F32], received: vec![Type::I32, Type::F32] }))
+    (prints_unterminated, "prints \"hi",
+        Err(ErrorKind::UnexpectedCharacter))
+    (global, "
+     proc show() print g endproc
+     global g = 1
+     show()
+     proc inc() g = (g + 1) endproc
+     inc() inc()
+     show()
+     proc local() var g = 10 print g endproc
+     local()
+     print g",
+     Ok("1\n3\n10\n3\n"))
+    (global_setpixel, "global x = 5 setpixel(1, 2, 3) print x", Ok("5\n"))
+    (global_nested, "if (1 == 1) global g = 1 endif",
+        Err(ErrorKind::UnexpectedToken {
+            expected: &[Token::Print, Token::Var, Token::Identifier, Token::While],
+            received: Token::Global,
+        }))
+    (chained_comparison, "
+     if (0 < 5 < 100) print 1 else print 0 endif
+     if (0 < 200 < 100) print 1 else print 0 endif
+     var x = 3
+     if (5 > x > 1 == 1) print 1 else print 0 endif
+     if (1 < x < (2 + 0)) print 1 else print 0 endif",
+     Ok("1\n0\n1\n0\n"))
+    (loop_break, "
+     var i = 0
+     loop
+         i = (i + 1)
+         if (i > 4)
+             break
+         endif
+     endloop
+     print i",
+     Ok("5\n"))
+    (break_nested, "
+     var i = 0
+     while (i < 3)
+         repeat 10
+             switch i
+             case 1 break
+             default print i
+             endswitch
+             break
+         endrepeat
+         loop break endloop
+         i = (i + 1)
+     endwhile
+     repeat 5 print 9 break endrepeat
+     print i",
+     Ok("0\n2\n9\n3\n"))
+    (break_outside_loop, "if (1 == 1) break endif", Err(ErrorKind::BreakOutsideLoop))
+    (break_in_proc_in_loop, "loop proc f() break endproc break endloop", Err(ErrorKind::BreakOutsideLoop))
+    (maxpixel, "
+     print maxpixel()
+     setpixel(3, 4, 10)
+     setpixel(99, 99, 200)
+     setpixel(50, 50, 10)
+     print maxpixel()
+     repeat 2 print (maxpixel() + 1) endrepeat",
+     Ok("0\n200\n201\n201\n"))
+    (maxpixel_args, "print maxpixel(1)",
+        Err(ErrorKind::ArgumentNumberMismatch { expected: 0, received: 1 }))
     (unclosed_paren, "LM((88,8",
         Err(ErrorKind::UnexpectedToken { expected: &[Token::Operator], received: Token::Comma }))
 );
@@ -131,6 +360,2325 @@ endwhile";
 
     let hash = blake3::hash(&output);
     assert_eq!(&hash.to_hex()[0..16], "28ad088dd153090f");
+    #[cfg(feature = "blake3")]
+    assert_eq!(crate::canvas_hash(&output), "28ad088dd153090f");
+
+    let interpreted = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(interpreted.canvas, output[..100 * 100]);
 
     Ok(())
 }
+
+#[test]
+fn arity_mismatch_call_site() {
+    let source = "foo(1, 2) foo(3) proc foo(a, b) print (a + b) endproc";
+    let err = compile(source).unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::ArgumentNumberMismatch {
+            expected: 2,
+            received: 1
+        }
+    );
+    assert_eq!(&source[err.span], "foo(3)");
+}
+
+#[test]
+fn column_with_tabs() {
+    let source = "print 1\n\tprint print";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.get_line_column(), (2, 8));
+    assert_eq!(err.column_with_tabs(4), 11);
+    assert_eq!(err.column_with_tabs(8), 15);
+    // a width of 0 is a width of 1, instead of dividing by zero
+    assert_eq!(err.column_with_tabs(0), 8);
+    assert_eq!(err.column_with_tabs(1), 8);
+}
+
+#[test]
+fn unicode_error_column() {
+    let source = "var ação = print";
+    let err = compile(source).unwrap_err();
+    assert_eq!(&source[err.span.clone()], "print");
+    assert_eq!(err.get_line_column(), (1, 12));
+}
+
+#[test]
+fn eof_error_line_column() {
+    let source = "print 1\nprint (1 +";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.span, source.len()..source.len());
+    assert_eq!(err.get_line_column(), (2, 11));
+    assert!(err.to_string().starts_with("error at 2:11:"));
+
+    let source = "print 1\nprint (1 +\n";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.get_line_column(), (2, 11));
+
+    let source = "var x = ";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.get_line_column(), (1, 9));
+}
+
+#[test]
+fn clamp_setpixel() {
+    let source = "setpixel(1000000000, -5, 7) setpixel(2, 200, 9) setpixel(50.5, 1, 3)";
+    let out = Arc::new(Mutex::new(String::new()));
+
+    let binary = compile(source).unwrap();
+    assert!(run_wasm::run_binary(&binary, out.clone()).is_err());
+
+    let options = CompileOptions {
+        clamp_setpixel: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run_wasm::run_binary(&binary, out).unwrap();
+    assert_eq!(output[99], 7);
+    assert_eq!(output[99 * 100 + 2], 9);
+    assert_eq!(output[100 + 50], 3);
+}
+
+#[test]
+fn legacy_setpixel_sideeffects() {
+    let source = "var x = 5 setpixel(0, 1, 2) print x print y print color";
+    let options = CompileOptions::default();
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "5\n0\n0\n");
+    assert_eq!(output.canvas[100], 2);
+
+    for optimize in [false, true] {
+        let options = CompileOptions {
+            legacy_setpixel_sideeffects: true,
+            optimize,
+            ..Default::default()
+        };
+        let binary = compile_with_options(source, &options).unwrap();
+        let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+        assert_eq!(output.printed, "0\n1\n2\n");
+        assert_eq!(output.canvas[100], 2);
+    }
+    // a global is also written
+    let options = CompileOptions {
+        legacy_setpixel_sideeffects: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options("global x = 5 setpixel(1, 2, 3) print x", &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "1\n");
+}
+
+#[test]
+fn skip_oob_setpixel() {
+    let source = "
+        setpixel(3, 3, 9)
+        setpixel(-1, 0, 5)
+        setpixel(100, 0, 6)
+        setpixel(5, 200, 7)
+        setpixel((0 / 0), 1, 8)";
+    let out = Arc::new(Mutex::new(String::new()));
+
+    let binary = compile(source).unwrap();
+    assert!(run_wasm::run_binary(&binary, out.clone()).is_err());
+
+    let options = CompileOptions {
+        skip_oob_setpixel: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run_wasm::run_binary(&binary, out).unwrap();
+    assert_eq!(output[3 * 100 + 3], 9);
+    assert_eq!(output.iter().filter(|&&x| x != 0).count(), 1);
+}
+
+#[test]
+fn shadow_warning() {
+    let source = "var x = 1 if (x == 1) var x = 2 print x endif var y = 3 var y = 4 print y";
+    let (_, warnings) = compile_with_warnings(source, &CompileOptions::default()).unwrap();
+    assert_eq!(
+        warnings,
+        vec![Warning::Shadowed {
+            name: "x".to_string(),
+            span: 26..27,
+            outer_span: 4..5,
+        }]
+    );
+}
+
+#[test]
+fn unused_variable_warning() {
+    let source = "
+var a = 1
+var b = 2
+proc f(p)
+    var c = a
+    var d, e = g()
+    print d
+endproc
+proc g()
+    return (b, 2)
+endproc
+print (a + b)";
+    let options = CompileOptions {
+        multivalue: true,
+        ..CompileOptions::default()
+    };
+    let (_, warnings) = compile_with_warnings(source, &options).unwrap();
+    let unused: Vec<_> = warnings
+        .iter()
+        .map(|x| (x.code(), &source[x.span()]))
+        .collect();
+    // the unused parameter `p` is not warned
+    assert_eq!(unused, [("W0003", "c"), ("W0003", "e")]);
+}
+
+#[test]
+fn imports_used() {
+    let names = |source| -> Vec<_> {
+        crate::imports_used(source)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect()
+    };
+    assert_eq!(
+        names("print 1"),
+        ["env print (func (param f32))", "env memory (memory 1)"]
+    );
+    // there are no math imports, so a string is the other import besides "print"
+    assert_eq!(
+        names("print 1 prints \"a\" print (1 < 2)"),
+        [
+            "env print (func (param f32))",
+            "env prints (func (param i32 i32))",
+            "env printb (func (param i32))",
+            "env memory (memory 1)"
+        ]
+    );
+    assert!(crate::imports_used("print (1 +").is_err());
+}
+
+#[test]
+fn ast_dump() {
+    let source = "\
+var i = 0
+while (i < 3)
+  if (i == 1)
+    print i
+  else
+    f(&g)
+  endif
+  i = (i + 1)
+endwhile";
+    let program = parse(source).unwrap();
+    assert_eq!(
+        program.to_string(),
+        "\
+program
+  var i @0..9
+    integer 0 @8..9
+  while @10..97
+    binary < @16..23
+      variable i @17..18
+      integer 3 @21..22
+    do
+      if @26..74
+        binary == @29..37
+          variable i @30..31
+          integer 1 @35..36
+        then
+          print @42..49
+            variable i @48..49
+        else
+          call f @61..66
+            reference g @63..65
+      assign i @77..88
+        binary + @81..88
+          variable i @82..83
+          integer 1 @86..87
+"
+    );
+}
+
+#[test]
+fn invalid_number_span() {
+    let source = "print (1.2.3 + 1)";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidNumber);
+    assert_eq!(&source[err.span.clone()], "1.2.3");
+    assert_eq!(
+        err.to_string(),
+        "error at 1:8: failed to parse `1.2.3` as a number"
+    );
+
+    let err = Error {
+        source: "print 1x",
+        span: 6..8,
+        kind: ErrorKind::ParseFloatError("1x".parse::<f32>().unwrap_err()),
+    };
+    assert_eq!(
+        err.message(),
+        "failed to parse `1x` as a number (invalid float literal)"
+    );
+}
+
+#[test]
+fn number_literals() {
+    check_output(
+        "print 0.5 print -2.25 print 25e-01 print .5e-01 print 16777217 print 3.0 print (7 / 2)",
+        Ok("0.5\n-2.25\n2.5\n0.05\n16777216\n3\n3.5\n"),
+    );
+    check_output(
+        "switch 2 case 1 print 1 case 2.0 print 2 endswitch data t = [4.5, -1] print t[1]",
+        Ok("2\n-1\n"),
+    );
+
+    // many literals, each parsed once when lexed
+    let source: String = (0..2000).map(|i| format!("print {}.5\n", i)).collect();
+    let binary = compile(&source).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed.lines().count(), 2000);
+    assert_eq!(output.printed.lines().last(), Some("1999.5"));
+
+    let err = compile("print (1 + 1.2.3)").unwrap_err();
+    assert_eq!((err.kind, err.span), (ErrorKind::InvalidNumber, 11..16));
+    let err = compile("switch 1 case x print 1 endswitch").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::UnexpectedToken {
+            expected: &[Token::Number],
+            received: Token::Identifier
+        }
+    );
+    assert_eq!(err.span, 14..15);
+    let err = compile("data t = [1, (2)]").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::UnexpectedToken {
+            expected: &[Token::Number],
+            received: Token::LeftParen
+        }
+    );
+}
+
+#[test]
+fn type_names() {
+    let err = compile("print (1.0 && 2.0)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "error at 1:12: unexpected type, expected condition and condition, received number and number"
+    );
+    let err = compile("var x = (1 < 2)").unwrap_err();
+    assert_eq!(
+        err.message(),
+        "unexpected type, expected number, received condition"
+    );
+}
+
+#[test]
+fn bool_print() {
+    let binary = compile("print (1 < 2) print ((1 > 2) && (3 == 3)) print 1 prints \"a\"").unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "1\n0\n1\na");
+    let options = run::RunOptions {
+        bool_print: true,
+        ..Default::default()
+    };
+    let output = run::run_binary(&binary, &options).unwrap();
+    assert_eq!(output.printed, "true\nfalse\n1\na");
+
+    let out = Arc::new(Mutex::new(String::new()));
+    run_wasm::run_binary(&binary, out.clone()).unwrap();
+    assert_eq!(*out.lock().unwrap(), "1\n0\n1\na");
+
+    // a integer counter is compared as a i32 too
+    let options = CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options("var i = 0 i = (i + 1) print (i == 1)", &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "1\n");
+}
+
+#[test]
+fn deterministic_output() {
+    let source = "
+        proc a(x) b(x, x) endproc
+        proc b(x, y) c(x, y, x) endproc
+        proc c(x, y, z) print z endproc
+        a(1)";
+    let binary = compile(source).unwrap();
+    for _ in 0..20 {
+        assert_eq!(compile(source).unwrap(), binary);
+    }
+}
+
+/// Split a module in its sections, returning the id and content of each one.
+fn sections(binary: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut sections = Vec::new();
+    // skip magic and version
+    let mut rest = &binary[8..];
+    while !rest.is_empty() {
+        let id = rest[0];
+        rest = &rest[1..];
+        let len = leb128::read_unsigned(&mut rest).unwrap() as usize;
+        sections.push((id, &rest[..len]));
+        rest = &rest[len..];
+    }
+    sections
+}
+
+#[test]
+fn producers_section() {
+    let binary = compile("print 1").unwrap();
+    let custom: Vec<_> = sections(&binary)
+        .into_iter()
+        .filter(|(id, _)| *id == 0)
+        .collect();
+    assert_eq!(custom.len(), 1);
+
+    let mut content = custom[0].1;
+    let read_str = |content: &mut &[u8]| {
+        let len = leb128::read_unsigned(content).unwrap() as usize;
+        let s = std::str::from_utf8(&content[..len]).unwrap().to_string();
+        *content = &content[len..];
+        s
+    };
+    assert_eq!(read_str(&mut content), "producers");
+    assert_eq!(leb128::read_unsigned(&mut content).unwrap(), 1);
+    assert_eq!(read_str(&mut content), "processed-by");
+    assert_eq!(leb128::read_unsigned(&mut content).unwrap(), 1);
+    assert_eq!(read_str(&mut content), "chasm-rs");
+    assert_eq!(read_str(&mut content), env!("CARGO_PKG_VERSION"));
+    assert!(content.is_empty());
+
+    let options = CompileOptions {
+        producers_section: false,
+        ..Default::default()
+    };
+    let binary = compile_with_options("print 1", &options).unwrap();
+    assert!(sections(&binary).iter().all(|(id, _)| *id != 0));
+}
+
+#[test]
+fn integer_literal() {
+    // "i32.const 4", directly stored in the repeat counter
+    let integer = [0x41, 0x04, 0x21];
+    // "f32.const 4.0; i32.trunc_f32_s"
+    let float = [0x43, 0x00, 0x00, 0x80, 0x40, 0xa8];
+    let contains = |binary: &[u8], code: &[u8]| binary.windows(code.len()).any(|x| x == code);
+
+    let binary = compile("repeat 4 print 1 endrepeat").unwrap();
+    assert!(contains(&binary, &integer));
+    assert!(!contains(&binary, &float));
+
+    let binary = compile("repeat 4.0 print 1 endrepeat").unwrap();
+    assert!(!contains(&binary, &integer));
+    assert!(contains(&binary, &float));
+
+    let program = parse("print 4 print 4.0 print 1e01").unwrap();
+    let kinds: Vec<_> = program
+        .statements
+        .iter()
+        .map(|x| match &x.kind {
+            ast::StatementKind::Print(e) => e.kind.clone(),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ast::ExpressionKind::Integer(4),
+            ast::ExpressionKind::Number(4.0),
+            ast::ExpressionKind::Number(10.0)
+        ]
+    );
+}
+
+#[test]
+fn bare_condition() {
+    let bare = parse("if x + 1 > 2 * y && 0 < x < 10 print x endif").unwrap();
+    let parenthesized =
+        parse("if (((x + 1) > (2 * y)) && ((0 < x) && (x < 10))) print x endif").unwrap();
+    assert!(bare.alpha_eq(&parenthesized));
+
+    let program = parse("while i < 3 i = (i + 1) endwhile").unwrap();
+    let source = "while i < 3 i = (i + 1) endwhile";
+    match &program.statements[0].kind {
+        ast::StatementKind::While { condition, .. } => {
+            assert_eq!(&source[condition.span.clone()], "i < 3")
+        }
+        _ => unreachable!(),
+    }
+    assert_eq!(
+        crate::format("if x > 0 print x endif").unwrap(),
+        "if (x > 0)\n    print x\nendif\n"
+    );
+}
+
+#[test]
+fn alpha_eq() {
+    let a = parse(
+        "
+        var n = 10
+        proc draw(p, c = 255)
+            setpixel(p, p, c)
+        endproc
+        while (n > 0)
+            draw(n)
+            n = (n - 1)
+        endwhile",
+    )
+    .unwrap();
+    let b = parse(
+        "var count = 10 proc line(i, shade = 255) setpixel(i, i, shade) endproc
+        while (count > 0) line(count) count = (count - 1) endwhile",
+    )
+    .unwrap();
+    assert!(a.alpha_eq(&b));
+    assert!(b.alpha_eq(&a));
+
+    // a changed operator
+    let c = parse(
+        "var count = 10 proc line(i, shade = 255) setpixel(i, i, shade) endproc
+        while (count < 0) line(count) count = (count - 1) endwhile",
+    )
+    .unwrap();
+    assert!(!a.alpha_eq(&c));
+
+    let eq = |a, b| parse(a).unwrap().alpha_eq(&parse(b).unwrap());
+    // two variables can't be renamed to the same one
+    assert!(eq(
+        "var a = 1 var b = 2 print a",
+        "var x = 1 var y = 2 print x"
+    ));
+    assert!(!eq(
+        "var a = 1 var b = 2 print a",
+        "var x = 1 var x = 2 print x"
+    ));
+    assert!(!eq(
+        "var a = 1 var b = 2 print a",
+        "var x = 1 var y = 2 print y"
+    ));
+    // each procedure renames its own variables
+    assert!(eq(
+        "proc f(a) print a endproc proc g(a) var b = a print b endproc",
+        "proc f(x) print x endproc proc g(y) var b = y print b endproc"
+    ));
+    assert!(!eq(
+        "proc f(a, b) print a endproc",
+        "proc f(x, y) print y endproc"
+    ));
+    // but not the globals, that a variable of a procedure may shadow
+    assert!(eq(
+        "global g = 1 proc f() print g var a = 2 endproc proc h() var g = 3 print g endproc",
+        "global k = 1 proc f() print k var a = 2 endproc proc h() var b = 3 print b endproc"
+    ));
+    assert!(!eq(
+        "global g = 1 proc f() print g endproc",
+        "global g = 1 proc f() var a = 2 print a endproc"
+    ));
+    assert!(eq(
+        "proc f(x) return (x) endproc print f(1)",
+        "proc g(x) return (x) endproc print g(1)"
+    ));
+    // builtins are not renamed
+    assert!(!eq("print sign(1)", "print fract(1)"));
+    assert!(!eq("setpixel(1, 2, 3)", "blendpixel(1, 2, 3)"));
+    assert!(!eq("print 1", "print 1.0"));
+}
+
+#[test]
+fn format() {
+    let source = "var  x=1   global g = - 2.50
+proc  f(a,b = ( x+1 ))
+      if (a<b<3) return ((a+b)) else prints \"no\" endif
+  return (-a)
+endproc
+
+
+var p, q = f( 1 ,2)
+switch p case -1 pass case 2.0
+default loop break endloop endswitch
+repeat 3 setpixel(x,x,&f) endrepeat";
+    let formatted = crate::format(source).unwrap();
+    assert_eq!(
+        formatted,
+        "var x = 1
+global g = -2.50
+proc f(a, b = (x + 1))
+    if (a < b < 3)
+        return (a + b)
+    else
+        prints \"no\"
+    endif
+    return -a
+endproc
+
+var p, q = f(1, 2)
+switch p
+    case -1
+        pass
+    case 2.0
+    default
+        loop
+            break
+        endloop
+endswitch
+repeat 3
+    setpixel(x, x, &f)
+endrepeat
+"
+    );
+    assert_eq!(crate::format(&formatted).unwrap(), formatted);
+    assert!(parse(source).unwrap().alpha_eq(&parse(&formatted).unwrap()));
+
+    for example in [
+        include_str!("../../examples/fibonacci.chasm"),
+        include_str!("../../examples/gradient.chasm"),
+        include_str!("../../examples/julia.chasm"),
+        include_str!("../../examples/mandelbrot.chasm"),
+        include_str!("../../examples/sierpinski.chasm"),
+    ] {
+        let formatted = crate::format(example).unwrap();
+        assert_eq!(crate::format(&formatted).unwrap(), formatted);
+        assert!(parse(example)
+            .unwrap()
+            .alpha_eq(&parse(&formatted).unwrap()));
+    }
+
+    assert_eq!(crate::format("").unwrap(), "");
+    assert!(crate::format("print (1 +").is_err());
+}
+
+#[test]
+fn format_chains() {
+    // chained declarations, assignments and comparisons keep their source form
+    let source = "var a = b = c = 5
+a = b = (c + 1)
+
+if (1 < a < b)
+    var x = y = (a > 0 > b)
+endif
+while (a < b > 0 < 2) && ((a < b) && (b < 2))
+    a = (a + 1)
+endwhile
+";
+    let formatted = crate::format(source).unwrap();
+    assert_eq!(
+        formatted,
+        "var a = b = c = 5
+a = b = (c + 1)
+
+if (1 < a < b)
+    var x = y = (a > 0 > b)
+endif
+while ((a < b > 0 < 2) && ((a < b) && (b < 2)))
+    a = (a + 1)
+endwhile
+"
+    );
+    assert_eq!(crate::format(&formatted).unwrap(), formatted);
+    assert!(parse(source).unwrap().alpha_eq(&parse(&formatted).unwrap()));
+
+    assert_eq!(
+        crate::format("if 1 < 2 < 3 print 1 endif").unwrap(),
+        "if (1 < 2 < 3)\n    print 1\nendif\n"
+    );
+    assert_eq!(crate::format("var a=b = 1").unwrap(), "var a = b = 1\n");
+}
+
+#[test]
+fn format_return() {
+    // a single value is written without the parentheses of a list
+    let source = "proc f(a) if (a > 1) return ((a*a)) endif return (a) endproc
+proc g(a) return (a, -a) endproc
+proc h() return endproc";
+    let formatted = crate::format(source).unwrap();
+    assert_eq!(
+        formatted,
+        "proc f(a)
+    if (a > 1)
+        return (a * a)
+    endif
+    return a
+endproc
+proc g(a)
+    return (a, -a)
+endproc
+proc h()
+    return
+endproc
+"
+    );
+    assert_eq!(crate::format(&formatted).unwrap(), formatted);
+    assert!(parse(source).unwrap().alpha_eq(&parse(&formatted).unwrap()));
+}
+
+#[test]
+fn memory_pages() {
+    // "memory" import name, followed by a memory import with limits
+    let import = |limits: &[u8]| [b"\x06memory\x02".as_slice(), limits].concat();
+    let contains = |binary: &[u8], code: &[u8]| binary.windows(code.len()).any(|x| x == code);
+
+    // writes at 100000, out of the first page
+    let source = "setpixel(0, 1000, 1)";
+    let out = Arc::new(Mutex::new(String::new()));
+
+    let binary = compile(source).unwrap();
+    assert!(contains(&binary, &import(&[0x00, 1])));
+    assert!(run_wasm::run_binary(&binary, out.clone()).is_err());
+
+    let options = CompileOptions {
+        memory_min_pages: 4,
+        ..Default::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    assert!(contains(&binary, &import(&[0x00, 4])));
+    assert!(run_wasm::run_binary(&binary, out.clone()).is_ok());
+
+    let options = CompileOptions {
+        memory_min_pages: 4,
+        memory_max_pages: Some(8),
+        ..Default::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    assert!(contains(&binary, &import(&[0x01, 4, 8])));
+    assert!(run_wasm::run_binary(&binary, out).is_ok());
+
+    // the RGB canvas doesn't fit in a memory of a single page
+    let options = CompileOptions {
+        memory_max_pages: Some(1),
+        ..Default::default()
+    };
+    assert!(compile_with_options("setpixel(0, 0, 7)", &options).is_ok());
+    let err = compile_with_options("setrgb(0, 0, 255, 0, 0)", &options).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidMemoryLimits { min: 2, max: 1 });
+    assert_eq!(err.kind.code(), "E0025");
+}
+
+#[test]
+fn while_branches_on_condition() {
+    let binary = compile("while (i < 3) i = (i + 1) endwhile").unwrap();
+    #[rustfmt::skip]
+    let code = [
+        // loop, (i < 3), if
+        0x03, 0x40, 0x20, 0x00, 0x43, 0x00, 0x00, 0x40, 0x40, 0x5d, 0x04, 0x40,
+        // i = (i + 1)
+        0x20, 0x00, 0x43, 0x00, 0x00, 0x80, 0x3f, 0x92, 0x21, 0x00,
+        // br 1, end, end, end of function
+        0x0c, 0x01, 0x0b, 0x0b, 0x0b,
+    ];
+    assert!(binary.windows(code.len()).any(|x| x == code));
+}
+
+#[test]
+fn double_eqz_removed() {
+    // local.get 0, i32.eqz, i32.eqz, i32.eqz, i32.eqz, br_if 0, i32.const 69, i32.eqz, end
+    let mut code = vec![
+        0x20, 0x00, 0x45, 0x45, 0x45, 0x45, 0x0d, 0x00, 0x41, 0xc5, 0x00, 0x45, 0x0b,
+    ];
+    crate::compiler::remove_double_eqz(&mut code);
+    assert_eq!(code, [0x20, 0x00, 0x0d, 0x00, 0x41, 0xc5, 0x00, 0x45, 0x0b]);
+
+    let mut code = vec![0x20, 0x00, 0x45, 0x45, 0x45, 0x0d, 0x00, 0x0b];
+    crate::compiler::remove_double_eqz(&mut code);
+    assert_eq!(code, [0x20, 0x00, 0x45, 0x0d, 0x00, 0x0b]);
+}
+
+#[test]
+fn trace() {
+    let binary = compile("print (1 + 1)").unwrap();
+
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert!(output.trace.is_empty());
+
+    let options = run::RunOptions {
+        trace: true,
+        ..Default::default()
+    };
+    let output = run::run_binary(&binary, &options).unwrap();
+    assert_eq!(output.printed, "2\n");
+    let trace: Vec<String> = output.trace.iter().map(|x| x.instr.to_string()).collect();
+    let add = trace.iter().position(|x| x == "f32.add").unwrap();
+    let print = trace.iter().position(|x| x == "call 0").unwrap();
+    assert!(add < print, "{:?}", trace);
+    assert_eq!(output.trace[add].to_string(), "func 1: f32.add");
+}
+
+#[test]
+fn empty_program() {
+    let out = Arc::new(Mutex::new(String::new()));
+    let binary = compile("").unwrap();
+    run_wasm::run_binary(&binary, out.clone()).unwrap();
+    assert_eq!(*out.lock().unwrap(), "");
+
+    let options = CompileOptions {
+        allow_empty: false,
+        ..Default::default()
+    };
+    let error = compile_with_options("", &options).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::EmptyProgram);
+    assert_eq!(error.span, 0..0);
+    assert!(compile_with_options(" \n", &options).is_err());
+    assert!(compile_with_options("pass", &options).is_ok());
+}
+
+#[test]
+fn procedures() {
+    let source = "
+proc a() pass endproc
+proc b(x) var y = x endproc
+c(1, 2, 3)
+proc c(x, y, z) setpixel(x, y, z) endproc
+";
+    let procedures = super::procedures(source).unwrap();
+    let view: Vec<_> = procedures
+        .iter()
+        .map(|x| (x.name(), x.idx(), x.param_count(), x.local_count()))
+        .collect();
+    assert_eq!(
+        view,
+        [
+            ("main", 1, 0, 0),
+            ("a", 2, 0, 0),
+            ("b", 3, 1, 1),
+            ("c", 4, 3, 3)
+        ]
+    );
+}
+
+/// Decode the instructions of all functions in the code section.
+fn instructions(binary: &[u8]) -> Vec<crate::instr::Instr> {
+    let (_, mut code) = sections(binary)
+        .into_iter()
+        .find(|(id, _)| *id == 10)
+        .unwrap();
+    let mut instrs = Vec::new();
+    for _ in 0..leb128::read_unsigned(&mut code).unwrap() {
+        let len = leb128::read_unsigned(&mut code).unwrap() as usize;
+        let (mut body, rest) = code.split_at(len);
+        code = rest;
+        for _ in 0..leb128::read_unsigned(&mut body).unwrap() {
+            leb128::read_unsigned(&mut body).unwrap();
+            body = &body[1..];
+        }
+        while !body.is_empty() {
+            instrs.push(crate::instr::Instr::decode(&mut body).unwrap());
+        }
+    }
+    instrs
+}
+
+#[test]
+fn large_main() {
+    // a `main` with many locals of both types, and a long body after them
+    let mut source = String::new();
+    for i in 0..20_000 {
+        source += &format!("var v{i} = {i}\nrepeat 1 v{i} = (v{i} + 1) endrepeat\n");
+    }
+    source += "print v19999";
+    let options = CompileOptions {
+        optimize: true,
+        ..CompileOptions::default()
+    };
+    let binary = compile_with_options(&source, &options).unwrap();
+
+    // the locals of main are declared before its body, which decodes to the final `end`
+    let (_, mut code) = sections(&binary)
+        .into_iter()
+        .find(|(id, _)| *id == 10)
+        .unwrap();
+    let functions = leb128::read_unsigned(&mut code).unwrap();
+    let mut last = &[][..];
+    for _ in 0..functions {
+        let len = leb128::read_unsigned(&mut code).unwrap() as usize;
+        (last, code) = code.split_at(len);
+    }
+    let mut body = last;
+    let mut num_locals = 0;
+    for _ in 0..leb128::read_unsigned(&mut body).unwrap() {
+        num_locals += leb128::read_unsigned(&mut body).unwrap();
+        body = &body[1..];
+    }
+    assert!(num_locals >= 20_000);
+    let mut last_instr = None;
+    while !body.is_empty() {
+        last_instr = Some(crate::instr::Instr::decode(&mut body).unwrap());
+    }
+    assert_eq!(last_instr, Some(crate::instr::Instr::End));
+    // the procedure keeps the declaration of the locals apart from the code
+    let module = compile_to_module(&source, &options).unwrap();
+    let main = &module.procedures[0];
+    assert_eq!([main.locals(), main.code()].concat(), last);
+    assert_eq!(
+        run::run_binary(&binary, &Default::default())
+            .unwrap()
+            .printed,
+        "20000\n"
+    );
+    assert_eq!(compile_with_options(&source, &options).unwrap(), binary);
+}
+
+#[test]
+fn constant_propagation() {
+    use crate::instr::Instr;
+
+    let options = CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    let source = "
+var w = 100
+var h = (w / 2)
+print (w * h)
+print -h
+proc f(w) print (w * 2) endproc
+f(3)
+var a = 1
+a = 2
+print (a * 3)";
+    let output = |binary: &[u8]| {
+        let out = Arc::new(Mutex::new(String::new()));
+        run_wasm::run_binary(binary, out.clone()).unwrap();
+        let out = out.lock().unwrap().clone();
+        out
+    };
+
+    let binary = compile(source).unwrap();
+    let optimized = compile_with_options(source, &options).unwrap();
+    assert_eq!(output(&optimized), "5000\n-50\n6\n6\n");
+    assert_eq!(output(&binary), output(&optimized));
+
+    let instrs = instructions(&optimized);
+    // "(w * h)" is folded, "-h" too
+    assert!(instrs.contains(&Instr::F32Const(5000.0)));
+    assert!(instrs.contains(&Instr::F32Const(-50.0)));
+    assert!(!instrs.contains(&Instr::F32Div));
+    assert!(!instrs.contains(&Instr::F32Neg));
+    // "w" is a parameter in "f", and "a" is reassigned
+    assert_eq!(instrs.iter().filter(|x| **x == Instr::F32Mul).count(), 2);
+}
+
+#[test]
+fn constant_propagation_scope() {
+    let options = CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    // the uses before the declaration, or out of its block, are not the same variable
+    let source = "
+print x
+var x = 5
+print x
+if (x == 5)
+    var y = 2
+    print (x * y)
+endif
+print y
+repeat 2
+    print z
+    var z = 3
+endrepeat";
+    for options in [CompileOptions::default(), options] {
+        let out = Arc::new(Mutex::new(String::new()));
+        let binary = compile_with_options(source, &options).unwrap();
+        run_wasm::run_binary(&binary, out.clone()).unwrap();
+        assert_eq!(*out.lock().unwrap(), "0\n5\n10\n0\n0\n0\n");
+    }
+}
+
+#[test]
+fn disassemble() {
+    let binary = compile("print (1+1)").unwrap();
+    let functions = crate::instr::disassemble(&binary).unwrap();
+    assert_eq!(
+        functions,
+        [["f32.const 1", "f32.const 1", "f32.add", "call 0", "end"]]
+    );
+
+    let binary = compile("proc f(x) setpixel(x, 0, 255) endproc f(1)").unwrap();
+    let functions = crate::instr::disassemble(&binary).unwrap();
+    assert_eq!(functions.len(), 2);
+    assert_eq!(functions[0], ["f32.const 1", "call 2", "end"]);
+    assert!(functions[1].contains(&"i32.store8".to_string()));
+
+    assert!(crate::instr::disassemble(&binary[..binary.len() - 1]).is_err());
+}
+
+#[test]
+fn print_precision() {
+    let binary = compile("print (1 / 3) print 2").unwrap();
+
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "0.33333334\n2\n");
+
+    let options = run::RunOptions {
+        print_precision: Some(2),
+        ..Default::default()
+    };
+    let output = run::run_binary(&binary, &options).unwrap();
+    assert_eq!(output.printed, "0.33\n2.00\n");
+}
+
+#[test]
+fn saturating_color() {
+    let source = "setpixel(0, 0, (0 / 0)) setpixel(1, 0, 200) setpixel(2, 0, 1e20)";
+    let binary = compile(source).unwrap();
+    assert_eq!(
+        run::run_binary(&binary, &run::RunOptions::default()),
+        Err(run::RunError::Trap(run::Trap::InvalidConversionToInteger))
+    );
+
+    let options = CompileOptions {
+        saturating_color: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    // 1e20 is clamped to i32::MAX, whose lowest byte is 255
+    assert_eq!(output.canvas[..4], [0, 200, 255, 0]);
+}
+
+#[test]
+fn parallel_codegen() {
+    let mut source = String::new();
+    for i in 0..50 {
+        source += &format!(
+            "proc p{i}(a, b = {i}) prints \"p{i}\" print (a + b) p{j}(a) endproc\n",
+            i = i,
+            j = (i * 7) % 50
+        );
+    }
+    source += "var f = &p3 f(1, 2) p0(1) if (1 < 0) p49(0) endif";
+
+    let serial = CompileOptions {
+        parallel: false,
+        ..Default::default()
+    };
+    let parallel = CompileOptions {
+        parallel: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        compile_with_options(&source, &serial).unwrap(),
+        compile_with_options(&source, &parallel).unwrap()
+    );
+
+    // the error that comes first in the source is the one reported
+    let source = "proc a() print (1 && 1) endproc print (2 && 2)";
+    for options in [&serial, &parallel] {
+        let err = compile_with_options(source, options).unwrap_err();
+        assert_eq!(err.span.start, 18);
+    }
+}
+
+#[test]
+fn module_serialize() {
+    let source = "proc frame(t) setpixel(t, 0, 255) endproc print 1";
+    let options = CompileOptions::default();
+    let module = compile_to_module(source, &options).unwrap();
+    assert_eq!(module.serialize(), compile(source).unwrap());
+
+    let mut renamed = module.clone();
+    for (name, _) in &mut renamed.exports {
+        if name == "main" {
+            *name = "start".to_string();
+        }
+    }
+    let binary = renamed.serialize();
+    assert!(binary.windows(5).any(|x| x == b"start"));
+    assert!(!binary.windows(4).any(|x| x == b"main"));
+    // the bundled interpreter only runs "main"
+    assert!(run::run_binary(&binary, &run::RunOptions::default()).is_err());
+    assert!(run::run_binary(&module.serialize(), &run::RunOptions::default()).is_ok());
+}
+
+#[test]
+fn int_counter() {
+    // a f32 stops incrementing at 16777216, so this only terminates with a i32 counter
+    let source = "
+        var i = 0
+        while (i < 20000000)
+            i = (i + 1)
+        endwhile
+        print i";
+    let options = CompileOptions {
+        optimize: true,
+        ..CompileOptions::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    let functions = instr::disassemble(&binary).unwrap();
+    assert!(functions[0].iter().any(|x| x == "i32.add"));
+    assert!(functions[0].iter().any(|x| x == "i32.lt_s"));
+
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "20000000\n");
+
+    // setpixel only assigns to `x` with the legacy side effects
+    let source = "
+        var x = 0
+        while (x < 20000000)
+            setpixel(0, 0, 9)
+            x = (x + 1)
+        endwhile
+        print x";
+    let module = compile_to_module(source, &options).unwrap();
+    assert_eq!(module.procedures[0].locals(), [2, 1, 0x7f, 3, 0x7d]);
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "20000000\n");
+    let options = CompileOptions {
+        legacy_setpixel_sideeffects: true,
+        ..options
+    };
+    let module = compile_to_module(source, &options).unwrap();
+    assert_eq!(module.procedures[0].locals(), [1, 3, 0x7d]);
+}
+
+#[test]
+fn int_counter_same_output() {
+    let options = CompileOptions {
+        optimize: true,
+        ..CompileOptions::default()
+    };
+    // large steps, or integers that a f32 doesn't represent exactly, keep the variable a f32
+    for source in [
+        "var i = 2000000000 i = (i + 2000000000) print i",
+        "var i = 0 i = (i - 2000000000) i = (i - 2000000000) print i",
+        "var i = 16777217 print i i = (i - 1) print (i == 16777216)",
+        "var i = 0 i = 30000000 i = (i + 1) print i",
+        "var i = 0 repeat 5 i = (i + 3) endrepeat print i i = (i - 1) print i",
+    ] {
+        let plain = compile(source).unwrap();
+        let plain = run::run_binary(&plain, &run::RunOptions::default()).unwrap();
+        let optimized = compile_with_options(source, &options).unwrap();
+        let optimized = run::run_binary(&optimized, &run::RunOptions::default()).unwrap();
+        assert_eq!(optimized.printed, plain.printed, "{}", source);
+    }
+}
+
+#[test]
+fn profile_calls() {
+    let source = "
+     proc A (x) B(x, 2) endproc
+     proc B (x, y) C(x, y, 4) endproc
+     proc C (x, y, z) print ((x+y)+z) endproc
+     A(1)";
+    let binary = compile(source).unwrap();
+    let options = run::RunOptions {
+        profile: true,
+        ..Default::default()
+    };
+    let output = run::run_binary(&binary, &options).unwrap();
+    let procedures = crate::procedures(source).unwrap();
+    assert_eq!(procedures.len(), 4);
+    for procedure in &procedures {
+        assert_eq!(output.calls[&procedure.idx()], 1, "{}", procedure.name());
+    }
+    // imported functions are not counted
+    assert_eq!(output.calls.len(), 4);
+
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert!(output.calls.is_empty());
+}
+
+#[test]
+fn blendpixel() {
+    let source = "
+        blendpixel(1, 0, 100)
+        blendpixel(1, 0, 100)
+        setpixel(2, 0, 200)
+        blendpixel(2, 0, 100)
+        blendpixel(3, 0, -5)
+        var x = 7
+        blendpixel(4, 0, 1)
+        print x";
+    let binary = compile(source).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.canvas[..5], [0, 200, 255, 0, 1]);
+    // unlike setpixel, the arguments are not assigned to variables
+    assert_eq!(output.printed, "7\n");
+
+    let out = Arc::new(Mutex::new(String::new()));
+    assert_eq!(run_wasm::run_binary(&binary, out).unwrap(), output.canvas);
+
+    let error = compile("blendpixel(1, 2)").unwrap_err();
+    assert_eq!(
+        error.kind,
+        ErrorKind::ArgumentNumberMismatch {
+            expected: 3,
+            received: 2
+        }
+    );
+}
+
+#[test]
+fn setrgb() {
+    let source = "setrgb(2, 1, 255, 0, 0) setrgb(99, 99, 1, 2, 3) setpixel(0, 0, 7)";
+    let module = compile_to_module(source, &CompileOptions::default()).unwrap();
+    // the RGB canvas is in the second page
+    assert_eq!(module.memory_min_pages, 2);
+
+    let options = run::RunOptions {
+        rgb: true,
+        ..Default::default()
+    };
+    let output = run::run_binary(&module.serialize(), &options).unwrap();
+    assert_eq!(output.canvas.len(), 30000);
+    let red = (100 + 2) * 3;
+    assert_eq!(output.canvas[red..red + 3], [255, 0, 0]);
+    assert_eq!(output.canvas[29997..], [1, 2, 3]);
+    // the grayscale canvas is separate
+    assert_eq!(output.canvas[..3], [0, 0, 0]);
+    let output = run::run_binary(&module.serialize(), &Default::default()).unwrap();
+    assert_eq!(output.canvas[..3], [7, 0, 0]);
+
+    let error = compile("setrgb(1, 2, 3)").unwrap_err();
+    assert_eq!(
+        error.kind,
+        ErrorKind::ArgumentNumberMismatch {
+            expected: 5,
+            received: 3
+        }
+    );
+}
+
+#[test]
+fn data_before_rgb_canvas() {
+    let limit = (crate::RGB_CANVAS_OFFSET - crate::compiler::STRING_DATA_OFFSET) as usize;
+    // the data ends right before the RGB canvas
+    let table = |n| format!("data t = [{}]", vec!["1"; n].join(", "));
+    let source = format!(
+        "{} prints \"abcd\" setrgb(0, 0, 1, 2, 3)",
+        table(limit / 4 - 1)
+    );
+    assert!(compile(&source).is_ok());
+
+    let source = format!(
+        "{} prints \"abcde\" setrgb(0, 0, 1, 2, 3)",
+        table(limit / 4 - 1)
+    );
+    let error = compile(&source).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ErrorKind::ModuleTooLarge {
+            section: "data",
+            size: limit as u64 + 1,
+            limit: limit as u64
+        }
+    );
+    assert_eq!(&source[error.span], "prints \"abcde\"");
+
+    let source = table(limit / 4 + 1);
+    let error = compile(&source).unwrap_err();
+    assert_eq!(error.span, 0..source.len());
+}
+
+#[test]
+fn infinite_loop_warning() {
+    let source = "
+        loop
+            if (1 == 1) break endif
+        endloop
+        loop
+            while (1 == 1) break endwhile
+        endloop";
+    let (_, warnings) = compile_with_warnings(source, &CompileOptions::default()).unwrap();
+    assert_eq!(
+        warnings,
+        vec![
+            Warning::ConstantCondition {
+                value: true,
+                span: 29..37
+            },
+            Warning::PossibleInfiniteLoop { span: 74..136 },
+            Warning::ConstantCondition {
+                value: true,
+                span: 97..105
+            },
+        ]
+    );
+    assert_eq!(
+        warnings[1].to_string(),
+        "this loop has no `break`, so it never ends"
+    );
+}
+
+#[test]
+fn constant_condition_warning() {
+    let options = CompileOptions::default();
+    let source = "if (1==2) print 1 endif";
+    let (_, warnings) = compile_with_warnings(source, &options).unwrap();
+    assert_eq!(
+        warnings,
+        vec![Warning::ConstantCondition {
+            value: false,
+            span: 3..9
+        }]
+    );
+    assert_eq!(warnings[0].to_string(), "condition always false");
+    assert_eq!(warnings[0].code(), "W0004");
+
+    // folded before comparing, and a `&&` with a false side is always false
+    let source = "var x = 1 if ((2 * 3) == (12 / 2)) print x endif";
+    let (_, warnings) = compile_with_warnings(source, &options).unwrap();
+    assert_eq!(warnings[0].to_string(), "condition always true");
+    let source = "var x = 1 if ((x > 0) && (1 > 2)) print x endif";
+    let (_, warnings) = compile_with_warnings(source, &options).unwrap();
+    assert_eq!(warnings[0].to_string(), "condition always false");
+
+    // a always true `while` without a `break` never ends
+    let source = "var x = 0 while (1 == 1) x = (x + 1) endwhile";
+    let (_, warnings) = compile_with_warnings(source, &options).unwrap();
+    let codes: Vec<_> = warnings.iter().map(|x| x.code()).collect();
+    assert_eq!(codes, ["W0002", "W0004"]);
+
+    // guards that depend on variables, and a `loop`, don't warn
+    let source =
+        "var x = 0 while (x < 3) x = (x + 1) endwhile if ((x > 0) && (x < 5)) print x endif
+        loop x = (x + 1) if x > 10 break endif endloop";
+    let (_, warnings) = compile_with_warnings(source, &options).unwrap();
+    assert_eq!(warnings, []);
+}
+
+#[test]
+fn optimized_warnings() {
+    // the warnings are about the program as written, so optimizing it changes none
+    let optimized = CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    for source in [
+        "var k = 3 print (k + 1)",
+        "var k = 3 var u = 2 print k",
+        "var x = 1 var x = 2 print x",
+        "var x = 1 if ((x > 0) && (1 > 2)) print x endif",
+        "var i = 0 while (i < 3) var s = (i * i) i = (i + 1) endwhile",
+        "var x = 0 while (1 == 1) x = (x + 1) endwhile",
+        "proc f(a) var b = 1 return (a) endproc print f(2)",
+    ] {
+        let (_, plain) = compile_with_warnings(source, &CompileOptions::default()).unwrap();
+        let (_, warnings) = compile_with_warnings(source, &optimized).unwrap();
+        assert_eq!(warnings, plain, "{}", source);
+    }
+}
+
+#[test]
+fn validate_source_errors() {
+    assert!(validate_source("var x = 1 print (x + 2)").is_ok());
+
+    let source = "
+        proc f() var x = (1 < 2) endproc
+        print sqrt(4)
+        f()
+        g(1)";
+    let errors = validate_source(source).unwrap_err();
+    let kinds: Vec<_> = errors.iter().map(|x| x.kind.code()).collect();
+    assert_eq!(kinds, ["E0004", "E0008", "E0005"]);
+    assert!(errors.windows(2).all(|x| x[0].span.start < x[1].span.start));
+    // compile reports the first one
+    let error = compile(source).unwrap_err();
+    assert_eq!(
+        (error.kind, error.span),
+        (
+            ErrorKind::UnexpectedType {
+                expected: &[Type::F32],
+                received: vec![Type::I32]
+            },
+            errors[0].span.clone()
+        )
+    );
+
+    let errors = validate_source("print print").unwrap_err();
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn mismatched_block_end() {
+    let cases = [
+        (
+            "while (x < 1)\n  x = 1\nendif",
+            Token::EndWhile,
+            Token::EndIf,
+            0..5,
+        ),
+        (
+            "print 1 if (1 < 2) print 2 endwhile",
+            Token::EndIf,
+            Token::EndWhile,
+            8..10,
+        ),
+        (
+            "proc f()\n  print 1\nendif",
+            Token::EndProc,
+            Token::EndIf,
+            0..4,
+        ),
+        (
+            "proc f() if (1 < 2) print 1 else print 2 endproc",
+            Token::EndIf,
+            Token::EndProc,
+            9..11,
+        ),
+        (
+            "switch 1 case 1 loop break endswitch",
+            Token::EndLoop,
+            Token::EndSwitch,
+            16..20,
+        ),
+    ];
+    for (source, expected, found, opened_at) in cases {
+        let err = compile(source).unwrap_err();
+        let end = source.rfind("end").unwrap();
+        assert_eq!(err.span.start, end, "{}", source);
+        assert_eq!(
+            err.kind,
+            ErrorKind::MismatchedBlockEnd {
+                expected,
+                found,
+                opened_at
+            }
+        );
+    }
+
+    let err = compile("while (x < 1)\n  x = 1\nendif").unwrap_err();
+    assert_eq!(err.kind.code(), "E0020");
+    assert_eq!(
+        err.to_string(),
+        "error at 3:1: mismatched block end, expected \"endwhile\", found \"endif\", for the \
+         block opened at 1:1"
+    );
+    // a end without a open block is still a unexpected token
+    let err = compile("print 1 endif").unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::UnexpectedToken { .. }));
+}
+
+#[test]
+fn non_boolean_condition() {
+    for source in [
+        "var x = 3 while x x = (x - 1) endwhile",
+        "var x = 3 if x print x endif",
+    ] {
+        let err = compile(source).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NonBooleanCondition);
+        assert_eq!(&source[err.span.clone()], "x");
+        assert_eq!(err.kind.code(), "E0019");
+        assert_eq!(
+            err.message(),
+            "conditions must be a comparison like `(x > 0)`"
+        );
+    }
+    // a number as a operand of `&&` is still a type error
+    let err = compile("var x = 3 if ((x > 1) && x) print x endif").unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::UnexpectedType { .. }));
+}
+
+#[test]
+fn setpixel_arg_types() {
+    let sources = [
+        "setpixel((1 < 2), 0, 255)",
+        "setpixel(0, (1 < 2), 255)",
+        "setpixel(0, 0, (1 < 2))",
+    ];
+    for source in sources {
+        let error = compile(source).unwrap_err();
+        let start = source.find('(').unwrap() + 1;
+        let start = start + source[start..].find("(1").unwrap();
+        assert_eq!(
+            (error.kind, error.span),
+            (
+                ErrorKind::UnexpectedType {
+                    expected: &[Type::F32],
+                    received: vec![Type::I32]
+                },
+                start..start + "(1 < 2)".len()
+            ),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn constant_expression() {
+    assert_eq!(compile_expr("((3*2)-(21/7))").unwrap(), 3.0);
+    assert_eq!(compile_expr("-(1 / 4)").unwrap(), -0.25);
+
+    for source in ["x", "(1 + x)", "sign(2)", "(1 < 2)"] {
+        let error = compile_expr(source).unwrap_err();
+        assert_eq!(
+            (error.kind, error.span),
+            (ErrorKind::NotConstant, 0..source.len()),
+            "{}",
+            source
+        );
+    }
+    let error = compile_expr("1 print 2").unwrap_err();
+    assert!(matches!(error.kind, ErrorKind::UnexpectedToken { .. }));
+}
+
+#[test]
+fn max_call_depth() {
+    let binary = compile("proc f(n) f((n + 1)) endproc f(0)").unwrap();
+    assert_eq!(
+        run::run_binary(&binary, &run::RunOptions::default()),
+        Err(run::RunError::StackOverflow)
+    );
+
+    // main, and 9 calls of f
+    let binary = compile(
+        "proc f(n) print n if (n < 9) f((n + 1)) endif endproc
+        f(1)",
+    )
+    .unwrap();
+    let options = run::RunOptions {
+        max_call_depth: Some(10),
+        ..Default::default()
+    };
+    let output = run::run_binary(&binary, &options).unwrap();
+    assert_eq!(output.printed, "1\n2\n3\n4\n5\n6\n7\n8\n9\n");
+    let options = run::RunOptions {
+        max_call_depth: Some(9),
+        ..Default::default()
+    };
+    assert_eq!(
+        run::run_binary(&binary, &options),
+        Err(run::RunError::StackOverflow)
+    );
+}
+
+#[test]
+fn and_short_circuit() {
+    // maxpixel reads the whole canvas, which is observable in the trace
+    let reads_canvas = |source| {
+        let binary = compile(source).unwrap();
+        let options = run::RunOptions {
+            trace: true,
+            ..Default::default()
+        };
+        let output = run::run_binary(&binary, &options).unwrap();
+        let reads = output
+            .trace
+            .iter()
+            .filter(|x| matches!(x.instr, crate::instr::Instr::I32Load8U { .. }));
+        (output.printed, reads.count())
+    };
+    let (printed, reads) = reads_canvas(
+        "var x = 0 if ((x > 1) && (maxpixel() == 0)) print 1 endif print ((x > 1) && (maxpixel() == 0))",
+    );
+    assert_eq!((printed.as_str(), reads), ("0\n", 0));
+
+    let (printed, reads) = reads_canvas("var x = 2 print ((x > 1) && (maxpixel() == 0))");
+    assert_eq!((printed.as_str(), reads), ("1\n", 10000));
+
+    // the result is reset each time the expression is evaluated
+    let source = "
+        var i = 0
+        while (i < 2)
+            print ((i < 1) && (i == 0))
+            i = (i + 1)
+        endwhile";
+    check_output(source, Ok("1\n0\n"));
+}
+
+#[test]
+fn data_table() {
+    let source = "
+proc shade(i)
+    print palette[i]
+endproc
+data palette = [0, 85.5, -1, 1000]
+var i = 0
+while (i < 4)
+    shade(i)
+    i = (i + 1)
+endwhile
+print palette[(3 - 0.5)]";
+    check_output(source, Ok("0\n85.5\n-1\n1000\n-1\n"));
+
+    // the table comes first in the data, before the strings
+    let options = CompileOptions::default();
+    let module = compile_to_module("data t = [1, 2] prints \"a\"", &options).unwrap();
+    let mut expected = [1.0f32.to_le_bytes(), 2.0f32.to_le_bytes()].concat();
+    expected.push(b'a');
+    assert_eq!(module.data, expected);
+
+    let err = compile("print t[0]").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::UndeclaredData {
+            name: "t".to_string()
+        }
+    );
+    assert_eq!(err.kind.code(), "E0018");
+    let source = "data t = [1] data t = [5, 6] print t[1]";
+    let err = compile(source).unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::DuplicateData {
+            name: "t".to_string()
+        }
+    );
+    assert_eq!(err.span, 18..19);
+    assert_eq!(err.kind.code(), "E0023");
+
+    // a constant index must be in the table, and other ones read its closest value
+    for source in [
+        "data t = [1, 2, 3] print t[3]",
+        "data t = [1, 2, 3] print t[(0 - 1)]",
+    ] {
+        let err = compile(source).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::DataOutOfBounds {
+                name: "t".to_string(),
+                len: 3
+            },
+            "{}",
+            source
+        );
+        assert_eq!(err.span, 27..source.len() - 1);
+        assert_eq!(err.kind.code(), "E0024");
+    }
+    let source = "data t = [1, 2, 3] var i = 3 print t[i] i = (0 - 2) print t[i]";
+    check_output(source, Ok("3\n1\n"));
+    let options = CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    let err = compile_to_module("data t = [1] print t[1]", &options).unwrap_err();
+    assert_eq!(err.kind.code(), "E0024");
+    let err = compile("proc f() data t = [1] endproc").unwrap_err();
+    assert_eq!(err.span, 9..13);
+    let err = compile("data t = [1, x]").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::UnexpectedToken {
+            expected: &[Token::Number],
+            received: Token::Identifier
+        }
+    );
+
+    let formatted = crate::format("data t=[1,-2.50]\nprint t[ 1 ]").unwrap();
+    assert_eq!(formatted, "data t = [1, -2.50]\nprint t[1]\n");
+    let a = parse("data t = [1] print t[0]").unwrap();
+    let b = parse("data u = [1] print u[0]").unwrap();
+    assert!(a.alpha_eq(&b));
+}
+
+#[test]
+fn fuel() {
+    let binary = compile("loop pass endloop").unwrap();
+    let options = run::RunOptions {
+        fuel: Some(1000),
+        ..Default::default()
+    };
+    assert_eq!(
+        run::run_binary(&binary, &options),
+        Err(run::RunError::OutOfFuel)
+    );
+
+    // exactly the fuel needed to run each instruction once
+    let binary = compile("var i = 0 while (i < 3) print i i = (i + 1) endwhile").unwrap();
+    let options = run::RunOptions {
+        trace: true,
+        ..Default::default()
+    };
+    let executed = run::run_binary(&binary, &options).unwrap().trace.len() as u64;
+    let options = run::RunOptions {
+        fuel: Some(executed),
+        ..Default::default()
+    };
+    assert_eq!(
+        run::run_binary(&binary, &options).unwrap().printed,
+        "0\n1\n2\n"
+    );
+    let options = run::RunOptions {
+        fuel: Some(executed - 1),
+        ..Default::default()
+    };
+    assert_eq!(
+        run::run_binary(&binary, &options),
+        Err(run::RunError::OutOfFuel)
+    );
+}
+
+#[test]
+fn max_prints() {
+    let binary = compile("while (1 == 1) print 1 endwhile").unwrap();
+    let options = run::RunOptions {
+        max_prints: Some(3),
+        ..Default::default()
+    };
+    assert_eq!(
+        run::run_binary(&binary, &options),
+        Err(run::RunError::OutputLimitExceeded {
+            printed: "1\n1\n1\n".to_string()
+        })
+    );
+
+    // strings and conditions count too, and exactly the limit is fine
+    let binary = compile("prints \"a\" print (1 < 2) print 2").unwrap();
+    assert_eq!(
+        run::run_binary(&binary, &options).unwrap().printed,
+        "a1\n2\n"
+    );
+}
+
+#[test]
+fn loop_invariant_hoisting() {
+    let options = CompileOptions {
+        optimize: true,
+        ..CompileOptions::default()
+    };
+    let source = "
+        proc f(limit, scale)
+            var x = 0
+            while (x < (limit / scale))
+                x = (x + 1)
+            endwhile
+            print x
+        endproc
+        f(100, 4)";
+    let binary = compile_with_options(source, &options).unwrap();
+    let functions = instr::disassemble(&binary).unwrap();
+    let f = &functions[1];
+    let divs: Vec<_> = (0..f.len()).filter(|&i| f[i] == "f32.div").collect();
+    let loop_start = f.iter().position(|x| x == "loop").unwrap();
+    assert_eq!(divs.len(), 1);
+    assert!(divs[0] < loop_start);
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "25\n");
+
+    // the legacy setpixel writes to `color`, and a procedure may write to any global, even when
+    // called in a expression
+    let options = CompileOptions {
+        legacy_setpixel_sideeffects: true,
+        ..options
+    };
+    let source = "
+        var color = 0
+        global g = 1
+        proc step() g = 2 endproc
+        var i = 0
+        while (i < (color + 3))
+            setpixel(i, 0, 10)
+            i = (i + 1)
+        endwhile
+        print i
+        while (i < (g * 20))
+            step()
+            i = (i + 1)
+        endwhile
+        print i
+        proc next() g = 3 return (1) endproc
+        while (i < ((g * 20) + 1))
+            i = (i + next())
+        endwhile
+        print i";
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "13\n40\n61\n");
+
+    // a table index traps when out of bounds, so it is not computed before the loop, and neither
+    // is the right side of a `&&`, that the loop may never evaluate
+    let source = "
+        data t = [1, 2, 3]
+        var k = (0 - 1000000000)
+        while ((k > 0) && ((t[k] + 0) > 5))
+            print k
+        endwhile
+        print 42";
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "42\n");
+
+    let source = "
+        proc f(n)
+            var i = 0
+            while ((i < 3) && (i < (n / 2)))
+                i = (i + 1)
+            endwhile
+            print i
+        endproc
+        f(4)";
+    let binary = compile_with_options(source, &options).unwrap();
+    let functions = instr::disassemble(&binary).unwrap();
+    let f = &functions[1];
+    let div = f.iter().position(|x| x == "f32.div").unwrap();
+    let loop_start = f.iter().position(|x| x == "loop").unwrap();
+    assert!(div > loop_start);
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "2\n");
+}
+
+#[test]
+fn shared_subexpressions() {
+    let source = include_str!("../../examples/mandelbrot.chasm");
+    // the squares `(i * i)` and `(j * j)` computed inside the innermost loop
+    let squares = |binary: &[u8]| {
+        let functions = instr::disassemble(binary).unwrap();
+        let main = &functions[0];
+        let start = main.iter().rposition(|x| x == "loop").unwrap();
+        let end = start + main[start..].iter().position(|x| x == "br 1").unwrap();
+        main[start..end]
+            .windows(3)
+            .filter(|x| x[0] == x[1] && x[0].starts_with("local.get") && x[2] == "f32.mul")
+            .count()
+    };
+    let binary = compile(source).unwrap();
+    assert_eq!(squares(&binary), 4);
+    let expected = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+
+    let options = CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options(source, &options).unwrap();
+    assert_eq!(squares(&binary), 2);
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(blake3::hash(&output.canvas), blake3::hash(&expected.canvas));
+
+    // a subexpression is not shared after its variable is assigned, or when it is shadowed
+    let source = "
+        var i = 0
+        while ((i * i) < 50)
+            i = (i + 1)
+            print (i * i)
+        endwhile
+        var j = 0
+        while ((j * 2) < 6)
+            print (j * 2)
+            var j = 5
+            print (j * 2)
+            break
+        endwhile";
+    let binary = compile(source).unwrap();
+    let expected = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    let binary = compile_with_options(source, &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, expected.printed);
+
+    let mut program =
+        parse("var i = 1 while ((i * i) < 50) print (i * i) i = (i + 1) endwhile").unwrap();
+    crate::optimize::optimize(&mut program);
+    let expected =
+        parse("var i = 1 var t = (i * i) while (t < 50) print t i = (i + 1) t = (i * i) endwhile")
+            .unwrap();
+    assert!(program.alpha_eq(&expected));
+}
+
+#[test]
+fn setpixel_writes_not_propagated() {
+    let options = CompileOptions {
+        optimize: true,
+        legacy_setpixel_sideeffects: true,
+        ..CompileOptions::default()
+    };
+    let binary =
+        compile_with_options("var color = 5 setpixel(0, 0, 9) print color", &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "9\n");
+}
+
+#[test]
+fn full_memory() {
+    let binary = compile("setpixel(0, 200, 7)").unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.canvas.len(), 100 * 100);
+    assert!(output.memory.is_empty());
+
+    let options = run::RunOptions {
+        full_memory: true,
+        ..Default::default()
+    };
+    let output = run::run_binary(&binary, &options).unwrap();
+    assert_eq!(output.canvas.len(), 100 * 100);
+    assert_eq!(output.memory.len(), 64 * 1024);
+    assert_eq!(output.memory[20000], 7);
+}
+
+#[test]
+fn assignment_in_condition() {
+    let err = compile("if (x = 1) print 1 endif").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::AssignmentInExpression);
+    assert_eq!(err.span, 6..7);
+    assert_eq!(
+        err.to_string(),
+        "error at 1:7: unexpected assignment in a expression, use `==` to compare values"
+    );
+    assert_eq!(err.kind.code(), "E0015");
+}
+
+#[test]
+fn unexpected_character() {
+    let err = compile("print @ 1").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnexpectedCharacter);
+    assert_eq!(err.span, 6..7);
+    assert_eq!(err.to_string(), "error at 1:7: unexpected character `@`");
+    assert_eq!(
+        compile("prints \"hi").unwrap_err().message(),
+        "unterminated string literal"
+    );
+    // a syntax error before the character is reported first
+    let err = compile("print print $").unwrap_err();
+    assert_eq!(err.span, 6..11);
+
+    // the characters are skipped, so the rest of the program is still checked
+    let errors = validate_source("print @ 1 var x = $ (1 < 2)").unwrap_err();
+    let errors: Vec<_> = errors.into_iter().map(|x| (x.kind, x.span)).collect();
+    assert_eq!(
+        errors,
+        [
+            (ErrorKind::UnexpectedCharacter, 6..7),
+            (ErrorKind::UnexpectedCharacter, 18..19),
+            (
+                ErrorKind::UnexpectedType {
+                    expected: &[Type::F32],
+                    received: vec![Type::I32]
+                },
+                20..27
+            ),
+        ]
+    );
+    let errors = validate_source("print # print print").unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].kind, ErrorKind::UnexpectedCharacter);
+}
+
+#[test]
+fn export_procedures() {
+    let source = "
+        proc helper(a, b) print (a * b) endproc
+        proc frame(t) print t endproc
+        print 1";
+    let options = CompileOptions {
+        exports: vec!["helper".to_string(), "frame".to_string()],
+        ..Default::default()
+    };
+    let module = compile_to_module(source, &options).unwrap();
+    let names: Vec<_> = module
+        .exports
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    assert_eq!(names, ["main", "frame", "helper"]);
+
+    let binary = module.serialize();
+    let out = Arc::new(Mutex::new(String::new()));
+    run_wasm::run_export(&binary, "helper", &[3.0, 4.0], out.clone()).unwrap();
+    assert_eq!(*out.lock().unwrap(), "12\n");
+
+    let options = CompileOptions {
+        exports: vec!["missing".to_string()],
+        ..Default::default()
+    };
+    let err = compile_with_options(source, &options).unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::UndeclaredProc {
+            name: "missing".to_string()
+        }
+    );
+}
+
+#[test]
+fn bounds_checks() {
+    let options = CompileOptions {
+        bounds_checks: true,
+        ..Default::default()
+    };
+    let binary = compile_with_options("setpixel(99, 99, 7)", &options).unwrap();
+    let output = run::run_binary(&binary, &run::RunOptions::default()).unwrap();
+    assert_eq!(output.canvas[9999], 7);
+
+    for source in [
+        "setpixel(0, 200, 7)",
+        "setpixel(-1, 0, 7)",
+        "blendpixel(0, 100, 7)",
+        "data t = [1, 2, 3] var i = 3 print t[i]",
+        "data t = [1, 2, 3] var i = (0 - 1) print t[i]",
+        "data t = [1, 2, 3] var i = (0 / 0) print t[i]",
+    ] {
+        let binary = compile_with_options(source, &options).unwrap();
+        assert_eq!(
+            run::run_binary(&binary, &run::RunOptions::default()),
+            Err(run::RunError::Trap(run::Trap::Unreachable)),
+            "{}",
+            source
+        );
+        let out = Arc::new(Mutex::new(String::new()));
+        let err = run_wasm::run_binary(&binary, out).unwrap_err();
+        assert!(err.to_string().contains("Unreachable"), "{}", err);
+    }
+}
+
+#[test]
+fn locals_declaration() {
+    let source = "
+        proc f(a)
+            var b = 1
+            repeat 2
+                setpixel(a, b, 255)
+            endrepeat
+        endproc
+        f(3)";
+    let module = compile_to_module(source, &CompileOptions::default()).unwrap();
+    let f = module.procedures.iter().find(|p| p.name() == "f").unwrap();
+    // `b`, the counter of the repeat, and `x`, `y` and `color`, written by setpixel
+    assert_eq!(f.local_count(), 5);
+    assert_eq!(f.locals(), [3, 1, 0x7d, 1, 0x7f, 3, 0x7d]);
+
+    let output = run::run_binary(&module.serialize(), &run::RunOptions::default()).unwrap();
+    assert_eq!(output.canvas[103], 255);
+
+    // the calls of setpixel in a function share the same locals
+    let source = "
+        setpixel(1, 0, 10)
+        if (1 < 2) setpixel(2, 0, 20) endif
+        setpixel(3, 0, 30)";
+    let module = compile_to_module(source, &CompileOptions::default()).unwrap();
+    assert_eq!(module.procedures[0].local_count(), 3);
+    let output = run::run_binary(&module.serialize(), &run::RunOptions::default()).unwrap();
+    assert_eq!(output.canvas[1..4], [10, 20, 30]);
+}
+
+#[test]
+fn module_too_large() {
+    use crate::compiler::check_size;
+
+    let limit = u32::MAX as u64;
+    assert_eq!(check_size("code", limit, limit), Ok(()));
+    let kind = check_size("code", limit + 1, limit).unwrap_err();
+    assert_eq!(
+        kind,
+        ErrorKind::ModuleTooLarge {
+            section: "code",
+            size: limit + 1,
+            limit
+        }
+    );
+    assert_eq!(kind.code(), "E0012");
+    assert_eq!(
+        kind.to_string(),
+        "the code section has 4294967296 bytes, more than the limit of 4294967295"
+    );
+
+    // a procedure larger than `max_function_size`
+    let source = "big(1) proc big(x) print (x * 2) print (x * 3) endproc";
+    let module = compile_to_module(source, &CompileOptions::default()).unwrap();
+    let size = |name| {
+        module
+            .procedures
+            .iter()
+            .find(|p| p.name() == name)
+            .unwrap()
+            .size()
+    };
+    let (main_size, big_size) = (size("main"), size("big"));
+    assert!(main_size < big_size);
+
+    let options = CompileOptions {
+        max_function_size: main_size as u32,
+        ..Default::default()
+    };
+    let error = compile_to_module(source, &options).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ErrorKind::ModuleTooLarge {
+            section: "code",
+            size: big_size,
+            limit: main_size
+        }
+    );
+    assert_eq!(&source[error.span], "big");
+
+    // `main` is pointed by all its statements
+    let options = CompileOptions {
+        max_function_size: main_size as u32 - 1,
+        ..Default::default()
+    };
+    let error = compile_to_module(source, &options).unwrap_err();
+    assert_eq!(error.span, 0..source.len());
+}
+
+#[test]
+fn too_many_locals_and_functions() {
+    let options = CompileOptions {
+        max_locals: 3,
+        max_functions: 3,
+        ..Default::default()
+    };
+    let compile = |source| compile_to_module(source, &options).map(|_| ());
+
+    // the hidden locals count too
+    assert!(compile("setpixel(1, 2, 255)").is_ok());
+    assert!(compile("var a = 1 setpixel(a, a, 255)").is_err());
+    let source = "var a = 1 var b = 2 var c = 3 var d = 4";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TooManyLocals { limit: 3 });
+    assert_eq!(err.span, 30..39);
+    assert_eq!(err.kind.code(), "E0016");
+    assert_eq!(
+        err.to_string(),
+        "error at 1:31: the function has more than 3 locals"
+    );
+
+    let source = "proc f(a, b, c, d) print a endproc f(1, 2, 3, 4)";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TooManyLocals { limit: 3 });
+    assert_eq!(err.span, 16..17);
+
+    // "print" and "main" are functions too
+    assert!(compile("proc f() print 1 endproc f()").is_ok());
+    let source = "proc f() print 1 endproc proc g() print 2 endproc f() g()";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TooManyFunctions { limit: 3 });
+    assert_eq!(err.span, 30..31);
+    assert_eq!(err.kind.code(), "E0017");
+
+    let source = "proc f() print 1 endproc var g = &h";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TooManyFunctions { limit: 3 });
+    assert_eq!(err.span, 33..35);
+}
+
+#[test]
+fn multivalue_return() {
+    let options = CompileOptions {
+        multivalue: true,
+        ..Default::default()
+    };
+    let source = "
+        proc halves(x, y = 30)
+            if (x > 50)
+                return ((x / 2), (y / 2))
+            endif
+            return (x, y)
+        endproc
+        var a, b = halves(80)
+        print a
+        print b
+        var c, d = halves(10, 20)
+        print c
+        print d
+        halves(1, 2)";
+    let module = compile_to_module(source, &options).unwrap();
+    let halves = module
+        .procedures
+        .iter()
+        .find(|p| p.name() == "halves")
+        .unwrap();
+    assert_eq!(halves.result_count(), 2);
+    assert_eq!(
+        module.types[halves.ty as usize].results,
+        [Type::F32, Type::F32]
+    );
+    let output = run::run_binary(&module.serialize(), &run::RunOptions::default()).unwrap();
+    assert_eq!(output.printed, "40\n15\n10\n20\n");
+
+    for (source, expected, received, span) in [
+        ("var a, b, c = halves(1)", 3, 2, 14..20),
+        ("var f = &halves", 0, 2, 8..15),
+        ("print halves(1)", 1, 2, 6..12),
+    ] {
+        let source = format!("proc halves(x) return (x, x) endproc {}", source);
+        let error = compile_with_options(&source, &options).unwrap_err();
+        assert_eq!(
+            error.kind,
+            ErrorKind::ResultNumberMismatch { expected, received }
+        );
+        assert_eq!(error.span, span.start + 37..span.end + 37, "{}", source);
+    }
+}
+
+#[test]
+fn try_const_eval() {
+    assert_eq!(
+        super::try_const_eval("print 1 print (2+3)"),
+        Some(vec![1.0, 5.0])
+    );
+    assert_eq!(
+        super::try_const_eval("var x = 1.5\nx = (x * 4)\nprint (x / 0)\nprint -x"),
+        Some(vec![f32::INFINITY, -6.0])
+    );
+    assert_eq!(super::try_const_eval("pass"), Some(vec![]));
+    // a assignment declares the variable
+    assert_eq!(super::try_const_eval("x = 3 print x"), Some(vec![3.0]));
+
+    let looping = "var x = 0\nwhile (x < 3)\n    print x\n    x = (x + 1)\nendwhile";
+    assert_eq!(super::try_const_eval(looping), None);
+    // not constant, draws, or doesn't parse
+    assert_eq!(super::try_const_eval("print x"), None);
+    assert_eq!(super::try_const_eval("setpixel(1, 1, 255)"), None);
+    assert_eq!(super::try_const_eval("print 1 print (1 +"), None);
+    assert_eq!(super::try_const_eval("print (1 < 2)"), None);
+}
+
+#[test]
+fn leb128_round_trip() {
+    let cases: &[(i64, &[u8], &[u8])] = &[
+        (0, &[0x00], &[0x00]),
+        (63, &[0x3f], &[0x3f]),
+        (64, &[0x40], &[0xc0, 0x00]),
+        (624485, &[0xe5, 0x8e, 0x26], &[0xe5, 0x8e, 0x26]),
+        (-123456, &[], &[0xc0, 0xbb, 0x78]),
+        (-1, &[], &[0x7f]),
+    ];
+    for &(val, unsigned, signed) in cases {
+        if val >= 0 {
+            let mut bytes = Vec::new();
+            assert_eq!(
+                leb128::write_unsigned(&mut bytes, val as u64),
+                unsigned.len()
+            );
+            assert_eq!(bytes, unsigned, "unsigned {}", val);
+            assert_eq!(leb128::read_unsigned(&mut &bytes[..]), Some(val as u64));
+        }
+        let mut bytes = Vec::new();
+        assert_eq!(leb128::write_signed(&mut bytes, val), signed.len());
+        assert_eq!(bytes, signed, "signed {}", val);
+        assert_eq!(leb128::read_signed(&mut &bytes[..]), Some(val));
+    }
+    for &val in &[u64::MAX, 1 << 63] {
+        let mut bytes = Vec::new();
+        leb128::write_unsigned(&mut bytes, val);
+        assert_eq!(leb128::read_unsigned(&mut &bytes[..]), Some(val));
+    }
+    for &val in &[i64::MIN, i64::MAX] {
+        let mut bytes = Vec::new();
+        leb128::write_signed(&mut bytes, val);
+        assert_eq!(leb128::read_signed(&mut &bytes[..]), Some(val));
+    }
+    // truncated, and too large to fit in 64 bits
+    assert_eq!(leb128::read_unsigned(&mut &[0x80, 0x80][..]), None);
+    assert_eq!(
+        leb128::read_unsigned(
+            &mut &[0xff; 9].iter().chain(&[0x02]).copied().collect::<Vec<_>>()[..]
+        ),
+        None
+    );
+}
+
+#[test]
+fn instr_encode() {
+    use crate::instr::Instr::{self, *};
+
+    // the bytes that the `wasm!` macro wrote for each instruction, before it used `Instr::encode`
+    let cases: &[(Instr, &[u8])] = &[
+        (Unreachable, &[0x00]),
+        (Nop, &[0x01]),
+        (Block, &[0x02, 0x40]),
+        (Loop, &[0x03, 0x40]),
+        (If, &[0x04, 0x40]),
+        (Else, &[0x05]),
+        (End, &[0x0b]),
+        (Br(1), &[0x0c, 0x01]),
+        (BrIf(200), &[0x0d, 0xc8, 0x01]),
+        (Return, &[0x0f]),
+        (Call(3), &[0x10, 0x03]),
+        (CallIndirect(2), &[0x11, 0x02, 0x00]),
+        (Drop, &[0x1a]),
+        (LocalGet(0), &[0x20, 0x00]),
+        (LocalSet(128), &[0x21, 0x80, 0x01]),
+        (GlobalGet(1), &[0x23, 0x01]),
+        (GlobalSet(1), &[0x24, 0x01]),
+        (
+            I32Load8U {
+                align: 0,
+                offset: 0,
+            },
+            &[0x2d, 0x00, 0x00],
+        ),
+        (
+            F32Load {
+                align: 2,
+                offset: 10000,
+            },
+            &[0x2a, 0x02, 0x90, 0x4e],
+        ),
+        (
+            I32Store8 {
+                align: 0,
+                offset: 0,
+            },
+            &[0x3a, 0x00, 0x00],
+        ),
+        (I32Const(-1), &[0x41, 0x7f]),
+        (I32Const(10000), &[0x41, 0x90, 0xce, 0x00]),
+        (F32Const(1.0), &[0x43, 0x00, 0x00, 0x80, 0x3f]),
+        (I32Eqz, &[0x45]),
+        (I32Eq, &[0x46]),
+        (I32LtS, &[0x48]),
+        (I32GtS, &[0x4a]),
+        (I32LeS, &[0x4c]),
+        (I32GeU, &[0x4f]),
+        (F32Eq, &[0x5b]),
+        (F32Lt, &[0x5d]),
+        (F32Gt, &[0x5e]),
+        (F32Ge, &[0x60]),
+        (I32Add, &[0x6a]),
+        (I32Sub, &[0x6b]),
+        (I32Mul, &[0x6c]),
+        (I32And, &[0x71]),
+        (F32Neg, &[0x8c]),
+        (F32Floor, &[0x8e]),
+        (F32Trunc, &[0x8f]),
+        (F32Nearest, &[0x90]),
+        (F32Add, &[0x92]),
+        (F32Sub, &[0x93]),
+        (F32Mul, &[0x94]),
+        (F32Div, &[0x95]),
+        (F32Min, &[0x96]),
+        (F32Max, &[0x97]),
+        (F32Copysign, &[0x98]),
+        (I32TruncF32S, &[0xa8]),
+        (F32ConvertI32S, &[0xb2]),
+        (I32TruncSatF32S, &[0xfc, 0x00]),
+    ];
+    for &(instr, bytes) in cases {
+        let mut out = Vec::new();
+        instr.encode(&mut out);
+        assert_eq!(out, bytes, "{}", instr);
+        assert_eq!(Instr::decode(&mut &*bytes), Ok(instr));
+    }
+}
+
+#[test]
+fn render_to_rgba() {
+    // a vertical gradient, darker at the top
+    let source = "
+        var y = 0
+        while (y < 100)
+            var x = 0
+            while (x < 100)
+                setpixel(x, y, (y * 2))
+                x = (x + 1)
+            endwhile
+            y = (y + 1)
+        endwhile";
+    let rgba = crate::render_to_rgba(source, 50, 200).unwrap();
+    assert_eq!(rgba.len(), 50 * 200 * 4);
+    let pixel = |x: usize, y: usize| &rgba[(y * 50 + x) * 4..][..4];
+    assert_eq!(pixel(0, 0), [0, 0, 0, 255]);
+    assert_eq!(pixel(49, 1), [0, 0, 0, 255]);
+    assert_eq!(pixel(10, 2), [2, 2, 2, 255]);
+    assert_eq!(pixel(25, 100), [100, 100, 100, 255]);
+    assert_eq!(pixel(0, 199), [198, 198, 198, 255]);
+
+    let rgba = crate::render_to_rgba("setrgb(1, 0, 10, 20, 30)", 100, 100).unwrap();
+    assert_eq!(rgba[4..8], [10, 20, 30, 255]);
+    assert_eq!(rgba[..4], [0, 0, 0, 255]);
+
+    let err = crate::render_to_rgba("print print", 1, 1).unwrap_err();
+    assert!(matches!(err, crate::ChasmError::Compile(_)));
+    let err = crate::render_to_rgba("setpixel((0 / 0), 0, 1)", 1, 1).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::ChasmError::Run(run::RunError::Trap(_))
+    ));
+    assert_eq!(err.to_string(), "trap: invalid conversion to integer");
+}
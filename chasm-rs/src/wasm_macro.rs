@@ -13,23 +13,23 @@ macro_rules! wasm {
     };
     // write the b"\0asm" magic header, and the version 0x0100_0000
     ($w:expr, magic version) => {
-        ($w).write_all(b"\0asm").unwrap();
-        ($w).write_all(&[0x01, 0x00, 0x00, 0x00]).unwrap();
+        ($w).write_all(b"\0asm");
+        ($w).write_all(&[0x01, 0x00, 0x00, 0x00]);
     };
     ($w:expr, str $e:literal) => {
         {
             let data = ($e).as_bytes();
-            leb128::write::unsigned($w, data.len() as u64).unwrap();
-            ($w).write_all(data).unwrap();
+            $crate::leb128::write_unsigned($w, data.len() as u64);
+            ($w).write_all(data);
         }
     };
     ($w:expr, $e:literal) => {
-        leb128::write::unsigned($w, $e).unwrap();
+        $crate::leb128::write_unsigned($w, $e);
     };
     // write a u8 slice, but prepend its lenght first
     ($w:expr, data $e:expr) => {
-        leb128::write::unsigned($w, ($e).len() as u64).unwrap();
-        ($w).write_all($e).unwrap();
+        $crate::leb128::write_unsigned($w, ($e).len() as u64);
+        ($w).write_all($e);
     };
     // write each element, but prepend the number of elements first
     ($w:expr, vec $($e:tt)*) => {
@@ -40,135 +40,108 @@ macro_rules! wasm {
                 wasm!( &mut vector, $e );
                 n += 1;
             )*
-            leb128::write::unsigned($w, n).unwrap();
-            ($w).write_all(&vector).unwrap();
-            drop(&mut vector);
-            drop(&mut n);
+            $crate::leb128::write_unsigned($w, n);
+            ($w).write_all(&vector);
+            let _ = &mut vector;
+            let _ = &mut n;
         }
     };
-    ($w:expr, end) => {
-        ($w).write_all(&[0x0b]).unwrap();
-    };
     ($w:expr, functype) => {
-        ($w).write_all(&[0x60]).unwrap();
+        ($w).write_all(&[0x60]);
     };
     ($w:expr, i32) => {
-        ($w).write_all(&[0x7f]).unwrap();
+        ($w).write_all(&[0x7f]);
     };
     ($w:expr, f32) => {
-        ($w).write_all(&[0x7d]).unwrap();
-    };
-    ($w:expr, exporttypefunc) => {
-        ($w).write_all(&[0x00]).unwrap();
-    };
-
-    // call instruction
-    ($w:expr, call $funcidx:expr) => {
-        ($w).write_all(&[0x10]).unwrap();
-        leb128::write::unsigned($w, ($funcidx) as u64).unwrap();
-    };
-    // f32.const instruction
-    ($w:expr, f32.const $z:expr) => {
-        ($w).write_all(&[0x43]).unwrap();
-        ($w).write_all(&(($z) as f32).to_le_bytes()).unwrap();
-    };
-    // local.get instruction
-    ($w:expr, local.get $e:expr) => {
-        ($w).write_all(&[0x20]).unwrap();
-        leb128::write::unsigned($w, ($e) as u64).unwrap();
-    };
-    // local.set instruction
-    ($w:expr, local.set $e:expr) => {
-        ($w).write_all(&[0x21]).unwrap();
-        leb128::write::unsigned($w, ($e) as u64).unwrap();
-    };
-    // i32.add instruction
-    ($w:expr, i32.add) => {
-        { ($w).write_all(&[0x6a]).unwrap(); }
-    };
-    ($w:expr, i32.eqz) => {
-        { ($w).write_all(&[0x45]).unwrap(); }
-    };
-    ($w:expr, i32.store8 $aling:literal $offset:literal) => {
-        {
-            ($w).write_all(&[0x3a]).unwrap();
-            leb128::write::unsigned($w, ($aling) as u64).unwrap();
-            leb128::write::unsigned($w, ($offset) as u64).unwrap();
-        }
-    };
-    ($w:expr, i32.trunc_f32_s) => {
-        { ($w).write_all(&[0xa8]).unwrap(); }
-    };
-    ($w:expr, f32.add) => {
-        { ($w).write_all(&[0x92]).unwrap(); }
-    };
-    ($w:expr, f32.sub) => {
-        { ($w).write_all(&[0x93]).unwrap(); }
-    };
-    ($w:expr, f32.mul) => {
-        { ($w).write_all(&[0x94]).unwrap(); }
-    };
-    ($w:expr, f32.div) => {
-        { ($w).write_all(&[0x95]).unwrap(); }
-    };
-    ($w:expr, f32.eq ) => {
-        { ($w).write_all(&[0x5b]).unwrap(); }
-    };
-    ($w:expr, f32.lt ) => {
-        { ($w).write_all(&[0x5d]).unwrap(); }
-    };
-    ($w:expr, f32.gt ) => {
-        { ($w).write_all(&[0x5e]).unwrap(); }
+        ($w).write_all(&[0x7d]);
     };
-    ($w:expr, i32.and) => {
-        { ($w).write_all(&[0x71]).unwrap(); }
+    ($w:expr, funcref) => {
+        ($w).write_all(&[0x70]);
     };
-
-
-    ($w:expr, br $label:expr) => {
-        {
-            ($w).write_all(&[0x0C]).unwrap();
-            leb128::write::unsigned($w, ($label) as u64).unwrap();
-        }
-    };
-    ($w:expr, br_if $label:expr) => {
-        {
-            ($w).write_all(&[0x0D]).unwrap();
-            leb128::write::unsigned($w, ($label) as u64).unwrap();
-        }
+    ($w:expr, exporttypefunc) => {
+        ($w).write_all(&[0x00]);
     };
 
+    // the instructions are encoded by `Instr::encode`
+    ($w:expr, unreachable) => { wasm!($w, instr Unreachable) };
+    ($w:expr, nop) => { wasm!($w, instr Nop) };
     // A block with a empty return type
-    ($w:expr, block) => {
-        ($w).write_all(&[0x02, 0x40]).unwrap();
-    };
-    // A if block with a empty return type
-    ($w:expr, if) => {
-        ($w).write_all(&[0x04, 0x40]).unwrap();
-    };
-    // A else block
-    ($w:expr, else) => {
-        ($w).write_all(&[0x05]).unwrap();
-    };
+    ($w:expr, block) => { wasm!($w, instr Block) };
     // A loop with a empty return type
-    ($w:expr, loop) => {
-        ($w).write_all(&[0x03, 0x40]).unwrap();
+    ($w:expr, loop) => { wasm!($w, instr Loop) };
+    // A if block with a empty return type
+    ($w:expr, if) => { wasm!($w, instr If) };
+    ($w:expr, else) => { wasm!($w, instr Else) };
+    ($w:expr, end) => { wasm!($w, instr End) };
+    ($w:expr, br $label:expr) => { wasm!($w, instr Br(($label) as u32)) };
+    ($w:expr, br_if $label:expr) => { wasm!($w, instr BrIf(($label) as u32)) };
+    ($w:expr, return) => { wasm!($w, instr Return) };
+    ($w:expr, call $funcidx:expr) => { wasm!($w, instr Call(($funcidx) as u32)) };
+    // call_indirect instruction, on the table 0
+    ($w:expr, call_indirect $typeidx:expr) => {
+        wasm!($w, instr CallIndirect(($typeidx) as u32))
+    };
+    ($w:expr, drop) => { wasm!($w, instr Drop) };
+    ($w:expr, local.get $e:expr) => { wasm!($w, instr LocalGet(($e) as u32)) };
+    ($w:expr, local.set $e:expr) => { wasm!($w, instr LocalSet(($e) as u32)) };
+    ($w:expr, global.get $e:expr) => { wasm!($w, instr GlobalGet(($e) as u32)) };
+    ($w:expr, global.set $e:expr) => { wasm!($w, instr GlobalSet(($e) as u32)) };
+    ($w:expr, i32.load8_u $align:literal $offset:literal) => {
+        wasm!($w, instr I32Load8U { align: $align, offset: $offset })
+    };
+    ($w:expr, f32.load $align:literal $offset:expr) => {
+        wasm!($w, instr F32Load { align: $align, offset: ($offset) as u32 })
+    };
+    ($w:expr, i32.store8 $align:literal $offset:literal) => {
+        wasm!($w, instr I32Store8 { align: $align, offset: $offset })
+    };
+    ($w:expr, i32.const $n:expr) => { wasm!($w, instr I32Const(($n) as i32)) };
+    ($w:expr, f32.const $z:expr) => { wasm!($w, instr F32Const(($z) as f32)) };
+    ($w:expr, i32.eqz) => { wasm!($w, instr I32Eqz) };
+    ($w:expr, i32.eq) => { wasm!($w, instr I32Eq) };
+    ($w:expr, i32.lt_s) => { wasm!($w, instr I32LtS) };
+    ($w:expr, i32.gt_s) => { wasm!($w, instr I32GtS) };
+    ($w:expr, i32.le_s) => { wasm!($w, instr I32LeS) };
+    ($w:expr, i32.ge_u) => { wasm!($w, instr I32GeU) };
+    ($w:expr, f32.eq) => { wasm!($w, instr F32Eq) };
+    ($w:expr, f32.lt) => { wasm!($w, instr F32Lt) };
+    ($w:expr, f32.gt) => { wasm!($w, instr F32Gt) };
+    ($w:expr, f32.ge) => { wasm!($w, instr F32Ge) };
+    ($w:expr, i32.add) => { wasm!($w, instr I32Add) };
+    ($w:expr, i32.sub) => { wasm!($w, instr I32Sub) };
+    ($w:expr, i32.mul) => { wasm!($w, instr I32Mul) };
+    ($w:expr, i32.and) => { wasm!($w, instr I32And) };
+    ($w:expr, f32.add) => { wasm!($w, instr F32Add) };
+    ($w:expr, f32.sub) => { wasm!($w, instr F32Sub) };
+    ($w:expr, f32.mul) => { wasm!($w, instr F32Mul) };
+    ($w:expr, f32.div) => { wasm!($w, instr F32Div) };
+    ($w:expr, f32.neg) => { wasm!($w, instr F32Neg) };
+    ($w:expr, f32.floor) => { wasm!($w, instr F32Floor) };
+    ($w:expr, f32.trunc) => { wasm!($w, instr F32Trunc) };
+    ($w:expr, f32.nearest) => { wasm!($w, instr F32Nearest) };
+    ($w:expr, f32.min) => { wasm!($w, instr F32Min) };
+    ($w:expr, f32.max) => { wasm!($w, instr F32Max) };
+    ($w:expr, f32.copysign) => { wasm!($w, instr F32Copysign) };
+    ($w:expr, i32.trunc_f32_s) => { wasm!($w, instr I32TruncF32S) };
+    ($w:expr, f32.convert_i32_s) => { wasm!($w, instr F32ConvertI32S) };
+    ($w:expr, i32.trunc_sat_f32_s) => { wasm!($w, instr I32TruncSatF32S) };
+    ($w:expr, instr $($instr:tt)*) => {
+        $crate::instr::Instr::$($instr)*.encode($w)
     };
 
-
     // creates a section
     // https://webassembly.github.io/spec/core/binary/modules.html#binary-section
     ($w:expr, section $id:tt $e:tt) => {
-        ($w).write_all(&[wasm!(section_type $id)]).unwrap();
+        ($w).write_all(&[wasm!(section_type $id)]);
         let mut section = Vec::new();
         wasm!(&mut section, $e);
-        leb128::write::unsigned($w, section.len() as u64).unwrap();
-        ($w).write_all(&section).unwrap();
+        $crate::leb128::write_unsigned($w, section.len() as u64);
+        ($w).write_all(&section);
     };
     // create a functype, is used in the type section
     // https://webassembly.github.io/spec/core/binary/types.html#binary-functype
     ($w:expr, functype $param:tt $result:tt) => {
-        ($w).write_all(&[0x60]).unwrap();
+        ($w).write_all(&[0x60]);
         wasm!($w, $param);
         wasm!($w, $result);
     };
@@ -178,11 +151,11 @@ macro_rules! wasm {
     ($w:expr, export $name:literal $id:tt $idx:tt) => {
         {
             let name = ($name).as_bytes();
-            leb128::write::unsigned($w, name.len() as u64).unwrap();
-            ($w).write_all(name).unwrap();
+            $crate::leb128::write_unsigned($w, name.len() as u64);
+            ($w).write_all(name);
         }
-        ($w).write_all(&[wasm!(export_type $id)]).unwrap();
-        leb128::write::unsigned($w, $idx as u64).unwrap();
+        ($w).write_all(&[wasm!(export_type $id)]);
+        $crate::leb128::write_unsigned($w, $idx as u64);
     };
 
     // creates a import, in used in the import section
@@ -190,41 +163,46 @@ macro_rules! wasm {
     ($w:expr, import $mod:literal $name:literal $desc:tt) => {
         {
             let module = ($mod).as_bytes();
-            leb128::write::unsigned($w, module.len() as u64).unwrap();
-            ($w).write_all(module).unwrap();
+            $crate::leb128::write_unsigned($w, module.len() as u64);
+            ($w).write_all(module);
         }
         {
             let name = ($name).as_bytes();
-            leb128::write::unsigned($w, name.len() as u64).unwrap();
-            ($w).write_all(name).unwrap();
+            $crate::leb128::write_unsigned($w, name.len() as u64);
+            ($w).write_all(name);
         }
         wasm!($w, import_desc $desc)
     };
     // import description of a function
     // https://webassembly.github.io/spec/core/binary/modules.html#binary-importdesc
     ($w:expr, import_desc (function $idx:expr)) => {
-        ($w).write_all(&[0x00]).unwrap();
-        leb128::write::unsigned($w, $idx as u64).unwrap();
+        ($w).write_all(&[0x00]);
+        $crate::leb128::write_unsigned($w, $idx as u64);
     };
     // import description of a memory
     // https://webassembly.github.io/spec/core/binary/modules.html#binary-importdesc
-    ($w:expr, import_desc (memory $min:literal $max:literal)) => {
-        ($w).write_all(&[0x02]).unwrap();
-        ($w).write_all(&[0x01]).unwrap();
-        leb128::write::unsigned($w, $min as u64).unwrap();
-        leb128::write::unsigned($w, $max as u64).unwrap();
+    ($w:expr, import_desc (memory $min:tt $max:tt)) => {
+        ($w).write_all(&[0x02]);
+        ($w).write_all(&[0x01]);
+        $crate::leb128::write_unsigned($w, $min as u64);
+        $crate::leb128::write_unsigned($w, $max as u64);
     };
-    ($w:expr, import_desc (memory $min:literal)) => {
-        ($w).write_all(&[0x02]).unwrap();
-        ($w).write_all(&[0x00]).unwrap();
-        leb128::write::unsigned($w, $min as u64).unwrap();
+    ($w:expr, import_desc (memory $min:tt)) => {
+        ($w).write_all(&[0x02]);
+        ($w).write_all(&[0x00]);
+        $crate::leb128::write_unsigned($w, $min as u64);
     };
 
+    (section_type custom) => { 0 };
     (section_type type) => { 1 };
     (section_type import) => { 2 };
     (section_type function) => { 3 };
+    (section_type table) => { 4 };
+    (section_type global) => { 6 };
     (section_type export) => { 7 };
+    (section_type element) => { 9 };
     (section_type code) => { 10 };
+    (section_type data) => { 11 };
 
     (export_type function) => { 0x00 };
     (export_type table) => { 0x01 };
@@ -1,17 +1,125 @@
+use chasm_rs::{ChasmError, JsonString, Token};
 use wasm_bindgen::prelude::*;
 
+/// The error as a JSON object, with the shared error fields, plus the token used by the
+/// playground to mark the error.
+fn error_json(x: chasm_rs::Error) -> JsValue {
+    let (line, column) = x.get_line_column();
+    let value = &x.source[x.span.clone()];
+    let json = format!(
+        r#"{{{}, "token": {{ "value": {}, "line": {}, "char": {} }} }}"#,
+        x.json_fields(),
+        JsonString(value),
+        line - 1,
+        column - 1
+    );
+    json.into()
+}
+
 #[wasm_bindgen]
 pub fn compile(source: &str) -> Result<Vec<u8>, JsValue> {
-    chasm_rs::compile(source).map_err(|x| -> JsValue {
-        let (line, column) = x.get_line_column();
-        let value = &x.source[x.span.clone()];
-        let json = format!(
-            r#"{{ "message": {:?}, "token": {{ "value": {:?}, "line": {}, "char": {} }} }}"#,
-            x.to_string(),
-            value,
-            line - 1,
-            column - 1
-        );
-        json.into()
+    chasm_rs::compile(source).map_err(error_json)
+}
+
+/// Compile the program, returning a JSON object with the `binary`, as a array of bytes, and the
+/// `warnings`, each with the same fields as a error. If the program has a error, it is returned in
+/// the same shape as the error of [`compile`].
+#[wasm_bindgen]
+pub fn compile_with_diagnostics(source: &str) -> JsValue {
+    let options = chasm_rs::CompileOptions::default();
+    let (binary, warnings) = match chasm_rs::compile_with_warnings(source, &options) {
+        Ok(x) => x,
+        Err(x) => return error_json(x),
+    };
+    let binary: Vec<String> = binary.iter().map(|x| x.to_string()).collect();
+    let warnings: Vec<String> = warnings.iter().map(|x| x.to_json(source)).collect();
+    format!(
+        r#"{{"binary": [{}], "warnings": [{}]}}"#,
+        binary.join(","),
+        warnings.join(", ")
+    )
+    .into()
+}
+
+/// Compile and run the program with the bundled interpreter, returning the 100x100 canvas as
+/// RGBA pixels, ready to be copied to a `ImageData`. Like [`chasm_rs::render_to_rgba`], a program
+/// that runs more than [`chasm_rs::RENDER_FUEL`] instructions is stopped with a error.
+#[wasm_bindgen]
+pub fn render(source: &str) -> Result<Vec<u8>, JsValue> {
+    chasm_rs::render_to_rgba(source, 100, 100).map_err(|err| match err {
+        ChasmError::Compile(x) => error_json(x),
+        // a trap has no position in the source, so only its message is given
+        ChasmError::Run(x) => {
+            JsValue::from(format!(r#"{{"message": {}}}"#, JsonString(&x.to_string())))
+        }
     })
 }
+
+/// The class used to highlight a token.
+fn highlight_class(token: Token) -> &'static str {
+    match token {
+        Token::Print
+        | Token::Prints
+        | Token::Var
+        | Token::Global
+        | Token::Data
+        | Token::While
+        | Token::EndWhile
+        | Token::If
+        | Token::EndIf
+        | Token::Else
+        | Token::Proc
+        | Token::EndProc
+        | Token::Switch
+        | Token::Case
+        | Token::Default
+        | Token::EndSwitch
+        | Token::Repeat
+        | Token::EndRepeat
+        | Token::Loop
+        | Token::EndLoop
+        | Token::Break
+        | Token::Return
+        | Token::Pass => "keyword",
+        Token::Number => "number",
+        Token::String => "string",
+        Token::Operator | Token::Assignment | Token::Ampersand => "operator",
+        Token::Comma
+        | Token::LeftParen
+        | Token::RightParen
+        | Token::LeftBracket
+        | Token::RightBracket => "punctuation",
+        Token::Identifier => "identifier",
+        Token::InvalidNumber | Token::Error | Token::Eof => "error",
+    }
+}
+
+/// Split the source code in tokens, for syntax highlighting. Returns a JSON array of objects with
+/// the `kind` of each token, like `"keyword"` or `"number"`, and its `start` and `end`. The
+/// offsets are in UTF-16 code units, like the indices of a JavaScript string.
+#[wasm_bindgen]
+pub fn tokenize(source: &str) -> JsValue {
+    // convert the byte offsets, which are increasing, to UTF-16 offsets
+    let mut byte = 0;
+    let mut utf16 = 0;
+    let mut to_utf16 = |offset: usize| {
+        utf16 += source[byte..offset].encode_utf16().count();
+        byte = offset;
+        utf16
+    };
+
+    let tokens: Vec<String> = chasm_rs::tokenize(source)
+        .into_iter()
+        .map(|(token, span)| {
+            let start = to_utf16(span.start);
+            let end = to_utf16(span.end);
+            format!(
+                r#"{{"kind": "{}", "start": {}, "end": {}}}"#,
+                highlight_class(token),
+                start,
+                end
+            )
+        })
+        .collect();
+    format!("[{}]", tokens.join(", ")).into()
+}
@@ -0,0 +1,63 @@
+use wasm_bindgen_test::*;
+
+#[wasm_bindgen_test]
+fn render_gradient() {
+    let source = "
+        var y = 0
+        while (y < 100)
+            var x = 0
+            while (x < 100)
+                setpixel (x, y, (x * 2))
+                x = (x + 1)
+            endwhile
+            y = (y + 1)
+        endwhile";
+    let rgba = chasm_wasm::render(source).unwrap();
+
+    assert_eq!(rgba.len(), 100 * 100 * 4);
+    assert_eq!(rgba[..8], [0, 0, 0, 255, 2, 2, 2, 255]);
+    // the last pixel, at x = 99
+    assert_eq!(rgba[rgba.len() - 4..], [198, 198, 198, 255]);
+}
+
+#[wasm_bindgen_test]
+fn render_rgb() {
+    let rgba = chasm_wasm::render("setrgb(1, 0, 10, 20, 30)").unwrap();
+    assert_eq!(rgba[..8], [0, 0, 0, 255, 10, 20, 30, 255]);
+}
+
+#[wasm_bindgen_test]
+fn error_token_escaped() {
+    let json = chasm_wasm::compile("print \u{7}")
+        .unwrap_err()
+        .as_string()
+        .unwrap();
+    assert!(json.contains(r#""token": { "value": "\u0007", "line": 0, "char": 6 }"#));
+}
+
+#[wasm_bindgen_test]
+fn tokenize_classes() {
+    let tokens = chasm_wasm::tokenize("var x = 1").as_string().unwrap();
+    assert_eq!(
+        tokens,
+        r#"[{"kind": "keyword", "start": 0, "end": 3}, {"kind": "identifier", "start": 4, "end": 5}, {"kind": "operator", "start": 6, "end": 7}, {"kind": "number", "start": 8, "end": 9}]"#
+    );
+}
+
+#[wasm_bindgen_test]
+fn diagnostics_unused_variable() {
+    let source = "var unused = 1\nprint 2";
+    let json = chasm_wasm::compile_with_diagnostics(source)
+        .as_string()
+        .unwrap();
+    let binary = chasm_rs::compile(source).unwrap();
+    let binary: Vec<String> = binary.iter().map(|x| x.to_string()).collect();
+
+    assert_eq!(
+        json,
+        format!(
+            r#"{{"binary": [{}], "warnings": [{{"message":"the variable \\"unused\\" is never read","line":1,"column":5,"span_start":4,"span_end":10,"code":"W0003"}}]}}"#,
+            binary.join(",")
+        )
+    );
+}
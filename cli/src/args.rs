@@ -0,0 +1,214 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: chasm-cli [OPTIONS] [FILE] [ascii]
+
+Compile and run the chasm program in FILE, rendering its output in a window. If any argument is
+passed after FILE, the output is rendered in the terminal as ASCII art. If no FILE is given, a
+REPL is started, where :load <PATH> runs a file, :save <PATH> writes the code run in the
+session to a file, and :verbose toggles printing the size of each compiled line. If FILE is -,
+the program is read from the standard input.
+
+Options:
+      --stdin          Read the program from the standard input, like a FILE of -
+      --force-window   Fail if a window can't be opened, instead of rendering as ASCII art
+  -o, --output <PATH>  Write the compiled WebAssembly module to PATH, instead of running it
+      --frames <N>     Run main, and then call the procedure \"frame(t)\" N times, writing the
+                       canvas after each call to FILE_0000.png, FILE_0001.png, and so on
+      --gamma <F>      Apply a gamma correction of F to the rendered canvas, mapping each byte b
+                       to (b/255)^F * 255. The default is 1
+      --brightness <F> Multiply each byte of the canvas by F, clamping it to 255, before rendering
+                       it as ASCII art. The default is 1
+      --invert         Reverse the characters of the ASCII art, for terminals with dark text on a
+                       light background
+      --rgb            Render the RGB canvas written by setrgb, instead of the grayscale one
+  -O, --optimize       Propagate constant variables, fold constant arithmetic, store integer loop
+                       counters as i32, hoist loop invariants out of while conditions, and share
+                       the arithmetic of while conditions with the loop bodies
+      --time-limit <MS>
+                       Stop the program if it runs for more than MS milliseconds, still rendering
+                       its canvas. The program runs in the bundled interpreter, that can be
+                       interrupted
+      --profile        Run the program in the bundled interpreter, and print to stderr the number
+                       of calls of each procedure
+      --memory-pages <N>
+                       Import a memory with at least N pages of 64KiB, instead of 1
+      --verify         Check that the compiled module is valid WebAssembly, instead of running it
+      --hexdump[=WIDTH]
+                       Print the compiled WebAssembly module in hexadecimal, WIDTH bytes per line
+                       followed by their ASCII characters, instead of running it. The default is 16
+      --ast            Print the syntax tree of FILE, with the byte range of each node, instead
+                       of running it
+      --pretty         Format FILE in place, instead of running it. A program read from the
+                       standard input is written formatted to the standard output
+      --check          Like --pretty, but only exit with a error if FILE is not formatted,
+                       without changing it
+      --emit imports   Print the imports that a host must provide to the compiled module, one
+                       per line, with their module, name and type, instead of running it
+      --json-errors    Print compilation errors to stderr as a JSON array of objects, with the
+                       fields message, line, column, span_start, span_end and code
+      --explain <CODE> Print a explanation of the error with the given code, like E0003, with
+                       a example that causes it and how to fix it
+  -h, --help           Print this help message";
+
+/// The command line arguments of the CLI.
+#[derive(Debug, Default)]
+pub struct Args {
+    /// The path to the chasm source file. If `None`, the REPL is started.
+    pub path: Option<PathBuf>,
+    /// Read the program from the standard input, instead of `path`.
+    pub stdin: bool,
+    /// Render the output as ASCII art in the terminal, instead of in a window.
+    pub ascii: bool,
+    /// Fail if the window can't be opened, instead of falling back to ASCII art.
+    pub force_window: bool,
+    /// Write the compiled module to this path, instead of running it.
+    pub output: Option<PathBuf>,
+    /// Render this number of frames of a animation, calling the procedure `frame(t)`.
+    pub frames: Option<u32>,
+    /// The gamma correction applied to the canvas before rendering it.
+    pub gamma: Option<f32>,
+    /// The factor that multiplies the canvas before rendering it as ASCII art.
+    pub brightness: Option<f32>,
+    /// Reverse the ramp of characters of the ASCII art.
+    pub invert: bool,
+    /// Render the RGB canvas, instead of the grayscale one.
+    pub rgb: bool,
+    /// Optimize the program before compiling it.
+    pub optimize: bool,
+    /// The maximum time, in milliseconds, that the program can run.
+    pub time_limit: Option<u64>,
+    /// Count the calls of each procedure, and print them after running.
+    pub profile: bool,
+    /// The minimum number of pages of the memory imported by the module.
+    pub memory_pages: Option<u32>,
+    /// Validate the compiled module, instead of running it.
+    pub verify: bool,
+    /// Print the compiled module in hexadecimal, with this number of bytes per line, instead of
+    /// running it.
+    pub hexdump: Option<usize>,
+    /// Print the syntax tree of the program, instead of running it.
+    pub ast: bool,
+    /// Format the program in place, instead of running it.
+    pub pretty: bool,
+    /// Check that the program is formatted, instead of running it.
+    pub check: bool,
+    /// Print the imports of the compiled module, instead of running it.
+    pub emit_imports: bool,
+    /// Print compilation errors as JSON.
+    pub json_errors: bool,
+    /// Print the explanation of this error code and exit.
+    pub explain: Option<String>,
+    /// Print the usage message and exit.
+    pub help: bool,
+}
+impl Args {
+    /// Parse the command line arguments, not including the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--stdin" => parsed.stdin = true,
+                "--force-window" => parsed.force_window = true,
+                "--memory-pages" => {
+                    let n = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    let n = n
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid number of pages {:?}", n))?;
+                    parsed.memory_pages = Some(n);
+                }
+                "--gamma" => {
+                    let f = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    let gamma = f
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x > 0.0)
+                        .ok_or_else(|| anyhow::anyhow!("invalid gamma {:?}", f))?;
+                    parsed.gamma = Some(gamma);
+                }
+                "--brightness" => {
+                    let f = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    let brightness = f
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|x| x.is_finite() && *x >= 0.0)
+                        .ok_or_else(|| anyhow::anyhow!("invalid brightness {:?}", f))?;
+                    parsed.brightness = Some(brightness);
+                }
+                "--invert" => parsed.invert = true,
+                "--rgb" => parsed.rgb = true,
+                "-O" | "--optimize" => parsed.optimize = true,
+                "--time-limit" => {
+                    let ms = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    let ms = ms
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid time limit {:?}", ms))?;
+                    parsed.time_limit = Some(ms);
+                }
+                "--profile" => parsed.profile = true,
+                "--ast" => parsed.ast = true,
+                "--pretty" => parsed.pretty = true,
+                "--check" => parsed.check = true,
+                "--verify" => parsed.verify = true,
+                "--hexdump" => parsed.hexdump = Some(16),
+                _ if arg.starts_with("--hexdump=") => {
+                    let width = &arg["--hexdump=".len()..];
+                    let width = width
+                        .parse()
+                        .ok()
+                        .filter(|&x| x > 0)
+                        .ok_or_else(|| anyhow::anyhow!("invalid hexdump width {:?}", width))?;
+                    parsed.hexdump = Some(width);
+                }
+                "--json-errors" => parsed.json_errors = true,
+                "--emit" => {
+                    let kind = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    match kind.as_str() {
+                        "imports" => parsed.emit_imports = true,
+                        _ => anyhow::bail!("unknown kind of output {:?}", kind),
+                    }
+                }
+                "--explain" => {
+                    let code = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    parsed.explain = Some(code);
+                }
+                "-o" | "--output" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    parsed.output = Some(path.into());
+                }
+                "--frames" => {
+                    let n = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+                    let n = n
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid number of frames {:?}", n))?;
+                    parsed.frames = Some(n);
+                }
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    anyhow::bail!("unknown option {}", arg)
+                }
+                "-" if parsed.path.is_none() && !parsed.stdin => parsed.stdin = true,
+                _ if parsed.path.is_none() && !parsed.stdin => parsed.path = Some(arg.into()),
+                _ => parsed.ascii = true,
+            }
+        }
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,415 @@
+//! Long explanations of the compilation errors, printed by `--explain <CODE>`.
+
+/// The explanation of each error code, with a description of the error, a minimal program that
+/// causes it, and how to fix it.
+static EXPLANATIONS: &[(&str, &str)] = &[
+    (
+        "E0001",
+        "\
+A token appeared where the grammar does not allow it. Each statement starts with a keyword, like
+`var`, `print`, `while` or `proc`, or with a identifier, for a assignment or a call, and each
+binary expression must be wrapped in parentheses, except the whole condition of a `if` or a
+`while`, like `if x > 0`.
+
+Erroneous example:
+
+    print 1 + 2
+
+Wrap the binary expression in parentheses:
+
+    print (1 + 2)",
+    ),
+    (
+        "E0002",
+        "\
+A number literal could not be parsed as a floating point number. The lexer only accepts literals
+that are valid numbers, so this error should not happen in practice.
+
+Check that the literal only contains digits, at most one decimal point, and a optional exponent,
+like `1.5e10`.",
+    ),
+    (
+        "E0003",
+        "\
+A procedure or builtin function was called with the wrong number of arguments.
+
+Erroneous example:
+
+    proc square(x)
+        print (x * x)
+    endproc
+    square(1, 2)
+
+Pass exactly the arguments that the procedure declares:
+
+    square(2)",
+    ),
+    (
+        "E0004",
+        "\
+A expression has a type that is not expected where it is used. A comparison, like `(a < b)`,
+is a boolean, and can only be used as a condition, as a operand of `&&`, or printed. Numbers
+can't be used as conditions.
+
+Erroneous example:
+
+    var x = (1 < 2)
+
+Use the comparison as a condition instead:
+
+    if (1 < 2)
+        print 1
+    endif",
+    ),
+    (
+        "E0005",
+        "\
+A procedure was called or referenced, but it is not declared anywhere in the program.
+
+Erroneous example:
+
+    draw(1)
+
+Declare the procedure, or fix the spelling of its name:
+
+    proc draw(x)
+        setpixel(x, x, 255)
+    endproc
+    draw(1)",
+    ),
+    (
+        "E0006",
+        "\
+A number literal is malformed, like having more than one decimal point.
+
+Erroneous example:
+
+    print 1.2.3
+
+Write the number with at most one decimal point:
+
+    print 1.23",
+    ),
+    (
+        "E0007",
+        "\
+The program has no statements, so there is nothing to compile. This is only a error when the
+compiler is configured to not allow empty programs, otherwise `main` does nothing.
+
+Erroneous example:
+
+    (an empty file)
+
+Add at least one statement:
+
+    print 0",
+    ),
+    (
+        "E0008",
+        "\
+A function was called inside a expression, but it is not one of the builtin functions, or a
+procedure of the program. A procedure called inside a expression must return a single value, and
+the ones that return several values are received with `var a, b = f()`.
+
+Erroneous example:
+
+    print sqrt(4)
+
+Use one of the builtins, `sign`, `fract`, `trunc`, `round`, `copysign` or `maxpixel`, or define a
+procedure that returns the value:
+
+    print sign(4)",
+    ),
+    (
+        "E0009",
+        "\
+A `break` was used outside of a loop. A `break` exits the innermost `while`, `repeat` or `loop`
+that contains it, in the same procedure.
+
+Erroneous example:
+
+    if (x > 10)
+        break
+    endif
+
+Move the `break` inside a loop:
+
+    loop
+        x = (x + 1)
+        if (x > 10)
+            break
+        endif
+    endloop",
+    ),
+    (
+        "E0010",
+        "\
+A expression was evaluated as a constant, but it uses variables or calls functions, so its value
+is only known when the program runs. This is reported when evaluating a single expression, and
+for the default value of a parameter, like `b` in `proc f(a, b = a)`, because it is computed by
+each caller, that can't see the other parameters. Comparisons are also not constants, because they
+have no number value.
+
+Erroneous example:
+
+    (x * 2)
+
+Use only number literals and arithmetic operations:
+
+    (21 * 2)",
+    ),
+    (
+        "E0011",
+        "\
+A character that is not part of any token appeared in the program, like `@` or `$`. A string
+literal without a closing quote is also reported as a unexpected character.
+
+Erroneous example:
+
+    print @ 1
+
+Remove the character, or replace it with the intended one:
+
+    print 1",
+    ),
+    (
+        "E0012",
+        "\
+The compiled module is larger than WebAssembly allows. The size of each section is limited to
+4294967295 bytes, so a program whose code, or string literals, exceed that can't be compiled.
+The body of each function is also limited, to 7654321 bytes by default, like in web browsers.
+The error points to the procedure that is too large, or to the top-level statements.
+
+The string literals and the `data` tables are placed in the memory between the canvas and the RGB
+canvas of `setrgb`, so together they are limited to 55536 bytes. The error points to the
+statement whose data doesn't fit.
+
+Split the program in smaller procedures, or reduce the string literals and the tables.",
+    ),
+    (
+        "E0013",
+        "\
+The number of values returned by a procedure doesn't match how it is used. Every `return` of a
+procedure must have as many values as its first one, a `var a, b = f()` must declare one variable
+for each value returned by `f`, and a procedure that returns values can't be referenced with `&`.
+
+Erroneous example:
+
+    proc half(x)
+        return ((x / 2), (x / 2))
+    endproc
+    var a, b, c = half(4)
+
+Declare exactly one variable for each returned value:
+
+    var a, b = half(4)",
+    ),
+    (
+        "E0014",
+        "\
+A `return` has more than one value, but the multi-value extension of WebAssembly, that allows a
+function to return several values, is not enabled. It is disabled by default, because some
+engines don't support it.
+
+Erroneous example:
+
+    proc center()
+        return (50, 50)
+    endproc
+
+Enable the `multivalue` option, or return a single value.",
+    ),
+    (
+        "E0015",
+        "\
+A `=` appeared inside a parenthesized expression, where a operator was expected. A `=` is only
+used to assign a variable, and values are compared with `==`.
+
+Erroneous example:
+
+    if (x = 1)
+        print 1
+    endif
+
+Use `==` to compare the values:
+
+    if (x == 1)
+        print 1
+    endif",
+    ),
+    (
+        "E0016",
+        "\
+A function has more locals than the limit of the compiler, which is 50000 by default. Each
+parameter and each variable declared in a function is a local, and so are some values stored by
+the compiler, like the counter of a `repeat`. Declaring a variable again in the same block reuses
+its local.
+
+Split the function in smaller procedures, or reuse the same variables.",
+    ),
+    (
+        "E0017",
+        "\
+The program has more procedures than the limit of the compiler, which is 1000000 functions by
+default, counting `main` and the imported `print` functions.
+
+Merge procedures that do the same thing, passing the differences as parameters.",
+    ),
+    (
+        "E0018",
+        "\
+A table was indexed, like `palette[i]`, but there is no `data` declaration with its name. Tables
+are declared at the top level of the program, and can be read by all procedures, even before
+their declaration.
+
+Erroneous example:
+
+    print palette[1]
+
+Declare the table, with its numbers:
+
+    data palette = [0, 85, 170, 255]
+    print palette[1]",
+    ),
+    (
+        "E0019",
+        "\
+The condition of a `while` or a `if` is a number, like `while x`. Conditions must be a comparison,
+like `x > 0`, or a `&&` of comparisons, and numbers are not implicitly compared with zero.
+
+Erroneous example:
+
+    while x
+        x = (x - 1)
+    endwhile
+
+Compare the number explicitly:
+
+    while x > 0
+        x = (x - 1)
+    endwhile",
+    ),
+    (
+        "E0020",
+        "\
+A block was closed by the end of another kind of block, like a `while` closed by a `endif`. Each
+`while`, `if`, `proc`, `switch`, `repeat` and `loop` must be closed by its own end, and the inner
+blocks must be closed before the outer ones.
+
+Erroneous example:
+
+    while (x < 10)
+        x = (x + 1)
+    endif
+
+Close the block with its own end:
+
+    while (x < 10)
+        x = (x + 1)
+    endwhile",
+    ),
+    (
+        "E0021",
+        "\
+A variable, a global or a parameter has the same name as a procedure. A call like `g(1)` calls the
+procedure reference stored in the variable `g`, or the procedure `g`, so each name can only be one
+of them, in the whole program.
+
+Erroneous example:
+
+    proc f(a)
+        print a
+    endproc
+    proc g(a)
+        print (a + 10)
+    endproc
+    var g = &f
+    g(1)
+
+Rename the variable:
+
+    var h = &f
+    h(1)",
+    ),
+    (
+        "E0022",
+        "\
+A procedure returns values with `return`, but some path reaches the end of the procedure without
+a `return`, so there would be no value to return. Every path must end in a `return`, like both
+branches of a `if` with a `else`, or a `loop` without a `break`.
+
+Erroneous example:
+
+    proc abs(x)
+        if (x < 0)
+            return (0 - x)
+        endif
+    endproc
+
+Return a value at the end of the procedure:
+
+    proc abs(x)
+        if (x < 0)
+            return (0 - x)
+        endif
+        return x
+    endproc",
+    ),
+    (
+        "E0023",
+        "\
+A table is declared with `data` more than once. Each table has a single list of numbers, so a
+second declaration with the same name would be ambiguous.
+
+Erroneous example:
+
+    data t = [1]
+    data t = [5, 6]
+    print t[1]
+
+Give each table its own name:
+
+    data t = [1]
+    data u = [5, 6]
+    print u[1]",
+    ),
+    (
+        "E0024",
+        "\
+A table is read with a constant index that is not the index of one of its values. The values of a
+table with `n` numbers have the indexes `0` to `n - 1`. A index that is only known when the program
+runs reads the closest value instead, or traps if the bounds checks are enabled.
+
+Erroneous example:
+
+    data t = [1, 2, 3]
+    print t[3]
+
+Read the last value with the index `2`:
+
+    data t = [1, 2, 3]
+    print t[2]",
+    ),
+    (
+        "E0025",
+        "\
+The maximum size of the memory imported by the module is less than its minimum size. The minimum
+is 1 page of 64KiB by default, but a program that calls `setrgb` needs at least 2 pages, because
+the RGB canvas is in the second page. A engine can't instantiate a module whose memory limits are
+invalid.
+
+Erroneous example, compiled with a maximum of 1 page:
+
+    setrgb(0, 0, 255, 0, 0)
+
+Allow the memory to have at least 2 pages, or don't set a maximum.",
+    ),
+];
+
+/// The explanation of the error with the given code, like `"E0003"`, if there is one.
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(code))
+        .map(|(_, text)| *text)
+}
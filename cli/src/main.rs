@@ -1,6 +1,20 @@
-use std::io::Write;
-use std::sync::{Arc, Mutex};
-use wasmer::{imports, Function, Instance, Memory, MemoryType, Module, Store, WasmerEnv};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use wasmer::{
+    imports, Function, Instance, Memory, MemoryType, Module, RuntimeError, Store, WasmerEnv,
+};
+
+mod args;
+use args::Args;
+
+mod explain;
+
+mod repl;
+
+#[cfg(test)]
+mod test;
 
 struct ToWriteFmt<T>(pub T);
 impl<T> std::fmt::Write for ToWriteFmt<T>
@@ -12,56 +26,374 @@ where
     }
 }
 
-fn print_ascii_art(art: &[u8]) {
+/// The default ramp of characters used to render ASCII art, from darkest to brightest.
+const RAMP: &[char] = &[' ', '-', '=', '#'];
+
+/// Render the 100x100 canvas as ASCII art, one line per row, mapping each byte to a character of
+/// `ramp`.
+fn ascii_art(art: &[u8], ramp: &[char]) -> String {
+    let mut string = String::with_capacity(101 * 100);
     for y in 0..100 {
         for x in 0..100 {
-            let b = art[y * 100 + x];
-            let c = [' ', '-', '=', '#'][(b / 64) as usize];
-            print!("{}", c);
+            let b = art[y * 100 + x] as usize;
+            string.push(ramp[b * ramp.len() / 256]);
         }
-        println!();
+        string.push('\n');
     }
+    string
+}
+
+/// Map each byte `b` of the canvas to `(b/255)^gamma * 255`.
+fn apply_gamma(art: &[u8], gamma: f32) -> Vec<u8> {
+    art.iter()
+        .map(|&b| ((b as f32 / 255.0).powf(gamma) * 255.0).round() as u8)
+        .collect()
+}
+
+/// Multiply each byte of the canvas by `brightness`, clamping the result to `0..=255`.
+fn apply_brightness(art: &[u8], brightness: f32) -> Vec<u8> {
+    art.iter()
+        .map(|&b| (b as f32 * brightness).round().clamp(0.0, 255.0) as u8)
+        .collect()
+}
+
+/// The ramp of characters of the ASCII art, reversed if `invert` is set, for terminals with dark
+/// text on a light background.
+fn ascii_ramp(invert: bool) -> Vec<char> {
+    match invert {
+        true => RAMP.iter().rev().copied().collect(),
+        false => RAMP.to_vec(),
+    }
+}
+
+/// Convert a RGB canvas to grayscale, with the luma of each pixel.
+fn luma(rgb: &[u8]) -> Vec<u8> {
+    rgb.chunks(3)
+        .map(|c| (0.299 * c[0] as f32 + 0.587 * c[1] as f32 + 0.114 * c[2] as f32).round() as u8)
+        .collect()
+}
+
+/// Print the canvas as ASCII art, with the gamma, brightness and ramp given in `args`. A RGB
+/// canvas is converted to grayscale.
+fn print_ascii_art(art: &[u8], args: &Args) {
+    let art = match args.rgb {
+        true => luma(art),
+        false => art.to_vec(),
+    };
+    let art = apply_gamma(&art, args.gamma.unwrap_or(1.0));
+    let art = apply_brightness(&art, args.brightness.unwrap_or(1.0));
+    print!("{}", ascii_art(&art, &ascii_ramp(args.invert)));
 }
 
 fn main() -> anyhow::Result<()> {
-    let args: Vec<_> = std::env::args().collect();
-    if args.len() > 1 {
-        let code = std::fs::read_to_string(&args[1])?;
-        let binary = match chasm_rs::compile(&code) {
-            Ok(it) => it,
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, args::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", args::USAGE);
+        return Ok(());
+    }
+    if let Some(code) = &args.explain {
+        match explain::explain(code) {
+            Some(text) => println!("{}", text),
+            None => {
+                eprintln!("error: no explanation for the error code {:?}", code);
+                std::process::exit(2);
+            }
+        }
+        return Ok(());
+    }
+
+    let input = if args.stdin {
+        // the output files are named after "stdin", like "stdin_0000.png"
+        let code = match read_program(std::io::stdin().lock()) {
+            Ok(code) => code,
             Err(err) => {
-                eprintln!("{}", err.to_string());
+                eprintln!("error: {}", err);
+                std::process::exit(2);
+            }
+        };
+        Some((code, PathBuf::from("stdin")))
+    } else if let Some(path) = &args.path {
+        Some((std::fs::read_to_string(path)?, path.clone()))
+    } else {
+        None
+    };
+
+    if let Some((code, path)) = input {
+        if args.pretty || args.check {
+            let formatted = match chasm_rs::format(&code) {
+                Ok(formatted) => formatted,
+                Err(err) => report_error(&err, args.json_errors),
+            };
+            if args.stdin && !args.check {
+                print!("{}", formatted);
+            } else if !write_formatted(&path, &code, &formatted, args.check)? {
+                eprintln!("{} is not formatted", path.display());
                 std::process::exit(1);
             }
+            return Ok(());
+        }
+        if args.ast {
+            match chasm_rs::parse(&code) {
+                Ok(program) => print!("{}", program),
+                Err(err) => report_error(&err, args.json_errors),
+            }
+            return Ok(());
+        }
+
+        let mut options = chasm_rs::CompileOptions::default();
+        if let Some(pages) = args.memory_pages {
+            options.memory_min_pages = pages;
+        }
+        options.optimize = args.optimize;
+        if args.emit_imports {
+            match chasm_rs::compile_to_module(&code, &options) {
+                Ok(module) => {
+                    for import in module.imports() {
+                        println!("{}", import);
+                    }
+                }
+                Err(err) => report_error(&err, args.json_errors),
+            }
+            return Ok(());
+        }
+        // the module is kept, so the profile reports the procedures that were actually compiled
+        let module = match chasm_rs::compile_to_module(&code, &options) {
+            Ok(module) => module,
+            Err(err) => report_error(&err, args.json_errors),
+        };
+        for warning in &module.warnings {
+            eprintln!("warning: {}", warning);
+        }
+        let binary = module.serialize();
+
+        if args.verify {
+            match verify(&binary) {
+                Ok(()) => println!("valid module ({} bytes)", binary.len()),
+                Err(err) => {
+                    eprintln!("invalid module: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(width) = args.hexdump {
+            print!("{}", dump_hex(&binary, width));
+            return Ok(());
+        }
+
+        if let Some(output) = &args.output {
+            write_atomically(output, &binary)?;
+            return Ok(());
+        }
+
+        if let Some(frames) = args.frames {
+            let out = Arc::new(Mutex::new(ToWriteFmt(std::io::stdout())));
+            let canvases = run_frames(&binary, out, frames, args.rgb)?;
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            for (i, canvas) in canvases.iter().enumerate() {
+                write_png(format!("{}_{:04}.png", stem, i), canvas, args.rgb)?;
+            }
+            return Ok(());
+        }
+
+        let mut interrupted = false;
+        let art = if args.time_limit.is_some() || args.profile {
+            // only the bundled interpreter can be interrupted or profiled
+            let options = chasm_rs::run::RunOptions {
+                profile: args.profile,
+                rgb: args.rgb,
+                ..Default::default()
+            };
+            let output = match args.time_limit {
+                Some(ms) => run_with_time_limit(&binary, Duration::from_millis(ms), options)?,
+                None => chasm_rs::run::run_binary(&binary, &options)?,
+            };
+            print!("{}", output.printed);
+            if output.interrupted {
+                let ms = args.time_limit.unwrap_or_default();
+                eprintln!("error: the program exceeded the time limit of {} ms", ms);
+                interrupted = true;
+            }
+            if args.profile {
+                eprint!("{}", profile_report(&module.procedures, &output.calls));
+            }
+            output.canvas
+        } else {
+            let out = Arc::new(Mutex::new(ToWriteFmt(std::io::stdout())));
+            run_binary(&binary, out, args.rgb)?
         };
-        let out = Arc::new(Mutex::new(ToWriteFmt(std::io::stdout())));
-        let art = run_binary(&binary, out)?;
 
-        if args.len() > 2 {
-            print_ascii_art(&art);
+        let gamma = args.gamma.unwrap_or(1.0);
+        if args.ascii {
+            print_ascii_art(&art, &args);
         } else {
-            screen(&art)?;
+            match window_or_fallback(open_window(), args.force_window)? {
+                Some(window) => screen(window, &art, gamma, args.rgb)?,
+                None => print_ascii_art(&art, &args),
+            }
         }
 
+        if interrupted {
+            std::process::exit(1);
+        }
         return Ok(());
     }
 
-    repl()
+    let flags = [
+        ("--output", args.output.is_some()),
+        ("--ast", args.ast),
+        ("--pretty", args.pretty),
+        ("--check", args.check),
+        ("--verify", args.verify),
+        ("--hexdump", args.hexdump.is_some()),
+        ("--emit", args.emit_imports),
+        ("--json-errors", args.json_errors),
+    ];
+    for (flag, used) in flags {
+        if used {
+            eprintln!("error: {} requires a FILE\n\n{}", flag, args::USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    repl::repl()
+}
+
+/// Read a whole program, like from the standard input. A input with only whitespace is a error,
+/// because nothing was likely piped in.
+fn read_program(mut reader: impl std::io::Read) -> anyhow::Result<String> {
+    let mut code = String::new();
+    reader.read_to_string(&mut code)?;
+    if code.trim().is_empty() {
+        anyhow::bail!("no program was given in the standard input");
+    }
+    Ok(code)
+}
+
+/// Print a compilation error to stderr, as JSON if `json` is set, and exit with a error code.
+fn report_error(err: &chasm_rs::Error, json: bool) -> ! {
+    if json {
+        eprintln!("{}", json_errors(std::slice::from_ref(err)));
+    } else {
+        eprintln!("{}", err);
+    }
+    std::process::exit(1);
 }
 
-fn screen(art: &[u8]) -> anyhow::Result<()> {
-    use minifb::{Key, Window, WindowOptions};
-    const SCALE: usize = 3;
-    const WIDTH: usize = 100 * SCALE;
-    const HEIGHT: usize = 100 * SCALE;
-    let mut window = Window::new("chasm", WIDTH, HEIGHT, WindowOptions::default())?;
+/// Serialize the errors as a JSON array.
+fn json_errors(errors: &[chasm_rs::Error]) -> String {
+    let errors: Vec<String> = errors.iter().map(|x| x.to_json()).collect();
+    format!("[{}]", errors.join(","))
+}
+
+/// Write `data` to a temporary file, and then rename it to `path`, so `path` is never left with a
+/// partially written file.
+fn write_atomically(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Write the `formatted` program to `path`, if it differs from its current `code`. If `check` is
+/// set, the file is never written, and false is returned if it is not formatted.
+fn write_formatted(path: &Path, code: &str, formatted: &str, check: bool) -> anyhow::Result<bool> {
+    if code == formatted {
+        return Ok(true);
+    }
+    if check {
+        return Ok(false);
+    }
+    write_atomically(path, formatted.as_bytes())?;
+    Ok(true)
+}
+
+/// Check that `binary` is a valid WebAssembly module, without running it.
+fn verify(binary: &[u8]) -> anyhow::Result<()> {
+    Module::validate(&Store::default(), binary)?;
+    Ok(())
+}
+
+/// Format `data` as a hex dump, with `width` bytes per line. Each line has the offset of its first
+/// byte, the bytes in hexadecimal, and their ASCII characters, with a `.` for each non-printable
+/// one.
+fn dump_hex(data: &[u8], width: usize) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(width).enumerate() {
+        dump += &format!("{:04x}: ", i * width);
+        for j in 0..width {
+            match line.get(j) {
+                Some(b) => dump += &format!("{:02x} ", b),
+                None => dump += "   ",
+            }
+        }
+        dump += ": ";
+        for &b in line {
+            let c = b as char;
+            dump.push(if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '.'
+            });
+        }
+        dump.push('\n');
+    }
+    dump
+}
+
+/// The scale of each pixel of the canvas in the window.
+const SCALE: usize = 3;
+const WIDTH: usize = 100 * SCALE;
+const HEIGHT: usize = 100 * SCALE;
+
+fn open_window() -> Result<minifb::Window, minifb::Error> {
+    minifb::Window::new("chasm", WIDTH, HEIGHT, minifb::WindowOptions::default())
+}
+
+/// Decide how to render the canvas, given the result of opening a window. If the window could not
+/// be created, like in a headless environment, this warns and returns `None`, to render as ASCII
+/// art instead, unless `force_window` is set.
+fn window_or_fallback<W, E>(window: Result<W, E>, force_window: bool) -> anyhow::Result<Option<W>>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match window {
+        Ok(window) => Ok(Some(window)),
+        Err(err) if force_window => Err(err.into()),
+        Err(err) => {
+            eprintln!(
+                "warning: failed to open a window ({}), rendering as ASCII art instead",
+                err
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Show the canvas in the window, until it is closed. If `rgb` is set, the canvas has the red,
+/// green and blue of each pixel.
+fn screen(mut window: minifb::Window, art: &[u8], gamma: f32, rgb: bool) -> anyhow::Result<()> {
+    use minifb::Key;
     window.limit_update_rate(Some(std::time::Duration::from_micros(16666)));
 
+    let channels = if rgb { 3 } else { 1 };
     let mut buffer = vec![0; WIDTH * HEIGHT];
-    for (i, &b) in art.iter().enumerate() {
+    for (i, pixel) in apply_gamma(art, gamma).chunks(channels).enumerate() {
         let x = SCALE * (i % 100);
         let y = SCALE * (i / 100);
-        let c = u32::from_be_bytes([0, b, b, b]);
+        let c = match *pixel {
+            [r, g, b] => u32::from_be_bytes([0, r, g, b]),
+            [b] => u32::from_be_bytes([0, b, b, b]),
+            _ => unreachable!(),
+        };
         for y in y..y + SCALE {
             for x in x..x + SCALE {
                 buffer[x + WIDTH * y] = c;
@@ -77,56 +409,177 @@ fn screen(art: &[u8]) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn repl() -> anyhow::Result<()> {
-    let mut line = String::new();
-    loop {
-        let mut stdout = std::io::stdout();
-        write!(stdout, ">> ").unwrap();
-        stdout.flush().unwrap();
-
-        line.clear();
-        std::io::stdin().read_line(&mut line).unwrap();
-        let binary = match chasm_rs::compile(&line) {
-            Ok(x) => x,
-            Err(e) => {
-                println!("error: {}", e);
-                continue;
-            }
-        };
-        let out = Arc::new(Mutex::new(ToWriteFmt(std::io::stdout())));
-        run_binary(&binary, out)?;
-    }
+/// Write the 100x100 canvas as a grayscale PNG image, or as a RGB one if `rgb` is set.
+fn write_png(path: impl AsRef<Path>, canvas: &[u8], rgb: bool) -> anyhow::Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, 100, 100);
+    let (color, len) = match rgb {
+        true => (png::ColorType::Rgb, 100 * 100 * 3),
+        false => (png::ColorType::Grayscale, 100 * 100),
+    };
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&canvas[..len])?;
+    Ok(())
 }
 
+/// Run `main`, returning the canvas, or the RGB canvas if `rgb` is set.
 pub fn run_binary<W: std::fmt::Write + Send + 'static>(
     binary: &[u8],
     out: Arc<Mutex<W>>,
+    rgb: bool,
 ) -> anyhow::Result<Vec<u8>> {
-    struct Writer<W: Send> {
-        w: Arc<Mutex<W>>,
+    let (instance, memory) = instantiate(binary, out)?;
+    let main = instance.exports.get_function("main")?;
+    main.call(&[])?;
+    Ok(canvas(&memory, rgb))
+}
+
+/// Run `main` in the bundled interpreter, on a worker thread, interrupting it if it doesn't return
+/// in `limit`. The output has the canvas and the text printed until then.
+pub fn run_with_time_limit(
+    binary: &[u8],
+    limit: Duration,
+    options: chasm_rs::run::RunOptions,
+) -> anyhow::Result<chasm_rs::run::Output> {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let options = chasm_rs::run::RunOptions {
+        interrupt: Some(interrupt.clone()),
+        ..options
+    };
+    let binary = binary.to_vec();
+    let (done, finished) = mpsc::channel();
+    let worker = std::thread::spawn(move || {
+        let output = chasm_rs::run::run_binary(&binary, &options);
+        let _ = done.send(());
+        output
+    });
+    if finished.recv_timeout(limit).is_err() {
+        interrupt.store(true, Ordering::Relaxed);
     }
-    impl<W: Send> WasmerEnv for Writer<W> {}
-    impl<W: Send> Clone for Writer<W> {
-        fn clone(&self) -> Self {
-            Self { w: self.w.clone() }
-        }
+    let output = worker.join().expect("the interpreter panicked")?;
+    Ok(output)
+}
+
+/// List the number of calls of each procedure, from the most called, one per line, like
+/// `"      1000 draw"`.
+pub fn profile_report(
+    procedures: &[chasm_rs::Procedure],
+    calls: &std::collections::HashMap<u32, u64>,
+) -> String {
+    let mut rows: Vec<(u64, &str)> = procedures
+        .iter()
+        .map(|p| (calls.get(&p.idx()).copied().unwrap_or(0), p.name()))
+        .collect();
+    // stable, so procedures with the same count stay in function index order
+    rows.sort_by_key(|&(count, _)| std::cmp::Reverse(count));
+    let mut report = format!("{:>10} procedure\n", "calls");
+    for (count, name) in rows {
+        report += &format!("{:>10} {}\n", count, name);
+    }
+    report
+}
+
+/// Run `main`, and then call the exported `frame` procedure `frames` times, with `t` going from 0
+/// to `frames - 1`, returning the canvas after each frame, or the RGB canvas if `rgb` is set.
+pub fn run_frames<W: std::fmt::Write + Send + 'static>(
+    binary: &[u8],
+    out: Arc<Mutex<W>>,
+    frames: u32,
+    rgb: bool,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let (instance, memory) = instantiate(binary, out)?;
+    let main = instance.exports.get_function("main")?;
+    main.call(&[])?;
+    let frame = instance
+        .exports
+        .get_function("frame")
+        .map_err(|_| anyhow::anyhow!("the program doesn't define a procedure \"frame(t)\""))?;
+    let mut canvases = Vec::with_capacity(frames as usize);
+    for t in 0..frames {
+        frame.call(&[wasmer::Value::F32(t as f32)])?;
+        canvases.push(canvas(&memory, rgb));
     }
+    Ok(canvases)
+}
+
+/// Copy the 100x100 canvas out of the memory, or the RGB canvas written by `setrgb` if `rgb` is
+/// set, with 3 bytes per pixel.
+fn canvas(memory: &Memory, rgb: bool) -> Vec<u8> {
+    let data = unsafe { memory.data_unchecked() };
+    let (start, len) = match rgb {
+        true => (chasm_rs::RGB_CANVAS_OFFSET as usize, 100 * 100 * 3),
+        false => (0, 100 * 100),
+    };
+    let mut canvas = data.get(start..).unwrap_or_default().to_vec();
+    canvas.resize(len, 0);
+    canvas
+}
 
-    let writer = Writer { w: out };
+/// The environment of the imported functions: the output of the program, and its memory.
+struct Writer<W: Send> {
+    w: Arc<Mutex<W>>,
+    memory: Memory,
+}
+impl<W: Send> WasmerEnv for Writer<W> {}
+impl<W: Send> Clone for Writer<W> {
+    fn clone(&self) -> Self {
+        Self {
+            w: self.w.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+}
 
+/// Instantiate the module, with `out` as the output of "print", returning the instance and its
+/// memory.
+fn instantiate<W: std::fmt::Write + Send + 'static>(
+    binary: &[u8],
+    out: Arc<Mutex<W>>,
+) -> anyhow::Result<(Instance, Memory)> {
     let store = Store::default();
-    let module = Module::new(&store, &binary)?;
-    let memory = Memory::new(&store, MemoryType::new(1, None, false)).unwrap();
+    let module = Module::new(&store, binary)?;
+    // the memory has the size that the module requires
+    let memory_type = module
+        .imports()
+        .memories()
+        .next()
+        .map_or(MemoryType::new(1, None, false), |x| *x.ty());
+    let memory = Memory::new(&store, memory_type)?;
+    let writer = Writer {
+        w: out,
+        memory: memory.clone(),
+    };
     let import_object = imports! {
         "env" => {
-            "print" => Function::new_native_with_env(&store, writer, |out: &Writer<W>, x: f32| writeln!(&mut *out.w.lock().unwrap(), "{}", x)),
+            "print" => Function::new_native_with_env(&store, writer.clone(), |out: &Writer<W>, x: f32| writeln!(&mut *out.w.lock().unwrap(), "{}", x)),
+            "prints" => Function::new_native_with_env(&store, writer.clone(), print_string),
+            "printb" => Function::new_native_with_env(&store, writer, |out: &Writer<W>, x: i32| writeln!(&mut *out.w.lock().unwrap(), "{}", x)),
             "memory" => memory.clone(),
         }
     };
     let instance = Instance::new(&module, &import_object)?;
-    let main = instance.exports.get_function("main")?;
-    main.call(&[])?;
-    let mut data = unsafe { memory.data_unchecked() }.to_owned();
-    data.resize(100 * 100, 0);
-    Ok(data)
+    Ok((instance, memory))
+}
+
+/// Print the UTF-8 string at `ptr..ptr + len` in the memory.
+fn print_string<W: std::fmt::Write + Send>(
+    out: &Writer<W>,
+    ptr: u32,
+    len: u32,
+) -> Result<(), RuntimeError> {
+    let view = out.memory.view::<u8>();
+    let bytes: Vec<u8> = view
+        .get(ptr as usize..ptr as usize + len as usize)
+        .ok_or_else(|| RuntimeError::new("out of bounds memory access"))?
+        .iter()
+        .map(|x| x.get())
+        .collect();
+    write!(
+        &mut *out.w.lock().unwrap(),
+        "{}",
+        String::from_utf8_lossy(&bytes)
+    )
+    .unwrap();
+    Ok(())
 }
@@ -0,0 +1,115 @@
+//! The interactive REPL, started when no FILE is given.
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::{run_binary, ToWriteFmt};
+
+/// A line entered in the REPL.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input<'a> {
+    /// Source code, to be compiled and run.
+    Source(&'a str),
+    /// `:load <path>`, compile and run the contents of a file.
+    Load(&'a Path),
+    /// `:save <path>`, write the source code run in the session to a file.
+    Save(&'a Path),
+    /// `:verbose`, toggle printing the size of each compiled module.
+    Verbose,
+}
+
+/// Parse a line entered in the REPL. A line starting with `:` is a command, and anything else is
+/// source code.
+pub fn parse_input(line: &str) -> anyhow::Result<Input<'_>> {
+    let line = line.trim();
+    let command = match line.strip_prefix(':') {
+        Some(command) => command,
+        None => return Ok(Input::Source(line)),
+    };
+    let (name, path) = match command.split_once(char::is_whitespace) {
+        Some((name, path)) => (name, path.trim()),
+        None => (command, ""),
+    };
+    match name {
+        "load" | "save" if path.is_empty() => anyhow::bail!(":{} requires a path", name),
+        "load" => Ok(Input::Load(Path::new(path))),
+        "save" => Ok(Input::Save(Path::new(path))),
+        "verbose" => Ok(Input::Verbose),
+        _ => anyhow::bail!(
+            "unknown command :{}, expected :load, :save or :verbose",
+            name
+        ),
+    }
+}
+
+/// A summary of the size of a compiled module, like `compiled 42 bytes, 11 instructions`,
+/// counting the instructions of all functions.
+pub fn summary(binary: &[u8]) -> anyhow::Result<String> {
+    let functions = chasm_rs::instr::disassemble(binary)?;
+    let instructions: usize = functions.iter().map(|x| x.len()).sum();
+    Ok(format!(
+        "compiled {} bytes, {} instructions",
+        binary.len(),
+        instructions
+    ))
+}
+
+pub fn repl() -> anyhow::Result<()> {
+    // the source code of every line and file that compiled, in order
+    let mut session = String::new();
+    let mut verbose = false;
+    let mut line = String::new();
+    loop {
+        let mut stdout = std::io::stdout();
+        write!(stdout, ">> ").unwrap();
+        stdout.flush().unwrap();
+
+        line.clear();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let loaded;
+        let code = match parse_input(&line) {
+            Ok(Input::Source(code)) => code,
+            Ok(Input::Load(path)) => match std::fs::read_to_string(path) {
+                Ok(code) => {
+                    loaded = code;
+                    &loaded
+                }
+                Err(e) => {
+                    println!("error: failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            },
+            Ok(Input::Save(path)) => {
+                if let Err(e) = std::fs::write(path, &session) {
+                    println!("error: failed to write {}: {}", path.display(), e);
+                }
+                continue;
+            }
+            Ok(Input::Verbose) => {
+                verbose = !verbose;
+                println!("verbose {}", if verbose { "on" } else { "off" });
+                continue;
+            }
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        let binary = match chasm_rs::compile(code) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        session += code;
+        session.push('\n');
+        if verbose {
+            println!("{}", summary(&binary)?);
+        }
+        let out = Arc::new(Mutex::new(ToWriteFmt(std::io::stdout())));
+        run_binary(&binary, out, false)?;
+    }
+}
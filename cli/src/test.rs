@@ -0,0 +1,305 @@
+use super::*;
+
+#[test]
+fn ascii_art_gradient() {
+    // a horizontal gradient, from 0 to 198
+    let art: Vec<u8> = (0..100 * 100).map(|i| (i % 100) as u8 * 2).collect();
+
+    let row = format!(
+        "{}{}{}{}\n",
+        " ".repeat(32),
+        "-".repeat(32),
+        "=".repeat(32),
+        "####"
+    );
+    assert_eq!(ascii_art(&art, RAMP), row.repeat(100));
+
+    let row = format!("{}{}\n", ".".repeat(64), "@".repeat(36));
+    assert_eq!(ascii_art(&art, &['.', '@']), row.repeat(100));
+}
+
+#[test]
+fn gamma() {
+    assert_eq!(apply_gamma(&[0, 128, 255], 1.0), [0, 128, 255]);
+    // (128/255)^2.2 * 255 = 55.98
+    assert_eq!(apply_gamma(&[0, 128, 255], 2.2), [0, 56, 255]);
+}
+
+#[test]
+fn ascii_invert_brightness() {
+    assert_eq!(apply_brightness(&[0, 100, 200], 1.0), [0, 100, 200]);
+    // clamped to 255
+    assert_eq!(apply_brightness(&[0, 100, 200], 2.0), [0, 200, 255]);
+    assert_eq!(apply_brightness(&[0, 100, 200], 0.5), [0, 50, 100]);
+
+    // the first row is 0, the second is 70, and the others are 140
+    let art: Vec<u8> = (0..100 * 100)
+        .map(|i| match i / 100 {
+            0 => 0,
+            1 => 70,
+            _ => 140,
+        })
+        .collect();
+    let rows = |art: &[u8], ramp: &[char]| -> Vec<char> {
+        ascii_art(art, ramp)
+            .lines()
+            .take(3)
+            .map(|x| x.chars().next().unwrap())
+            .collect()
+    };
+    assert_eq!(rows(&art, &ascii_ramp(false)), [' ', '-', '=']);
+    assert_eq!(rows(&art, &ascii_ramp(true)), ['#', '=', '-']);
+    assert_eq!(
+        rows(&apply_brightness(&art, 2.0), &ascii_ramp(false)),
+        [' ', '=', '#']
+    );
+    assert_eq!(
+        rows(&apply_brightness(&art, 2.0), &ascii_ramp(true)),
+        ['#', '-', ' ']
+    );
+
+    let args = Args::parse(["--invert", "--brightness", "1.5"].map(String::from)).unwrap();
+    assert!(args.invert);
+    assert_eq!(args.brightness, Some(1.5));
+    assert!(Args::parse(["--brightness", "-1"].map(String::from)).is_err());
+}
+
+#[test]
+fn frames() {
+    let source = "
+        var n = 0
+        proc frame(t)
+            print t
+            setpixel(t, 0, (t + 1))
+        endproc";
+    let binary = chasm_rs::compile(source).unwrap();
+    let out = Arc::new(Mutex::new(String::new()));
+    let canvases = run_frames(&binary, out.clone(), 3, false).unwrap();
+
+    assert_eq!(*out.lock().unwrap(), "0\n1\n2\n");
+    assert_eq!(canvases.len(), 3);
+    assert_eq!(canvases[0][..4], [1, 0, 0, 0]);
+    assert_eq!(canvases[2][..4], [1, 2, 3, 0]);
+}
+
+#[test]
+fn verify_module() {
+    let options = chasm_rs::CompileOptions {
+        producers_section: false,
+        ..Default::default()
+    };
+    let mut binary = chasm_rs::compile_with_options("print 1", &options).unwrap();
+    assert!(verify(&binary).is_ok());
+
+    // without the custom section, the module ends with the "end" of "main", that is replaced by a
+    // invalid opcode
+    let last = binary.len() - 1;
+    assert_eq!(binary[last], 0x0b);
+    binary[last] = 0xff;
+    assert!(verify(&binary).is_err());
+}
+
+#[test]
+fn hexdump() {
+    let data = b"\0asm\x01\0\0\0hello, world";
+
+    assert_eq!(
+        dump_hex(data, 8),
+        "\
+0000: 00 61 73 6d 01 00 00 00 : .asm....
+0008: 68 65 6c 6c 6f 2c 20 77 : hello, w
+0010: 6f 72 6c 64             : orld
+"
+    );
+    assert_eq!(
+        dump_hex(data, 16),
+        "\
+0000: 00 61 73 6d 01 00 00 00 68 65 6c 6c 6f 2c 20 77 : .asm....hello, w
+0010: 6f 72 6c 64                                     : orld
+"
+    );
+    assert_eq!(dump_hex(&[], 16), "");
+
+    assert_eq!(
+        Args::parse(["--hexdump".to_string()]).unwrap().hexdump,
+        Some(16)
+    );
+    let args = Args::parse(["--hexdump=8".to_string()]).unwrap();
+    assert_eq!(args.hexdump, Some(8));
+    assert!(Args::parse(["--hexdump=0".to_string()]).is_err());
+}
+
+#[test]
+fn json_error() {
+    let error = chasm_rs::compile("print print").unwrap_err();
+    assert_eq!(
+        json_errors(&[error]),
+        r#"[{"message":"unexpected token value, expected <number> or \"(\", received \"print\"","line":1,"column":7,"span_start":6,"span_end":11,"code":"E0001"}]"#
+    );
+}
+
+#[test]
+fn time_limit() {
+    let source = "
+        setpixel(0, 0, 7)
+        while (1 == 1)
+            setpixel(1, 0, 8)
+        endwhile";
+    let binary = chasm_rs::compile(source).unwrap();
+
+    let start = std::time::Instant::now();
+    let output =
+        run_with_time_limit(&binary, Duration::from_millis(100), Default::default()).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(output.interrupted);
+    assert_eq!(output.canvas[..3], [7, 8, 0]);
+
+    let binary = chasm_rs::compile("print 1").unwrap();
+    let output = run_with_time_limit(&binary, Duration::from_secs(10), Default::default()).unwrap();
+    assert!(!output.interrupted);
+    assert_eq!(output.printed, "1\n");
+}
+
+#[test]
+fn explain_code() {
+    let text = explain::explain("E0003").unwrap();
+    assert!(text.contains("number of arguments"));
+    assert_eq!(explain::explain("e0003"), Some(text));
+    assert_eq!(explain::explain("E9999"), None);
+    for i in 1..=25 {
+        assert!(explain::explain(&format!("E{:04}", i)).is_some());
+    }
+
+    let args = Args::parse(vec!["--explain".to_string(), "E0001".to_string()]).unwrap();
+    assert_eq!(args.explain.as_deref(), Some("E0001"));
+}
+
+#[test]
+fn profile() {
+    let source = "
+        proc draw(x) setpixel(x, 0, 255) endproc
+        proc unused() pass endproc
+        repeat 3 draw(1) endrepeat";
+    // the procedures come from the module that runs, compiled with the same options
+    let options = chasm_rs::CompileOptions {
+        optimize: true,
+        ..Default::default()
+    };
+    let module = chasm_rs::compile_to_module(source, &options).unwrap();
+    let options = chasm_rs::run::RunOptions {
+        profile: true,
+        ..Default::default()
+    };
+    let output = chasm_rs::run::run_binary(&module.serialize(), &options).unwrap();
+    assert_eq!(
+        profile_report(&module.procedures, &output.calls),
+        "     calls procedure\n         3 draw\n         1 main\n         0 unused\n"
+    );
+}
+
+#[test]
+fn stdin_program() {
+    let args = Args::parse(vec!["-".to_string(), "ascii".to_string()]).unwrap();
+    assert!(args.stdin && args.ascii && args.path.is_none());
+    let args = Args::parse(vec!["--stdin".to_string()]).unwrap();
+    assert!(args.stdin && args.path.is_none());
+
+    let code = read_program(&b"print 1 setpixel(0, 0, 255)\n"[..]).unwrap();
+    let binary = chasm_rs::compile(&code).unwrap();
+    let out = Arc::new(Mutex::new(String::new()));
+    let art = run_binary(&binary, out.clone(), false).unwrap();
+    assert_eq!(*out.lock().unwrap(), "1\n");
+    assert_eq!(art[0], 255);
+
+    let err = read_program(&b" \n"[..]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "no program was given in the standard input"
+    );
+}
+
+#[test]
+fn window_fallback() {
+    let failed = || Err::<(), _>(std::io::Error::other("no display"));
+    assert!(window_or_fallback(failed(), false).unwrap().is_none());
+    let err = window_or_fallback(failed(), true).unwrap_err();
+    assert_eq!(err.to_string(), "no display");
+    assert_eq!(
+        window_or_fallback(Ok::<_, std::io::Error>(7), false).unwrap(),
+        Some(7)
+    );
+}
+
+#[test]
+fn repl_commands() {
+    use repl::{parse_input, Input};
+
+    assert_eq!(
+        parse_input(":load examples/gradient.chasm\n").unwrap(),
+        Input::Load(Path::new("examples/gradient.chasm"))
+    );
+    assert_eq!(
+        parse_input("  :save  session.chasm ").unwrap(),
+        Input::Save(Path::new("session.chasm"))
+    );
+    assert_eq!(parse_input("print 1\n").unwrap(), Input::Source("print 1"));
+    assert_eq!(
+        parse_input(":load").unwrap_err().to_string(),
+        ":load requires a path"
+    );
+    assert_eq!(
+        parse_input(":quit").unwrap_err().to_string(),
+        "unknown command :quit, expected :load, :save or :verbose"
+    );
+    assert_eq!(parse_input(":verbose").unwrap(), Input::Verbose);
+}
+
+#[test]
+fn repl_summary() {
+    let binary = chasm_rs::compile("print 2").unwrap();
+    assert_eq!(
+        repl::summary(&binary).unwrap(),
+        format!("compiled {} bytes, 3 instructions", binary.len())
+    );
+}
+
+#[test]
+fn rgb_canvas() {
+    let binary = chasm_rs::compile("setrgb(1, 0, 255, 0, 0) setrgb(2, 0, 0, 0, 255)").unwrap();
+    let out = Arc::new(Mutex::new(String::new()));
+    let rgb = run_binary(&binary, out, true).unwrap();
+    assert_eq!(rgb.len(), 30000);
+    assert_eq!(rgb[..9], [0, 0, 0, 255, 0, 0, 0, 0, 255]);
+    assert_eq!(luma(&rgb[..9]), [0, 76, 29]);
+    assert!(Args::parse(["--rgb"].map(String::from)).unwrap().rgb);
+}
+
+#[test]
+fn pretty() {
+    let path = std::env::temp_dir().join(format!("chasm_pretty_{}.chasm", std::process::id()));
+    let ugly = "var x=1\nwhile (x<3)\n  x=(x+1) endwhile";
+    let canonical = "var x = 1\nwhile (x < 3)\n    x = (x + 1)\nendwhile\n";
+    std::fs::write(&path, ugly).unwrap();
+
+    let formatted = chasm_rs::format(ugly).unwrap();
+    assert_eq!(formatted, canonical);
+    // --check doesn't change the file
+    assert!(!write_formatted(&path, ugly, &formatted, true).unwrap());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), ugly);
+
+    assert!(write_formatted(&path, ugly, &formatted, false).unwrap());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), canonical);
+    assert!(write_formatted(&path, canonical, &formatted, true).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    let args = Args::parse(["--pretty", "--check", "a.chasm"].map(String::from)).unwrap();
+    assert!(args.pretty && args.check);
+}
+
+#[test]
+fn emit_imports() {
+    let args = Args::parse(["--emit", "imports", "a.chasm"].map(String::from)).unwrap();
+    assert!(args.emit_imports);
+    assert!(Args::parse(["--emit", "wat"].map(String::from)).is_err());
+    assert!(Args::parse(["--emit"].map(String::from)).is_err());
+}
@@ -5,7 +5,7 @@ const canvas = document.getElementById("canvas");
 const compileButton = document.getElementById("compile");
 const outputArea = document.getElementById("output");
 
-let keywords = [ "print", "var", "while", "endwhile", "if", "endif", "else", "proc", "endproc" ];
+let keywords = [ "print", "prints", "var", "global", "data", "while", "endwhile", "if", "endif", "else", "proc", "endproc", "loop", "endloop", "break", "return" ];
 CodeMirror.defineSimpleMode("simplemode", {
     start: [
         {
@@ -63,6 +63,7 @@ function updateCanvas(buffer) {
 
 let marker;
 const logMessage = (message) => (outputArea.value = outputArea.value + message + "\n");
+const logText = (text) => (outputArea.value = outputArea.value + text);
 const markError = (token) => {
     marker = editor.markText({ line: token.line, ch: token.char }, { line: token.line, ch: token.char + token.value.length }, { className: "error" });
     console.log(marker);
@@ -82,6 +83,8 @@ async function runCode() {
         let wasm = await WebAssembly.instantiate(bin, {
             env: {
                 print: logMessage,
+                prints: (ptr, len) => logText(new TextDecoder().decode(new Uint8Array(memory.buffer, ptr, len))),
+                printb: logMessage,
                 memory: memory,
             }
         })
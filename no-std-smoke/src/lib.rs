@@ -0,0 +1,22 @@
+//! A `no_std` crate that uses chasm-rs, to check that the compiler builds and works with only
+//! `core` and `alloc`. Run with `cargo test --manifest-path no-std-smoke/Cargo.toml`.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// Compile `source` to a WebAssembly module, returning `None` if it has a error.
+pub fn compile(source: &str) -> Option<Vec<u8>> {
+    chasm_rs::compile(source).ok()
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn compile() {
+        let wasm = super::compile("var x = 1\nwhile (x < 10)\n    x = (x * 2)\nendwhile\nprint x").unwrap();
+        assert_eq!(&wasm[..4], b"\0asm");
+        assert!(super::compile("print (1 +").is_none());
+    }
+}